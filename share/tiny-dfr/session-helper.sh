@@ -0,0 +1,146 @@
+#!/bin/sh
+# Speaks tiny-dfr announcements (Announcements = true in config.toml),
+# plays press sounds (PressSounds = true), applies volume sliders
+# (Slider = "volume" and "app-volume") and SinkToggle buttons and reports the
+# volume for them and Volume buttons. The daemon runs as nobody and
+# cannot reach your audio server, so run this inside your graphical session,
+# e.g. from your compositor's autostart.
+# Needs socat and jq, spd-say (speech-dispatcher) or espeak-ng for speech and
+# canberra-gtk-play or pw-play for sounds, wpctl or pactl for volume,
+# pw-dump and wpctl for app volume, pactl for SinkToggle and to follow
+# volume changes made elsewhere.
+
+SOCKET=/run/tiny-dfr/control.sock
+# the default sink before a SinkToggle switched away from it
+RAW_SINK_FILE="${XDG_RUNTIME_DIR:-/tmp}/tiny-dfr-raw-sink"
+
+if command -v spd-say >/dev/null 2>&1; then
+    say() { spd-say --wait -- "$1"; }
+else
+    say() { espeak-ng -- "$1"; }
+fi
+
+# $1 sound event id, $2 theme name (may be empty)
+play() {
+    if command -v canberra-gtk-play >/dev/null 2>&1; then
+        if [ -n "$2" ]; then
+            canberra-gtk-play -i "$1" --property=canberra.xdg-theme.name="$2" &
+        else
+            canberra-gtk-play -i "$1" &
+        fi
+    else
+        file="/usr/share/sounds/${2:-freedesktop}/stereo/$1.oga"
+        [ -f "$file" ] && pw-play "$file" &
+    fi
+}
+
+# $1 volume in percent
+set_volume() {
+    if command -v wpctl >/dev/null 2>&1; then
+        wpctl set-volume @DEFAULT_AUDIO_SINK@ "$1%"
+    else
+        pactl set-sink-volume @DEFAULT_SINK@ "$1%"
+    fi
+}
+
+# tells the daemon the volume and mute of the default output
+report_volume() {
+    if command -v wpctl >/dev/null 2>&1; then
+        level=$(wpctl get-volume @DEFAULT_AUDIO_SINK@) || return
+        volume=$(printf '%s' "$level" | awk '{ printf "%d", $2 * 100 + 0.5 }')
+    else
+        level=$(pactl get-sink-mute @DEFAULT_SINK@) || return
+        volume=$(pactl get-sink-volume @DEFAULT_SINK@ \
+            | awk -F/ 'NR == 1 { gsub(/[ %]/, "", $2); print $2 }')
+    fi
+    case "$level" in
+        *MUTED* | *yes*) muted=true ;;
+        *) muted=false ;;
+    esac
+    printf '{"Volume": [%s, %s]}\n' "$volume" "$muted" \
+        | socat - "UNIX-CONNECT:$SOCKET" >/dev/null
+}
+
+# $1 app_id, ids of its playback streams, matched against the application
+# id, name and binary of the PipeWire node
+app_streams() {
+    pw-dump 2>/dev/null | jq -r --arg app "$1" '
+        .[] | select(.type == "PipeWire:Interface:Node")
+        | select(.info.props["media.class"] == "Stream/Output/Audio")
+        | select([.info.props["application.id"], .info.props["application.name"],
+                  .info.props["application.process.binary"],
+                  .info.props["pipewire.access.portal.app_id"]]
+                 | map(select(. != null) | ascii_downcase)
+                 | index($app | ascii_downcase))
+        | .id'
+}
+
+# $1 app_id, tells the daemon the volume of its first stream
+report_app_volume() {
+    id=$(app_streams "$1" | head -n 1)
+    [ -n "$id" ] || return
+    volume=$(wpctl get-volume "$id" | awk '{ printf "%d", $2 * 100 + 0.5 }')
+    printf '{"AppVolume": [%s, %s]}\n' "$(printf '%s' "$1" | jq -R .)" "$volume" \
+        | socat - "UNIX-CONNECT:$SOCKET" >/dev/null
+}
+
+# $1 app_id, $2 volume in percent
+set_app_volume() {
+    for id in $(app_streams "$1"); do
+        wpctl set-volume "$id" "$2%"
+    done
+}
+
+# tells the daemon which sink is the default output
+report_default_sink() {
+    sink=$(pactl get-default-sink 2>/dev/null) || return
+    printf '{"DefaultSink": %s}\n' "$(printf '%s' "$sink" | jq -R .)" \
+        | socat - "UNIX-CONNECT:$SOCKET" >/dev/null
+}
+
+# $1 sink name, made the default or swapped back for the one it replaced
+toggle_sink() {
+    current=$(pactl get-default-sink)
+    if [ "$current" = "$1" ]; then
+        [ -s "$RAW_SINK_FILE" ] && pactl set-default-sink "$(cat "$RAW_SINK_FILE")"
+    else
+        printf '%s' "$current" > "$RAW_SINK_FILE"
+        pactl set-default-sink "$1"
+    fi
+    report_default_sink
+}
+
+# volume keys, other mixers and a new default sink change what the bar shows
+pactl subscribe 2>/dev/null | while IFS= read -r event; do
+    case "$event" in
+        *"on sink "*) report_volume ;;
+        *"on server"*) report_default_sink; report_volume ;;
+    esac
+done &
+
+while true; do
+    { echo '"EventStream"'; sleep infinity; } \
+        | socat - "UNIX-CONNECT:$SOCKET" \
+        | while IFS= read -r line; do
+            # connected, the daemon does not know the default sink or volume yet
+            [ "$line" = '{"Ok":null}' ] && { report_default_sink; report_volume; } &
+            text=$(printf '%s' "$line" | jq -r '.Announce // empty')
+            [ -n "$text" ] && say "$text"
+            sound=$(printf '%s' "$line" | jq -r '.Sound.id // empty')
+            if [ -n "$sound" ]; then
+                play "$sound" "$(printf '%s' "$line" | jq -r '.Sound.theme // empty')"
+            fi
+            volume=$(printf '%s' "$line" | jq -r '.Volume // empty')
+            [ -n "$volume" ] && set_volume "$volume"
+            app=$(printf '%s' "$line" | jq -r '.FocusedApp // empty')
+            [ -n "$app" ] && report_app_volume "$app" &
+            sink=$(printf '%s' "$line" | jq -r '.ToggleSink // empty')
+            [ -n "$sink" ] && toggle_sink "$sink" &
+            app=$(printf '%s' "$line" | jq -r '.AppVolume.app_id // empty')
+            if [ -n "$app" ]; then
+                set_app_volume "$app" "$(printf '%s' "$line" | jq -r '.AppVolume.percent')"
+            fi
+        done
+    # daemon restarted or not up yet
+    sleep 2
+done
@@ -0,0 +1,129 @@
+use serde::Deserialize;
+use std::time::Instant;
+
+// how a fade moves from start to end over its duration
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    #[default]
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    fn apply(self, t: f64) -> f64 {
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut if t < 0.5 => 2.0 * t * t,
+            Easing::EaseInOut => 1.0 - (2.0 - 2.0 * t).powi(2) / 2.0,
+        }
+    }
+}
+
+// A transition between two levels, 0 is a button at rest and 1 a pressed one.
+#[derive(Clone, Copy)]
+pub struct Fade {
+    from: f64,
+    pub to: f64,
+    started: Instant,
+}
+
+impl Fade {
+    pub fn new(from: f64, to: f64) -> Fade {
+        Fade { from, to, started: Instant::now() }
+    }
+
+    fn progress(&self, duration_ms: u32) -> f64 {
+        (self.started.elapsed().as_millis() as f64 / duration_ms.max(1) as f64).min(1.0)
+    }
+
+    pub fn finished(&self, duration_ms: u32) -> bool {
+        self.progress(duration_ms) >= 1.0
+    }
+
+    pub fn level(&self, duration_ms: u32, easing: Easing) -> f64 {
+        if self.finished(duration_ms) {
+            return self.to;
+        }
+        self.from + (self.to - self.from) * easing.apply(self.progress(duration_ms))
+    }
+}
+
+pub fn mix(a: (f64, f64, f64), b: (f64, f64, f64), t: f64) -> (f64, f64, f64) {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t, a.2 + (b.2 - a.2) * t)
+}
+
+// px per ms lost per ms of flinging, and the speed it stops below
+const SCROLL_FRICTION: f64 = 0.004;
+const SCROLL_MIN_SPEED: f64 = 0.02;
+// a finger that rested this long before lifting flings nothing
+const SCROLL_FLING_MS: u128 = 80;
+
+// How far a layer wider than the bar is scrolled, follows the finger while
+// dragged and keeps going with the speed it was let go with.
+#[derive(Default)]
+pub struct Scroll {
+    pub offset: f64,
+    // px per ms, positive moves the content left
+    velocity: f64,
+    last: Option<Instant>,
+    dragging: bool,
+}
+
+impl Scroll {
+    // a finger came down, a fling in progress stops under it
+    pub fn grab(&mut self) {
+        self.velocity = 0.0;
+        self.last = None;
+    }
+
+    pub fn drag(&mut self, dx: f64, max: f64) {
+        let now = Instant::now();
+        if let Some(last) = self.last {
+            let ms = (now - last).as_secs_f64() * 1000.0;
+            if ms > 0.0 {
+                // smoothed, single motion events are jittery
+                self.velocity = self.velocity * 0.5 + (-dx / ms) * 0.5;
+            }
+        }
+        self.dragging = true;
+        self.offset = (self.offset - dx).clamp(0.0, max.max(0.0));
+        self.last = Some(now);
+    }
+
+    pub fn release(&mut self, fling: bool) {
+        let rested = self.last.map_or(true, |l| l.elapsed().as_millis() > SCROLL_FLING_MS);
+        if !fling || rested {
+            self.velocity = 0.0;
+        }
+        self.dragging = false;
+        self.last = Some(Instant::now());
+    }
+
+    pub fn moving(&self) -> bool {
+        !self.dragging && self.velocity != 0.0
+    }
+
+    // advances a fling, true if the offset moved
+    pub fn step(&mut self, max: f64) -> bool {
+        if !self.moving() {
+            return false;
+        }
+        let now = Instant::now();
+        let ms = self.last.map_or(0.0, |l| (now - l).as_secs_f64() * 1000.0);
+        self.last = Some(now);
+        self.offset += self.velocity * ms;
+        if self.offset <= 0.0 || self.offset >= max {
+            self.offset = self.offset.clamp(0.0, max.max(0.0));
+            self.velocity = 0.0;
+            return true;
+        }
+        let speed = (self.velocity.abs() - SCROLL_FRICTION * ms).max(0.0);
+        self.velocity = if speed < SCROLL_MIN_SPEED { 0.0 } else { speed.copysign(self.velocity) };
+        true
+    }
+}
@@ -1,8 +1,9 @@
 use crate::config::Config;
-use crate::TIMEOUT_MS;
+use crate::error;
 use anyhow::{anyhow, Result};
 use input::event::{
     switch::{Switch, SwitchEvent, SwitchState},
+    touch::TouchEvent,
     Event,
 };
 use std::{
@@ -10,21 +11,28 @@ use std::{
     fs::{self, File, OpenOptions},
     io::Write,
     path::{Path, PathBuf},
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 const MAX_DISPLAY_BRIGHTNESS: u32 = 509;
 const MAX_TOUCH_BAR_BRIGHTNESS: u32 = 255;
-const BRIGHTNESS_DIM_TIMEOUT: i32 = TIMEOUT_MS * 3; // should be a multiple of TIMEOUT_MS
-const BRIGHTNESS_OFF_TIMEOUT: i32 = TIMEOUT_MS * 6; // should be a multiple of TIMEOUT_MS
 const DIMMED_BRIGHTNESS: u32 = 1;
+// how often the light sensor is read, and how quickly the bar follows it
+const ALS_POLL_MS: u64 = 1000;
+const ALS_SMOOTHING_MS: f64 = 1000.0;
+// steps of a transition in progress
+const ALS_STEP_MS: u64 = 50;
 
-fn read_attr(path: &Path, attr: &str) -> u32 {
-    fs::read_to_string(path.join(attr))
-        .unwrap_or_else(|_| panic!("Failed to read {attr}"))
+fn read_attr(path: &Path, attr: &str) -> error::Result<u32> {
+    fs::read_to_string(path.join(attr))?
         .trim()
         .parse::<u32>()
-        .unwrap_or_else(|_| panic!("Failed to parse {attr}"))
+        .map_err(|_| error::Error::Parse(path.join(attr).display().to_string()))
+}
+
+// only at startup, there is nothing to fall back to without them
+fn read_attr_or_panic(path: &Path, attr: &str) -> u32 {
+    read_attr(path, attr).unwrap_or_else(|e| panic!("Failed to read {attr}: {e}"))
 }
 
 fn find_backlight() -> Result<PathBuf> {
@@ -61,8 +69,85 @@ fn find_display_backlight() -> Result<PathBuf> {
     Err(anyhow!("No Built-in Retina Display backlight device found"))
 }
 
+// the first iio device reporting illuminance, the ALS of T2 Macs is a HID sensor
+fn find_light_sensor() -> Option<PathBuf> {
+    fs::read_dir("/sys/bus/iio/devices/")
+        .ok()?
+        .flatten()
+        .map(|e| e.path())
+        .find(|p| p.join("in_illuminance_input").exists() || p.join("in_illuminance_raw").exists())
+}
+
+fn read_lux(dir: &Path) -> Option<f64> {
+    let num = |attr: &str| {
+        fs::read_to_string(dir.join(attr))
+            .ok()
+            .and_then(|s| s.trim().parse::<f64>().ok())
+    };
+    num("in_illuminance_input").or_else(|| {
+        let raw = num("in_illuminance_raw")?;
+        let offset = num("in_illuminance_offset").unwrap_or(0.0);
+        Some((raw + offset) * num("in_illuminance_scale").unwrap_or(1.0))
+    })
+}
+
+// AmbientLightCurve, the level for a lux reading, interpolated between the points
+fn curve_level(curve: &[(f64, u32)], lux: f64) -> f64 {
+    let (Some(first), Some(last)) = (curve.first(), curve.last()) else {
+        return 0.0;
+    };
+    if lux <= first.0 {
+        return first.1 as f64;
+    }
+    if lux >= last.0 {
+        return last.1 as f64;
+    }
+    let i = curve.iter().position(|p| p.0 >= lux).unwrap();
+    let ((x0, l0), (x1, l1)) = (curve[i - 1], curve[i]);
+    let t = (lux - x0) / (x1 - x0).max(f64::EPSILON);
+    l0 as f64 + (l1 as f64 - l0 as f64) * t
+}
+
+// the light sensor and the level eased towards what the curve asks for
+struct AmbientLight {
+    path: PathBuf,
+    target: f64,
+    level: Option<f64>,
+    read_at: Option<Instant>,
+    stepped_at: Instant,
+}
+
+impl AmbientLight {
+    fn level(&mut self, curve: &[(f64, u32)]) -> u32 {
+        if self.read_at.map_or(true, |t| t.elapsed().as_millis() as u64 >= ALS_POLL_MS) {
+            self.read_at = Some(Instant::now());
+            match read_lux(&self.path) {
+                Some(lux) => self.target = curve_level(curve, lux),
+                None => eprintln!("[backlight] failed to read {}", self.path.display()),
+            }
+        }
+        let dt = self.stepped_at.elapsed().as_millis() as f64;
+        self.stepped_at = Instant::now();
+        // exponential, big jumps in the light are followed quickly at first
+        let level = match self.level {
+            Some(level) if (self.target - level).abs() >= 1.0 => {
+                level + (self.target - level) * (1.0 - (-dt / ALS_SMOOTHING_MS).exp())
+            }
+            _ => self.target,
+        };
+        self.level = Some(level);
+        level.round() as u32
+    }
+
+    fn settled(&self) -> bool {
+        self.level.is_some_and(|level| (self.target - level).abs() < 1.0)
+    }
+}
+
 fn set_backlight(mut file: &File, value: u32) {
-    file.write_all(format!("{}\n", value).as_bytes()).unwrap();
+    if let Err(e) = file.write_all(format!("{}\n", value).as_bytes()) {
+        eprintln!("[backlight] failed to set brightness {}: {}", value, e);
+    }
 }
 
 pub struct BacklightManager {
@@ -70,8 +155,25 @@ pub struct BacklightManager {
     max_bl: u32,
     current_bl: u32,
     lid_state: SwitchState,
+    // forced off until the next touch
+    blanked: bool,
+    // the Dark OffLayer is shown, off until another layer is
+    layer_off: bool,
+    // AmbientLightCurve, when the machine has a light sensor
+    ambient: Option<AmbientLight>,
+    // FollowSeatIdle, None when off and Some(None) while the seat is in use
+    seat_idle: Option<Option<Instant>>,
+    // the system is about to sleep
+    suspended: bool,
+    // written again on the next update, the hardware may have lost it
+    stale: bool,
+    // presentation mode, full configured brightness and no idle dimming
+    boosted: bool,
     bl_file: File,
     display_bl_path: PathBuf,
+    // opened before privilege drop for Slider = "brightness"
+    display_bl_file: Option<File>,
+    display_max_bl: u32,
 }
 
 impl BacklightManager {
@@ -82,13 +184,32 @@ impl BacklightManager {
             .write(true)
             .open(bl_path.join("brightness"))
             .unwrap();
+        let display_bl_file = OpenOptions::new()
+            .write(true)
+            .open(display_bl_path.join("brightness"))
+            .ok();
         BacklightManager {
             bl_file,
             lid_state: SwitchState::Off,
-            max_bl: read_attr(&bl_path, "max_brightness"),
-            current_bl: read_attr(&bl_path, "brightness"),
+            blanked: false,
+            layer_off: false,
+            seat_idle: None,
+            ambient: find_light_sensor().map(|path| AmbientLight {
+                path,
+                target: 0.0,
+                level: None,
+                read_at: None,
+                stepped_at: Instant::now(),
+            }),
+            suspended: false,
+            stale: false,
+            boosted: false,
+            max_bl: read_attr_or_panic(&bl_path, "max_brightness"),
+            current_bl: read_attr_or_panic(&bl_path, "brightness"),
             last_active: Instant::now(),
+            display_max_bl: read_attr_or_panic(&display_bl_path, "max_brightness"),
             display_bl_path,
+            display_bl_file,
         }
     }
     fn display_to_touchbar(display: u32, active_brightness: u32) -> u32 {
@@ -99,6 +220,10 @@ impl BacklightManager {
     }
     pub fn process_event(&mut self, event: &Event) {
         match event {
+            Event::Touch(TouchEvent::Down(_)) => {
+                self.blanked = false;
+                self.last_active = Instant::now();
+            }
             Event::Keyboard(_) | Event::Pointer(_) | Event::Gesture(_) | Event::Touch(_) => {
                 self.last_active = Instant::now();
             }
@@ -114,33 +239,120 @@ impl BacklightManager {
             _ => {}
         }
     }
+    // touches on the bar count even while the seat is idle, they never reach
+    // the compositor
+    fn idle_for(&self) -> Duration {
+        let local = self.last_active.elapsed();
+        match self.seat_idle {
+            None => local,
+            Some(None) => Duration::ZERO,
+            Some(Some(since)) => local.min(since.elapsed()),
+        }
+    }
+    pub fn set_seat_idle(&mut self, seat_idle: Option<Option<Instant>>) {
+        self.seat_idle = seat_idle;
+    }
+    // until the idle timeouts or the light sensor change the level next, to
+    // wake up in time
+    pub fn next_change_ms(&self, cfg: &Config) -> Option<u64> {
+        let idle = self.idle_for().as_millis() as u64;
+        let timeout = [cfg.dim_timeout_ms, cfg.off_timeout_ms]
+            .into_iter()
+            .map(u64::from)
+            .find(|&t| idle < t)
+            .map(|t| t - idle);
+        let ambient = match self.ambient {
+            Some(ref als) if !cfg.ambient_light_curve.is_empty() && idle < cfg.dim_timeout_ms as u64 => {
+                Some(if als.settled() { ALS_POLL_MS } else { ALS_STEP_MS })
+            }
+            _ => None,
+        };
+        timeout.into_iter().chain(ambient).min()
+    }
     pub fn update_backlight(&mut self, cfg: &Config) {
-        let since_last_active = (Instant::now() - self.last_active).as_millis() as u64;
+        let since_last_active = self.idle_for().as_millis() as u64;
         let new_bl = min(
             self.max_bl,
-            if self.lid_state == SwitchState::On {
+            if self.lid_state == SwitchState::On || self.blanked || self.layer_off || self.suspended {
                 0
-            } else if since_last_active < BRIGHTNESS_DIM_TIMEOUT as u64 {
-                if cfg.adaptive_brightness {
-                    BacklightManager::display_to_touchbar(
-                        read_attr(&self.display_bl_path, "brightness"),
-                        cfg.active_brightness,
-                    )
+            } else if self.boosted {
+                cfg.presentation_brightness
+            } else if since_last_active < cfg.dim_timeout_ms as u64 {
+                if let (Some(als), false) = (&mut self.ambient, cfg.ambient_light_curve.is_empty()) {
+                    // kept above off, like the display following curve
+                    als.level(&cfg.ambient_light_curve).max(1)
+                } else if cfg.adaptive_brightness {
+                    // the fixed brightness until the display's can be read again
+                    match read_attr(&self.display_bl_path, "brightness") {
+                        Ok(display) => {
+                            BacklightManager::display_to_touchbar(display, cfg.active_brightness)
+                        }
+                        Err(_) => cfg.active_brightness,
+                    }
                 } else {
                     cfg.active_brightness
                 }
-            } else if since_last_active < BRIGHTNESS_OFF_TIMEOUT as u64 {
+            } else if since_last_active < cfg.off_timeout_ms as u64 {
                 DIMMED_BRIGHTNESS
             } else {
                 0
             },
         );
-        if self.current_bl != new_bl {
+        if self.current_bl != new_bl || self.stale {
+            self.stale = false;
             self.current_bl = new_bl;
             set_backlight(&self.bl_file, self.current_bl);
         }
     }
+    pub fn blank(&mut self) {
+        self.blanked = true;
+    }
+    pub fn set_layer_off(&mut self, off: bool) {
+        self.layer_off = off;
+    }
+    pub fn set_suspended(&mut self, suspended: bool) {
+        self.suspended = suspended;
+        self.stale = !suspended;
+        self.last_active = Instant::now();
+    }
+    pub fn set_boost(&mut self, boosted: bool) {
+        self.boosted = boosted;
+        self.last_active = Instant::now();
+    }
     pub fn current_bl(&self) -> u32 {
         self.current_bl
     }
+    // idle and turned down, but not off yet
+    pub fn dimmed(&self) -> bool {
+        self.current_bl > 0 && self.current_bl <= DIMMED_BRIGHTNESS
+    }
+    // the DimTheme contrast for the current level, interpolated between the points
+    pub fn theme_contrast(&self, curve: &[(u32, f64)]) -> f64 {
+        let level = self.current_bl;
+        let (Some(first), Some(last)) = (curve.first(), curve.last()) else {
+            return 1.0;
+        };
+        if level <= first.0 {
+            return first.1.clamp(0.0, 1.0);
+        }
+        if level >= last.0 {
+            return last.1.clamp(0.0, 1.0);
+        }
+        let i = curve.iter().position(|p| p.0 >= level).unwrap();
+        let ((l0, c0), (l1, c1)) = (curve[i - 1], curve[i]);
+        let t = (level - l0) as f64 / (l1 - l0).max(1) as f64;
+        (c0 + (c1 - c0) * t).clamp(0.0, 1.0)
+    }
+    pub fn max_bl(&self) -> u32 {
+        self.max_bl
+    }
+    pub fn display_brightness_percent(&self) -> u32 {
+        read_attr(&self.display_bl_path, "brightness").unwrap_or(0) * 100 / self.display_max_bl.max(1)
+    }
+    pub fn set_display_brightness(&mut self, percent: u32) {
+        if let Some(ref file) = self.display_bl_file {
+            // 0 turns the panel off, which is not what dragging to the left means
+            set_backlight(file, (self.display_max_bl * percent.min(100) / 100).max(1));
+        }
+    }
 }
@@ -0,0 +1,207 @@
+use crate::bus;
+use dbus::{
+    arg::{prop_cast, PropMap},
+    blocking::{
+        stdintf::org_freedesktop_dbus::{ObjectManager, PropertiesPropertiesChanged},
+        Connection,
+    },
+    channel::BusType,
+    message::SignalArgs,
+    Message,
+};
+use std::{
+    collections::HashMap,
+    os::unix::io::{AsFd, BorrowedFd},
+};
+
+const BLUEZ_DEST: &str = "org.bluez";
+const DEVICE_IFACE: &str = "org.bluez.Device1";
+const BATTERY_IFACE: &str = "org.bluez.Battery1";
+// BluetoothBattery value that follows whichever audio device connected first
+pub const FIRST_AUDIO: &str = "audio";
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct DeviceState {
+    pub alias: Option<String>,
+    pub connected: bool,
+    // a Connect/Disconnect call is in flight
+    pub pending: bool,
+    // percent from org.bluez.Battery1, for devices that report it
+    pub battery: Option<u8>,
+    path: Option<String>,
+    // BlueZ icon is audio-headset, audio-headphones, audio-card and so on
+    audio: bool,
+    // order in which devices connected, for FIRST_AUDIO
+    connected_seq: u64,
+}
+
+pub struct BluetoothManager {
+    conn: Connection,
+    // keyed by upper case MAC address, the configured ones and everything BlueZ knows
+    devices: HashMap<String, DeviceState>,
+    next_seq: u64,
+    // serials of Connect/Disconnect calls in flight, to the device they were for
+    calls: HashMap<u32, String>,
+}
+
+pub fn normalize_mac(mac: &str) -> String {
+    mac.trim().to_ascii_uppercase().replace('-', ":")
+}
+
+impl BluetoothManager {
+    pub fn connect(macs: Vec<String>) -> Option<BluetoothManager> {
+        let conn = match bus::open(BusType::System) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[bluetooth] system bus unavailable: {}", e);
+                return None;
+            }
+        };
+        for rule in [
+            "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='org.bluez.Device1'",
+            "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='org.bluez.Battery1'",
+            "type='signal',sender='org.bluez',interface='org.freedesktop.DBus.ObjectManager'",
+        ] {
+            if let Err(e) = conn.add_match_no_cb(rule) {
+                eprintln!("[bluetooth] failed to subscribe: {}", e);
+                return None;
+            }
+        }
+        let devices = macs
+            .into_iter()
+            .map(|m| (normalize_mac(&m), DeviceState::default()))
+            .collect();
+        let mut mgr = BluetoothManager { conn, devices, next_seq: 0, calls: HashMap::new() };
+        mgr.refresh();
+        Some(mgr)
+    }
+
+    fn refresh(&mut self) {
+        let proxy = self.conn.with_proxy(BLUEZ_DEST, "/", bus::CALL_TIMEOUT);
+        let objects = match proxy.get_managed_objects() {
+            Ok(o) => o,
+            Err(e) => {
+                eprintln!("[bluetooth] failed to list devices: {}", e);
+                return;
+            }
+        };
+        for dev in self.devices.values_mut() {
+            dev.path = None;
+        }
+        for (path, ifaces) in objects {
+            let Some(props) = ifaces.get(DEVICE_IFACE) else { continue };
+            let Some(addr) = prop_cast::<String>(props, "Address") else { continue };
+            let dev = self.devices.entry(normalize_mac(addr)).or_default();
+            dev.path = Some(path.to_string());
+            dev.battery = None;
+            apply_props(dev, props, &mut self.next_seq);
+            if let Some(battery) = ifaces.get(BATTERY_IFACE) {
+                apply_props(dev, battery, &mut self.next_seq);
+            }
+        }
+        // unpaired since, kept around only for configured buttons
+        for dev in self.devices.values_mut() {
+            if dev.path.is_none() {
+                dev.connected = false;
+                dev.battery = None;
+            }
+        }
+    }
+
+    pub fn device(&self, mac: &str) -> Option<&DeviceState> {
+        self.devices.get(&normalize_mac(mac))
+    }
+
+    // a MAC address or FIRST_AUDIO
+    pub fn battery_device(&self, device: &str) -> Option<&DeviceState> {
+        if device != FIRST_AUDIO {
+            return self.device(device).filter(|d| d.connected);
+        }
+        self.devices
+            .values()
+            .filter(|d| d.audio && d.connected)
+            .min_by_key(|d| d.connected_seq)
+    }
+
+    pub fn toggle(&mut self, mac: &str) {
+        let mac = normalize_mac(mac);
+        let Some(dev) = self.devices.get_mut(&mac) else { return };
+        let Some(ref path) = dev.path else {
+            eprintln!("[bluetooth] {} is not paired", mac);
+            return;
+        };
+        let method = if dev.connected { "Disconnect" } else { "Connect" };
+        let msg = match Message::new_method_call(BLUEZ_DEST, path.as_str(), DEVICE_IFACE, method) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("[bluetooth] bad device path {}: {}", path, e);
+                return;
+            }
+        };
+        // connecting can take seconds, the result shows up as a PropertiesChanged later
+        if let Ok(serial) = self.conn.channel().send(msg) {
+            dev.pending = true;
+            self.calls.insert(serial, mac);
+        }
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        let mut changed = false;
+        let mut needs_refresh = false;
+        for msg in bus::drain(&self.conn) {
+            if let Some(sig) = PropertiesPropertiesChanged::from_message(&msg) {
+                let Some(path) = msg.path() else { continue };
+                for dev in self.devices.values_mut() {
+                    if dev.path.as_deref() == Some(&*path) {
+                        let before = dev.clone();
+                        apply_props(dev, &sig.changed_properties, &mut self.next_seq);
+                        changed |= *dev != before;
+                    }
+                }
+            } else if msg.interface().as_deref() == Some("org.freedesktop.DBus.ObjectManager") {
+                needs_refresh = true;
+            } else if let Some(mac) = msg.get_reply_serial().and_then(|s| self.calls.remove(&s)) {
+                // failed Connect/Disconnect, nothing is going to change anymore
+                if msg.msg_type() == dbus::message::MessageType::Error {
+                    if let Some(dev) = self.devices.get_mut(&mac) {
+                        changed |= dev.pending;
+                        dev.pending = false;
+                    }
+                }
+            }
+        }
+        if needs_refresh {
+            self.refresh();
+            changed = true;
+        }
+        changed
+    }
+}
+
+fn apply_props(dev: &mut DeviceState, props: &PropMap, next_seq: &mut u64) {
+    if let Some(alias) = prop_cast::<String>(props, "Alias") {
+        dev.alias = Some(alias.clone());
+    }
+    if let Some(icon) = prop_cast::<String>(props, "Icon") {
+        dev.audio = icon.starts_with("audio-");
+    }
+    if let Some(connected) = prop_cast::<bool>(props, "Connected") {
+        if *connected != dev.connected {
+            dev.pending = false;
+            if *connected {
+                *next_seq += 1;
+                dev.connected_seq = *next_seq;
+            }
+        }
+        dev.connected = *connected;
+    }
+    if let Some(percent) = prop_cast::<u8>(props, "Percentage") {
+        dev.battery = Some(*percent);
+    }
+}
+
+impl AsFd for BluetoothManager {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::{anyhow, Result};
+use dbus::{
+    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection},
+    channel::{BusType, Channel},
+    Message, Path,
+};
+use std::{
+    os::unix::io::{BorrowedFd, RawFd},
+    path::PathBuf,
+    time::Duration,
+};
+
+pub const CALL_TIMEOUT: Duration = Duration::from_millis(500);
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const SEAT_PATH: &str = "/org/freedesktop/login1/seat/seat0";
+
+// private connection with the watch fd enabled so it can live in the main epoll set
+pub fn open(bus: BusType) -> Result<Connection> {
+    let mut channel = Channel::get_private(bus)?;
+    channel.set_watch_enabled(true);
+    Ok(Connection::from(channel))
+}
+
+// seat0's active session and the uid of its user, over a system bus connection
+pub fn active_session(conn: &Connection) -> Option<(u32, Path<'static>)> {
+    let seat = conn.with_proxy(LOGIND_DEST, SEAT_PATH, CALL_TIMEOUT);
+    let (_, session): (String, Path) = seat.get("org.freedesktop.login1.Seat", "ActiveSession").ok()?;
+    let proxy = conn.with_proxy(LOGIND_DEST, &session, CALL_TIMEOUT);
+    let (uid, _): (u32, Path) = proxy.get("org.freedesktop.login1.Session", "User").ok()?;
+    Some((uid, session))
+}
+
+// we are not part of any user session, the bus of the active one is used
+fn find_session_bus() -> Option<PathBuf> {
+    let (uid, _) = active_session(&open(BusType::System).ok()?)?;
+    let path = PathBuf::from(format!("/run/user/{}/bus", uid));
+    path.exists().then_some(path)
+}
+
+// must be called before privilege drop, the bus only lets its owner and root in
+pub fn open_session() -> Result<Connection> {
+    let path = find_session_bus().ok_or(anyhow!("No user session bus found"))?;
+    let mut channel = Channel::open_private(&format!("unix:path={}", path.display()))?;
+    channel.register()?;
+    channel.set_watch_enabled(true);
+    Ok(Connection::from(channel))
+}
+
+pub fn fd(conn: &Connection) -> BorrowedFd<'_> {
+    let fd: RawFd = conn.channel().watch().fd;
+    // the fd is owned by libdbus and lives as long as the connection
+    unsafe { BorrowedFd::borrow_raw(fd) }
+}
+
+// non-blocking read of everything queued on the connection
+pub fn drain(conn: &Connection) -> Vec<Message> {
+    let channel = conn.channel();
+    if channel.read_write(Some(Duration::ZERO)).is_err() {
+        return Vec::new();
+    }
+    let mut msgs = Vec::new();
+    while let Some(msg) = channel.pop_message() {
+        msgs.push(msg);
+    }
+    msgs
+}
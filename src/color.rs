@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use std::fs;
+
+// A per channel 1D lookup table applied to every frame before it reaches the
+// panel, so theme colors on the strip can be matched to the main display.
+pub struct ColorLut {
+    // red, green, blue
+    tables: [[u8; 256]; 3],
+}
+
+// a curve given as evenly spaced samples, stretched over all 256 inputs
+fn interpolate(points: &[f64]) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let last = (points.len() - 1) as f64;
+    for (i, out) in table.iter_mut().enumerate() {
+        let pos = i as f64 / 255.0 * last;
+        let lo = pos.floor() as usize;
+        let hi = (lo + 1).min(points.len() - 1);
+        let v = points[lo] + (points[hi] - points[lo]) * (pos - lo as f64);
+        *out = (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    }
+    table
+}
+
+fn be_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+// the calibration curves of an ICC profile, from its 'vcgt' tag
+fn parse_vcgt(icc: &[u8]) -> Option<[[u8; 256]; 3]> {
+    let tag_count = be_u32(icc, 128)? as usize;
+    let mut vcgt = None;
+    for i in 0..tag_count {
+        let entry = 132 + i * 12;
+        if icc.get(entry..entry + 4)? == b"vcgt" {
+            vcgt = Some((be_u32(icc, entry + 4)? as usize, be_u32(icc, entry + 8)? as usize));
+            break;
+        }
+    }
+    let (offset, size) = vcgt?;
+    let tag = icc.get(offset..offset + size)?;
+    if tag.get(0..4)? != b"vcgt" {
+        return None;
+    }
+    let mut tables = [[0u8; 256]; 3];
+    match be_u32(tag, 8)? {
+        // table: channels, entries per channel, bytes per entry
+        0 => {
+            let channels = be_u16(tag, 12)? as usize;
+            let entries = be_u16(tag, 14)? as usize;
+            let entry_size = be_u16(tag, 16)? as usize;
+            if channels == 0 || entries < 2 || !matches!(entry_size, 1 | 2) {
+                return None;
+            }
+            for (c, table) in tables.iter_mut().enumerate() {
+                // a single channel applies to all three
+                let base = 18 + c.min(channels - 1) * entries * entry_size;
+                let points = (0..entries)
+                    .map(|i| match entry_size {
+                        1 => tag.get(base + i).map(|&v| v as f64 / 255.0),
+                        _ => be_u16(tag, base + i * 2).map(|v| v as f64 / 65535.0),
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                *table = interpolate(&points);
+            }
+        }
+        // formula: gamma, min and max per channel as s15Fixed16
+        1 => {
+            for (c, table) in tables.iter_mut().enumerate() {
+                let fixed = |i: usize| be_u32(tag, 12 + (c * 3 + i) * 4).map(|v| v as f64 / 65536.0);
+                let (gamma, min, max) = (fixed(0)?, fixed(1)?, fixed(2)?);
+                let points = (0..256)
+                    .map(|i| min + (max - min) * (i as f64 / 255.0).powf(gamma))
+                    .collect::<Vec<_>>();
+                *table = interpolate(&points);
+            }
+        }
+        _ => return None,
+    }
+    Some(tables)
+}
+
+impl ColorLut {
+    // output levels 0-255 for evenly spaced inputs, the same curve for every channel
+    pub fn from_points(points: &[u8]) -> Result<ColorLut> {
+        if points.len() < 2 {
+            return Err(anyhow!("a color LUT needs at least two points"));
+        }
+        let points = points.iter().map(|&p| p as f64 / 255.0).collect::<Vec<_>>();
+        let table = interpolate(&points);
+        Ok(ColorLut {
+            tables: [table; 3],
+        })
+    }
+
+    pub fn from_icc(path: &str) -> Result<ColorLut> {
+        let icc = fs::read(path)?;
+        let tables = parse_vcgt(&icc).ok_or(anyhow!("{} has no usable vcgt tag", path))?;
+        Ok(ColorLut { tables })
+    }
+
+    // XRGB8888, blue comes first in memory
+    pub fn apply(&self, data: &mut [u8]) {
+        for px in data.chunks_exact_mut(4) {
+            px[0] = self.tables[2][px[0] as usize];
+            px[1] = self.tables[1][px[1] as usize];
+            px[2] = self.tables[0][px[2] as usize];
+        }
+    }
+}
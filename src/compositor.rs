@@ -0,0 +1,395 @@
+use crate::{
+    hyprland::{self, HyprlandState},
+    niri::{self, NiriState},
+    sway::{self, SwayState},
+};
+use nix::{
+    cmsg_space,
+    sys::{
+        signal::{signal, SigHandler, Signal},
+        socket::{
+            getsockopt, recvmsg, sendmsg, socketpair, sockopt::PeerCredentials, AddressFamily,
+            ControlMessage, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+        },
+    },
+    unistd::{fork, geteuid, read, setgid, setgroups, setuid, write, ForkResult, Gid, Uid},
+};
+use serde_json::{json, Value};
+use std::{
+    fs,
+    io::{IoSlice, IoSliceMut},
+    os::unix::{
+        fs::MetadataExt,
+        io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+// how often the waiter looks for a compositor that was not up yet
+const WAIT_RETRY: Duration = Duration::from_secs(2);
+// longest handoff header, Hyprland's carries its whole initial state
+const MAX_HANDOFF: usize = 256 * 1024;
+
+// where the focused window sits, shown next to its title
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WindowLayout {
+    // column and tile within the column, counted from 1
+    Tiled { column: usize, tile: usize },
+    Floating,
+    Fullscreen,
+}
+
+#[derive(Debug, Clone)]
+pub struct Workspace {
+    pub id: u64,
+    pub idx: u8,
+    pub is_focused: bool,
+    // has windows, backends that do not track it always say true
+    pub occupied: bool,
+    // a window on it asked for attention, only niri reports it
+    pub urgent: bool,
+    // only niri reports names, unnamed workspaces go by idx
+    pub name: Option<String>,
+}
+
+// an open window as the window switcher lists it
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub id: u64,
+    pub title: String,
+    pub app_id: Option<String>,
+    pub is_focused: bool,
+}
+
+// What the info layer needs from a compositor: workspace buttons, the focused
+// window title and app and a way to switch workspaces. The fd is added to the main epoll set.
+pub trait CompositorBackend {
+    // the event stream, kept open after the compositor went away
+    fn event_fd(&self) -> Option<BorrowedFd<'_>>;
+    fn workspaces(&self) -> &[Workspace];
+    fn focused_window_title(&self) -> Option<&str>;
+    // wayland app_id, or the X11 class of XWayland windows
+    fn focused_app_id(&self) -> Option<&str>;
+    // only niri reports it
+    fn focused_window_layout(&self) -> Option<WindowLayout> {
+        None
+    }
+    // true if anything shown on the bar changed
+    fn process_events(&mut self) -> bool;
+    fn focus_workspace(&mut self, idx: u8);
+    // the focused window, which keeps the focus where it is
+    fn move_window_to_workspace(&mut self, idx: u8);
+    // every open window by workspace, only niri lists them
+    fn windows(&self) -> Vec<Window> {
+        Vec::new()
+    }
+    fn focus_window(&mut self, _id: u64) {}
+    // turns the output off on the compositor's side, the touch bar panel is ours
+    fn release_output(&mut self, name: &str);
+    // every output the compositor knows about, disabled ones too
+    fn outputs(&mut self) -> Vec<Output>;
+    fn set_output_enabled(&mut self, name: &str, enabled: bool);
+    // started by the compositor, so as the user and in their session
+    fn spawn(&mut self, command: &[String]);
+    fn toggle_overview(&mut self) {
+        eprintln!("[compositor] no overview to toggle");
+    }
+    // the compositor went away, the next one comes through the Waiter
+    fn disconnected(&self) -> bool {
+        false
+    }
+}
+
+// uid of the compositor on the other end of its socket, the user its
+// spawned commands run as
+pub fn owner(compositor: &dyn CompositorBackend) -> Option<u32> {
+    getsockopt(&compositor.event_fd()?, PeerCredentials).ok().map(|c| c.uid())
+}
+
+// for compositors that hand exec to sh -c
+pub fn shell_join(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|arg| format!("'{}'", arg.replace('\'', "'\\''")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub name: String,
+    pub enabled: bool,
+}
+
+// What a Displays button cycles through. Mirroring is left out, niri and
+// sway cannot do it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayMode {
+    // every output on
+    Extend,
+    // only the external outputs, the lid can be closed
+    External,
+    // only the laptop panel
+    Internal,
+}
+
+impl DisplayMode {
+    pub fn label(self) -> &'static str {
+        match self {
+            DisplayMode::Extend => "Extend",
+            DisplayMode::External => "External",
+            DisplayMode::Internal => "Internal",
+        }
+    }
+}
+
+fn is_internal(name: &str) -> bool {
+    ["eDP", "LVDS", "DSI"].iter().any(|p| name.starts_with(p))
+}
+
+// outputs split into internal and external ones, without the touch bar panel
+fn split_outputs(outputs: Vec<Output>, touch_bar: &str) -> (Vec<Output>, Vec<Output>) {
+    outputs
+        .into_iter()
+        .filter(|o| o.name != touch_bar)
+        .partition(|o| is_internal(&o.name))
+}
+
+// None while nothing is plugged in
+pub fn display_mode(compositor: &mut dyn CompositorBackend, touch_bar: &str) -> Option<DisplayMode> {
+    let (internal, external) = split_outputs(compositor.outputs(), touch_bar);
+    let internal_on = internal.iter().any(|o| o.enabled);
+    match (internal_on, external.iter().any(|o| o.enabled)) {
+        _ if external.is_empty() => None,
+        (true, true) => Some(DisplayMode::Extend),
+        (false, true) => Some(DisplayMode::External),
+        _ => Some(DisplayMode::Internal),
+    }
+}
+
+// Switches to the mode after the current one, outputs are turned on before
+// others go off so the session is never left without one.
+pub fn cycle_displays(compositor: &mut dyn CompositorBackend, touch_bar: &str) -> Option<DisplayMode> {
+    let next = match display_mode(compositor, touch_bar)? {
+        DisplayMode::Extend => DisplayMode::External,
+        DisplayMode::External => DisplayMode::Internal,
+        DisplayMode::Internal => DisplayMode::Extend,
+    };
+    let (internal, external) = split_outputs(compositor.outputs(), touch_bar);
+    let (on, off) = match next {
+        DisplayMode::Extend => ([internal, external].concat(), vec![]),
+        DisplayMode::External => (external, internal),
+        DisplayMode::Internal => (internal, external),
+    };
+    for o in on.iter().filter(|o| !o.enabled) {
+        compositor.set_output_enabled(&o.name, true);
+    }
+    for o in off.iter().filter(|o| o.enabled) {
+        compositor.set_output_enabled(&o.name, false);
+    }
+    Some(next)
+}
+
+// A connection opened for the daemon: the socket it went to, whatever else
+// the backend had to ask for as the session user and the connected streams,
+// the event stream first.
+pub struct Handoff {
+    pub socket: PathBuf,
+    pub extra: Value,
+    pub fds: Vec<OwnedFd>,
+}
+
+// the backends compositor = kind is looked for among, in order
+fn kinds(kind: Option<&str>) -> &'static [&'static str] {
+    match kind.unwrap_or("auto") {
+        "auto" => &["niri", "hyprland", "sway"],
+        "niri" => &["niri"],
+        "hyprland" => &["hyprland"],
+        "sway" | "i3" => &["sway"],
+        _ => &[],
+    }
+}
+
+fn handoff(kind: &str) -> Option<Handoff> {
+    match kind {
+        "niri" => niri::handoff(),
+        "hyprland" => hyprland::handoff(),
+        _ => sway::handoff(),
+    }
+}
+
+fn from_handoff(kind: &str, handoff: Handoff) -> Option<Box<dyn CompositorBackend>> {
+    match kind {
+        "niri" => NiriState::from_handoff(handoff).map(|c| Box::new(c) as Box<dyn CompositorBackend>),
+        "hyprland" => HyprlandState::from_handoff(handoff).map(|c| Box::new(c) as Box<dyn CompositorBackend>),
+        _ => SwayState::from_handoff(handoff).map(|c| Box::new(c) as Box<dyn CompositorBackend>),
+    }
+}
+
+// must be called before privilege drop, compositor sockets live in the user's runtime dir
+pub fn connect(kind: Option<&str>) -> Option<Box<dyn CompositorBackend>> {
+    match kind.unwrap_or("auto") {
+        "auto" | "niri" | "hyprland" | "sway" | "i3" => {}
+        "none" => return None,
+        other => {
+            eprintln!("[compositor] unknown compositor {:?}, accepted: auto, niri, hyprland, sway, i3, none", other);
+            return None;
+        }
+    }
+    kinds(kind).iter().find_map(|k| from_handoff(k, handoff(k)?))
+}
+
+// becomes the user owning the socket, with no supplementary groups
+pub fn become_owner(socket: &Path) -> bool {
+    let Ok(meta) = fs::metadata(socket) else { return false };
+    setgroups(&[])
+        .and_then(|_| setgid(Gid::from_raw(meta.gid())))
+        .and_then(|_| setuid(Uid::from_raw(meta.uid())))
+        .is_ok()
+}
+
+fn set_parent_death_signal() -> bool {
+    unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == 0 }
+}
+
+// runs in the waiter child, a byte from the daemon asks for the next connection
+fn wait(kinds: &[&str], channel: OwnedFd, connected: bool) -> ! {
+    // nothing left to wait for once the daemon is gone
+    set_parent_death_signal();
+    // Hyprland's dispatchers are forked from here and never waited for
+    let _ = unsafe { signal(Signal::SIGCHLD, SigHandler::SigIgn) };
+    let mut wanted = !connected;
+    loop {
+        let mut request = [0u8; 1];
+        if !wanted && !matches!(read(channel.as_raw_fd(), &mut request), Ok(1)) {
+            unsafe { libc::_exit(0) };
+        }
+        wanted = false;
+        let (kind, found) = loop {
+            std::thread::sleep(WAIT_RETRY);
+            if let Some(found) = kinds.iter().find_map(|k| Some((*k, handoff(k)?))) {
+                break found;
+            }
+        };
+        let header = json!({
+            "kind": kind,
+            "socket": found.socket.to_string_lossy(),
+            "extra": found.extra,
+        })
+        .to_string();
+        let fds: Vec<RawFd> = found.fds.iter().map(|fd| fd.as_raw_fd()).collect();
+        let cmsg = [ControlMessage::ScmRights(&fds)];
+        let payload = [IoSlice::new(header.as_bytes())];
+        if sendmsg::<()>(channel.as_raw_fd(), &payload, &cmsg, MsgFlags::empty(), None).is_err() {
+            unsafe { libc::_exit(1) };
+        }
+        // root was only needed to find the session, a compositor that
+        // restarts belongs to the same user
+        if geteuid().is_root() {
+            if !(become_owner(&found.socket) && set_parent_death_signal()) {
+                unsafe { libc::_exit(1) };
+            }
+            eprintln!(
+                "[compositor] waiter now runs as uid {}, compositors of other users can not be reached until restart",
+                geteuid()
+            );
+        }
+    }
+}
+
+// Connects for us once the compositor is up, for a daemon started before the
+// session, and again whenever it restarts. The sockets are only reachable by
+// the session user, so a forked child keeps root until it found one, hands
+// the connection over and then becomes that user. It stays around to be
+// asked for the next one, which has to be run by the same user: the switch
+// is permanent, so after a user switch the waiter can not reach the new
+// session's compositor. The daemon exits when the active session's user
+// changes and systemd starts it again, which forks a new waiter as root.
+pub struct Waiter {
+    channel: OwnedFd,
+    buf: Vec<u8>,
+    // the child is gone, there is nothing more to come
+    done: bool,
+}
+
+impl Waiter {
+    // must be called before privilege drop, starts waiting right away
+    // unless there is a connection already
+    pub fn spawn(kind: Option<&str>, connected: bool) -> Option<Waiter> {
+        let kinds = kinds(kind);
+        if kinds.is_empty() {
+            return None;
+        }
+        let (channel, child_end) =
+            socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)
+                .map_err(|e| eprintln!("[compositor] failed to create the waiter channel: {}", e))
+                .ok()?;
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                drop(channel);
+                wait(kinds, child_end, connected)
+            }
+            Ok(ForkResult::Parent { .. }) => {
+                drop(child_end);
+                if !connected {
+                    eprintln!("[compositor] not running yet, waiting for it");
+                }
+                Some(Waiter { channel, buf: vec![0; MAX_HANDOFF], done: false })
+            }
+            Err(e) => {
+                eprintln!("[compositor] failed to fork waiter: {}", e);
+                None
+            }
+        }
+    }
+
+    // waits for the compositor to come back after the connection was lost
+    pub fn reconnect(&mut self) {
+        if write(&self.channel, &[1]).is_err() {
+            eprintln!("[compositor] waiter is gone, not reconnecting");
+            self.done = true;
+        }
+    }
+
+    // the connection once it came in, None while still waiting
+    pub fn receive(&mut self) -> Option<Box<dyn CompositorBackend>> {
+        let mut iov = [IoSliceMut::new(&mut self.buf)];
+        let mut cmsg = cmsg_space!([RawFd; 2]);
+        let msg = recvmsg::<()>(
+            self.channel.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_DONTWAIT | MsgFlags::MSG_CMSG_CLOEXEC,
+        )
+        .ok()?;
+        let len = msg.bytes;
+        if len == 0 {
+            self.done = true;
+            return None;
+        }
+        let mut fds: Vec<OwnedFd> = Vec::new();
+        for c in msg.cmsgs().ok()? {
+            if let ControlMessageOwned::ScmRights(received) = c {
+                fds.extend(received.into_iter().map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }));
+            }
+        }
+        let header: Value = serde_json::from_slice(&self.buf[..len]).ok()?;
+        let handoff = Handoff {
+            socket: PathBuf::from(header["socket"].as_str()?),
+            extra: header["extra"].clone(),
+            fds,
+        };
+        from_handoff(header["kind"].as_str()?, handoff)
+    }
+
+    pub fn done(&self) -> bool {
+        self.done
+    }
+}
+
+impl AsFd for Waiter {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.channel.as_fd()
+    }
+}
@@ -1,21 +1,51 @@
+use crate::animation::Easing;
+use crate::bus;
+use crate::color::ColorLut;
 use crate::fonts::{FontConfig, Pattern};
-use crate::FunctionLayer;
+use crate::ipc::Request;
+use crate::{FallbackIcons, FunctionLayer};
 use anyhow::Error;
 use cairo::FontFace;
+use dbus::{
+    blocking::{
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+        Connection,
+    },
+    channel::BusType,
+    message::SignalArgs,
+};
 use freetype::Library as FtLibrary;
 use input_linux::Key;
 use nix::{
     errno::Errno,
     sys::inotify::{AddWatchFlags, InitFlags, Inotify, InotifyEvent, WatchDescriptor},
+    unistd::{Uid, User},
 };
+use pango::FontDescription;
 use serde::{
     de::{self, Visitor},
     Deserialize, Deserializer,
 };
-use std::{fmt, fs::read_to_string, os::fd::AsFd};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, read_to_string},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::fs::PermissionsExt,
+    },
+    panic,
+    path::{Path, PathBuf},
+};
 
 const USER_CFG_PATH: &str = "/etc/tiny-dfr/config.toml";
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const SEAT_PATH: &str = "/org/freedesktop/login1/seat/seat0";
+const SEAT_IFACE: &str = "org.freedesktop.login1.Seat";
+// Nerd Font glyphs of the volume, wifi and brightness widgets
+const NERD_GLYPH_PROBES: [char; 3] = ['\u{f057e}', '\u{f0928}', '\u{fe256}'];
 
+#[derive(Clone, PartialEq)]
 pub struct Theme {
     pub background:       (f64, f64, f64),
     pub foreground:       (f64, f64, f64),
@@ -40,7 +70,36 @@ impl Default for Theme {
     }
 }
 
-fn hex_to_rgb(s: &str) -> Option<(f64, f64, f64)> {
+impl Theme {
+    // a background darker than mid grey, by relative luminance
+    pub fn is_dark(&self) -> bool {
+        let (r, g, b) = self.background;
+        0.2126 * r + 0.7152 * g + 0.0722 * b < 0.5
+    }
+
+    // every color moved towards the background, 1.0 leaves the theme as it is
+    pub fn with_contrast(&self, contrast: f64) -> Theme {
+        let bg = self.background;
+        let scale = |(r, g, b): (f64, f64, f64)| {
+            (
+                bg.0 + (r - bg.0) * contrast,
+                bg.1 + (g - bg.1) * contrast,
+                bg.2 + (b - bg.2) * contrast,
+            )
+        };
+        Theme {
+            background: bg,
+            foreground: scale(self.foreground),
+            button_inactive: scale(self.button_inactive),
+            button_active: scale(self.button_active),
+            accent: scale(self.accent),
+            success: scale(self.success),
+            warning: scale(self.warning),
+        }
+    }
+}
+
+pub fn hex_to_rgb(s: &str) -> Option<(f64, f64, f64)> {
     let s = s.trim_start_matches('#');
     if s.len() != 6 { return None; }
     let r = u8::from_str_radix(&s[0..2], 16).ok()?;
@@ -52,11 +111,78 @@ fn hex_to_rgb(s: &str) -> Option<(f64, f64, f64)> {
 pub struct Config {
     pub show_button_outlines: bool,
     pub enable_pixel_shift: bool,
+    // for the cairo text API Script widgets draw with
     pub font_face: FontFace,
+    // FontTemplate as matched by fontconfig, for the pango text of widgets
+    pub font: FontDescription,
+    // SVGs for the built-in widgets, set when the font has no Nerd Font glyphs
+    pub fallback_icons: Option<FallbackIcons>,
     pub font_size: f64,
     pub adaptive_brightness: bool,
     pub active_brightness: u32,
+    // lux and backlight level, sorted by lux
+    pub ambient_light_curve: Vec<(f64, u32)>,
+    pub dim_timeout_ms: u32,
+    pub off_timeout_ms: u32,
+    pub follow_seat_idle: bool,
+    pub auto_timezone: bool,
+    pub display_off_gesture: bool,
+    pub presentation_layer: Option<String>,
+    pub presentation_brightness: u32,
+    // widgets must draw a static form instead of animating when set
+    pub reduce_motion: bool,
+    pub announcements: bool,
+    pub compositor: Option<String>,
+    pub release_output: bool,
+    pub press_sounds: bool,
+    pub flash_on_keypress: bool,
+    pub sound_theme: Option<String>,
+    pub haptics: bool,
+    pub haptics_strength: u32,
+    pub haptics_device: Option<String>,
+    pub reset_hotkey: Vec<Key>,
+    pub fkey_lock_hotkey: Vec<Key>,
+    // RemapKeys, physical keys taken over and what they send instead
+    pub remap_keys: Vec<(Key, Key)>,
+    pub long_press_ms: u32,
+    pub return_to_default_after_ms: Option<u32>,
+    // TypingBlockMs, layers can set their own
+    pub typing_block_ms: u32,
+    pub persistent_escape: bool,
+    pub press_fade_ms: u32,
+    pub press_easing: Easing,
+    pub power_aware_refresh: bool,
+    pub live_poll_ms: u32,
+    pub notification_popups: bool,
+    pub notification_timeout_ms: u32,
+    pub swipe_left_action: Option<SwipeAction>,
+    pub two_finger_tap_action: Option<SwipeAction>,
+    pub swipe_right_action: Option<SwipeAction>,
+    pub hot_corner_left_action: Option<SwipeAction>,
+    pub hot_corner_right_action: Option<SwipeAction>,
+    // share of the bar width each hot corner takes
+    pub hot_corner_percent: u32,
+    // DoNotCover, sorted ranges of the bar no button is put on
+    pub do_not_cover: Vec<(i32, i32)>,
+    // indices of the layers Fn taps and NextLayer/PreviousLayer go through, in order
+    pub layer_cycle: Vec<usize>,
+    // index of the built-in "windows" layer, left out of the cycle
+    pub window_switcher: Option<usize>,
+    // LeftDock/RightDock, the index of their layer and how wide they are
+    pub left_dock: Option<(usize, i32)>,
+    pub right_dock: Option<(usize, i32)>,
     pub theme: Theme,
+    // the theme as configured, `theme` is this with DimTheme applied
+    pub full_theme: Theme,
+    // themed icons are looked up as -symbolic first
+    pub symbolic_icons: bool,
+    // backlight level and contrast, sorted by level
+    pub dim_theme: Vec<(u32, f64)>,
+    pub color_lut: Option<ColorLut>,
+    // uid of the active session's user, Spawn actions only go to their compositor
+    pub session_uid: Option<u32>,
+    // from timedated with AutoTimezone, the Time widget uses it over the local zone
+    pub time_zone: Option<chrono_tz::Tz>,
 }
 
 fn build_theme(
@@ -76,6 +202,17 @@ fn build_theme(
     }
 }
 
+// IconVariant, which variant of themed icons to prefer
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum IconVariant {
+    // symbolic on dark backgrounds, full color on light ones
+    #[default]
+    Auto,
+    Symbolic,
+    Color,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct ConfigProxy {
@@ -93,10 +230,60 @@ struct ConfigProxy {
     theme_accent:          Option<String>,
     theme_success:         Option<String>,
     theme_warning:         Option<String>,
+    color_lut: Option<Vec<u8>>,
+    dim_theme: Option<Vec<(u32, f64)>>,
+    color_profile: Option<String>,
+    icon_variant: Option<IconVariant>,
     active_brightness: Option<u32>,
+    ambient_light_curve: Option<Vec<(f64, u32)>>,
+    dim_timeout_ms: Option<u32>,
+    off_timeout_ms: Option<u32>,
+    follow_seat_idle: Option<bool>,
+    auto_timezone: Option<bool>,
+    display_off_gesture: Option<bool>,
+    presentation_layer: Option<String>,
+    presentation_brightness: Option<u32>,
+    reduce_motion: Option<bool>,
+    announcements: Option<bool>,
+    compositor: Option<String>,
+    release_output: Option<bool>,
+    press_sounds: Option<bool>,
+    flash_on_keypress: Option<bool>,
+    sound_theme: Option<String>,
+    haptics: Option<bool>,
+    haptics_strength: Option<u32>,
+    haptics_device: Option<String>,
+    #[serde(deserialize_with = "opt_array_or_single", default)]
+    reset_hotkey: Option<Vec<Key>>,
+    #[serde(deserialize_with = "opt_array_or_single", default)]
+    fkey_lock_hotkey: Option<Vec<Key>>,
+    remap_keys: Option<Vec<(Key, Key)>>,
+    long_press_ms: Option<u32>,
+    return_to_default_after_ms: Option<u32>,
+    typing_block_ms: Option<u32>,
+    persistent_escape: Option<bool>,
+    press_fade_ms: Option<u32>,
+    press_easing: Option<Easing>,
+    power_aware_refresh: Option<bool>,
+    live_poll_ms: Option<u32>,
+    notification_popups: Option<bool>,
+    notification_timeout_ms: Option<u32>,
+    swipe_left_action: Option<SwipeAction>,
+    two_finger_tap_action: Option<SwipeAction>,
+    swipe_right_action: Option<SwipeAction>,
+    hot_corner_left_action: Option<SwipeAction>,
+    hot_corner_right_action: Option<SwipeAction>,
+    hot_corner_percent: Option<u32>,
+    do_not_cover: Option<Vec<(u32, u32)>>,
+    layer_cycle: Option<Vec<String>>,
+    off_layer: Option<OffLayer>,
+    window_switcher: Option<bool>,
+    left_dock: Option<DockConfig>,
+    right_dock: Option<DockConfig>,
     primary_layer_keys: Option<Vec<ButtonConfig>>,
     info_layer_keys: Option<Vec<ButtonConfig>>,
     media_layer_keys: Option<Vec<ButtonConfig>>,
+    layers: Option<Vec<LayerConfig>>,
 }
 
 fn array_or_single<'de, D>(deserializer: D) -> Result<Vec<Key>, D::Error>
@@ -126,27 +313,261 @@ where
     deserializer.deserialize_any(ArrayOrSingle)
 }
 
-#[derive(Deserialize, Clone)]
+fn opt_array_or_single<'de, D>(deserializer: D) -> Result<Option<Vec<Key>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    array_or_single(deserializer).map(Some)
+}
+
+#[derive(Deserialize, Clone, Default, PartialEq)]
 #[serde(rename_all = "PascalCase")]
 pub struct ButtonConfig {
+    // how IPC and saved widget state refer to the button, see derived_id otherwise
+    pub id: Option<String>,
     #[serde(alias = "Svg")]
     pub icon: Option<String>,
     pub text: Option<String>,
+    pub icon_spacing: Option<f64>,
+    pub content_align: Option<String>,
     pub theme: Option<String>,
     pub time: Option<String>,
     pub battery: Option<String>,
+    // power_supply name, the first battery when not set
+    pub battery_device: Option<String>,
     pub locale: Option<String>,
     #[serde(deserialize_with = "array_or_single", default)]
     pub action: Vec<Key>,
     pub stretch: Option<usize>,
+    // an empty gap, Stretch and FixedWidth still work on a plain { Stretch = 3 }
+    pub spacer: Option<SpacerSize>,
+    // a thin line between the buttons on either side
+    pub separator: Option<bool>,
+    // pixels, stretch only shares out what these leave
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub fixed_width: Option<u32>,
+    // "left", "center" or "right" group of the bar
+    pub align: Option<String>,
+    // next to buttons of the same segment the button is drawn as one with them
+    pub segment: Option<String>,
+    #[serde(alias = "Workspaces")]
     pub niri_workspaces: Option<bool>,
+    // workspace buttons shown at once, the others are paged to through "…"
+    pub max_workspaces: Option<usize>,
+    // workspace name to the icon shown instead of it, looked up in Theme
+    pub workspace_icons: Option<HashMap<String, String>>,
+    #[serde(alias = "WindowTitle")]
     pub niri_window_title: Option<bool>,
+    // a button for every open window, tapped to focus it
+    #[serde(alias = "Windows")]
+    pub niri_windows: Option<bool>,
     pub volume: Option<bool>,
     pub brightness: Option<bool>,
+    pub slider: Option<String>,
     pub wifi: Option<bool>,
+    pub displays: Option<bool>,
+    pub screen_recording: Option<bool>,
+    pub privacy: Option<bool>,
+    pub cpu: Option<String>,
+    pub memory: Option<String>,
+    pub load_avg: Option<bool>,
+    pub temperature: Option<String>,
+    pub temperature_warning: Option<f64>,
+    pub custom: Option<String>,
+    pub interval_ms: Option<u32>,
+    pub return_type: Option<String>,
+    pub script: Option<String>,
+    pub sparkline: Option<u32>,
+    pub sparkline_color: Option<String>,
+    pub bluetooth: Option<String>,
+    pub bluetooth_battery: Option<String>,
+    pub sink_toggle: Option<String>,
+    pub media: Option<bool>,
+    pub do_not_disturb: Option<bool>,
+    pub sound: Option<bool>,
+    pub display_off: Option<bool>,
+    pub command: Option<Request>,
+    #[serde(deserialize_with = "array_or_single", default)]
+    pub long_press_action: Vec<Key>,
+    pub long_press_exec: Option<String>,
+    #[serde(deserialize_with = "array_or_single", default)]
+    pub chord_modifier: Vec<Key>,
+}
+
+impl ButtonConfig {
+    // what the button shows or does, an Id that stays when it is moved
+    // around or to another layer
+    fn derived_id(&self) -> String {
+        let flags = [
+            (self.volume, "volume"),
+            (self.brightness, "brightness"),
+            (self.wifi, "wifi"),
+            (self.displays, "displays"),
+            (self.screen_recording, "screen-recording"),
+            (self.privacy, "privacy"),
+            (self.load_avg, "load-avg"),
+            (self.media, "media"),
+            (self.do_not_disturb, "do-not-disturb"),
+            (self.display_off, "display-off"),
+            (self.niri_workspaces, "workspaces"),
+            (self.niri_window_title, "window-title"),
+            (self.niri_windows, "windows"),
+            (self.separator, "separator"),
+        ];
+        if let Some((_, name)) = flags.iter().find(|(on, _)| *on == Some(true)) {
+            return name.to_string();
+        }
+        let named = [
+            ("script", &self.script),
+            ("custom", &self.custom),
+            ("slider", &self.slider),
+            ("bluetooth", &self.bluetooth),
+            ("bluetooth-battery", &self.bluetooth_battery),
+            ("sink", &self.sink_toggle),
+            ("temperature", &self.temperature),
+            ("cpu", &self.cpu),
+            ("memory", &self.memory),
+            ("battery", &self.battery),
+            ("time", &self.time),
+            ("text", &self.text),
+            ("icon", &self.icon),
+        ];
+        if let Some((kind, Some(value))) = named.iter().find(|(_, v)| v.is_some()) {
+            return format!("{}:{}", kind, value);
+        }
+        if !self.action.is_empty() {
+            let keys: Vec<String> = self.action.iter().map(|k| format!("{:?}", k)).collect();
+            return format!("keys:{}", keys.join("+"));
+        }
+        "spacer".to_string()
+    }
+}
+
+// Fills in the Id of buttons without one. A derived Id already taken gets
+// #2, #3 and so on, counted across the layers in order.
+fn assign_ids(buttons: Vec<&mut ButtonConfig>) {
+    let mut taken: HashMap<String, usize> = HashMap::new();
+    for cfg in &buttons {
+        if let Some(ref id) = cfg.id {
+            let n = taken.entry(id.clone()).or_default();
+            *n += 1;
+            if *n == 2 {
+                eprintln!("[config] Id {} is used by more than one button", id);
+            }
+        }
+    }
+    for cfg in buttons {
+        if cfg.id.is_some() {
+            continue;
+        }
+        let base = cfg.derived_id();
+        let mut id = base.clone();
+        let mut n = 1;
+        while taken.contains_key(&id) {
+            n += 1;
+            id = format!("{}#{}", base, n);
+        }
+        taken.insert(id.clone(), 1);
+        cfg.id = Some(id);
+    }
+}
+
+// Spacer = { Px = 24 } or { Flex = 2 }, a gap exactly that wide or one that
+// takes a share of the free room like Stretch does
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SpacerSize {
+    Px(u32),
+    Flex(usize),
 }
 
-fn load_font(name: &str) -> FontFace {
+impl ButtonConfig {
+    // virtual buttons the button takes, Stretch or the Flex of a Spacer
+    pub fn units(&self) -> usize {
+        match self.spacer {
+            Some(SpacerSize::Flex(n)) => n,
+            _ => self.stretch.unwrap_or(1),
+        }
+    }
+}
+
+// SwipeLeftAction/SwipeRightAction/TwoFingerTapAction and the hot corners,
+// a name, { Keys = [...] } or { Spawn = [...] }
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum SwipeAction {
+    NextLayer,
+    PreviousLayer,
+    NextWorkspace,
+    PreviousWorkspace,
+    SeekForward,
+    SeekBackward,
+    Keys(#[serde(deserialize_with = "array_or_single")] Vec<Key>),
+    // niri's overview
+    ToggleOverview,
+    // the WindowSwitcher layer, or back to the layer it was opened from
+    WindowSwitcher,
+    // run by the compositor in the user's session
+    Spawn(Vec<String>),
+}
+
+// OffLayer, a built-in layer without buttons named "off"
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OffLayer {
+    // nothing drawn and nothing to touch, the backlight stays on
+    Empty,
+    // the backlight is off as well
+    Dark,
+}
+
+// LeftDock/RightDock, buttons kept at that edge of the bar on every layer
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockConfig {
+    width: u32,
+    keys: Vec<ButtonConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct LayerConfig {
+    name: String,
+    keys: Vec<ButtonConfig>,
+    fn_hold: Option<bool>,
+    apps: Option<Vec<String>>,
+    visible_buttons: Option<usize>,
+    typing_block_ms: Option<u32>,
+}
+
+// DrmDriver/DrmConnector, read on their own since the card is opened
+// before the rest of the config and kept across reloads
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct DrmSelection {
+    pub drm_driver: Option<String>,
+    pub drm_connector: Option<String>,
+    // "WIDTHxHEIGHT", most wanted first
+    pub drm_modes: Option<Vec<String>>,
+}
+
+pub fn load_drm_selection() -> DrmSelection {
+    let read = |path: &str| {
+        read_to_string(path)
+            .ok()
+            .and_then(|s| toml::from_str::<DrmSelection>(&s).ok())
+            .unwrap_or_default()
+    };
+    let base = read("/usr/share/tiny-dfr/config.toml");
+    let user = read(USER_CFG_PATH);
+    DrmSelection {
+        drm_driver: user.drm_driver.or(base.drm_driver),
+        drm_connector: user.drm_connector.or(base.drm_connector),
+        drm_modes: user.drm_modes.or(base.drm_modes),
+    }
+}
+
+// the face and whether it has the Nerd Font glyphs widgets draw, pango
+// falls back to other fonts but the glyphs may well be in none of them
+fn load_font(name: &str) -> (FontFace, FontDescription, bool) {
     let fontconfig = FontConfig::new();
     let mut pattern = Pattern::new(name);
     fontconfig.perform_substitutions(&mut pattern);
@@ -158,172 +579,615 @@ fn load_font(name: &str) -> FontFace {
     let file_idx = pat_match.get_font_index();
     let ft_library = FtLibrary::init().unwrap();
     let face = ft_library.new_face(file_name, file_idx).unwrap();
-    FontFace::create_from_ft(&face).unwrap()
+    let nerd_glyphs = NERD_GLYPH_PROBES.iter().all(|&c| face.get_char_index(c as usize).is_some());
+    (FontFace::create_from_ft(&face).unwrap(), pat_match.font_description(), nerd_glyphs)
 }
 
-fn load_config(width: u16) -> (Config, Vec<FunctionLayer>) {
-    let mut base =
-        toml::from_str::<ConfigProxy>(&read_to_string("/usr/share/tiny-dfr/config.toml").unwrap())
-            .unwrap();
-    let user = read_to_string(USER_CFG_PATH)
-        .map_err::<Error, _>(|e| e.into())
-        .and_then(|r| Ok(toml::from_str::<ConfigProxy>(&r)?));
-    if let Ok(user) = user {
-        base.media_layer_default = user.media_layer_default.or(base.media_layer_default);
-        base.show_button_outlines = user.show_button_outlines.or(base.show_button_outlines);
-        base.enable_pixel_shift = user.enable_pixel_shift.or(base.enable_pixel_shift);
-        base.font_template = user.font_template.or(base.font_template);
-        base.font_size = user.font_size.or(base.font_size);
-        base.adaptive_brightness = user.adaptive_brightness.or(base.adaptive_brightness);
-        base.media_layer_keys = user.media_layer_keys.or(base.media_layer_keys);
-        base.info_layer_keys = user.info_layer_keys.or(base.info_layer_keys);
-        base.primary_layer_keys = user.primary_layer_keys.or(base.primary_layer_keys);
-        base.active_brightness = user.active_brightness.or(base.active_brightness);
-        base.theme_background      = user.theme_background.or(base.theme_background);
-        base.theme_foreground      = user.theme_foreground.or(base.theme_foreground);
-        base.theme_button_inactive = user.theme_button_inactive.or(base.theme_button_inactive);
-        base.theme_button_active   = user.theme_button_active.or(base.theme_button_active);
-        base.theme_accent          = user.theme_accent.or(base.theme_accent);
-        base.theme_success         = user.theme_success.or(base.theme_success);
-        base.theme_warning         = user.theme_warning.or(base.theme_warning);
-    };
-
-    let mut media_layer_keys = base.media_layer_keys.unwrap();
-    let mut primary_layer_keys = base.primary_layer_keys.unwrap();
-
-    let mut info_layer_keys = base.info_layer_keys.unwrap_or_else(|| {
+// PrimaryLayerKeys/InfoLayerKeys/MediaLayerKeys, used when no [[Layers]] are defined
+fn legacy_layers(
+    primary: Vec<ButtonConfig>,
+    info: Option<Vec<ButtonConfig>>,
+    media: Vec<ButtonConfig>,
+) -> Vec<LayerConfig> {
+    let info = info.unwrap_or_else(|| {
         vec![
             ButtonConfig {
                 niri_workspaces: Some(true),
-                stretch: None,
-                icon: None, text: None, theme: None, time: None,
-                battery: None, locale: None, action: vec![],
-                niri_window_title: None,
-                volume: None, brightness: None, wifi: None,
+                ..Default::default()
             },
             ButtonConfig {
                 niri_window_title: Some(true),
                 stretch: Some(6),
-                icon: None, text: None, theme: None, time: None,
-                battery: None, locale: None, action: vec![],
-                niri_workspaces: None,
-                volume: None, brightness: None, wifi: None,
+                ..Default::default()
             },
             ButtonConfig {
                 time: Some("%a %b %d %I:%M:%S %p".into()),
                 stretch: Some(4),
-                icon: None, text: None, theme: None,
-                battery: None, locale: None, action: vec![],
-                niri_workspaces: None, niri_window_title: None,
-                volume: None, brightness: None, wifi: None,
+                ..Default::default()
             },
         ]
     });
+    [("primary", primary), ("info", info), ("media", media)]
+        .into_iter()
+        .map(|(name, keys)| LayerConfig {
+            name: name.to_string(),
+            keys,
+            fn_hold: None,
+            apps: None,
+            visible_buttons: None,
+            typing_block_ms: None,
+        })
+        .collect()
+}
+
+// values set in user win over the ones in base
+fn merge(base: &mut ConfigProxy, user: ConfigProxy) {
+    base.media_layer_default = user.media_layer_default.or(base.media_layer_default);
+    base.show_button_outlines = user.show_button_outlines.or(base.show_button_outlines);
+    base.enable_pixel_shift = user.enable_pixel_shift.or(base.enable_pixel_shift);
+    base.font_template = user.font_template.or(base.font_template);
+    base.font_size = user.font_size.or(base.font_size);
+    base.adaptive_brightness = user.adaptive_brightness.or(base.adaptive_brightness);
+    base.media_layer_keys = user.media_layer_keys.or(base.media_layer_keys);
+    base.info_layer_keys = user.info_layer_keys.or(base.info_layer_keys);
+    base.primary_layer_keys = user.primary_layer_keys.or(base.primary_layer_keys);
+    base.layers = user.layers.or(base.layers);
+    base.active_brightness = user.active_brightness.or(base.active_brightness);
+    base.ambient_light_curve = user.ambient_light_curve.or(base.ambient_light_curve);
+    base.dim_timeout_ms = user.dim_timeout_ms.or(base.dim_timeout_ms);
+    base.off_timeout_ms = user.off_timeout_ms.or(base.off_timeout_ms);
+    base.follow_seat_idle = user.follow_seat_idle.or(base.follow_seat_idle);
+    base.auto_timezone = user.auto_timezone.or(base.auto_timezone);
+    base.display_off_gesture = user.display_off_gesture.or(base.display_off_gesture);
+    base.presentation_layer = user.presentation_layer.or(base.presentation_layer);
+    base.presentation_brightness = user.presentation_brightness.or(base.presentation_brightness);
+    base.reduce_motion = user.reduce_motion.or(base.reduce_motion);
+    base.announcements = user.announcements.or(base.announcements);
+    base.compositor = user.compositor.or(base.compositor);
+    base.release_output = user.release_output.or(base.release_output);
+    base.press_sounds = user.press_sounds.or(base.press_sounds);
+    base.flash_on_keypress = user.flash_on_keypress.or(base.flash_on_keypress);
+    base.sound_theme = user.sound_theme.or(base.sound_theme);
+    base.haptics = user.haptics.or(base.haptics);
+    base.haptics_strength = user.haptics_strength.or(base.haptics_strength);
+    base.haptics_device = user.haptics_device.or(base.haptics_device);
+    base.reset_hotkey = user.reset_hotkey.or(base.reset_hotkey);
+    base.remap_keys = user.remap_keys.or(base.remap_keys);
+    base.fkey_lock_hotkey = user.fkey_lock_hotkey.or(base.fkey_lock_hotkey);
+    base.long_press_ms = user.long_press_ms.or(base.long_press_ms);
+    base.return_to_default_after_ms =
+        user.return_to_default_after_ms.or(base.return_to_default_after_ms);
+    base.typing_block_ms = user.typing_block_ms.or(base.typing_block_ms);
+    base.persistent_escape = user.persistent_escape.or(base.persistent_escape);
+    base.press_fade_ms = user.press_fade_ms.or(base.press_fade_ms);
+    base.press_easing = user.press_easing.or(base.press_easing);
+    base.power_aware_refresh = user.power_aware_refresh.or(base.power_aware_refresh);
+    base.live_poll_ms = user.live_poll_ms.or(base.live_poll_ms);
+    base.notification_popups = user.notification_popups.or(base.notification_popups);
+    base.notification_timeout_ms = user.notification_timeout_ms.or(base.notification_timeout_ms);
+    base.swipe_left_action = user.swipe_left_action.or(base.swipe_left_action);
+    base.two_finger_tap_action = user.two_finger_tap_action.or(base.two_finger_tap_action);
+    base.swipe_right_action = user.swipe_right_action.or(base.swipe_right_action);
+    base.hot_corner_left_action = user.hot_corner_left_action.or(base.hot_corner_left_action);
+    base.hot_corner_right_action = user.hot_corner_right_action.or(base.hot_corner_right_action);
+    base.hot_corner_percent = user.hot_corner_percent.or(base.hot_corner_percent);
+    base.do_not_cover = user.do_not_cover.or(base.do_not_cover);
+    base.layer_cycle = user.layer_cycle.or(base.layer_cycle);
+    base.off_layer = user.off_layer.or(base.off_layer);
+    base.window_switcher = user.window_switcher.or(base.window_switcher);
+    base.left_dock = user.left_dock.or(base.left_dock);
+    base.right_dock = user.right_dock.or(base.right_dock);
+    base.theme_background      = user.theme_background.or(base.theme_background);
+    base.theme_foreground      = user.theme_foreground.or(base.theme_foreground);
+    base.theme_button_inactive = user.theme_button_inactive.or(base.theme_button_inactive);
+    base.theme_button_active   = user.theme_button_active.or(base.theme_button_active);
+    base.theme_accent          = user.theme_accent.or(base.theme_accent);
+    base.theme_success         = user.theme_success.or(base.theme_success);
+    base.theme_warning         = user.theme_warning.or(base.theme_warning);
+    base.color_lut = user.color_lut.or(base.color_lut);
+    base.dim_theme = user.dim_theme.or(base.dim_theme);
+    base.color_profile = user.color_profile.or(base.color_profile);
+    base.icon_variant = user.icon_variant.or(base.icon_variant);
+}
 
-    if width >= 2170 {
-        for layer in [&mut media_layer_keys, &mut info_layer_keys, &mut primary_layer_keys] {
-            layer.insert(
+// Whether nobody, which every reload runs as, may read the file. The first
+// load runs as root and would otherwise take settings the next one loses.
+fn readable_by_nobody(path: &Path) -> bool {
+    let mode = |p: &Path| fs::metadata(p).map(|m| m.permissions().mode()).unwrap_or(0);
+    mode(path) & 0o004 != 0 && path.ancestors().skip(1).all(|dir| mode(dir) & 0o001 != 0)
+}
+
+// Settings of the session user's file that run commands or open devices.
+// Commands run as the daemon user, which can read every keyboard through
+// the input group, so only /etc may set them.
+fn drop_privileged(user: &mut ConfigProxy, path: &Path) {
+    let mut dropped = Vec::new();
+    if user.remap_keys.take().is_some() {
+        dropped.push("RemapKeys");
+    }
+    if user.haptics_device.take().is_some() {
+        dropped.push("HapticsDevice");
+    }
+    if user.color_profile.take().is_some() {
+        dropped.push("ColorProfile");
+    }
+    let buttons = user
+        .primary_layer_keys
+        .iter_mut()
+        .chain(&mut user.info_layer_keys)
+        .chain(&mut user.media_layer_keys)
+        .flatten()
+        .chain(user.layers.iter_mut().flatten().flat_map(|l| &mut l.keys))
+        .chain(
+            user.left_dock
+                .iter_mut()
+                .chain(&mut user.right_dock)
+                .flat_map(|d| &mut d.keys),
+        );
+    for button in buttons {
+        if button.custom.take().is_some() {
+            dropped.push("Custom");
+        }
+        if button.long_press_exec.take().is_some() {
+            dropped.push("LongPressExec");
+        }
+        if button.script.take().is_some() {
+            dropped.push("Script");
+        }
+    }
+    dropped.sort();
+    dropped.dedup();
+    if !dropped.is_empty() {
+        eprintln!(
+            "[config] ignoring {} in {}, only /etc/tiny-dfr/config.toml may set them",
+            dropped.join(", "),
+            path.display()
+        );
+    }
+}
+
+// A session user's file that does not pass validation is left out instead
+// of taking the bar down.
+fn load_config(width: u16, session_cfg: Option<&Path>) -> (Config, Vec<FunctionLayer>) {
+    if let Some(path) = session_cfg {
+        match panic::catch_unwind(|| build_config(width, Some(path))) {
+            Ok(parts) => return parts,
+            Err(_) => eprintln!("[config] {} is invalid, it is skipped", path.display()),
+        }
+    }
+    build_config(width, None)
+}
+
+fn build_config(width: u16, session_cfg: Option<&Path>) -> (Config, Vec<FunctionLayer>) {
+    let mut base =
+        toml::from_str::<ConfigProxy>(&read_to_string("/usr/share/tiny-dfr/config.toml").unwrap())
+            .unwrap();
+    if let Ok(user) = read_to_string(USER_CFG_PATH)
+        .map_err::<Error, _>(|e| e.into())
+        .and_then(|r| Ok(toml::from_str::<ConfigProxy>(&r)?))
+    {
+        merge(&mut base, user);
+    }
+    // the session user's own file wins over /etc
+    if let Some(path) = session_cfg.filter(|p| p.exists()) {
+        let user = if readable_by_nobody(path) {
+            read_to_string(path)
+                .map_err::<Error, _>(|e| e.into())
+                .and_then(|r| Ok(toml::from_str::<ConfigProxy>(&r)?))
+        } else {
+            Err(Error::msg("it or a directory above it is not readable by everyone"))
+        };
+        match user {
+            Ok(mut user) => {
+                drop_privileged(&mut user, path);
+                merge(&mut base, user);
+            }
+            Err(e) => eprintln!("[config] skipping {}: {}", path.display(), e),
+        }
+    }
+
+    let mut layer_cfgs = match base.layers {
+        Some(layers) if !layers.is_empty() => layers,
+        _ => legacy_layers(
+            base.primary_layer_keys.unwrap(),
+            base.info_layer_keys,
+            base.media_layer_keys.unwrap(),
+        ),
+    };
+
+    let mut docks = [base.left_dock, base.right_dock];
+    assign_ids(
+        layer_cfgs
+            .iter_mut()
+            .flat_map(|l| &mut l.keys)
+            .chain(docks.iter_mut().flatten().flat_map(|d| &mut d.keys))
+            .collect(),
+    );
+
+    // off can not come before dimmed
+    let dim_timeout_ms = base.dim_timeout_ms.unwrap_or(30_000);
+
+    // with PersistentEscape the key lives outside the layers
+    let persistent_escape = base.persistent_escape.unwrap_or(false);
+    if width >= 2170 && !persistent_escape {
+        for layer in &mut layer_cfgs {
+            layer.keys.insert(
                 0,
                 ButtonConfig {
-                    icon: None,
                     text: Some("esc".into()),
-                    theme: None,
                     action: vec![Key::Esc],
-                    stretch: None,
-                    time: None,
-                    locale: None,
-                    battery: None,
-                    niri_workspaces: None,
-                    niri_window_title: None,
-                    volume: None,
-                    brightness: None,
-                    wifi: None,
+                    ..Default::default()
                 },
             );
         }
     }
 
-    let fkey_layer = FunctionLayer::with_config(primary_layer_keys);
-    let mut info_layer = FunctionLayer::with_config(info_layer_keys.clone());
-    info_layer.source_config = info_layer_keys;
-    let media_layer = FunctionLayer::with_config(media_layer_keys);
-
-    let layers = vec![fkey_layer, info_layer, media_layer];
+    let mut layers: Vec<FunctionLayer> = layer_cfgs
+        .into_iter()
+        .map(|l| {
+            let mut layer = FunctionLayer::with_config(l.keys.clone());
+            layer.name = l.name;
+            layer.fn_hold = l.fn_hold == Some(true);
+            layer.apps = l.apps.unwrap_or_default();
+            layer.visible_units = l.visible_buttons;
+            layer.typing_block_ms = l.typing_block_ms;
+            layer.source_config = l.keys;
+            layer
+        })
+        .collect();
+    // the layers added below are never the one shown while Fn is held
+    if !layers.iter().any(|l| l.fn_hold) {
+        if let Some(last) = layers.last_mut() {
+            last.fn_hold = true;
+        }
+    }
+    // last in the Fn tap cycle
+    if let Some(mode) = base.off_layer {
+        layers.push(FunctionLayer::off(mode));
+    }
+    let layer_cycle: Vec<usize> = match base.layer_cycle {
+        Some(names) => names
+            .iter()
+            .filter_map(|name| {
+                let idx = layers.iter().position(|l| l.name == *name);
+                if idx.is_none() {
+                    eprintln!("[config] LayerCycle: no layer named {}", name);
+                }
+                idx
+            })
+            .collect(),
+        None => (0..layers.len()).collect(),
+    };
+    // added after the cycle so only its gesture, button or SetLayer get there
+    let window_switcher = (base.window_switcher == Some(true)).then(|| {
+        let keys = vec![ButtonConfig {
+            niri_windows: Some(true),
+            ..Default::default()
+        }];
+        let mut layer = FunctionLayer::with_config(keys.clone());
+        layer.name = "windows".into();
+        // titles need the room, more windows than this scroll
+        layer.visible_units = Some(6);
+        layer.source_config = keys;
+        layers.push(layer);
+        layers.len() - 1
+    });
+    // layers of their own as well, drawn next to whichever layer is active
+    let [left, right] = docks;
+    let mut add_dock = |name: &str, dock: Option<DockConfig>| {
+        let dock = dock.filter(|d| !d.keys.is_empty())?;
+        let mut layer = FunctionLayer::with_config(dock.keys.clone());
+        layer.name = name.into();
+        layer.source_config = dock.keys;
+        layers.push(layer);
+        Some((layers.len() - 1, dock.width.min(width as u32 / 2) as i32))
+    };
+    let left_dock = add_dock("left-dock", left);
+    let right_dock = add_dock("right-dock", right);
 
     let theme = build_theme(
         base.theme_background, base.theme_foreground,
         base.theme_button_inactive, base.theme_button_active,
         base.theme_accent, base.theme_success, base.theme_warning,
     );
+    // a profile wins over hand written points
+    let color_lut = match (base.color_profile, base.color_lut) {
+        (Some(path), _) => Some(ColorLut::from_icc(&path)),
+        (None, Some(points)) => Some(ColorLut::from_points(&points)),
+        (None, None) => None,
+    }
+    .and_then(|lut| lut.map_err(|e| eprintln!("[config] color LUT ignored: {}", e)).ok());
+    let mut dim_theme = base.dim_theme.unwrap_or_default();
+    dim_theme.sort_by_key(|&(level, _)| level);
+    let mut ambient_light_curve = base.ambient_light_curve.unwrap_or_default();
+    ambient_light_curve.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut do_not_cover: Vec<(i32, i32)> = base
+        .do_not_cover
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(a, b)| (a.min(b) as i32, a.max(b) as i32))
+        .collect();
+    do_not_cover.sort();
+    // overlapping ranges are one, buttons are moved past each range once
+    do_not_cover.dedup_by(|next, prev| {
+        let overlaps = next.0 <= prev.1;
+        if overlaps {
+            prev.1 = prev.1.max(next.1);
+        }
+        overlaps
+    });
+    let reduce_motion = base.reduce_motion.unwrap_or(false);
+    let (font_face, font, nerd_glyphs) = load_font(&base.font_template.unwrap());
+    let fallback_icons = if nerd_glyphs {
+        None
+    } else {
+        eprintln!("[config] the font has no Nerd Font icons, widgets use SVG icons instead");
+        Some(FallbackIcons::load())
+    };
     let cfg = Config {
         show_button_outlines: base.show_button_outlines.unwrap(),
-        enable_pixel_shift: base.enable_pixel_shift.unwrap(),
+        // shifting the whole bar around is motion too
+        enable_pixel_shift: base.enable_pixel_shift.unwrap() && !reduce_motion,
         adaptive_brightness: base.adaptive_brightness.unwrap(),
-        font_face: load_font(&base.font_template.unwrap()),
+        font_face,
+        font,
+        fallback_icons,
         font_size: base.font_size.unwrap_or(26.0),
         active_brightness: base.active_brightness.unwrap(),
+        ambient_light_curve,
+        dim_timeout_ms,
+        off_timeout_ms: base.off_timeout_ms.unwrap_or(60_000).max(dim_timeout_ms),
+        follow_seat_idle: base.follow_seat_idle.unwrap_or(false),
+        auto_timezone: base.auto_timezone.unwrap_or(false),
+        display_off_gesture: base.display_off_gesture.unwrap_or(false),
+        presentation_layer: base.presentation_layer,
+        presentation_brightness: base.presentation_brightness.unwrap_or(255),
+        reduce_motion,
+        announcements: base.announcements.unwrap_or(false),
+        compositor: base.compositor,
+        release_output: base.release_output.unwrap_or(false),
+        press_sounds: base.press_sounds.unwrap_or(false),
+        flash_on_keypress: base.flash_on_keypress.unwrap_or(false),
+        sound_theme: base.sound_theme,
+        haptics: base.haptics.unwrap_or(false),
+        haptics_strength: base.haptics_strength.unwrap_or(50).min(100),
+        haptics_device: base.haptics_device,
+        reset_hotkey: base.reset_hotkey.unwrap_or_default(),
+        fkey_lock_hotkey: base.fkey_lock_hotkey.unwrap_or_default(),
+        remap_keys: base.remap_keys.unwrap_or_default(),
+        long_press_ms: base.long_press_ms.unwrap_or(500).max(1),
+        return_to_default_after_ms: base.return_to_default_after_ms.filter(|&ms| ms > 0),
+        typing_block_ms: base.typing_block_ms.unwrap_or(0),
+        persistent_escape,
+        press_fade_ms: base.press_fade_ms.unwrap_or(120),
+        press_easing: base.press_easing.unwrap_or_default(),
+        power_aware_refresh: base.power_aware_refresh.unwrap_or(true),
+        live_poll_ms: base.live_poll_ms.unwrap_or(3000).max(100),
+        notification_popups: base.notification_popups.unwrap_or(false),
+        notification_timeout_ms: base.notification_timeout_ms.unwrap_or(5000),
+        swipe_left_action: base.swipe_left_action,
+        two_finger_tap_action: base.two_finger_tap_action,
+        swipe_right_action: base.swipe_right_action,
+        hot_corner_left_action: base.hot_corner_left_action,
+        hot_corner_right_action: base.hot_corner_right_action,
+        hot_corner_percent: base.hot_corner_percent.unwrap_or(5).clamp(1, 25),
+        do_not_cover,
+        layer_cycle,
+        window_switcher,
+        left_dock,
+        right_dock,
+        symbolic_icons: match base.icon_variant.unwrap_or_default() {
+            IconVariant::Auto => theme.is_dark(),
+            IconVariant::Symbolic => true,
+            IconVariant::Color => false,
+        },
+        full_theme: theme.clone(),
         theme,
+        dim_theme,
+        color_lut,
+        session_uid: None,
+        time_zone: None,
     };
     (cfg, layers)
 }
 
+// what a reload of the user config changed
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Reload {
+    Nothing,
+    // theme or other options, the layers were kept with their state
+    Options,
+    Layers,
+}
+
+// same buttons in the same layers, so the built ones can stay
+fn same_layers(old: &[FunctionLayer], new: &[FunctionLayer]) -> bool {
+    old.len() == new.len()
+        && old.iter().zip(new).all(|(a, b)| {
+            a.name == b.name
+                && a.fn_hold == b.fn_hold
+                && a.off == b.off
+                && a.apps == b.apps
+                && a.visible_units == b.visible_units
+                && a.typing_block_ms == b.typing_block_ms
+                && a.source_config == b.source_config
+        })
+}
+
+// The user of the active session on seat0 and their
+// $XDG_CONFIG_HOME/gmt-dfr/config.toml. XDG_CONFIG_HOME is taken from the
+// environment of the session leader, ~/.config if it has none or, after a
+// user switch, nobody may not read it.
+fn session_config_path(conn: &Connection) -> Option<(u32, PathBuf)> {
+    let (uid, session) = bus::active_session(conn)?;
+    let session = conn.with_proxy(LOGIND_DEST, session, bus::CALL_TIMEOUT);
+    let user = User::from_uid(Uid::from_raw(uid)).ok()??;
+    let leader: Option<u32> = session.get("org.freedesktop.login1.Session", "Leader").ok();
+    let config_home = leader
+        .and_then(|pid| std::fs::read(format!("/proc/{}/environ", pid)).ok())
+        .and_then(|env| {
+            env.split(|&b| b == 0)
+                .find_map(|var| var.strip_prefix(b"XDG_CONFIG_HOME="))
+                .map(|v| PathBuf::from(String::from_utf8_lossy(v).into_owned()))
+        })
+        .unwrap_or_else(|| user.dir.join(".config"));
+    Some((uid, config_home.join("gmt-dfr/config.toml")))
+}
+
 pub struct ConfigManager {
     inotify_fd: Inotify,
     watch_desc: Option<WatchDescriptor>,
+    // the session user's config, read on top of the one in /etc
+    session_cfg: Option<PathBuf>,
+    session_uid: Option<u32>,
+    session_watch: Option<WatchDescriptor>,
+    // follows seat0's ActiveSession, the config goes with the session
+    logind: Option<Connection>,
 }
 
-fn arm_inotify(inotify_fd: &Inotify) -> Option<WatchDescriptor> {
+// the system bus, subscribed to ActiveSession changes when that works
+fn open_logind() -> Option<Connection> {
+    let conn = match bus::open(BusType::System) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("[config] system bus unavailable: {}", e);
+            return None;
+        }
+    };
+    let rule = format!(
+        "type='signal',interface='org.freedesktop.DBus.Properties',\
+         member='PropertiesChanged',path='{}',arg0='{}'",
+        SEAT_PATH, SEAT_IFACE
+    );
+    if let Err(e) = conn.add_match_no_cb(&rule) {
+        eprintln!("[config] failed to follow session changes: {}", e);
+    }
+    Some(conn)
+}
+
+fn arm_inotify(inotify_fd: &Inotify, path: &Path) -> Option<WatchDescriptor> {
     let flags = AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_CLOSE | AddWatchFlags::IN_ONESHOT;
-    match inotify_fd.add_watch(USER_CFG_PATH, flags) {
+    match inotify_fd.add_watch(path, flags) {
         Ok(wd) => Some(wd),
-        Err(Errno::ENOENT) => None,
+        // a home directory nobody may not look into counts as no config
+        Err(Errno::ENOENT | Errno::EACCES) => None,
         e => Some(e.unwrap()),
     }
 }
 
 impl ConfigManager {
+    // must be called before privilege drop, logind is asked for the session user
     pub fn new() -> ConfigManager {
         let inotify_fd = Inotify::init(InitFlags::IN_NONBLOCK).unwrap();
-        let watch_desc = arm_inotify(&inotify_fd);
+        let watch_desc = arm_inotify(&inotify_fd, Path::new(USER_CFG_PATH));
+        let logind = open_logind();
+        let (session_uid, session_cfg) =
+            logind.as_ref().and_then(session_config_path).unzip();
+        let session_watch = session_cfg.as_deref().and_then(|p| arm_inotify(&inotify_fd, p));
         ConfigManager {
             inotify_fd,
             watch_desc,
+            session_cfg,
+            session_uid,
+            session_watch,
+            logind,
         }
     }
     pub fn load_config(&self, width: u16) -> (Config, Vec<FunctionLayer>) {
-        load_config(width)
+        let (mut cfg, layers) = load_config(width, self.session_cfg.as_deref());
+        cfg.session_uid = self.session_uid;
+        (cfg, layers)
     }
+    // `extra` layers at the end were added by the daemon, like the F-key lock
+    // one, they are left out of the comparison and dropped with the rest
+    // the layers that were replaced come back with Reload::Layers, their
+    // held buttons still have to be released
     pub fn update_config(
         &mut self,
         cfg: &mut Config,
         layers: &mut Vec<FunctionLayer>,
         width: u16,
-    ) -> bool {
-        if self.watch_desc.is_none() {
-            self.watch_desc = arm_inotify(&self.inotify_fd);
+        extra: usize,
+    ) -> (Reload, Vec<FunctionLayer>) {
+        // a config file that did not exist before is loaded once it shows up
+        let mut changed = self.arm_missing();
+        changed |= self.follow_session();
+        match self.inotify_fd.read_events() {
+            Err(Errno::EAGAIN) => {}
+            r => changed |= self.handle_events(r),
+        }
+        if !changed {
+            return (Reload::Nothing, Vec::new());
+        }
+        let parts = self.load_config(width);
+        *cfg = parts.0;
+        // keeps the active layer, held touches and compositor buttons
+        // when only options changed
+        let own = layers.len().saturating_sub(extra);
+        if same_layers(&layers[..own], &parts.1) {
+            (Reload::Options, Vec::new())
+        } else {
+            (Reload::Layers, std::mem::replace(layers, parts.1))
+        }
+    }
+    // true when another user's session became the active one
+    fn follow_session(&mut self) -> bool {
+        let Some(ref conn) = self.logind else { return false };
+        let switched = bus::drain(conn).iter().any(|msg| {
+            PropertiesPropertiesChanged::from_message(msg).is_some_and(|sig| {
+                sig.interface_name == SEAT_IFACE
+                    && (sig.changed_properties.contains_key("ActiveSession")
+                        || sig.invalidated_properties.iter().any(|p| p == "ActiveSession"))
+            })
+        });
+        if !switched {
             return false;
         }
-        match self.inotify_fd.read_events() {
-            Err(Errno::EAGAIN) => false,
-            r => self.handle_events(cfg, layers, width, r),
+        let (uid, path) = session_config_path(conn).unzip();
+        if path == self.session_cfg && uid == self.session_uid {
+            return false;
+        }
+        self.session_uid = uid;
+        eprintln!("[config] active session changed, user config: {:?}", path);
+        if let Some(wd) = self.session_watch.take() {
+            let _ = self.inotify_fd.rm_watch(wd);
+        }
+        self.session_watch = path.as_deref().and_then(|p| arm_inotify(&self.inotify_fd, p));
+        self.session_cfg = path;
+        true
+    }
+    fn arm_missing(&mut self) -> bool {
+        let mut armed = false;
+        if self.watch_desc.is_none() {
+            self.watch_desc = arm_inotify(&self.inotify_fd, Path::new(USER_CFG_PATH));
+            armed |= self.watch_desc.is_some();
         }
+        if let (None, Some(path)) = (self.session_watch, self.session_cfg.as_deref()) {
+            self.session_watch = arm_inotify(&self.inotify_fd, path);
+            armed |= self.session_watch.is_some();
+        }
+        armed
     }
     #[cold]
-    fn handle_events(&mut self, cfg: &mut Config, layers: &mut Vec<FunctionLayer>, width: u16, evts: Result<Vec<InotifyEvent>, Errno>) -> bool {
-        let mut ret = false;
+    fn handle_events(&mut self, evts: Result<Vec<InotifyEvent>, Errno>) -> bool {
+        let mut changed = false;
         for evt in evts.unwrap() {
-            if Some(evt.wd) != self.watch_desc {
+            if Some(evt.wd) == self.watch_desc {
+                self.watch_desc = arm_inotify(&self.inotify_fd, Path::new(USER_CFG_PATH));
+            } else if Some(evt.wd) == self.session_watch {
+                let path = self.session_cfg.as_deref().unwrap();
+                self.session_watch = arm_inotify(&self.inotify_fd, path);
+            } else {
                 continue;
             }
-            let parts = load_config(width);
-            *cfg = parts.0;
-            *layers = parts.1;
-            ret = true;
-            self.watch_desc = arm_inotify(&self.inotify_fd);
+            changed = true;
         }
-        ret
+        changed
     }
     pub fn fd(&self) -> &impl AsFd {
         &self.inotify_fd
     }
+    pub fn session_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.logind.as_ref().map(bus::fd)
+    }
 }
@@ -0,0 +1,178 @@
+use crate::launcher::Launcher;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{ErrorKind, Read, Write},
+    os::unix::{
+        io::{AsFd, BorrowedFd},
+        net::UnixStream,
+    },
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+// one command run by one worker, buttons with the same command share it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CustomKey {
+    pub command: String,
+    pub interval_ms: u32,
+    pub json: bool,
+}
+
+// what the command printed, waybar custom module style
+#[derive(Deserialize, Clone, Default, PartialEq)]
+pub struct CustomOutput {
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub tooltip: Option<String>,
+    #[serde(default)]
+    pub class: Option<String>,
+    // 0-100, what a sparkline follows instead of the text
+    #[serde(default)]
+    pub percentage: Option<f64>,
+}
+
+// theme color a class asks for
+pub enum Highlight {
+    Warning,
+    Success,
+    Accent,
+}
+
+impl CustomOutput {
+    pub fn highlight(&self) -> Option<Highlight> {
+        match self.class.as_deref()? {
+            "warning" | "critical" | "urgent" => Some(Highlight::Warning),
+            "success" | "good" => Some(Highlight::Success),
+            "accent" | "active" => Some(Highlight::Accent),
+            _ => None,
+        }
+    }
+}
+
+// JSON output is the last object printed, plain output the first line
+fn parse(stdout: &str, json: bool) -> CustomOutput {
+    if json {
+        let line = stdout.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or("");
+        match serde_json::from_str(line) {
+            Ok(output) => return output,
+            Err(e) => eprintln!("[custom] invalid JSON output {:?}: {}", line, e),
+        }
+    }
+    CustomOutput {
+        text: stdout.lines().next().unwrap_or("").to_string(),
+        ..Default::default()
+    }
+}
+
+fn spawn_worker(
+    launcher: Launcher,
+    key: CustomKey,
+    running: Arc<AtomicBool>,
+    results: mpsc::Sender<(CustomKey, CustomOutput)>,
+    wake: UnixStream,
+) {
+    thread::spawn(move || {
+        while running.load(Ordering::Relaxed) {
+            let output = match launcher.output(&key.command) {
+                Ok(out) => parse(&String::from_utf8_lossy(&out), key.json),
+                Err(e) => {
+                    eprintln!("[custom] failed to run {:?}: {}", key.command, e);
+                    CustomOutput::default()
+                }
+            };
+            if results.send((key.clone(), output)).is_err() {
+                break;
+            }
+            let _ = (&wake).write_all(&[0]);
+            thread::sleep(Duration::from_millis(key.interval_ms as u64));
+        }
+    });
+}
+
+// Runs the commands of Custom buttons, each on its own thread so a slow one
+// never holds up the bar. The commands are started by the launcher, as
+// nobody without the daemon's groups.
+pub struct CustomRunner {
+    launcher: Launcher,
+    workers: HashMap<CustomKey, Arc<AtomicBool>>,
+    latest: HashMap<CustomKey, CustomOutput>,
+    sender: mpsc::Sender<(CustomKey, CustomOutput)>,
+    results: mpsc::Receiver<(CustomKey, CustomOutput)>,
+    // workers write a byte here after each result, the read end is in the epoll set
+    wake_read: UnixStream,
+    wake_write: UnixStream,
+}
+
+impl CustomRunner {
+    pub fn new(launcher: Launcher) -> Option<CustomRunner> {
+        let (wake_read, wake_write) = UnixStream::pair()
+            .map_err(|e| eprintln!("[custom] failed to create wakeup socket: {}", e))
+            .ok()?;
+        wake_read.set_nonblocking(true).ok()?;
+        let (sender, results) = mpsc::channel();
+        Some(CustomRunner {
+            launcher,
+            workers: HashMap::new(),
+            latest: HashMap::new(),
+            sender,
+            results,
+            wake_read,
+            wake_write,
+        })
+    }
+
+    // starts workers for new commands and stops the ones no button uses anymore
+    pub fn sync(&mut self, wanted: Vec<CustomKey>) {
+        self.workers.retain(|key, running| {
+            let keep = wanted.contains(key);
+            if !keep {
+                running.store(false, Ordering::Relaxed);
+            }
+            keep
+        });
+        self.latest.retain(|key, _| wanted.contains(key));
+        for key in wanted {
+            if self.workers.contains_key(&key) {
+                continue;
+            }
+            let Ok(wake) = self.wake_write.try_clone() else { continue };
+            let running = Arc::new(AtomicBool::new(true));
+            spawn_worker(self.launcher.clone(), key.clone(), running.clone(), self.sender.clone(), wake);
+            self.workers.insert(key, running);
+        }
+    }
+
+    pub fn process_events(&mut self) {
+        let mut buf = [0u8; 64];
+        loop {
+            match (&self.wake_read).read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) => break,
+            }
+        }
+        for (key, output) in self.results.try_iter() {
+            // late results of a stopped worker
+            if self.workers.contains_key(&key) {
+                self.latest.insert(key, output);
+            }
+        }
+    }
+
+    pub fn output(&self, key: &CustomKey) -> Option<&CustomOutput> {
+        self.latest.get(key)
+    }
+}
+
+impl AsFd for CustomRunner {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.wake_read.as_fd()
+    }
+}
@@ -0,0 +1,41 @@
+use crate::config::ButtonConfig;
+
+// Everything shown on the diagnostics layer, rebuilt whenever any of it changes.
+#[derive(Clone, Default, PartialEq)]
+pub struct Diagnostics {
+    pub touch: Option<(f64, f64)>,
+    pub fingers: usize,
+    pub digitizer: Option<String>,
+    // width, height, refresh rate
+    pub mode: (u16, u16, u32),
+    pub backlight: (u32, u32),
+    pub sources: Vec<&'static str>,
+}
+
+impl Diagnostics {
+    pub fn to_buttons(&self) -> Vec<ButtonConfig> {
+        let touch = match self.touch {
+            Some((x, y)) => format!("Touch {:.0},{:.0} ({})", x, y, self.fingers),
+            None => "Touch -".to_string(),
+        };
+        let digitizer = match self.digitizer {
+            Some(ref name) => format!("Digitizer: {}", name),
+            None => "Digitizer: missing".to_string(),
+        };
+        let (w, h, hz) = self.mode;
+        [
+            (touch, 3),
+            (digitizer, 4),
+            (format!("Mode {}x{}@{}", w, h, hz), 3),
+            (format!("BL {}/{}", self.backlight.0, self.backlight.1), 2),
+            (format!("Epoll: {}", self.sources.join(" ")), 6),
+        ]
+        .into_iter()
+        .map(|(text, stretch)| ButtonConfig {
+            text: Some(text),
+            stretch: Some(stretch),
+            ..Default::default()
+        })
+        .collect()
+    }
+}
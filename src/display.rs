@@ -1,20 +1,24 @@
+use crate::{config::DrmSelection, error};
 use anyhow::{anyhow, Result};
 use drm::{
     buffer::DrmFourcc,
     control::{
         atomic, connector,
         dumbbuffer::{DumbBuffer, DumbMapping},
-        framebuffer, property, AtomicCommitFlags, ClipRect, Device as ControlDevice, Mode,
-        ResourceHandle,
+        framebuffer, plane, property, AtomicCommitFlags, ClipRect, Device as ControlDevice, Mode,
+        ModeTypeFlags, ResourceHandle,
     },
     ClientCapability, Device as DrmDevice,
 };
 use std::{
     fs::{self, File, OpenOptions},
     os::unix::io::{AsFd, BorrowedFd},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+// touch bar display drivers on T2 and Apple Silicon Macs
+const TOUCHBAR_DRIVERS: [&str; 2] = ["appletbdrm", "adp"];
+
 struct Card(File);
 impl AsFd for Card {
     fn as_fd(&self) -> BorrowedFd<'_> {
@@ -37,15 +41,27 @@ impl Card {
 
 pub struct DrmBackend {
     card: Card,
+    path: PathBuf,
+    connector: String,
     mode: Mode,
-    db: DumbBuffer,
-    fb: framebuffer::Handle,
+    // drawn into while the other one is on screen, then flipped to
+    buffers: [(DumbBuffer, framebuffer::Handle); 2],
+    front: usize,
+    plane: plane::Handle,
+    fb_prop: property::Handle,
+    // without it the driver sends the whole buffer on every flip
+    damage_prop: Option<property::Handle>,
+    // changed in the front buffer by the last flip, the back one lacks it
+    stale: Vec<ClipRect>,
 }
 
 impl Drop for DrmBackend {
     fn drop(&mut self) {
-        self.card.destroy_framebuffer(self.fb).unwrap();
-        self.card.destroy_dumb_buffer(self.db).unwrap();
+        // fails when the card went away under us, the kernel cleans up then
+        for (db, fb) in self.buffers {
+            let _ = self.card.destroy_framebuffer(fb);
+            let _ = self.card.destroy_dumb_buffer(db);
+        }
     }
 }
 
@@ -64,8 +80,91 @@ fn find_prop_id<T: ResourceHandle>(
     Err(anyhow!("Property not found"))
 }
 
-fn try_open_card(path: &Path) -> Result<DrmBackend> {
+fn connector_name(info: &connector::Info) -> String {
+    format!("{}-{}", info.interface().as_str(), info.interface_id())
+}
+
+fn mode_name(mode: &Mode) -> String {
+    let (w, h) = mode.size();
+    format!("{}x{}", w, h)
+}
+
+// the configured resolutions in order, or the preferred mode followed by the rest
+fn candidate_modes(con: &connector::Info, wanted: Option<&[String]>) -> Result<Vec<Mode>> {
+    let modes = con.modes();
+    if modes.is_empty() {
+        return Err(anyhow!("No modes found"));
+    }
+    let preferred = |m: &&Mode| m.mode_type().contains(ModeTypeFlags::PREFERRED);
+    let candidates = match wanted {
+        Some(wanted) => wanted
+            .iter()
+            .flat_map(|name| modes.iter().filter(move |m| mode_name(m) == *name))
+            .copied()
+            .collect::<Vec<_>>(),
+        None => modes
+            .iter()
+            .filter(preferred)
+            .chain(modes.iter().filter(|m| !preferred(m)))
+            .copied()
+            .collect(),
+    };
+    if candidates.is_empty() {
+        let available = modes.iter().map(mode_name).collect::<Vec<_>>();
+        return Err(anyhow!(
+            "None of the configured modes are available, the connector has: {}",
+            available.join(", ")
+        ));
+    }
+    Ok(candidates)
+}
+
+// the first candidate that looks like a touch bar and gets a framebuffer
+fn create_framebuffer(
+    card: &Card,
+    candidates: &[Mode],
+) -> Result<(Mode, DumbBuffer, framebuffer::Handle)> {
+    let mut errors = Vec::new();
+    for &mode in candidates {
+        let (disp_width, disp_height) = mode.size();
+        if disp_height / disp_width.max(1) < 30 {
+            errors.push(format!("{}: not a touchbar", mode_name(&mode)));
+            continue;
+        }
+        let fmt = DrmFourcc::Xrgb8888;
+        let size = (disp_width.next_multiple_of(64).into(), disp_height.into());
+        let db = match card.create_dumb_buffer(size, fmt, 32) {
+            Ok(db) => db,
+            Err(e) => {
+                errors.push(format!("{}: {}", mode_name(&mode), e));
+                continue;
+            }
+        };
+        match card.add_framebuffer(&db, 24, 32) {
+            Ok(fb) => return Ok((mode, db, fb)),
+            Err(e) => {
+                errors.push(format!("{}: {}", mode_name(&mode), e));
+                let _ = card.destroy_dumb_buffer(db);
+            }
+        }
+    }
+    Err(anyhow!("No usable mode, attempted: {}", errors.join(", ")))
+}
+
+fn try_open_card(path: &Path, selection: &DrmSelection) -> Result<DrmBackend> {
     let card = Card::open(path)?;
+    // checked before taking master, other cards are none of our business
+    let driver = card.get_driver()?;
+    let driver = driver.name().to_string_lossy();
+    match selection.drm_driver {
+        Some(ref wanted) if *wanted != driver => {
+            return Err(anyhow!("Driver {} is not the configured {}", driver, wanted));
+        }
+        None if !TOUCHBAR_DRIVERS.contains(&driver.as_ref()) => {
+            return Err(anyhow!("Driver {} is not a touch bar driver", driver));
+        }
+        _ => {}
+    }
     card.set_client_capability(ClientCapability::UniversalPlanes, true)?;
     card.set_client_capability(ClientCapability::Atomic, true)?;
     card.acquire_master_lock()?;
@@ -84,19 +183,19 @@ fn try_open_card(path: &Path) -> Result<DrmBackend> {
 
     let con = coninfo
         .iter()
-        .find(|&i| i.state() == connector::State::Connected)
-        .ok_or(anyhow!("No connected connectors found"))?;
+        .filter(|&i| i.state() == connector::State::Connected)
+        .find(|&i| {
+            selection
+                .drm_connector
+                .as_ref()
+                .map_or(true, |wanted| connector_name(i) == *wanted)
+        })
+        .ok_or(anyhow!("No matching connected connectors found"))?;
 
-    let &mode = con.modes().first().ok_or(anyhow!("No modes found"))?;
-    let (disp_width, disp_height) = mode.size();
-    if disp_height / disp_width < 30 {
-        return Err(anyhow!("This does not look like a touchbar"));
-    }
     let crtc = crtcinfo.first().ok_or(anyhow!("No crtcs found"))?;
-    let fmt = DrmFourcc::Xrgb8888;
-    let db = card.create_dumb_buffer((64, disp_height.into()), fmt, 32)?;
-
-    let fb = card.add_framebuffer(&db, 24, 32)?;
+    let candidates = candidate_modes(con, selection.drm_modes.as_deref())?;
+    let (mode, db, fb) = create_framebuffer(&card, &candidates)?;
+    let (_, back_db, back_fb) = create_framebuffer(&card, &[mode])?;
     let plane = *card
         .plane_handles()?
         .first()
@@ -173,41 +272,129 @@ fn try_open_card(path: &Path) -> Result<DrmBackend> {
 
     card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req)?;
 
-    Ok(DrmBackend { card, mode, db, fb })
+    let fb_prop = find_prop_id(&card, plane, "FB_ID")?;
+    let damage_prop = find_prop_id(&card, plane, "FB_DAMAGE_CLIPS").ok();
+    Ok(DrmBackend {
+        card,
+        path: path.to_path_buf(),
+        connector: connector_name(con),
+        mode,
+        buffers: [(db, fb), (back_db, back_fb)],
+        front: 0,
+        plane,
+        fb_prop,
+        damage_prop,
+        stale: Vec::new(),
+    })
+}
+
+// /dev/dri/card*, in order
+fn card_paths() -> Result<Vec<PathBuf>> {
+    let mut entries = fs::read_dir("/dev/dri/")?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    Ok(entries
+        .into_iter()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("card"))
+        .map(|e| e.path())
+        .collect())
+}
+
+fn no_touchbar(errors: Vec<String>) -> anyhow::Error {
+    anyhow!(
+        "No touchbar device found, attempted: [\n    {}\n]",
+        errors.join(",\n    ")
+    )
 }
 
 impl DrmBackend {
-    pub fn open_card() -> Result<DrmBackend> {
+    pub fn open_card(selection: &DrmSelection) -> Result<DrmBackend> {
         let mut errors = Vec::new();
-        for entry in fs::read_dir("/dev/dri/")? {
-            let entry = entry?;
-            if !entry.file_name().to_string_lossy().starts_with("card") {
-                continue;
-            }
-            match try_open_card(&entry.path()) {
+        for path in card_paths()? {
+            match try_open_card(&path, selection) {
                 Ok(card) => return Ok(card),
-                Err(err) => errors.push(format!(
-                    "{}: {}",
-                    entry.path().as_os_str().to_string_lossy(),
-                    err
-                )),
+                Err(err) => errors.push(format!("{}: {}", path.to_string_lossy(), err)),
             }
         }
-        Err(anyhow!(
-            "No touchbar device found, attempted: [\n    {}\n]",
-            errors.join(",\n    ")
-        ))
+        Err(no_touchbar(errors))
+    }
+    // every touch bar display there is, for rigs with more than one
+    pub fn open_cards(selection: &DrmSelection) -> Result<Vec<DrmBackend>> {
+        let mut errors = Vec::new();
+        let mut cards = Vec::new();
+        for path in card_paths()? {
+            match try_open_card(&path, selection) {
+                Ok(card) => cards.push(card),
+                Err(err) => errors.push(format!("{}: {}", path.to_string_lossy(), err)),
+            }
+        }
+        if cards.is_empty() {
+            return Err(no_touchbar(errors));
+        }
+        Ok(cards)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn connector(&self) -> &str {
+        &self.connector
     }
     pub fn mode(&self) -> Mode {
         self.mode
     }
     pub fn fb_info(&self) -> Result<framebuffer::Info> {
-        Ok(self.card.get_framebuffer(self.fb)?)
+        Ok(self.card.get_framebuffer(self.buffers[self.front].1)?)
     }
-    pub fn dirty(&self, clips: &[ClipRect]) -> Result<()> {
-        Ok(self.card.dirty_framebuffer(self.fb, clips)?)
+    // the buffer on screen, written to in place
+    pub fn dirty(&self, clips: &[ClipRect]) -> error::Result<()> {
+        Ok(self.card.dirty_framebuffer(self.buffers[self.front].1, clips)?)
     }
-    pub fn map(&mut self) -> Result<DumbMapping> {
-        Ok(self.card.map_dumb_buffer(&mut self.db)?)
+    pub fn map(&mut self) -> error::Result<DumbMapping> {
+        Ok(self.card.map_dumb_buffer(&mut self.buffers[self.front].0)?)
+    }
+    // What has to be copied into the back buffer for a frame that changed
+    // `clips`: those and what the last flip changed, which it missed.
+    pub fn back_damage(&self, clips: &[ClipRect]) -> Vec<ClipRect> {
+        let (w, h) = self.mode.size();
+        clips
+            .iter()
+            .chain(&self.stale)
+            .map(|r| ClipRect::new(r.x1().min(w), r.y1().min(h), r.x2().min(w), r.y2().min(h)))
+            .filter(|r| r.x1() < r.x2() && r.y1() < r.y2())
+            .collect()
+    }
+    pub fn map_back(&mut self) -> error::Result<DumbMapping> {
+        Ok(self.card.map_dumb_buffer(&mut self.buffers[1 - self.front].0)?)
+    }
+    // puts the back buffer on screen once the panel is done with the front one
+    pub fn flip(&mut self, clips: &[ClipRect]) -> error::Result<()> {
+        let back = 1 - self.front;
+        let mut req = atomic::AtomicModeReq::new();
+        req.add_property(
+            self.plane,
+            self.fb_prop,
+            property::Value::Framebuffer(Some(self.buffers[back].1)),
+        );
+        let blob = match self.damage_prop {
+            Some(prop) => {
+                // struct drm_mode_rect, four i32 where ClipRect has u16
+                let mut rects: Vec<u8> = clips
+                    .iter()
+                    .flat_map(|r| [r.x1(), r.y1(), r.x2(), r.y2()])
+                    .flat_map(|v| (v as i32).to_ne_bytes())
+                    .collect();
+                let blob = drm_ffi::mode::create_property_blob(self.card.as_fd(), &mut rects)?;
+                req.add_property(self.plane, prop, property::Value::Blob(blob.blob_id.into()));
+                Some(blob.blob_id)
+            }
+            None => None,
+        };
+        let res = self.card.atomic_commit(AtomicCommitFlags::empty(), req);
+        if let Some(id) = blob {
+            let _ = self.card.destroy_property_blob(id.into());
+        }
+        res?;
+        self.front = back;
+        self.stale = clips.to_vec();
+        Ok(())
     }
 }
@@ -0,0 +1,209 @@
+use crate::bus;
+use dbus::{
+    arg::PropMap,
+    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection},
+    message::MessageType,
+};
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+// mako has no do-not-disturb of its own, this is the mode its man page suggests
+const MAKO_DND_MODE: &str = "do-not-disturb";
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct DndState {
+    // shown or waiting, not the history
+    pub count: u32,
+    pub dnd: bool,
+}
+
+// The notification daemons with a control interface, each found by its
+// well-known name on the session bus.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Daemon {
+    Swaync,
+    Dunst,
+    Mako,
+}
+
+impl Daemon {
+    const ALL: [Daemon; 3] = [Daemon::Swaync, Daemon::Dunst, Daemon::Mako];
+
+    fn dest(self) -> &'static str {
+        match self {
+            Daemon::Swaync => "org.erikreider.swaync.cc",
+            // dunst has no name of its own
+            Daemon::Dunst => "org.freedesktop.Notifications",
+            Daemon::Mako => "fr.emersion.Mako",
+        }
+    }
+
+    fn path(self) -> &'static str {
+        match self {
+            Daemon::Swaync => "/org/erikreider/swaync/cc",
+            Daemon::Dunst => "/org/freedesktop/Notifications",
+            Daemon::Mako => "/fr/emersion/Mako",
+        }
+    }
+
+    fn iface(self) -> &'static str {
+        match self {
+            Daemon::Swaync => "org.erikreider.swaync.cc",
+            Daemon::Dunst => "org.dunstproject.cmd0",
+            Daemon::Mako => "fr.emersion.Mako",
+        }
+    }
+}
+
+pub struct DndMonitor {
+    conn: Connection,
+    daemon: Option<Daemon>,
+    state: Option<DndState>,
+}
+
+impl DndMonitor {
+    // must be called before privilege drop
+    pub fn connect() -> Option<DndMonitor> {
+        let conn = match bus::open_session() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[dnd] session bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let mut rules: Vec<String> = Daemon::ALL
+            .iter()
+            .map(|d| {
+                format!(
+                    "type='signal',sender='org.freedesktop.DBus',member='NameOwnerChanged',arg0='{}'",
+                    d.dest()
+                )
+            })
+            .collect();
+        // swaync sends Subscribe with every change, dunst a PropertiesChanged
+        // when paused, anything else is picked up by polling
+        rules.push("type='signal',interface='org.erikreider.swaync.cc'".to_string());
+        rules.push(
+            "type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',arg0='org.dunstproject.cmd0'"
+                .to_string(),
+        );
+        rules.push(
+            "type='signal',interface='org.freedesktop.Notifications',member='NotificationClosed'".to_string(),
+        );
+        for rule in &rules {
+            if let Err(e) = conn.add_match_no_cb(rule) {
+                eprintln!("[dnd] failed to subscribe: {}", e);
+                return None;
+            }
+        }
+        let mut monitor = DndMonitor { conn, daemon: None, state: None };
+        monitor.find_daemon();
+        monitor.poll();
+        Some(monitor)
+    }
+
+    fn find_daemon(&mut self) {
+        let proxy = self.conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", bus::CALL_TIMEOUT);
+        let daemon = Daemon::ALL.into_iter().find(|d| {
+            let owned: Result<(bool,), _> =
+                proxy.method_call("org.freedesktop.DBus", "NameHasOwner", (d.dest(),));
+            // another daemon owning the notifications name is not dunst
+            owned.is_ok_and(|(o,)| o) && (*d != Daemon::Dunst || self.query(*d).is_some())
+        });
+        if daemon != self.daemon {
+            eprintln!("[dnd] notification daemon: {:?}", daemon);
+            self.daemon = daemon;
+        }
+    }
+
+    fn query(&self, daemon: Daemon) -> Option<DndState> {
+        let proxy = self.conn.with_proxy(daemon.dest(), daemon.path(), bus::CALL_TIMEOUT);
+        let iface = daemon.iface();
+        match daemon {
+            Daemon::Swaync => {
+                let (count,): (u32,) = proxy.method_call(iface, "NotificationCount", ()).ok()?;
+                let (dnd,): (bool,) = proxy.method_call(iface, "GetDnd", ()).ok()?;
+                Some(DndState { count, dnd })
+            }
+            Daemon::Dunst => {
+                let dnd: bool = proxy.get(iface, "paused").ok()?;
+                let shown: u32 = proxy.get(iface, "displayedLength").unwrap_or(0);
+                let waiting: u32 = proxy.get(iface, "waitingLength").unwrap_or(0);
+                Some(DndState { count: shown + waiting, dnd })
+            }
+            Daemon::Mako => {
+                let (list,): (Vec<PropMap>,) =
+                    proxy.method_call(iface, "ListNotifications", ()).ok()?;
+                Some(DndState {
+                    count: list.len() as u32,
+                    dnd: self.mako_modes()?.iter().any(|m| m == MAKO_DND_MODE),
+                })
+            }
+        }
+    }
+
+    // ListModes since mako 1.9, a single mode before that
+    fn mako_modes(&self) -> Option<Vec<String>> {
+        let d = Daemon::Mako;
+        let proxy = self.conn.with_proxy(d.dest(), d.path(), bus::CALL_TIMEOUT);
+        if let Ok((modes,)) = proxy.method_call::<(Vec<String>,), _, _, _>(d.iface(), "ListModes", ()) {
+            return Some(modes);
+        }
+        let (mode,): (String,) = proxy.method_call(d.iface(), "GetMode", ()).ok()?;
+        Some(vec![mode])
+    }
+
+    pub fn state(&self) -> Option<DndState> {
+        self.state
+    }
+
+    // asks the daemon again, true if anything changed
+    pub fn poll(&mut self) -> bool {
+        let state = self.daemon.and_then(|d| self.query(d));
+        let changed = state != self.state;
+        self.state = state;
+        changed
+    }
+
+    pub fn toggle(&mut self) {
+        let Some(daemon) = self.daemon else { return };
+        let dnd = !self.state.is_some_and(|s| s.dnd);
+        let proxy = self.conn.with_proxy(daemon.dest(), daemon.path(), bus::CALL_TIMEOUT);
+        let iface = daemon.iface();
+        let res = match daemon {
+            Daemon::Swaync => proxy.method_call::<(), _, _, _>(iface, "SetDnd", (dnd,)),
+            Daemon::Dunst => proxy.set(iface, "paused", dnd),
+            Daemon::Mako => {
+                let mut modes = self.mako_modes().unwrap_or_default();
+                modes.retain(|m| m != MAKO_DND_MODE);
+                if dnd {
+                    modes.push(MAKO_DND_MODE.to_string());
+                }
+                proxy.method_call(iface, "SetModes", (modes.clone(),)).or_else(|_| {
+                    let mode = if dnd { MAKO_DND_MODE } else { "default" };
+                    proxy.method_call(iface, "SetMode", (mode,))
+                })
+            }
+        };
+        if let Err(e) = res {
+            eprintln!("[dnd] failed to toggle do not disturb: {}", e);
+        }
+        self.poll();
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        let msgs = bus::drain(&self.conn);
+        if !msgs.iter().any(|m| m.msg_type() == MessageType::Signal) {
+            return false;
+        }
+        if msgs.iter().any(|m| m.member().as_deref() == Some("NameOwnerChanged")) {
+            self.find_daemon();
+        }
+        self.poll()
+    }
+}
+
+impl AsFd for DndMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
@@ -0,0 +1,54 @@
+use std::{fmt, io};
+
+// Errors of the drawing, input and sysfs paths. They are logged and the part
+// that failed is switched off, the daemon keeps running. Setup still uses
+// anyhow, nothing can be recovered there.
+#[derive(Debug)]
+pub enum Error {
+    Cairo(cairo::Error),
+    Surface(cairo::BorrowError),
+    Svg(String),
+    Io(io::Error),
+    // a sysfs attribute that did not hold a number
+    Parse(String),
+    // widget code panicked, see Button::sandboxed
+    Panic,
+    // a Rhai widget failed to run
+    Script(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Cairo(e) => write!(f, "cairo: {}", e),
+            Error::Surface(e) => write!(f, "surface: {}", e),
+            Error::Svg(e) => write!(f, "svg: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Parse(what) => write!(f, "failed to parse {}", what),
+            Error::Panic => write!(f, "a widget panicked"),
+            Error::Script(e) => write!(f, "script: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<cairo::Error> for Error {
+    fn from(e: cairo::Error) -> Self {
+        Error::Cairo(e)
+    }
+}
+
+impl From<cairo::BorrowError> for Error {
+    fn from(e: cairo::BorrowError) -> Self {
+        Error::Surface(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -1,4 +1,5 @@
 #![allow(non_upper_case_globals)]
+use pango::glib::translate::from_glib_full;
 use std::ffi::{c_char, c_int, CStr, CString};
 use std::ptr;
 
@@ -107,6 +108,10 @@ impl Pattern {
             index as isize
         }
     }
+    // family, weight, slant and width of a matched font, for pango to look up
+    pub fn font_description(&self) -> pango::FontDescription {
+        unsafe { from_glib_full(pango_fc_font_description_from_pattern(self.pattern, 0)) }
+    }
 }
 
 impl Drop for Pattern {
@@ -135,4 +140,8 @@ extern "C" {
     ) -> FcResult;
     fn FcConfigSubstitute(_: *const FcConfig, _: *const FcPattern, _: FcMatchKind) -> c_int;
     fn FcDefaultSubstitute(_: *const FcPattern);
+    fn pango_fc_font_description_from_pattern(
+        _: *const FcPattern,
+        include_size: c_int,
+    ) -> *mut pango::ffi::PangoFontDescription;
 }
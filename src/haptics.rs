@@ -0,0 +1,130 @@
+use input_linux_sys::{input_event, timeval};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    slice,
+};
+
+const EV_FF: u16 = 0x15;
+const FF_RUMBLE: u16 = 0x50;
+const PRESS_MS: u16 = 20;
+const RELEASE_MS: u16 = 10;
+
+// struct ff_effect as laid out on 64 bit kernels, only the rumble member of
+// the union is ever filled in
+#[repr(C)]
+struct FfEffect {
+    type_: u16,
+    id: i16,
+    direction: u16,
+    // button, interval
+    trigger: [u16; 2],
+    // length, delay
+    replay: [u16; 2],
+    // strong and weak magnitude in the low four bytes
+    u: [u64; 4],
+}
+
+nix::ioctl_write_ptr!(eviocsff, b'E', 0x80, FfEffect);
+nix::ioctl_read_buf!(eviocgbit_ff, b'E', 0x20 + EV_FF, u8);
+
+fn supports_rumble(file: &File) -> bool {
+    let mut bits = [0u8; 16];
+    let res = unsafe { eviocgbit_ff(file.as_raw_fd(), &mut bits) };
+    res.is_ok() && bits[(FF_RUMBLE / 8) as usize] & (1 << (FF_RUMBLE % 8)) != 0
+}
+
+// event nodes of input devices that look like a Touch Bar actuator, the
+// first rumble device on the system could just as well be a gamepad
+fn candidates() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir("/sys/class/input") else { return Vec::new() };
+    let mut found = entries
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("event"))
+        .filter(|e| {
+            let name = fs::read_to_string(e.path().join("device/name")).unwrap_or_default();
+            let name = name.to_lowercase();
+            name.contains("touch bar") || name.contains("haptic")
+        })
+        .map(|e| Path::new("/dev/input").join(e.file_name()))
+        .collect::<Vec<_>>();
+    found.sort();
+    found
+}
+
+// Click feedback through the force feedback interface of the haptic device,
+// HapticsDevice or whatever looks like the Touch Bar one. Must be opened
+// before privilege drop.
+pub struct Haptics {
+    file: File,
+    // effect ids of the press and release clicks, -1 until uploaded
+    effects: [i16; 2],
+    strength: u32,
+}
+
+impl Haptics {
+    pub fn open(device: Option<&str>) -> Option<Haptics> {
+        let paths = match device {
+            Some(d) => vec![PathBuf::from(d)],
+            None => candidates(),
+        };
+        for path in paths {
+            let file = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(f) => f,
+                Err(e) => {
+                    eprintln!("[haptics] failed to open {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            if supports_rumble(&file) {
+                return Some(Haptics { file, effects: [-1; 2], strength: 0 });
+            }
+            eprintln!("[haptics] {} has no rumble effect", path.display());
+        }
+        None
+    }
+
+    fn upload(&mut self, index: usize, length: u16, magnitude: u16) -> io::Result<()> {
+        let mut effect = FfEffect {
+            type_: FF_RUMBLE,
+            id: self.effects[index],
+            direction: 0,
+            trigger: [0; 2],
+            replay: [length, 0],
+            u: [magnitude as u64 | (magnitude as u64) << 16, 0, 0, 0],
+        };
+        unsafe { eviocsff(self.file.as_raw_fd(), &mut effect) }?;
+        self.effects[index] = effect.id;
+        Ok(())
+    }
+
+    // strength in percent, effects are uploaded again when it changed
+    pub fn click(&mut self, press: bool, strength: u32) {
+        let strength = strength.min(100);
+        if strength != self.strength || self.effects.contains(&-1) {
+            let magnitude = (strength * 0xffff / 100) as u16;
+            let res = self
+                .upload(0, PRESS_MS, magnitude)
+                .and_then(|_| self.upload(1, RELEASE_MS, magnitude / 2));
+            if let Err(e) = res {
+                eprintln!("[haptics] failed to upload effects: {}", e);
+                return;
+            }
+            self.strength = strength;
+        }
+        let play = input_event {
+            time: timeval { tv_sec: 0, tv_usec: 0 },
+            type_: EV_FF,
+            code: self.effects[if press { 0 } else { 1 }] as u16,
+            value: 1,
+        };
+        let bytes = unsafe {
+            slice::from_raw_parts(&play as *const _ as *const u8, std::mem::size_of::<input_event>())
+        };
+        if let Err(e) = self.file.write_all(bytes) {
+            eprintln!("[haptics] failed to play effect: {}", e);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::error;
+use cairo::Context;
+use std::collections::VecDeque;
+
+// The last samples of a numeric widget, oldest first.
+pub struct History {
+    samples: VecDeque<f64>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            samples: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(2),
+        }
+    }
+
+    pub fn push(&mut self, value: f64) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+    }
+}
+
+// a History drawn behind the content of its button
+pub struct Sparkline {
+    pub history: History,
+    // theme accent when not set
+    pub color: Option<(f64, f64, f64)>,
+}
+
+// Samples as a filled line over the box, newest at the right edge. Without
+// a max the largest sample is the top.
+pub fn render_sparkline(
+    c: &Context,
+    samples: &[f64],
+    max: Option<f64>,
+    (left, top, width, height): (f64, f64, f64, f64),
+    (r, g, b): (f64, f64, f64),
+) -> error::Result<()> {
+    if samples.len() < 2 {
+        return Ok(());
+    }
+    let max = max
+        .unwrap_or_else(|| samples.iter().copied().fold(0.0, f64::max))
+        .max(f64::EPSILON);
+    let step = width / (samples.len() - 1) as f64;
+    let y = |v: f64| top + height - (v / max).clamp(0.0, 1.0) * height;
+    c.move_to(left, y(samples[0]));
+    for (i, &v) in samples.iter().enumerate().skip(1) {
+        c.line_to(left + step * i as f64, y(v));
+    }
+    c.set_source_rgb(r, g, b);
+    c.set_line_width(2.0);
+    c.stroke_preserve()?;
+    c.line_to(left + width, top + height);
+    c.line_to(left, top + height);
+    c.close_path();
+    c.set_source_rgba(r, g, b, 0.3);
+    c.fill()?;
+    Ok(())
+}
+
+impl Sparkline {
+    pub fn render(
+        &self,
+        c: &Context,
+        max: Option<f64>,
+        area: (f64, f64, f64, f64),
+        accent: (f64, f64, f64),
+    ) -> error::Result<()> {
+        let samples = self.history.samples.iter().copied().collect::<Vec<_>>();
+        render_sparkline(c, &samples, max, area, self.color.unwrap_or(accent))
+    }
+}
@@ -0,0 +1,348 @@
+use crate::compositor::{self, CompositorBackend, Handoff, Output, Workspace};
+use nix::unistd::{fork, geteuid, pipe, ForkResult};
+use serde_json::Value;
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::{
+        io::{AsFd, BorrowedFd, OwnedFd},
+        net::UnixStream,
+    },
+    path::{Path, PathBuf},
+};
+
+#[derive(Default)]
+pub struct HyprlandState {
+    workspaces: Vec<Workspace>,
+    focused_window_title: Option<String>,
+    // the window class, which is the app_id for wayland clients
+    focused_app_id: Option<String>,
+    focused_window_addr: Option<String>,
+    // from the monitor events, disabled ones are only known if we turned them off
+    monitors: Vec<Output>,
+    event_stream: Option<UnixStream>,
+    // partial lines read from the non-blocking event stream
+    event_buf: Vec<u8>,
+    // Hyprland closed the event stream, it exited or restarted
+    lost: bool,
+    // pipe to the helper that sends dispatch commands as the session user
+    dispatcher: Option<File>,
+}
+
+// $XDG_RUNTIME_DIR/hypr/<instance>, older releases used /tmp/hypr/<instance>
+fn find_instance_dir() -> Option<PathBuf> {
+    if let (Ok(runtime), Ok(sig)) = (
+        std::env::var("XDG_RUNTIME_DIR"),
+        std::env::var("HYPRLAND_INSTANCE_SIGNATURE"),
+    ) {
+        let path = Path::new(&runtime).join("hypr").join(sig);
+        if path.join(".socket2.sock").exists() {
+            return Some(path);
+        }
+    }
+    let mut roots: Vec<PathBuf> = fs::read_dir("/run/user")
+        .ok()?
+        .flatten()
+        .map(|d| d.path().join("hypr"))
+        .collect();
+    roots.push(PathBuf::from("/tmp/hypr"));
+    for root in roots {
+        let Ok(instances) = fs::read_dir(root) else { continue };
+        for instance in instances.flatten() {
+            if instance.path().join(".socket2.sock").exists() {
+                return Some(instance.path());
+            }
+        }
+    }
+    None
+}
+
+// hyprctl style request, Hyprland closes the socket after replying
+fn request(dir: &Path, cmd: &str) -> Option<Value> {
+    let mut stream = UnixStream::connect(dir.join(".socket.sock")).ok()?;
+    stream.write_all(cmd.as_bytes()).ok()?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).ok()?;
+    serde_json::from_str(&reply).ok()
+}
+
+// Every request needs a fresh connection and the socket is only reachable by
+// the session user, so once we are nobody the commands go through a forked
+// child that switched to that user instead.
+fn spawn_dispatcher(socket: PathBuf) -> Option<File> {
+    let (read, write) = pipe().ok()?;
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            drop(write);
+            // forked by the compositor waiter it may be that user already
+            if geteuid().is_root() && !compositor::become_owner(&socket) {
+                unsafe { libc::_exit(1) };
+            }
+            // exits once the daemon goes away and the pipe closes
+            for cmd in BufReader::new(File::from(read)).lines().map_while(Result::ok) {
+                if let Ok(mut stream) = UnixStream::connect(&socket) {
+                    let _ = stream.write_all(cmd.as_bytes());
+                    let _ = stream.read_to_end(&mut Vec::new());
+                }
+            }
+            unsafe { libc::_exit(0) };
+        }
+        Ok(ForkResult::Parent { .. }) => Some(File::from(write)),
+        Err(e) => {
+            eprintln!("[hyprland] failed to fork dispatcher: {}", e);
+            None
+        }
+    }
+}
+
+fn workspace_id(data: &str) -> Option<u64> {
+    data.split(',').next()?.parse().ok()
+}
+
+// What the initial state is built from, asked for by whoever can reach the
+// socket and handed over with the connection.
+const INITIAL_REQUESTS: [&str; 4] = ["j/activeworkspace", "j/workspaces", "j/activewindow", "j/monitors all"];
+
+// The event stream, the pipe to a dispatcher and the replies to
+// INITIAL_REQUESTS. Must be called before privilege drop or by the session
+// user.
+pub fn handoff() -> Option<Handoff> {
+    let dir = find_instance_dir()?;
+    let socket = dir.join(".socket2.sock");
+    let stream = UnixStream::connect(&socket).ok()?;
+    let replies = INITIAL_REQUESTS
+        .iter()
+        .map(|cmd| request(&dir, cmd).unwrap_or(Value::Null))
+        .collect();
+    let mut fds = vec![OwnedFd::from(stream)];
+    fds.extend(spawn_dispatcher(dir.join(".socket.sock")).map(OwnedFd::from));
+    Some(Handoff { socket, extra: Value::Array(replies), fds })
+}
+
+impl HyprlandState {
+    pub fn from_handoff(handoff: Handoff) -> Option<HyprlandState> {
+        let dir = handoff.socket.parent()?;
+        eprintln!("[hyprland] instance: {}", dir.display());
+
+        let mut fds = handoff.fds.into_iter();
+        let stream = UnixStream::from(fds.next()?);
+        let mut state = HyprlandState {
+            dispatcher: fds.next().map(File::from),
+            ..Default::default()
+        };
+        let reply = |i: usize| handoff.extra.get(i).filter(|r| !r.is_null());
+
+        let focused = reply(0).and_then(|w| w["id"].as_i64());
+        if let Some(Value::Array(arr)) = reply(1) {
+            state.workspaces = arr
+                .iter()
+                .filter_map(|w| w["id"].as_i64())
+                // special (scratchpad) workspaces have negative ids
+                .filter(|id| *id > 0)
+                .map(|id| Workspace {
+                    id: id as u64,
+                    idx: id.min(u8::MAX as i64) as u8,
+                    is_focused: Some(id) == focused,
+                    occupied: true,
+                    urgent: false,
+                    name: None,
+                })
+                .collect();
+            state.workspaces.sort_by_key(|w| w.id);
+        }
+        if let Some(win) = reply(2) {
+            state.focused_window_addr = win["address"].as_str().map(|a| a.to_string());
+            state.focused_window_title = win["title"].as_str().map(|t| t.to_string());
+            state.focused_app_id = win["class"].as_str().map(|c| c.to_string()).filter(|c| !c.is_empty());
+        }
+
+        if let Some(Value::Array(arr)) = reply(3) {
+            state.monitors = arr
+                .iter()
+                .filter_map(|m| {
+                    Some(Output {
+                        name: m["name"].as_str()?.to_string(),
+                        enabled: !m["disabled"].as_bool().unwrap_or(false),
+                    })
+                })
+                .collect();
+        }
+
+        eprintln!("[hyprland] ready: {} workspaces window: {:?}",
+            state.workspaces.len(), state.focused_window_title);
+
+        let _ = stream.set_nonblocking(true);
+        state.event_stream = Some(stream);
+        Some(state)
+    }
+
+    fn send(&mut self, cmd: &str) {
+        let Some(ref mut pipe) = self.dispatcher else { return };
+        if pipe.write_all(format!("{}\n", cmd).as_bytes()).is_err() {
+            eprintln!("[hyprland] dispatcher is gone");
+            self.dispatcher = None;
+        }
+    }
+
+    fn apply_event_line(&mut self, line: &str) -> bool {
+        let Some((event, data)) = line.split_once(">>") else { return false };
+        match event {
+            "workspacev2" => {
+                let Some(id) = workspace_id(data) else { return false };
+                let mut changed = false;
+                for ws in &mut self.workspaces {
+                    let was = ws.is_focused;
+                    ws.is_focused = ws.id == id;
+                    changed |= ws.is_focused != was;
+                }
+                changed
+            }
+            "createworkspacev2" => {
+                let Some(id) = workspace_id(data) else { return false };
+                if self.workspaces.iter().any(|w| w.id == id) {
+                    return false;
+                }
+                self.workspaces.push(Workspace {
+                    id,
+                    idx: id.min(u8::MAX as u64) as u8,
+                    is_focused: false,
+                    occupied: true,
+                    urgent: false,
+                    name: None,
+                });
+                self.workspaces.sort_by_key(|w| w.id);
+                true
+            }
+            "destroyworkspacev2" => {
+                let Some(id) = workspace_id(data) else { return false };
+                let before = self.workspaces.len();
+                self.workspaces.retain(|w| w.id != id);
+                before != self.workspaces.len()
+            }
+            "activewindowv2" => {
+                let addr = Some(data.to_string()).filter(|a| !a.is_empty() && a != ",");
+                self.focused_window_addr = addr.map(|a| format!("0x{}", a.trim_start_matches("0x")));
+                false
+            }
+            // CLASS,TITLE where the title may contain commas itself
+            "activewindow" => {
+                let (class, title) = data.split_once(',').unwrap_or((data, ""));
+                let title = Some(title.to_string()).filter(|t| !t.is_empty());
+                let app_id = Some(class.to_string()).filter(|c| !c.is_empty());
+                let changed = title != self.focused_window_title || app_id != self.focused_app_id;
+                self.focused_window_title = title;
+                self.focused_app_id = app_id;
+                changed
+            }
+            "windowtitlev2" => {
+                let Some((addr, title)) = data.split_once(',') else { return false };
+                let addr = format!("0x{}", addr.trim_start_matches("0x"));
+                if self.focused_window_addr.as_deref() != Some(addr.as_str()) {
+                    return false;
+                }
+                let title = Some(title.to_string());
+                if title != self.focused_window_title {
+                    self.focused_window_title = title;
+                    return true;
+                }
+                false
+            }
+            "monitoradded" => {
+                match self.monitors.iter_mut().find(|m| m.name == data) {
+                    Some(m) => m.enabled = true,
+                    None => self.monitors.push(Output { name: data.to_string(), enabled: true }),
+                }
+                false
+            }
+            // unplugged, or disabled by set_output_enabled which already marked it
+            "monitorremoved" => {
+                self.monitors.retain(|m| m.name != data || !m.enabled);
+                false
+            }
+            _ => false,
+        }
+    }
+}
+
+impl CompositorBackend for HyprlandState {
+    fn event_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.event_stream.as_ref().map(|s| s.as_fd())
+    }
+
+    fn workspaces(&self) -> &[Workspace] {
+        &self.workspaces
+    }
+
+    fn focused_window_title(&self) -> Option<&str> {
+        self.focused_window_title.as_deref()
+    }
+
+    fn focused_app_id(&self) -> Option<&str> {
+        self.focused_app_id.as_deref()
+    }
+
+    fn process_events(&mut self) -> bool {
+        let Some(ref mut stream) = self.event_stream else { return false };
+        if self.lost {
+            return false;
+        }
+        let mut chunk = [0u8; 4096];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    eprintln!("[hyprland] event stream closed");
+                    self.lost = true;
+                    break;
+                }
+                Ok(n) => self.event_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("[hyprland] event stream broke: {}", e);
+                    self.lost = true;
+                    break;
+                }
+            }
+        }
+        let mut changed = false;
+        while let Some(end) = self.event_buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.event_buf.drain(..=end).collect();
+            changed |= self.apply_event_line(String::from_utf8_lossy(&line).trim_end());
+        }
+        changed
+    }
+
+    fn focus_workspace(&mut self, idx: u8) {
+        self.send(&format!("dispatch workspace {}", idx));
+    }
+
+    fn move_window_to_workspace(&mut self, idx: u8) {
+        self.send(&format!("dispatch movetoworkspacesilent {}", idx));
+    }
+
+    fn release_output(&mut self, name: &str) {
+        self.send(&format!("keyword monitor {},disable", name));
+    }
+
+    fn outputs(&mut self) -> Vec<Output> {
+        self.monitors.clone()
+    }
+
+    fn set_output_enabled(&mut self, name: &str, enabled: bool) {
+        if let Some(m) = self.monitors.iter_mut().find(|m| m.name == name) {
+            m.enabled = enabled;
+        }
+        if enabled {
+            self.send(&format!("keyword monitor {},preferred,auto,1", name));
+        } else {
+            self.send(&format!("keyword monitor {},disable", name));
+        }
+    }
+
+    fn spawn(&mut self, command: &[String]) {
+        self.send(&format!("dispatch exec {}", compositor::shell_join(command)));
+    }
+
+    fn disconnected(&self) -> bool {
+        self.lost
+    }
+}
@@ -0,0 +1,36 @@
+// The essential icons compiled into the binary, the last place an icon is
+// looked for so a missing share directory does not leave buttons blank.
+const BUNDLED: &[(&str, &[u8])] = &[
+    ("battery_0_bar", include_bytes!("../share/tiny-dfr/battery_0_bar.svg")),
+    ("battery_1_bar", include_bytes!("../share/tiny-dfr/battery_1_bar.svg")),
+    ("battery_2_bar", include_bytes!("../share/tiny-dfr/battery_2_bar.svg")),
+    ("battery_3_bar", include_bytes!("../share/tiny-dfr/battery_3_bar.svg")),
+    ("battery_4_bar", include_bytes!("../share/tiny-dfr/battery_4_bar.svg")),
+    ("battery_5_bar", include_bytes!("../share/tiny-dfr/battery_5_bar.svg")),
+    ("battery_6_bar", include_bytes!("../share/tiny-dfr/battery_6_bar.svg")),
+    ("battery_full", include_bytes!("../share/tiny-dfr/battery_full.svg")),
+    ("battery_charging_20", include_bytes!("../share/tiny-dfr/battery_charging_20.svg")),
+    ("battery_charging_30", include_bytes!("../share/tiny-dfr/battery_charging_30.svg")),
+    ("battery_charging_50", include_bytes!("../share/tiny-dfr/battery_charging_50.svg")),
+    ("battery_charging_60", include_bytes!("../share/tiny-dfr/battery_charging_60.svg")),
+    ("battery_charging_80", include_bytes!("../share/tiny-dfr/battery_charging_80.svg")),
+    ("battery_charging_90", include_bytes!("../share/tiny-dfr/battery_charging_90.svg")),
+    ("battery_charging_full", include_bytes!("../share/tiny-dfr/battery_charging_full.svg")),
+    ("bolt", include_bytes!("../share/tiny-dfr/bolt.svg")),
+    ("volume_off", include_bytes!("../share/tiny-dfr/volume_off.svg")),
+    ("volume_down", include_bytes!("../share/tiny-dfr/volume_down.svg")),
+    ("volume_up", include_bytes!("../share/tiny-dfr/volume_up.svg")),
+    ("wifi", include_bytes!("../share/tiny-dfr/wifi.svg")),
+    ("wifi_off", include_bytes!("../share/tiny-dfr/wifi_off.svg")),
+    ("brightness_low", include_bytes!("../share/tiny-dfr/brightness_low.svg")),
+    ("brightness_high", include_bytes!("../share/tiny-dfr/brightness_high.svg")),
+    ("backlight_low", include_bytes!("../share/tiny-dfr/backlight_low.svg")),
+    ("backlight_high", include_bytes!("../share/tiny-dfr/backlight_high.svg")),
+    ("play_pause", include_bytes!("../share/tiny-dfr/play_pause.svg")),
+    ("fast_forward", include_bytes!("../share/tiny-dfr/fast_forward.svg")),
+    ("fast_rewind", include_bytes!("../share/tiny-dfr/fast_rewind.svg")),
+];
+
+pub fn bundled(name: &str) -> Option<&'static [u8]> {
+    BUNDLED.iter().find(|(n, _)| *n == name).map(|(_, data)| *data)
+}
@@ -0,0 +1,91 @@
+use crate::bus;
+use dbus::{
+    blocking::{
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+        Connection,
+    },
+    channel::BusType,
+    message::SignalArgs,
+};
+use std::{
+    os::unix::io::{AsFd, BorrowedFd},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const SEAT_PATH: &str = "/org/freedesktop/login1/seat/seat0";
+const SEAT_IFACE: &str = "org.freedesktop.login1.Seat";
+
+// IdleHint of seat0, set when the compositor (or swayidle and the like)
+// considers the session idle, so idle inhibitors of video players count too
+pub struct SeatIdleMonitor {
+    conn: Connection,
+    idle_since: Option<Instant>,
+}
+
+impl SeatIdleMonitor {
+    pub fn connect() -> Option<SeatIdleMonitor> {
+        let conn = match bus::open(BusType::System) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[idle] system bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let rule = format!(
+            "type='signal',interface='org.freedesktop.DBus.Properties',\
+             member='PropertiesChanged',path='{}',arg0='{}'",
+            SEAT_PATH, SEAT_IFACE
+        );
+        if let Err(e) = conn.add_match_no_cb(&rule) {
+            eprintln!("[idle] failed to subscribe: {}", e);
+            return None;
+        }
+        let mut monitor = SeatIdleMonitor { conn, idle_since: None };
+        monitor.refresh();
+        Some(monitor)
+    }
+
+    fn refresh(&mut self) {
+        let proxy = self.conn.with_proxy(LOGIND_DEST, SEAT_PATH, bus::CALL_TIMEOUT);
+        let idle = match proxy.get::<bool>(SEAT_IFACE, "IdleHint") {
+            Ok(idle) => idle,
+            Err(e) => {
+                eprintln!("[idle] failed to read IdleHint: {}", e);
+                false
+            }
+        };
+        self.idle_since = idle.then(|| {
+            // wall clock microseconds, turned into how long ago that was
+            let since = proxy.get::<u64>(SEAT_IFACE, "IdleSinceHint").unwrap_or(0);
+            let ago = SystemTime::now()
+                .duration_since(UNIX_EPOCH + Duration::from_micros(since))
+                .unwrap_or_default();
+            Instant::now().checked_sub(ago).unwrap_or_else(Instant::now)
+        });
+    }
+
+    pub fn process_events(&mut self) {
+        let changed = bus::drain(&self.conn).iter().any(|msg| {
+            PropertiesPropertiesChanged::from_message(msg).is_some_and(|sig| {
+                sig.interface_name == SEAT_IFACE
+                    && (sig.changed_properties.contains_key("IdleHint")
+                        || sig.invalidated_properties.iter().any(|p| p == "IdleHint"))
+            })
+        });
+        if changed {
+            self.refresh();
+        }
+    }
+
+    // None while the seat is in use
+    pub fn idle_since(&self) -> Option<Instant> {
+        self.idle_since
+    }
+}
+
+impl AsFd for SeatIdleMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
@@ -0,0 +1,275 @@
+use nix::sys::{
+    epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
+    socket::{getsockopt, sockopt::PeerCredentials},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    io::{ErrorKind, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsFd, BorrowedFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    time::{Duration, Instant},
+};
+
+pub const SOCKET_DIR: &str = "/run/tiny-dfr";
+pub const SOCKET_PATH: &str = "/run/tiny-dfr/control.sock";
+// a client gets this long to send its request line
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// longest request line, and clients waiting to finish theirs at once
+const MAX_REQUEST: usize = 64 * 1024;
+const MAX_PENDING: usize = 16;
+
+// One JSON request per line, same shape niri uses: "Unit" or {"Variant": arg}.
+// Buttons can carry one of these as their `Command` too.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub enum Request {
+    DisplayOff,
+    TogglePresentation,
+    SetPresentation(bool),
+    // full redraw with freshly built layers, for when something got stuck
+    Reset,
+    // touch coordinates, grid and device state instead of the normal layers
+    ToggleDiagnostics,
+    // plain F1 to F12 with layer switching frozen, or back to normal
+    ToggleFKeyLock,
+    // render times, damage and what triggered the recent redraws
+    FrameStats,
+    // switch to the layer with this name
+    SetLayer(String),
+    // the WindowSwitcher layer, or back to the layer it was opened from
+    ToggleWindowSwitcher,
+    // Id, layer and spoken label of every configured button
+    Buttons,
+    // the keys or Command of the button with this Id, wherever it is
+    TapButton(String),
+    // touch bar brightness while in use, until the config is reloaded
+    SetBrightness(u32),
+    // text shown over the active layer for this many ms, 0 for the default
+    Notify(String, u32),
+    // log every wakeup to the journal for this many minutes, then a summary
+    WakeupAudit(u32),
+    // keeps the connection open and streams `Event`s to it
+    EventStream,
+    // volume in percent of the audio streams of an app_id, reported by
+    // session-helper.sh for app volume sliders
+    AppVolume(String, u32),
+    // name of the default output sink, reported by session-helper.sh for
+    // SinkToggle buttons
+    DefaultSink(String),
+    // volume in percent and mute of the default output, reported by
+    // session-helper.sh for Volume buttons and volume sliders
+    Volume(u32, bool),
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub enum Event {
+    // text for screen readers, see share/tiny-dfr/session-helper.sh
+    Announce(String),
+    // freedesktop sound theme event to play
+    Sound { id: String, theme: Option<String> },
+    // absolute volume of the default output in percent, from a volume Slider
+    Volume(u32),
+    // app_id of the newly focused window, answered with an AppVolume request
+    FocusedApp(String),
+    // volume in percent for the audio streams of an app, from an app volume Slider
+    AppVolume { app_id: String, percent: u32 },
+    // sink to make the default output, or to switch away from if it is
+    ToggleSink(String),
+}
+
+pub type Response = Result<Value, String>;
+
+// an accepted client whose request line has not fully arrived yet
+struct Pending {
+    stream: UnixStream,
+    buf: Vec<u8>,
+    since: Instant,
+}
+
+// Clients are read without blocking, whatever they sent so far is kept
+// until the line is complete. The listener and the clients share an epoll
+// set of their own, which is what goes into the main one.
+pub struct IpcServer {
+    listener: UnixListener,
+    readiness: Epoll,
+    pending: Vec<Pending>,
+    subscribers: Vec<UnixStream>,
+    // the user of the active session, who may use the socket besides root
+    session_uid: Option<u32>,
+}
+
+fn peer_uid(stream: &UnixStream) -> Option<u32> {
+    getsockopt(stream, PeerCredentials).ok().map(|c| c.uid())
+}
+
+impl IpcServer {
+    // must be called before privilege drop, /run is not writable for nobody
+    pub fn bind() -> Option<IpcServer> {
+        let _ = fs::create_dir_all(SOCKET_DIR);
+        if Path::new(SOCKET_PATH).exists() {
+            let _ = fs::remove_file(SOCKET_PATH);
+        }
+        let listener = match UnixListener::bind(SOCKET_PATH) {
+            Ok(l) => l,
+            Err(e) => {
+                eprintln!("[ipc] failed to bind {}: {}", SOCKET_PATH, e);
+                return None;
+            }
+        };
+        let _ = listener.set_nonblocking(true);
+        // the daemon runs as nobody afterwards, anyone may connect and
+        // clients that are not the session user are turned away in accept
+        let _ = fs::set_permissions(SOCKET_PATH, fs::Permissions::from_mode(0o666));
+        let readiness = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)
+            .and_then(|e| e.add(&listener, EpollEvent::new(EpollFlags::EPOLLIN, 0)).map(|_| e));
+        let readiness = match readiness {
+            Ok(e) => e,
+            Err(e) => {
+                eprintln!("[ipc] failed to create epoll set: {}", e);
+                return None;
+            }
+        };
+        Some(IpcServer {
+            listener,
+            readiness,
+            pending: vec![],
+            subscribers: vec![],
+            session_uid: None,
+        })
+    }
+
+    fn allowed(&self, stream: &UnixStream) -> bool {
+        peer_uid(stream).is_some_and(|uid| uid == 0 || Some(uid) == self.session_uid)
+    }
+
+    // after a user switch the subscribers of the last user stop getting events
+    pub fn set_session(&mut self, uid: Option<u32>) {
+        if uid == self.session_uid {
+            return;
+        }
+        self.session_uid = uid;
+        let subscribers = std::mem::take(&mut self.subscribers);
+        self.subscribers = subscribers.into_iter().filter(|s| self.allowed(s)).collect();
+    }
+
+    // requests whose line is complete, with the stream to reply on
+    pub fn accept(&mut self) -> Vec<(Request, UnixStream)> {
+        // subscribers send nothing after their request, only closing is of interest
+        self.subscribers.retain_mut(|s| match s.read(&mut [0u8; 4096]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => e.kind() == ErrorKind::WouldBlock,
+        });
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("[ipc] accept failed: {}", e);
+                    break;
+                }
+            };
+            if !self.allowed(&stream) {
+                reply(stream, Err("only the user of the active session may use the socket".to_string()));
+                continue;
+            }
+            if self.pending.len() >= MAX_PENDING {
+                reply(stream, Err("too many clients".to_string()));
+                continue;
+            }
+            let _ = stream.set_nonblocking(true);
+            if self.readiness.add(&stream, EpollEvent::new(EpollFlags::EPOLLIN, 0)).is_err() {
+                continue;
+            }
+            self.pending.push(Pending { stream, buf: Vec::new(), since: Instant::now() });
+        }
+
+        let mut requests = Vec::new();
+        for mut client in std::mem::take(&mut self.pending) {
+            let mut chunk = [0u8; 4096];
+            let closed = loop {
+                match client.stream.read(&mut chunk) {
+                    Ok(0) => break true,
+                    Ok(n) => client.buf.extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break false,
+                    Err(_) => break true,
+                }
+                if client.buf.contains(&b'\n') || client.buf.len() > MAX_REQUEST {
+                    break false;
+                }
+            };
+            let line = match client.buf.iter().position(|&b| b == b'\n') {
+                Some(end) => Some(&client.buf[..end]),
+                // a client may close its side right after the request
+                None if closed => Some(&client.buf[..]),
+                None => None,
+            };
+            let done = line.is_some() || client.buf.len() > MAX_REQUEST || client.since.elapsed() > CLIENT_TIMEOUT;
+            if !done {
+                self.pending.push(client);
+                continue;
+            }
+            let _ = self.readiness.delete(&client.stream);
+            let res = match line {
+                Some(line) => serde_json::from_slice::<Request>(line.trim_ascii())
+                    .map_err(|e| format!("invalid request: {}", e)),
+                None if client.buf.len() > MAX_REQUEST => Err("request too long".to_string()),
+                None => Err("timed out waiting for the request".to_string()),
+            };
+            match res {
+                Ok(req) => requests.push((req, client.stream)),
+                Err(_) if closed && client.buf.is_empty() => {}
+                Err(e) => reply(client.stream, Err(e)),
+            }
+        }
+        requests
+    }
+}
+
+impl IpcServer {
+    pub fn subscribe(&mut self, mut stream: UnixStream) {
+        let _ = stream.write_all(b"{\"Ok\":null}\n");
+        // a subscriber that stops reading gets dropped instead of blocking the bar
+        let _ = stream.set_nonblocking(true);
+        // wakes the main loop once the subscriber hangs up
+        if self.readiness.add(&stream, EpollEvent::new(EpollFlags::EPOLLIN, 0)).is_ok() {
+            self.subscribers.push(stream);
+        }
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn broadcast(&mut self, event: &Event) {
+        let Ok(line) = serde_json::to_string(event) else { return };
+        let line = format!("{}\n", line);
+        self.subscribers
+            .retain_mut(|s| s.write_all(line.as_bytes()).is_ok());
+    }
+}
+
+pub fn reply(mut stream: UnixStream, response: Response) {
+    let body = match response {
+        Ok(v) => serde_json::json!({ "Ok": v }),
+        Err(e) => serde_json::json!({ "Err": e }),
+    };
+    let _ = stream.write_all(format!("{}\n", body).as_bytes());
+}
+
+impl AsFd for IpcServer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.readiness.0.as_fd()
+    }
+}
+
+impl Drop for IpcServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(SOCKET_PATH);
+    }
+}
@@ -0,0 +1,111 @@
+use nix::{
+    sys::{
+        signal::{signal, SigHandler, Signal},
+        socket::{
+            recvmsg, sendmsg, socketpair, AddressFamily, ControlMessage, ControlMessageOwned,
+            MsgFlags, SockFlag, SockType,
+        },
+    },
+    unistd::{fork, pipe, setgid, setgroups, setuid, ForkResult, User},
+};
+use std::{
+    fs::File,
+    io::{self, IoSlice, IoSliceMut, Read},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    process::{Command, Stdio},
+    sync::Arc,
+};
+
+// longest command line sent in one message
+const MAX_COMMAND: usize = 64 * 1024;
+
+fn serve(sock: OwnedFd) -> ! {
+    // nobody waits for the commands, the kernel reaps them
+    let _ = unsafe { signal(Signal::SIGCHLD, SigHandler::SigIgn) };
+    let mut buf = vec![0u8; MAX_COMMAND];
+    loop {
+        let mut cmsg = nix::cmsg_space!([std::os::fd::RawFd; 1]);
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let (len, stdout) = match recvmsg::<()>(sock.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::MSG_CMSG_CLOEXEC) {
+            // the daemon went away
+            Ok(msg) if msg.bytes == 0 => unsafe { libc::_exit(0) },
+            Ok(msg) => {
+                let fd = msg.cmsgs().ok().and_then(|mut c| {
+                    c.find_map(|c| match c {
+                        ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+                        _ => None,
+                    })
+                });
+                (msg.bytes, fd.map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }))
+            }
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => unsafe { libc::_exit(1) },
+        };
+        let command = String::from_utf8_lossy(&buf[..len]).into_owned();
+        let stdout = stdout.map_or_else(Stdio::null, Stdio::from);
+        if let Err(e) = Command::new("/bin/sh").arg("-c").arg(&command).stdout(stdout).spawn() {
+            eprintln!("[launcher] failed to run {:?}: {}", command, e);
+        }
+    }
+}
+
+// Runs the commands of LongPressExec and Custom buttons. The daemon keeps the
+// input and video groups to reopen the touch bar, and without root it can not
+// give them up for a child, so commands are started by a helper forked before
+// privilege drop that left every supplementary group behind.
+#[derive(Clone)]
+pub struct Launcher {
+    sock: Arc<OwnedFd>,
+}
+
+impl Launcher {
+    // must be called before privilege drop
+    pub fn spawn() -> Option<Launcher> {
+        let (ours, theirs) =
+            socketpair(AddressFamily::Unix, SockType::SeqPacket, None, SockFlag::SOCK_CLOEXEC)
+                .map_err(|e| eprintln!("[launcher] failed to create socket: {}", e))
+                .ok()?;
+        let nobody = User::from_name("nobody").ok().flatten()?;
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                drop(ours);
+                let dropped = setgroups(&[])
+                    .and_then(|_| setgid(nobody.gid))
+                    .and_then(|_| setuid(nobody.uid));
+                if dropped.is_err() {
+                    unsafe { libc::_exit(1) };
+                }
+                serve(theirs)
+            }
+            Ok(ForkResult::Parent { .. }) => Some(Launcher { sock: Arc::new(ours) }),
+            Err(e) => {
+                eprintln!("[launcher] failed to fork: {}", e);
+                None
+            }
+        }
+    }
+
+    // starts `sh -c command`, what it prints goes to `stdout` or nowhere
+    pub fn run(&self, command: &str, stdout: Option<&OwnedFd>) -> io::Result<()> {
+        if command.is_empty() {
+            return Ok(());
+        }
+        if command.len() > MAX_COMMAND {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "command too long"));
+        }
+        let fds = stdout.map(|fd| [fd.as_raw_fd()]);
+        let cmsgs: Vec<ControlMessage> = fds.iter().map(|fds| ControlMessage::ScmRights(fds)).collect();
+        sendmsg::<()>(self.sock.as_raw_fd(), &[IoSlice::new(command.as_bytes())], &cmsgs, MsgFlags::empty(), None)?;
+        Ok(())
+    }
+
+    // runs `sh -c command` and returns what it printed once it is done
+    pub fn output(&self, command: &str) -> io::Result<Vec<u8>> {
+        let (read, write) = pipe()?;
+        self.run(command, Some(&write))?;
+        drop(write);
+        let mut out = Vec::new();
+        File::from(read).read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
@@ -1,6 +1,9 @@
 use anyhow::{anyhow, Result};
 use cairo::{Antialias, Context, Format, ImageSurface, Surface};
-use chrono::{Local, Locale, Timelike, format::{StrftimeItems, Item as ChronoItem}};
+use chrono::{
+    format::{Item as ChronoItem, StrftimeItems},
+    DateTime, Datelike, Days, Local, Locale, Months, Timelike, Utc, Weekday,
+};
 use drm::control::ClipRect;
 use freedesktop_icons::lookup;
 use input::{
@@ -19,47 +22,177 @@ use librsvg_rebind::{prelude::HandleExt, Handle, Rectangle};
 use nix::{
     errno::Errno,
     sys::{
-        epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
+        epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
         signal::{SigSet, Signal},
     },
 };
+use pango::FontDescription;
 use privdrop::PrivDrop;
+use serde_json::json;
 use std::{
     cmp::min,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::{self, File, OpenOptions},
     os::{
         fd::{AsFd, AsRawFd},
-        unix::{fs::OpenOptionsExt, io::OwnedFd},
+        unix::{fs::OpenOptionsExt, io::OwnedFd, net::UnixStream},
     },
     panic::{self, AssertUnwindSafe},
     path::{Path, PathBuf},
+    time::Duration,
 };
-use udev::MonitorBuilder;
+use udev::{EventType, MonitorBuilder};
 
+mod animation;
 mod backlight;
+mod bluetooth;
+mod bus;
+mod color;
+mod compositor;
 mod config;
+mod custom;
+mod diagnostics;
+mod dnd;
 mod display;
+mod error;
 mod fonts;
+mod haptics;
+mod history;
+mod hyprland;
+mod icons;
+mod idle;
+mod ipc;
+mod launcher;
+mod mpris;
+mod network;
 mod niri;
+mod notifications;
+mod persist;
 mod pixel_shift;
+mod power;
+mod privacy;
+mod remap;
+mod screencast;
+mod script;
+mod service;
+mod sleep;
+mod stats;
+mod sway;
+mod sysmon;
+mod text;
+mod timezone;
 
 use crate::config::ConfigManager;
 use backlight::BacklightManager;
-use config::{ButtonConfig, Config};
+use compositor::{CompositorBackend, DisplayMode, Window, WindowLayout, Workspace};
+use config::{ButtonConfig, Config, OffLayer, Reload, SpacerSize, SwipeAction};
 use display::DrmBackend;
 use pixel_shift::{PixelShiftManager, PIXEL_SHIFT_WIDTH_PX};
+use stats::{FrameStats, RedrawCause, WakeupAudit};
 
 const BUTTON_SPACING_PX: i32 = 16;
+// what is left of the spacing between buttons of one Segment
+const SEGMENT_DIVIDER_PX: f64 = 2.0;
+// a Separator without FixedWidth, the spacing on both sides makes the gap
+const SEPARATOR_WIDTH_PX: u32 = 2;
 const ICON_SIZE: i32 = 48;
 const TIMEOUT_MS: i32 = 10 * 1000;
 const FN_TAP_THRESHOLD_MS: u128 = 300;
+const RECORDING_DOT_RADIUS: f64 = 10.0;
+const POPUP_TIMEOUT_MS: u128 = 10 * 1000;
+const DOUBLE_TAP_MS: u128 = 400;
+// workspace buttons shown at once without MaxWorkspaces, the rest behind "…"
+const DEFAULT_MAX_WORKSPACES: usize = 8;
+const ANIMATION_FRAME_MS: i32 = 500;
+const GRID_SPACING_PX: usize = 50;
+// between the button edge and left/right aligned content
+const CONTENT_PADDING_PX: f64 = 8.0;
+const LONG_PRESS_FRAME_MS: i32 = 30;
+const PRESS_FADE_FRAME_MS: i32 = 16;
+// how long a widget stays lit for a key pressed on a keyboard, see FlashOnKeypress
+const KEY_FLASH_MS: u128 = 250;
+// held for combinations with the buttons, not typing, see TypingBlockMs
+const MODIFIER_KEYS: [Key; 9] = [
+    Key::Fn,
+    Key::LeftCtrl,
+    Key::RightCtrl,
+    Key::LeftShift,
+    Key::RightShift,
+    Key::LeftAlt,
+    Key::RightAlt,
+    Key::LeftMeta,
+    Key::RightMeta,
+];
+const VIRTUAL_DEVICE_NAME: &str = "Dynamic Function Row Virtual Input Device";
+// keyboard keys that light up the widgets showing what they change
+const FLASH_KEYS: [Key; 8] = [
+    Key::VolumeUp,
+    Key::VolumeDown,
+    Key::Mute,
+    Key::BrightnessUp,
+    Key::BrightnessDown,
+    Key::PlayPause,
+    Key::NextSong,
+    Key::PreviousSong,
+];
+// kept free at the left edge for the Esc key of PersistentEscape
+const ESCAPE_WIDTH_PX: i32 = 160;
+const SWIPE_MIN_DISTANCE_PX: f64 = 250.0;
+// average over the whole swipe, in px/ms
+const SWIPE_MIN_SPEED: f64 = 0.8;
+const SWIPE_SEEK_US: i64 = 10 * 1000 * 1000;
+// how far a finger on a scrolling layer moves before it drags instead of tapping
+const SCROLL_SLOP_PX: f64 = 12.0;
+// second finger of a two finger tap lands within this of the first
+const TWO_FINGER_WINDOW_MS: u128 = 100;
+// and both are lifted within this of it
+const TWO_FINGER_TAP_MS: u128 = 300;
+// how long FKeyLockHotkey has to be held
+const FKEY_LOCK_HOLD_MS: u64 = 2000;
+const FKEYS: [Key; 12] = [
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6,
+    Key::F7, Key::F8, Key::F9, Key::F10, Key::F11, Key::F12,
+];
+// panics after which a widget is replaced by a placeholder
+const MAX_WIDGET_PANICS: u32 = 3;
+// indexed by epoll token
+const EPOLL_SOURCES: [&str; 21] = [
+    "input", "touchbar", "config", "udev", "compositor", "timezone",
+    "screencast", "camera", "bluetooth", "network", "ipc", "mpris", "dbus",
+    "notifications", "custom", "sleep", "idle", "dnd", "compositor-waiter", "session",
+    "remap",
+];
+
+// a finger on a scrolling layer, by slot
+struct Drag {
+    start: (f64, f64),
+    last_x: f64,
+    scrolling: bool,
+    layer: usize,
+}
+
+#[derive(Clone, Copy)]
+enum TouchPhase {
+    Down,
+    Motion,
+    Up,
+}
+
+// where a queued request came from, to send the response back
+enum Origin {
+    // buttons and hotkeys, nobody to answer
+    Local,
+    Socket(UnixStream),
+    Bus(dbus::Message),
+}
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum BatteryState {
     NotCharging,
     Charging,
     Low,
+    // a BatteryDevice that is not there, like a disconnected peripheral
+    Absent,
 }
 
 struct BatteryImages {
@@ -84,44 +217,165 @@ impl BatteryIconMode {
     }
 }
 
-fn get_volume_percent() -> Option<(u32, bool)> {
-    Some((0, true))
-}
-
-fn get_brightness_percent() -> Option<u32> {
-    None
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ContentAlign {
+    Left,
+    Center,
+    Right,
 }
 
-#[derive(Clone, Debug)]
-pub struct WifiInfo {
-    pub ssid: String,
-    pub signal: i32,
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SliderKind {
+    Volume,
+    Brightness,
+    // streams of the focused app
+    AppVolume,
 }
 
-fn get_wifi_info() -> Option<WifiInfo> {
+fn get_brightness_percent() -> Option<u32> {
     None
 }
 
+#[derive(Default)]
 enum ButtonImage {
     Text(String),
     Svg(Handle),
     Bitmap(ImageSurface),
+    // Svg or Bitmap with a label next to it
+    IconText { icon: Box<ButtonImage>, text: String, spacing: f64, align: ContentAlign },
     Time(Vec<ChronoItem<'static>>, Locale),
-    Battery(String, BatteryIconMode, BatteryImages),
-    Volume,
+    // the month around this day, shown after tapping a Time button
+    Calendar(DateTime<Local>, Locale),
+    // device, mode, icons and the capacity/state last read by update_battery
+    Battery(String, BatteryIconMode, BatteryImages, (u32, BatteryState)),
+    // level and mute of the default output, None until session-helper.sh reported them
+    Volume(Option<(u32, bool)>),
     Brightness,
-    Wifi,
-    NiriWorkspace { idx: u8, focused: bool },
-    NiriWindowTitle(String),
+    Wifi(Option<network::WifiInfo>),
+    // None while no external output is plugged in
+    Displays(Option<DisplayMode>),
+    // icon from WorkspaceIcons for its name, drawn instead of the name or idx
+    NiriWorkspace {
+        idx: u8,
+        name: Option<String>,
+        icon: Option<Box<ButtonImage>>,
+        focused: bool,
+        occupied: bool,
+        urgent: bool,
+    },
+    // "…" at either end of a capped workspace strip, how many it hides that way
+    WorkspaceOverflow { forward: bool, hidden: usize },
+    NiriWindowTitle(String, Option<WindowLayout>),
+    // a window in the window switcher, app icon looked up by app_id in Theme
+    NiriWindow {
+        id: u64,
+        title: String,
+        app_id: Option<String>,
+        icon: Option<Box<ButtonImage>>,
+        focused: bool,
+    },
+    ScreenRecording(bool),
+    Privacy { camera: bool, mic: bool },
+    // percentages, drawn over a bar when graph is set
+    Cpu { graph: bool, sampler: sysmon::CpuSampler, usage: Option<u32> },
+    Memory { graph: bool, usage: Option<u32> },
+    LoadAvg(Option<f64>),
+    // sensor file, degrees from which the button turns to the warning color, last reading
+    Temperature { sensor: Option<PathBuf>, warning: f64, celsius: Option<f64> },
+    // output of an external command, None until it first ran
+    Custom { key: custom::CustomKey, output: Option<custom::CustomOutput> },
+    // drawn by a Rhai script
+    Script(Box<script::ScriptWidget>),
+    Bluetooth { mac: String, state: bluetooth::DeviceState },
+    // MAC or bluetooth::FIRST_AUDIO, name and percent of the device shown, None while
+    // nothing matching is connected or it does not report a battery
+    BluetoothBattery { device: String, shown: Option<(String, u8)> },
+    // sink made the default output on tap, whether it is, None until known
+    SinkToggle { sink: String, label: String, on: Option<bool> },
+    Media(Option<mpris::MediaState>),
+    // None while no supported notification daemon runs
+    DoNotDisturb(Option<dnd::DndState>),
+    // percent, None until known
+    Slider { kind: SliderKind, value: Option<u32> },
+    // a widget that kept panicking
+    Failed,
+    // a thin line between the buttons on either side
+    Separator,
+    #[default]
     Spacer,
 }
 
+#[derive(Default)]
 struct Button {
+    // Id from the config, None for buttons the daemon makes up itself
+    id: Option<String>,
     image: ButtonImage,
     changed: bool,
     active: bool,
     action: Vec<Key>,
     clickable: bool,
+    // tapping runs this instead of sending keys
+    command: Option<ipc::Request>,
+    // no press/release sounds for this one
+    muted: bool,
+    long_press_action: Vec<Key>,
+    long_press_exec: Option<String>,
+    // set while held and the long press has not fired yet
+    press_started: Option<std::time::Instant>,
+    // times the widget code of this button panicked
+    panics: u32,
+    // background between button_inactive (0) and button_active (1) as last drawn
+    shown_level: f64,
+    fade: Option<animation::Fade>,
+    // recent values drawn behind the content, sampled on every live poll
+    sparkline: Option<history::Sparkline>,
+    // icon name and icon theme, looked up again when the preferred variant changes
+    icon_source: Option<(String, String)>,
+    symbolic: bool,
+    // held down with the keys of buttons tapped while this one is held
+    chord_modifier: Vec<Key>,
+    // another button was tapped while this chord modifier was held
+    chorded: bool,
+    // MinWidth and MaxWidth in pixels, FixedWidth sets both
+    width_range: (Option<f64>, Option<f64>),
+    // the group of the bar this button goes in, see FunctionLayer::aligned_spans
+    align: Option<ContentAlign>,
+    // Segment, joined with the buttons next to it that have the same one
+    segment: Option<String>,
+    // the content as last rendered and how wide, see render_cached
+    render_cache: Option<(ImageSurface, u64)>,
+    // when a keyboard key this button shows was pressed, lit up for a moment
+    flash: Option<std::time::Instant>,
+}
+
+fn width_range(cfg: &ButtonConfig) -> (Option<f64>, Option<f64>) {
+    let fixed = match (cfg.fixed_width, cfg.spacer) {
+        (Some(w), _) | (None, Some(SpacerSize::Px(w))) => Some(w),
+        _ if cfg.separator == Some(true) => Some(SEPARATOR_WIDTH_PX),
+        _ => None,
+    };
+    match fixed {
+        Some(w) => (Some(w as f64), Some(w as f64)),
+        None => (cfg.min_width.map(f64::from), cfg.max_width.map(f64::from)),
+    }
+}
+
+fn button_align(cfg: &ButtonConfig) -> Option<ContentAlign> {
+    match cfg.align.as_deref() {
+        Some("left") => Some(ContentAlign::Left),
+        Some("center") => Some(ContentAlign::Center),
+        Some("right") => Some(ContentAlign::Right),
+        None => None,
+        _ => panic!("invalid button alignment, accepted: left, center, right"),
+    }
+}
+
+fn monitor_graph(style: &str) -> bool {
+    match style {
+        "percentage" => false,
+        "graph" => true,
+        _ => panic!("invalid system monitor style, accepted: percentage, graph"),
+    }
 }
 
 fn try_load_svg(path: &str) -> Result<ButtonImage> {
@@ -198,11 +452,28 @@ fn try_load_image(name: impl AsRef<str>, theme: Option<impl AsRef<str>>) -> Resu
         };
     }
 
+    if let Some(data) = icons::bundled(name) {
+        return match Handle::from_data(data) {
+            Ok(Some(svg)) => Ok(ButtonImage::Svg(svg)),
+            _ => Err(anyhow!("failed to load bundled icon {name}")),
+        };
+    }
+
     Err(last_err.context(format!(
         "failed loading all possible paths for icon {name}"
     )))
 }
 
+// Themed icons in the preferred variant, -symbolic or full color, or the
+// other one when the theme lacks it.
+fn try_load_variant(name: &str, theme: Option<&str>, symbolic: bool) -> Result<ButtonImage> {
+    let Some(theme) = theme else { return try_load_image(name, theme) };
+    let base = name.strip_suffix("-symbolic").unwrap_or(name);
+    let symbolic_name = format!("{base}-symbolic");
+    let order = if symbolic { [symbolic_name.as_str(), base] } else { [base, symbolic_name.as_str()] };
+    try_load_image(order[0], Some(theme)).or_else(|_| try_load_image(order[1], Some(theme)))
+}
+
 fn find_battery_device() -> Option<String> {
     let power_supply_path = "/sys/class/power_supply";
     if let Ok(entries) = fs::read_dir(power_supply_path) {
@@ -221,34 +492,24 @@ fn find_battery_device() -> Option<String> {
     None
 }
 
-fn get_battery_state(battery: &str) -> (u32, BatteryState) {
-    let status_path = format!("/sys/class/power_supply/{}/status", battery);
-    let status = fs::read_to_string(&status_path).unwrap_or_else(|_| "Unknown".to_string());
-
-    #[cfg(target_arch = "x86_64")]
-    let capacity = {
-        let charge_now_path = format!("/sys/class/power_supply/{}/charge_now", battery);
-        let charge_full_path = format!("/sys/class/power_supply/{}/charge_full", battery);
-        let charge_now = fs::read_to_string(&charge_now_path)
-            .ok()
-            .and_then(|s| s.trim().parse::<f64>().ok());
-        let charge_full = fs::read_to_string(&charge_full_path)
-            .ok()
-            .and_then(|s| s.trim().parse::<f64>().ok());
-        match (charge_now, charge_full) {
-            (Some(now), Some(full)) if full > 0.0 => ((now / full) * 100.0).round() as u32,
-            _ => 100,
-        }
+// capacity and state from the attributes of a power_supply, read from sysfs
+// or taken from the properties of its uevent
+fn battery_state_from(attr: impl Fn(&str) -> Option<String>) -> (u32, BatteryState) {
+    let num = |name: &str| attr(name).and_then(|s| s.trim().parse::<f64>().ok());
+    let Some(status) = attr("status") else {
+        return (0, BatteryState::Absent);
     };
 
-    #[cfg(target_arch = "aarch64")]
-    let capacity = {
-        let capacity_path = format!("/sys/class/power_supply/{}/capacity", battery);
-        fs::read_to_string(&capacity_path)
-            .ok()
-            .and_then(|s| s.trim().parse::<u32>().ok())
-            .unwrap_or(100)
+    // On T2 Macs the energy left of what a full charge holds, elsewhere and
+    // for peripherals without charge counters the reported capacity
+    #[cfg(target_arch = "x86_64")]
+    let ratio = match (num("charge_now"), num("charge_full")) {
+        (Some(now), Some(full)) if full > 0.0 => Some((now / full) * 100.0),
+        _ => None,
     };
+    #[cfg(not(target_arch = "x86_64"))]
+    let ratio = None;
+    let capacity = ratio.or_else(|| num("capacity")).map_or(100, |c| c.round() as u32);
 
     let state = match status.trim() {
         "Charging" | "Full" => BatteryState::Charging,
@@ -258,58 +519,270 @@ fn get_battery_state(battery: &str) -> (u32, BatteryState) {
     (capacity, state)
 }
 
+fn get_battery_state(battery: &str) -> (u32, BatteryState) {
+    battery_state_from(|attr| {
+        fs::read_to_string(format!("/sys/class/power_supply/{}/{}", battery, attr)).ok()
+    })
+}
+
 impl Button {
     fn with_config(cfg: ButtonConfig) -> Button {
-        if let Some(text) = cfg.text {
+        let command = match cfg.command {
+            Some(ref c) => Some(c.clone()),
+            None if cfg.display_off == Some(true) => Some(ipc::Request::DisplayOff),
+            None => None,
+        };
+        let muted = cfg.sound == Some(false);
+        let long_press_action = cfg.long_press_action.clone();
+        let long_press_exec = cfg.long_press_exec.clone();
+        let chord_modifier = cfg.chord_modifier.clone();
+        let id = cfg.id.clone();
+        let segment = cfg.segment.clone();
+        let width_range = width_range(&cfg);
+        let align = button_align(&cfg);
+        let sparkline = cfg.sparkline.map(|samples| history::Sparkline {
+            history: history::History::new(samples as usize),
+            color: cfg.sparkline_color.as_deref().and_then(crate::config::hex_to_rgb),
+        });
+        let mut button = Button::with_image_config(cfg);
+        button.sparkline = sparkline;
+        button.command = command;
+        button.muted = muted;
+        button.long_press_action = long_press_action;
+        button.long_press_exec = long_press_exec;
+        button.chord_modifier = chord_modifier;
+        button.id = id;
+        button.width_range = width_range;
+        button.align = align;
+        button.segment = segment;
+        button
+    }
+
+    fn with_image_config(cfg: ButtonConfig) -> Button {
+        if cfg.spacer.is_some() {
+            return Button::new_spacer();
+        }
+        if cfg.separator == Some(true) {
+            return Button::new_simple(ButtonImage::Separator, vec![], false);
+        }
+        if let (Some(icon), Some(text)) = (&cfg.icon, &cfg.text) {
+            let align = match cfg.content_align.as_deref() {
+                Some("left") => ContentAlign::Left,
+                Some("center") | None => ContentAlign::Center,
+                Some("right") => ContentAlign::Right,
+                _ => panic!("invalid content alignment, accepted: left, center, right"),
+            };
+            Button::new_icon_text(
+                icon,
+                text.clone(),
+                cfg.theme.as_ref(),
+                cfg.action,
+                cfg.icon_spacing.unwrap_or(8.0),
+                align,
+            )
+        } else if let Some(text) = cfg.text {
             Button::new_text(text, cfg.action)
         } else if let Some(icon) = cfg.icon {
             Button::new_icon(&icon, cfg.theme, cfg.action)
         } else if let Some(time) = cfg.time {
             Button::new_time(cfg.action, &time, cfg.locale.as_deref())
         } else if let Some(battery_mode) = cfg.battery {
-            if let Some(battery) = find_battery_device() {
+            // a configured device is kept even while it is gone, it may come back
+            if let Some(battery) = cfg.battery_device.or_else(find_battery_device) {
                 Button::new_battery(cfg.action, battery, battery_mode, cfg.theme)
             } else {
                 Button::new_text("Battery N/A".to_string(), cfg.action)
             }
         } else if cfg.volume == Some(true) {
-            Button::new_simple(ButtonImage::Volume, cfg.action, false)
+            Button::new_simple(ButtonImage::Volume(None), cfg.action, false)
         } else if cfg.brightness == Some(true) {
             Button::new_simple(ButtonImage::Brightness, cfg.action, false)
+        } else if let Some(kind) = cfg.slider {
+            let kind = match kind.as_str() {
+                "volume" => SliderKind::Volume,
+                "brightness" => SliderKind::Brightness,
+                "app-volume" => SliderKind::AppVolume,
+                _ => panic!("invalid slider, accepted kinds: volume, brightness, app-volume"),
+            };
+            Button::new_simple(ButtonImage::Slider { kind, value: None }, cfg.action, true)
+        } else if cfg.displays == Some(true) {
+            Button::new_simple(ButtonImage::Displays(None), cfg.action, true)
         } else if cfg.wifi == Some(true) {
-            Button::new_simple(ButtonImage::Wifi, cfg.action, false)
+            Button::new_simple(ButtonImage::Wifi(None), cfg.action, false)
+        } else if cfg.screen_recording == Some(true) {
+            Button::new_simple(ButtonImage::ScreenRecording(false), cfg.action, false)
+        } else if let Some(mac) = cfg.bluetooth {
+            Button::new_simple(
+                ButtonImage::Bluetooth { mac, state: Default::default() },
+                cfg.action,
+                true,
+            )
+        } else if let Some(device) = cfg.bluetooth_battery {
+            Button::new_simple(ButtonImage::BluetoothBattery { device, shown: None }, cfg.action, false)
+        } else if let Some(sink) = cfg.sink_toggle {
+            let label = cfg.text.unwrap_or_else(|| "EQ".to_string());
+            Button::new_simple(ButtonImage::SinkToggle { sink, label, on: None }, cfg.action, true)
+        } else if cfg.media == Some(true) {
+            Button::new_simple(ButtonImage::Media(None), cfg.action, true)
+        } else if cfg.do_not_disturb == Some(true) {
+            Button::new_simple(ButtonImage::DoNotDisturb(None), cfg.action, true)
+        } else if cfg.privacy == Some(true) {
+            Button::new_simple(
+                ButtonImage::Privacy { camera: false, mic: false },
+                cfg.action,
+                false,
+            )
+        } else if let Some(style) = cfg.cpu {
+            let graph = monitor_graph(&style);
+            let sampler = sysmon::CpuSampler::new();
+            Button::new_simple(ButtonImage::Cpu { graph, sampler, usage: None }, cfg.action, false)
+        } else if let Some(style) = cfg.memory {
+            let graph = monitor_graph(&style);
+            let usage = sysmon::memory_percent();
+            Button::new_simple(ButtonImage::Memory { graph, usage }, cfg.action, false)
+        } else if cfg.load_avg == Some(true) {
+            Button::new_simple(ButtonImage::LoadAvg(sysmon::load_avg()), cfg.action, false)
+        } else if let Some(path) = cfg.script {
+            match script::ScriptWidget::load(&path, cfg.interval_ms.unwrap_or(1000).max(16)) {
+                Ok(widget) => {
+                    let clickable = !cfg.action.is_empty();
+                    Button::new_simple(ButtonImage::Script(Box::new(widget)), cfg.action, clickable)
+                }
+                Err(e) => {
+                    eprintln!("[script] {}", e);
+                    Button::new_text("Script error".to_string(), cfg.action)
+                }
+            }
+        } else if let Some(command) = cfg.custom {
+            let json = match cfg.return_type.as_deref() {
+                Some("text") | None => false,
+                Some("json") => true,
+                _ => panic!("invalid return type, accepted: text, json"),
+            };
+            let key = custom::CustomKey {
+                command,
+                interval_ms: cfg.interval_ms.unwrap_or(5000).max(100),
+                json,
+            };
+            let clickable = !cfg.action.is_empty();
+            Button::new_simple(ButtonImage::Custom { key, output: None }, cfg.action, clickable)
+        } else if let Some(sensor) = cfg.temperature {
+            let path = sysmon::find_temperature_sensor(&sensor);
+            if path.is_none() {
+                eprintln!("[sysmon] no temperature sensor {:?}", sensor);
+            }
+            let celsius = path.as_deref().and_then(sysmon::read_temperature);
+            Button::new_simple(
+                ButtonImage::Temperature {
+                    sensor: path,
+                    warning: cfg.temperature_warning.unwrap_or(80.0),
+                    celsius,
+                },
+                cfg.action,
+                false,
+            )
         } else {
             Button::new_spacer()
         }
     }
 
+    // refreshes a system monitor, marks the button changed if its value moved
+    fn poll_monitor(&mut self) {
+        match self.image {
+            ButtonImage::Cpu { ref mut sampler, ref mut usage, .. } => {
+                let current = sampler.sample();
+                if *usage != current {
+                    *usage = current;
+                    self.changed = true;
+                }
+            }
+            ButtonImage::Memory { ref mut usage, .. } => {
+                let current = sysmon::memory_percent();
+                if *usage != current {
+                    *usage = current;
+                    self.changed = true;
+                }
+            }
+            ButtonImage::LoadAvg(ref mut load) => {
+                let current = sysmon::load_avg();
+                if *load != current {
+                    *load = current;
+                    self.changed = true;
+                }
+            }
+            ButtonImage::Temperature { ref sensor, ref mut celsius, .. } => {
+                // whole degrees are all that is shown
+                let current = sensor.as_deref().and_then(sysmon::read_temperature);
+                if celsius.map(f64::round) != current.map(f64::round) {
+                    *celsius = current;
+                    self.changed = true;
+                }
+            }
+            _ => {}
+        }
+        let value = self.sample();
+        if let (Some(sparkline), Some(value)) = (self.sparkline.as_mut(), value) {
+            sparkline.history.push(value);
+            self.changed = true;
+        }
+    }
+
+    // the number a sparkline of this button follows
+    fn sample(&self) -> Option<f64> {
+        match self.image {
+            ButtonImage::Cpu { usage, .. } | ButtonImage::Memory { usage, .. } => {
+                usage.map(|u| u as f64)
+            }
+            ButtonImage::LoadAvg(load) => load,
+            ButtonImage::Temperature { celsius, .. } => celsius,
+            ButtonImage::Wifi(ref info) => info.as_ref().map(|i| i.signal as f64),
+            ButtonImage::Custom { output: Some(ref o), .. } => {
+                o.percentage.or_else(|| o.text.trim().parse().ok())
+            }
+            _ => None,
+        }
+    }
+
+    // top of the sparkline scale, None follows the largest sample
+    fn sample_max(&self) -> Option<f64> {
+        match self.image {
+            ButtonImage::Cpu { .. } | ButtonImage::Memory { .. } | ButtonImage::Wifi(_) => {
+                Some(100.0)
+            }
+            ButtonImage::Custom { output: Some(ref o), .. } if o.percentage.is_some() => {
+                Some(100.0)
+            }
+            _ => None,
+        }
+    }
+
     fn new_spacer() -> Button {
         Button {
             action: vec![],
-            active: false,
             changed: false,
             clickable: true,
             image: ButtonImage::Spacer,
+            ..Default::default()
         }
     }
 
     fn new_text(text: String, action: Vec<Key>) -> Button {
         Button {
             action,
-            active: false,
             changed: false,
             clickable: true,
             image: ButtonImage::Text(text),
+            ..Default::default()
         }
     }
 
     fn new_simple(image: ButtonImage, action: Vec<Key>, clickable: bool) -> Button {
         Button {
             action,
-            active: false,
             changed: true,
             clickable,
             image,
+            ..Default::default()
         }
     }
 
@@ -318,13 +791,40 @@ impl Button {
         theme: Option<impl AsRef<str>>,
         action: Vec<Key>,
     ) -> Button {
-        let image = try_load_image(path, theme).expect("failed to load icon");
+        let (path, theme) = (path.as_ref(), theme.as_ref().map(|t| t.as_ref()));
+        let image = try_load_variant(path, theme, false).expect("failed to load icon");
         Button {
             action,
             image,
-            active: false,
             changed: false,
             clickable: true,
+            icon_source: theme.map(|t| (path.to_string(), t.to_string())),
+            ..Default::default()
+        }
+    }
+
+    fn new_icon_text(
+        path: impl AsRef<str>,
+        text: String,
+        theme: Option<impl AsRef<str>>,
+        action: Vec<Key>,
+        spacing: f64,
+        align: ContentAlign,
+    ) -> Button {
+        let (path, theme) = (path.as_ref(), theme.as_ref().map(|t| t.as_ref()));
+        let icon = try_load_variant(path, theme, false).expect("failed to load icon");
+        Button {
+            action,
+            image: ButtonImage::IconText {
+                icon: Box::new(icon),
+                text,
+                spacing,
+                align,
+            },
+            changed: false,
+            clickable: true,
+            icon_source: theme.map(|t| (path.to_string(), t.to_string())),
+            ..Default::default()
         }
     }
 
@@ -373,9 +873,9 @@ impl Button {
             "both" => BatteryIconMode::Both,
             _ => panic!("invalid battery mode, accepted modes: icon, percentage, both"),
         };
+        let shown = get_battery_state(&battery);
         Button {
             action,
-            active: false,
             changed: false,
             clickable: true,
             image: ButtonImage::Battery(
@@ -386,7 +886,9 @@ impl Button {
                     bolt,
                     charging,
                 },
+                shown,
             ),
+            ..Default::default()
         }
     }
 
@@ -409,31 +911,278 @@ impl Button {
             .unwrap_or(Locale::POSIX);
         Button {
             action,
-            active: false,
             changed: false,
-            clickable: false,
+            clickable: true,
             image: ButtonImage::Time(format_items, locale),
+            ..Default::default()
         }
     }
 
-    fn new_niri_workspace(idx: u8, focused: bool, id: u64) -> Button {
-        let _ = id;
+    fn new_calendar(locale: Locale) -> Button {
+        Button {
+            image: ButtonImage::Calendar(Local::now(), locale),
+            ..Default::default()
+        }
+    }
+
+    fn new_niri_workspace(ws: &Workspace, icon: Option<Box<ButtonImage>>) -> Button {
+        Button {
+            action: vec![],
+            changed: true,
+            clickable: true,
+            image: ButtonImage::NiriWorkspace {
+                idx: ws.idx,
+                name: ws.name.clone(),
+                icon,
+                focused: ws.is_focused,
+                occupied: ws.occupied,
+                urgent: ws.urgent,
+            },
+            ..Default::default()
+        }
+    }
+
+    fn new_workspace_overflow(forward: bool, hidden: usize) -> Button {
         Button {
             action: vec![],
-            active: false,
             changed: true,
             clickable: true,
-            image: ButtonImage::NiriWorkspace { idx, focused },
+            image: ButtonImage::WorkspaceOverflow { forward, hidden },
+            ..Default::default()
         }
     }
 
-    fn new_niri_window_title(title: String) -> Button {
+    fn new_niri_window_title(title: String, layout: Option<WindowLayout>) -> Button {
         Button {
             action: vec![],
-            active: false,
             changed: true,
             clickable: false,
-            image: ButtonImage::NiriWindowTitle(title),
+            image: ButtonImage::NiriWindowTitle(title, layout),
+            ..Default::default()
+        }
+    }
+
+    fn new_niri_window(window: &Window, icon: Option<Box<ButtonImage>>) -> Button {
+        Button {
+            action: vec![],
+            changed: true,
+            clickable: true,
+            image: ButtonImage::NiriWindow {
+                id: window.id,
+                title: window.title.clone(),
+                app_id: window.app_id.clone(),
+                icon,
+                focused: window.is_focused,
+            },
+            ..Default::default()
+        }
+    }
+
+    // taps that do more than send keys, ignored in presentation mode
+    fn has_tap_action(&self) -> bool {
+        matches!(
+            self.image,
+            ButtonImage::Battery(..)
+                | ButtonImage::Time(..)
+                | ButtonImage::Bluetooth { .. }
+                | ButtonImage::SinkToggle { .. }
+                | ButtonImage::Displays(_)
+                | ButtonImage::Media(_)
+                | ButtonImage::DoNotDisturb(_)
+                | ButtonImage::NiriWorkspace { .. }
+                | ButtonImage::WorkspaceOverflow { .. }
+                | ButtonImage::NiriWindow { .. }
+                | ButtonImage::Slider { .. }
+        )
+    }
+
+    // a finger at `fraction` of the slider's width, the new value if it moved
+    fn set_slider(&mut self, fraction: f64) -> Option<(SliderKind, u32)> {
+        let ButtonImage::Slider { kind, ref mut value } = self.image else { return None };
+        // volumes are set by session-helper.sh, which also reports where they start
+        if kind != SliderKind::Brightness && value.is_none() {
+            return None;
+        }
+        let percent = (fraction.clamp(0.0, 1.0) * 100.0).round() as u32;
+        if *value == Some(percent) {
+            return None;
+        }
+        *value = Some(percent);
+        self.changed = true;
+        Some((kind, percent))
+    }
+
+    // workspace buttons move the focused window there when held
+    fn has_long_press(&self) -> bool {
+        !self.long_press_action.is_empty()
+            || self.long_press_exec.is_some()
+            || matches!(self.image, ButtonImage::NiriWorkspace { .. })
+    }
+
+    // how far the fill has grown while the button is held
+    fn long_press_progress(&self, cfg: &Config) -> Option<f64> {
+        if cfg.reduce_motion {
+            return None;
+        }
+        let started = self.press_started?;
+        Some((started.elapsed().as_millis() as f64 / cfg.long_press_ms as f64).min(1.0))
+    }
+
+    fn fire_long_press<F>(&mut self, uinput: &mut UInputHandle<F>, launcher: &Option<launcher::Launcher>)
+    where
+        F: AsRawFd,
+    {
+        self.press_started = None;
+        self.changed = true;
+        toggle_keys(uinput, &self.long_press_action, 1);
+        toggle_keys(uinput, &self.long_press_action, 0);
+        if let Some(ref cmd) = self.long_press_exec {
+            match launcher {
+                Some(l) => {
+                    if let Err(e) = l.run(cmd, None) {
+                        eprintln!("[long-press] failed to run {:?}: {}", cmd, e);
+                    }
+                }
+                None => eprintln!("[long-press] no launcher to run {:?} with", cmd),
+            }
+        }
+    }
+
+    // lifted before the long press fired, send the keys of a plain tap
+    fn finish_tap<F>(&mut self, uinput: &mut UInputHandle<F>)
+    where
+        F: AsRawFd,
+    {
+        // chord modifiers send their own keys when nothing was chorded with them
+        let lone_modifier =
+            !self.chord_modifier.is_empty() && !self.has_long_press() && !self.chorded;
+        if self.active && (self.press_started.is_some() || lone_modifier) {
+            toggle_keys(uinput, &self.action, 1);
+            toggle_keys(uinput, &self.action, 0);
+        }
+    }
+
+    // the background fades after a press or release, not drawn yet counts too
+    fn lit(&self) -> bool {
+        self.active || self.flash.is_some_and(|t| t.elapsed().as_millis() < KEY_FLASH_MS)
+    }
+
+    // a flash keeps it here until it is over and faded back
+    fn fading(&self) -> bool {
+        self.fade.is_some()
+            || self.flash.is_some()
+            || self.shown_level != if self.lit() { 1.0 } else { 0.0 }
+    }
+
+    // how far the background has faded towards button_active, starts a new
+    // fade from wherever the last one got to when the button changed state
+    fn press_level(&mut self, cfg: &Config) -> f64 {
+        if !self.lit() {
+            self.flash = None;
+        }
+        let target = if self.lit() { 1.0 } else { 0.0 };
+        if cfg.reduce_motion || cfg.press_fade_ms == 0 {
+            self.fade = None;
+            self.shown_level = target;
+            return target;
+        }
+        if !self.fade.is_some_and(|f| f.to == target) {
+            if self.shown_level == target {
+                self.fade = None;
+                return target;
+            }
+            self.fade = Some(animation::Fade::new(self.shown_level, target));
+        }
+        let fade = self.fade.unwrap();
+        self.shown_level = fade.level(cfg.press_fade_ms, cfg.press_easing);
+        if fade.finished(cfg.press_fade_ms) {
+            self.fade = None;
+        }
+        self.shown_level
+    }
+
+    // a key pressed on a keyboard does what this button shows or sends
+    fn shows_key(&self, key: Key) -> bool {
+        let shown: &[Key] = match self.image {
+            ButtonImage::Volume(_) | ButtonImage::Slider { kind: SliderKind::Volume, .. } => {
+                &[Key::VolumeUp, Key::VolumeDown, Key::Mute]
+            }
+            ButtonImage::Brightness | ButtonImage::Slider { kind: SliderKind::Brightness, .. } => {
+                &[Key::BrightnessUp, Key::BrightnessDown]
+            }
+            ButtonImage::Media(_) => &[Key::PlayPause, Key::NextSong, Key::PreviousSong],
+            _ => &[],
+        };
+        shown.contains(&key) || self.action == [key]
+    }
+
+    fn is_animated(&self) -> bool {
+        matches!(self.image, ButtonImage::Bluetooth { ref state, .. } if state.pending)
+    }
+
+    // what a screen reader should say when this button is pressed
+    fn spoken_label(&self) -> Option<String> {
+        match &self.image {
+            ButtonImage::Text(text) | ButtonImage::IconText { text, .. } => Some(text.clone()),
+            ButtonImage::Battery(_, _, _, (_, BatteryState::Absent)) => {
+                Some("Battery unavailable".to_string())
+            }
+            ButtonImage::Battery(_, _, _, (capacity, _)) => {
+                Some(format!("Battery {}%", capacity))
+            }
+            ButtonImage::Bluetooth { mac, state } => {
+                Some(state.alias.clone().unwrap_or_else(|| mac.clone()))
+            }
+            ButtonImage::BluetoothBattery { shown: Some((name, percent)), .. } => {
+                Some(format!("{} battery {}%", name, percent))
+            }
+            ButtonImage::SinkToggle { label, on, .. } => Some(match on {
+                Some(true) => format!("{} on", label),
+                Some(false) => format!("{} off", label),
+                None => label.clone(),
+            }),
+            ButtonImage::Displays(mode) => Some(match mode {
+                Some(mode) => format!("Displays {}", mode.label()),
+                None => "Displays".to_string(),
+            }),
+            ButtonImage::Media(Some(state)) => state.title.clone(),
+            ButtonImage::DoNotDisturb(Some(s)) => Some(format!(
+                "{} notifications, do not disturb {}",
+                s.count,
+                if s.dnd { "on" } else { "off" }
+            )),
+            ButtonImage::NiriWorkspace { name: Some(name), .. } => Some(format!("Workspace {}", name)),
+            ButtonImage::NiriWorkspace { idx, .. } => Some(format!("Workspace {}", idx)),
+            ButtonImage::WorkspaceOverflow { hidden, .. } => Some(format!("{} more workspaces", hidden)),
+            ButtonImage::NiriWindow { title, .. } => Some(title.clone()),
+            ButtonImage::Cpu { usage: Some(v), .. } => Some(format!("CPU {}%", v)),
+            ButtonImage::Memory { usage: Some(v), .. } => Some(format!("Memory {}%", v)),
+            ButtonImage::LoadAvg(Some(l)) => Some(format!("Load {:.2}", l)),
+            ButtonImage::Temperature { celsius: Some(t), .. } => {
+                Some(format!("Temperature {:.0} degrees", t))
+            }
+            ButtonImage::Custom { output: Some(o), .. } => {
+                Some(o.tooltip.clone().unwrap_or_else(|| o.text.clone()))
+            }
+            ButtonImage::Slider { kind, value } => {
+                let name = match kind {
+                    SliderKind::Volume => "Volume",
+                    SliderKind::Brightness => "Brightness",
+                    SliderKind::AppVolume => "App volume",
+                };
+                Some(match value {
+                    Some(v) => format!("{} {}%", name, v),
+                    None => name.to_string(),
+                })
+            }
+            _ if !self.action.is_empty() => Some(
+                self.action
+                    .iter()
+                    .map(|k| format!("{:?}", k))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            _ => None,
         }
     }
 
@@ -448,8 +1197,8 @@ impl Button {
                         | Item::Numeric(Numeric::Timestamp, _)
                 )
             }),
-            // Volume and brightness poll on every redraw cycle
-            ButtonImage::Volume | ButtonImage::Brightness | ButtonImage::Wifi => false,
+            // brightness polls on every redraw cycle
+            ButtonImage::Brightness => false,
             _ => false,
         }
     }
@@ -462,49 +1211,96 @@ impl Button {
         button_width: u64,
         y_shift: f64,
         cfg: &Config,
-    ) {
+    ) -> error::Result<()> {
+        if let Some(ref sparkline) = self.sparkline {
+            let inset = 12.0;
+            let area = (
+                button_left_edge + inset,
+                y_shift + height as f64 * 0.2,
+                button_width as f64 - inset * 2.0,
+                height as f64 * 0.6,
+            );
+            sparkline.render(c, self.sample_max(), area, cfg.theme.accent)?;
+            let (r, g, b) = cfg.theme.foreground;
+            c.set_source_rgb(r, g, b);
+        }
         match &self.image {
             ButtonImage::Text(text) => {
-                let extents = c.text_extents(text).unwrap();
-                c.move_to(
-                    button_left_edge
-                        + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
-                    y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round(),
-                );
-                c.show_text(text).unwrap();
+                render_ellipsized_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, text)?;
             }
-            ButtonImage::Svg(svg) => {
+            ButtonImage::Svg(_) | ButtonImage::Bitmap(_) => {
                 let x = button_left_edge
                     + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
                 let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
-                svg.render_document(c, &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64))
-                    .unwrap();
+                render_icon(c, &self.image, x, y)?;
             }
-            ButtonImage::Bitmap(surf) => {
-                let x = button_left_edge
-                    + (button_width as f64 / 2.0 - (ICON_SIZE / 2) as f64).round();
+            ButtonImage::IconText { icon, text, spacing, align } => {
+                let line = text::Line::new(c, &cfg.font, text);
+                let width = ICON_SIZE as f64 + spacing + line.width();
+                let x = match align {
+                    ContentAlign::Left => button_left_edge + CONTENT_PADDING_PX,
+                    ContentAlign::Center => {
+                        button_left_edge + (button_width as f64 / 2.0 - width / 2.0).round()
+                    }
+                    ContentAlign::Right => {
+                        button_left_edge + button_width as f64 - CONTENT_PADDING_PX - width.round()
+                    }
+                };
                 let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
-                c.set_source_surface(surf, x, y).unwrap();
-                c.rectangle(x, y, ICON_SIZE as f64, ICON_SIZE as f64);
-                c.fill().unwrap();
-            }
-            ButtonImage::Time(format, locale) => {
-                let current_time = Local::now();
-                let formatted_time = current_time
-                    .format_localized_with_items(format.iter(), *locale)
-                    .to_string();
-                let time_extents = c.text_extents(&formatted_time).unwrap();
+                render_icon(c, icon, x, y)?;
                 c.move_to(
-                    button_left_edge
-                        + (button_width as f64 / 2.0 - time_extents.width() / 2.0).round(),
-                    y_shift + (height as f64 / 2.0 + time_extents.height() / 2.0).round(),
+                    (x + ICON_SIZE as f64 + spacing).round(),
+                    y_shift + (height as f64 / 2.0 + line.height() / 2.0).round(),
                 );
-                c.show_text(&formatted_time).unwrap();
+                line.show(c)?;
             }
-            ButtonImage::Volume => {
+            ButtonImage::Time(format, locale) => {
+                let formatted_time = match cfg.time_zone {
+                    Some(tz) => Utc::now()
+                        .with_timezone(&tz)
+                        .format_localized_with_items(format.iter(), *locale)
+                        .to_string(),
+                    None => Local::now()
+                        .format_localized_with_items(format.iter(), *locale)
+                        .to_string(),
+                };
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &formatted_time)?;
+            }
+            ButtonImage::Calendar(today, locale) => {
+                render_calendar(c, height, button_left_edge, button_width, y_shift, today, *locale, cfg)?;
+            }
+            ButtonImage::Volume(level) if cfg.fallback_icons.is_some() => {
+                let (icon, text) = match *level {
+                    Some((_, true)) => ("volume_off", String::new()),
+                    Some((0, _)) => ("volume_off", "0%".to_string()),
+                    Some((v, _)) if v < 50 => ("volume_down", format!("{}%", v)),
+                    Some((v, _)) => ("volume_up", format!("{}%", v)),
+                    None => ("volume_up", "--".to_string()),
+                };
+                let icons = cfg.fallback_icons.as_ref().unwrap();
+                render_fallback(c, &cfg.font, icons, (icon, &text), height, (button_left_edge, button_width), y_shift)?;
+            }
+            ButtonImage::Brightness if cfg.fallback_icons.is_some() => {
+                let (icon, text) = match get_brightness_percent() {
+                    Some(v) if v < 50 => ("brightness_low", format!("{}%", v)),
+                    Some(v) => ("brightness_high", format!("{}%", v)),
+                    None => ("brightness_high", "--".to_string()),
+                };
+                let icons = cfg.fallback_icons.as_ref().unwrap();
+                render_fallback(c, &cfg.font, icons, (icon, &text), height, (button_left_edge, button_width), y_shift)?;
+            }
+            ButtonImage::Wifi(info) if cfg.fallback_icons.is_some() => {
+                let (icon, text) = match info {
+                    Some(info) => ("wifi", truncate_ssid(&info.ssid, 8)),
+                    None => ("wifi_off", String::new()),
+                };
+                let icons = cfg.fallback_icons.as_ref().unwrap();
+                render_fallback(c, &cfg.font, icons, (icon, &text), height, (button_left_edge, button_width), y_shift)?;
+            }
+            ButtonImage::Volume(level) => {
                 // Icons match waybar pulseaudio format-icons: 󰕿 󰖀 󰕾 and muted 󰝟
-                let text = match get_volume_percent() {
-                    Some((v, muted)) if muted => "\u{f075f}".to_string(),
+                let text = match *level {
+                    Some((_, true)) => "\u{f075f}".to_string(),
                     Some((v, _)) => {
                         let icon = if v == 0 { "\u{f057f}" }
                                    else if v < 50 { "\u{f0580}" }
@@ -513,7 +1309,7 @@ impl Button {
                     }
                     None => "\u{f057e} --".to_string(),
                 };
-                render_centered_text(c, height, button_left_edge, button_width, y_shift, &text);
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
             }
             ButtonImage::Brightness => {
                 // Icons match waybar backlight format-icons: 󱩎 through 󱩖 (9 steps)
@@ -526,71 +1322,200 @@ impl Button {
                     }
                     None => "\u{fe256} --".to_string(),
                 };
-                render_centered_text(c, height, button_left_edge, button_width, y_shift, &text);
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::Slider { kind, value } => {
+                let inset = 12.0;
+                let fill = (button_width as f64 - inset * 2.0) * value.unwrap_or(0).min(100) as f64 / 100.0;
+                let (r, g, b) = cfg.theme.accent;
+                c.set_source_rgb(r, g, b);
+                c.rectangle(
+                    button_left_edge + inset,
+                    y_shift + height as f64 * 0.3,
+                    fill,
+                    height as f64 * 0.4,
+                );
+                c.fill()?;
+                let icon = match kind {
+                    SliderKind::Volume | SliderKind::AppVolume => "\u{f057e}",
+                    SliderKind::Brightness => "\u{fe256}",
+                };
+                let text = match value {
+                    Some(v) => format!("{} {}%", icon, v),
+                    None => format!("{} --", icon),
+                };
+                let (r, g, b) = cfg.theme.foreground;
+                c.set_source_rgb(r, g, b);
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::Cpu { graph, usage, .. } | ButtonImage::Memory { graph, usage } => {
+                // 󰻠 cpu, 󰍛 memory
+                let icon = if matches!(self.image, ButtonImage::Cpu { .. }) {
+                    "\u{f0ee0}"
+                } else {
+                    "\u{f035b}"
+                };
+                if *graph {
+                    let inset = 12.0;
+                    let track = button_width as f64 - inset * 2.0;
+                    let (r, g, b) = cfg.theme.accent;
+                    c.set_source_rgb(r, g, b);
+                    c.rectangle(
+                        button_left_edge + inset,
+                        y_shift + height as f64 * 0.3,
+                        track * usage.unwrap_or(0) as f64 / 100.0,
+                        height as f64 * 0.4,
+                    );
+                    c.fill()?;
+                    let (r, g, b) = cfg.theme.foreground;
+                    c.set_source_rgb(r, g, b);
+                }
+                let text = match usage {
+                    Some(v) => format!("{} {}%", icon, v),
+                    None => format!("{} --", icon),
+                };
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::LoadAvg(load) => {
+                // 󰊚 gauge
+                let text = match load {
+                    Some(l) => format!("\u{f029a} {:.2}", l),
+                    None => "\u{f029a} --".to_string(),
+                };
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::Temperature { celsius, .. } => {
+                // 󰔏 thermometer
+                let text = match celsius {
+                    Some(t) => format!("\u{f050f} {:.0}°C", t),
+                    None => "\u{f050f} --".to_string(),
+                };
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::Custom { output, .. } => {
+                let text = output.as_ref().map_or("…", |o| o.text.as_str());
+                render_ellipsized_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, text)?;
             }
-            ButtonImage::Wifi => {
+            ButtonImage::Script(widget) => {
+                widget.draw(
+                    c,
+                    (button_left_edge, y_shift),
+                    button_width as f64,
+                    height as f64,
+                    self.active,
+                    &cfg.theme,
+                )?;
+            }
+            ButtonImage::Wifi(info) => {
                 // Network icons: 󰤨 connected, 󰤭  disconnected
-                let text = match get_wifi_info() {
+                let text = match info {
                     Some(info) => {
                         let icon = wifi_icon(info.signal);
                         format!("{} {}", icon, truncate_ssid(&info.ssid, 8))
                     }
                     None => "\u{f0935}".to_string(),
                 };
-                render_centered_text(c, height, button_left_edge, button_width, y_shift, &text);
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::NiriWorkspace { icon: Some(icon), focused, occupied, .. } => {
+                let (r, g, b) = cfg.theme.foreground;
+                let x = button_left_edge + ((button_width as f64 - ICON_SIZE as f64) / 2.0).round();
+                let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+                // the accent color cannot tint an icon, the focused one is underlined
+                let source = c.source();
+                c.push_group();
+                render_icon(c, icon, x, y)?;
+                c.pop_group_to_source()?;
+                c.paint_with_alpha(if *occupied || *focused { 1.0 } else { 0.4 })?;
+                c.set_source(&source)?;
+                let bottom = y_shift + height as f64 - 3.0;
+                if *focused {
+                    c.rectangle(x + ICON_SIZE as f64 / 4.0, bottom - 1.0, ICON_SIZE as f64 / 2.0, 2.0);
+                    c.fill()?;
+                } else if *occupied {
+                    c.arc(button_left_edge + button_width as f64 / 2.0, bottom, 2.0, 0.0, 2.0 * std::f64::consts::PI);
+                    c.fill()?;
+                }
+                c.set_source_rgb(r, g, b);
             }
-            ButtonImage::NiriWorkspace { idx, .. } => {
-                let label = idx.to_string();
-                let extents = c.text_extents(&label).unwrap();
+            ButtonImage::NiriWorkspace { idx, name, icon: None, focused, occupied, .. } => {
+                let label = name.clone().unwrap_or_else(|| idx.to_string());
+                let line = text::Line::new(c, &cfg.font, &label);
+                let (r, g, b) = cfg.theme.foreground;
+                // empty workspaces fade back, ones with windows get a dot under the number
+                if !occupied && !focused {
+                    c.set_source_rgba(r, g, b, 0.4);
+                }
                 c.move_to(
                     button_left_edge
-                        + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
-                    y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round(),
+                        + (button_width as f64 / 2.0 - line.width() / 2.0).round(),
+                    y_shift + (height as f64 / 2.0 + line.height() / 2.0).round(),
                 );
-                c.show_text(&label).unwrap();
-            }
-            ButtonImage::NiriWindowTitle(title) => {
-                let max_w = button_width as f64 - 16.0;
-                let full_extents = c.text_extents(title).unwrap();
-                if full_extents.width() <= max_w {
-                    let extents = c.text_extents(title).unwrap();
-                    c.move_to(
-                        button_left_edge
-                            + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
-                        y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round(),
+                line.show(c)?;
+                if *occupied {
+                    c.arc(
+                        button_left_edge + button_width as f64 / 2.0,
+                        y_shift + (height as f64 / 2.0 + line.height() / 2.0).round() + 6.0,
+                        2.0,
+                        0.0,
+                        2.0 * std::f64::consts::PI,
                     );
-                    c.show_text(title).unwrap();
-                } else {
-                    let ellipsis = "…";
-                    let ellipsis_w = c.text_extents(ellipsis).unwrap().width();
-                    let char_indices: Vec<_> = title.char_indices().collect();
-                    let mut lo = 0usize;
-                    let mut hi = char_indices.len();
-                    while lo + 1 < hi {
-                        let mid = (lo + hi) / 2;
-                        let byte_end = char_indices[mid].0;
-                        let candidate = &title[..byte_end];
-                        let w = c.text_extents(candidate).unwrap().width();
-                        if w + ellipsis_w <= max_w {
-                            lo = mid;
-                        } else {
-                            hi = mid;
-                        }
+                    c.fill()?;
+                }
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::WorkspaceOverflow { .. } => {
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, "\u{2026}")?;
+            }
+            ButtonImage::NiriWindowTitle(title, layout) => {
+                // Nerd Font icons: 󰊓 fullscreen, 󰖲 floating, column:tile otherwise
+                let text = match layout {
+                    Some(WindowLayout::Fullscreen) => format!("\u{f0293} {}", title),
+                    Some(WindowLayout::Floating) => format!("\u{f05b2} {}", title),
+                    Some(WindowLayout::Tiled { column, tile }) => {
+                        format!("{}:{} {}", column, tile, title)
                     }
-                    let byte_end = char_indices.get(lo).map(|(i, _)| *i).unwrap_or(0);
-                    let truncated = format!("{}{}", &title[..byte_end], ellipsis);
-                    let extents = c.text_extents(&truncated).unwrap();
-                    c.move_to(
-                        button_left_edge
-                            + (button_width as f64 / 2.0 - extents.width() / 2.0).round(),
-                        y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round(),
-                    );
-                    c.show_text(&truncated).unwrap();
+                    None => title.clone(),
+                };
+                render_ellipsized_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::NiriWindow { title, icon, focused, .. } => {
+                let mut left = button_left_edge;
+                let mut text_width = button_width;
+                if let Some(icon) = icon {
+                    let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+                    render_icon(c, icon, left + 8.0, y)?;
+                    left += ICON_SIZE as f64 + 8.0;
+                    text_width = text_width.saturating_sub(ICON_SIZE as u64 + 8);
+                }
+                render_ellipsized_text(c, &cfg.font, height, left, text_width, y_shift, title)?;
+                // like a focused workspace icon, the focused window is underlined
+                if *focused {
+                    let bottom = y_shift + height as f64 - 3.0;
+                    c.rectangle(button_left_edge + button_width as f64 / 4.0, bottom - 1.0, button_width as f64 / 2.0, 2.0);
+                    c.fill()?;
                 }
             }
-            ButtonImage::Battery(battery, battery_mode, icons) => {
-                let (capacity, state) = get_battery_state(battery);
-                let icon = if battery_mode.should_draw_icon() {
+            ButtonImage::Media(state) => {
+                // Nerd Font icons: 󰐊 play, 󰏤 pause, 󰝚 nothing playing
+                let text = match state {
+                    Some(s) => {
+                        let icon = if s.playing { "\u{f03e4}" } else { "\u{f040a}" };
+                        match (&s.title, &s.artist) {
+                            (Some(t), Some(a)) => format!("{} {} – {}", icon, t, a),
+                            (Some(t), None) => format!("{} {}", icon, t),
+                            _ => icon.to_string(),
+                        }
+                    }
+                    None => "\u{f075a}".to_string(),
+                };
+                render_ellipsized_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::Battery(_, battery_mode, icons, (capacity, state)) => {
+                let (capacity, state) = (*capacity, *state);
+                let icon = if state == BatteryState::Absent {
+                    None
+                } else if battery_mode.should_draw_icon() {
                     Some(match state {
                         BatteryState::Charging => match capacity {
                             0..=20 => &icons.charging[0],
@@ -617,9 +1542,13 @@ impl Button {
                 } else {
                     None
                 };
-                let percent_str = format!("{:.0}%", capacity);
-                let extents = c.text_extents(&percent_str).unwrap();
-                let mut width = extents.width();
+                let percent_str = if state == BatteryState::Absent {
+                    "--".to_string()
+                } else {
+                    format!("{:.0}%", capacity)
+                };
+                let line = text::Line::new(c, &cfg.font, &percent_str);
+                let mut width = line.width();
                 let mut text_offset = 0;
                 if let Some(svg) = icon {
                     if !battery_mode.should_draw_text() {
@@ -635,7 +1564,7 @@ impl Button {
                         c,
                         &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64),
                     )
-                    .unwrap();
+                    .map_err(|e| error::Error::Svg(e.to_string()))?;
                 }
                 if battery_mode.should_draw_text() {
                     c.move_to(
@@ -643,13 +1572,166 @@ impl Button {
                             + (button_width as f64 / 2.0 - width / 2.0
                                 + text_offset as f64)
                                 .round(),
-                        y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round(),
+                        y_shift + (height as f64 / 2.0 + line.height() / 2.0).round(),
                     );
-                    c.show_text(&percent_str).unwrap();
+                    line.show(c)?;
                 }
             }
-            ButtonImage::Spacer => (),
-        }
+            ButtonImage::ScreenRecording(active) => {
+                if !*active {
+                    return Ok(());
+                }
+                let (r, g, b) = cfg.theme.warning;
+                c.set_source_rgb(r, g, b);
+                c.arc(
+                    button_left_edge + button_width as f64 / 2.0,
+                    y_shift + height as f64 / 2.0,
+                    RECORDING_DOT_RADIUS,
+                    0.0,
+                    (360.0f64).to_radians(),
+                );
+                c.fill()?;
+            }
+            ButtonImage::Privacy { camera, mic } => {
+                // Nerd Font icons: 󰄀 camera, 󰍬 microphone
+                let text = match (camera, mic) {
+                    (true, true) => "\u{f0100} \u{f036c}",
+                    (true, false) => "\u{f0100}",
+                    (false, true) => "\u{f036c}",
+                    (false, false) => return Ok(()),
+                };
+                let (r, g, b) = cfg.theme.warning;
+                c.set_source_rgb(r, g, b);
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, text)?;
+            }
+            ButtonImage::Bluetooth { mac, state } => {
+                // Nerd Font icons: 󰂱 connected, 󰂯 disconnected, 󰂰 connecting
+                // blinks while connecting
+                let icon = if state.pending && animation_frame(cfg) % 2 == 0 {
+                    "\u{f00b0}"
+                } else if state.connected {
+                    "\u{f00b1}"
+                } else {
+                    "\u{f00af}"
+                };
+                let name = state.alias.as_deref().unwrap_or(mac);
+                let text = format!("{} {}", icon, truncate_ssid(name, 10));
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::BluetoothBattery { shown, .. } => {
+                // 󰥊
+                let text = match shown {
+                    Some((_, percent)) => format!("\u{f094a} {}%", percent),
+                    None => "\u{f094a} --".to_string(),
+                };
+                if matches!(shown, Some((_, p)) if *p <= 20) {
+                    let (r, g, b) = cfg.theme.warning;
+                    c.set_source_rgb(r, g, b);
+                }
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::DoNotDisturb(state) => {
+                // Nerd Font icons: 󰂚 bell, 󰂛 bell off
+                let text = match state {
+                    Some(s) if s.dnd => format!("\u{f009b} {}", s.count),
+                    Some(s) => format!("\u{f009a} {}", s.count),
+                    None => "\u{f009a} --".to_string(),
+                };
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::Displays(mode) => {
+                // 󰍹
+                let text = format!("\u{f0379} {}", mode.map_or("--", DisplayMode::label));
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, &text)?;
+            }
+            ButtonImage::SinkToggle { label, .. } => {
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, label)?;
+            }
+            ButtonImage::Failed => {
+                render_centered_text(c, &cfg.font, height, button_left_edge, button_width, y_shift, "!")?;
+            }
+            ButtonImage::Separator => {
+                let (r, g, b) = cfg.theme.foreground;
+                c.set_source_rgba(r, g, b, 0.3);
+                c.rectangle(
+                    button_left_edge + (button_width as f64 / 2.0 - 0.5).round(),
+                    y_shift + height as f64 * 0.25,
+                    1.0,
+                    height as f64 * 0.5,
+                );
+                c.fill()?;
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::Spacer => (),
+        }
+        Ok(())
+    }
+
+    // Runs widget code so that a panic counts against this button instead of
+    // taking the bar down, the button gives up after MAX_WIDGET_PANICS.
+    fn sandboxed<T>(&mut self, f: impl FnOnce(&mut Button) -> T) -> Option<T> {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                self.panics += 1;
+                eprintln!("[widget] a button panicked ({} of {})", self.panics, MAX_WIDGET_PANICS);
+                if self.panics >= MAX_WIDGET_PANICS {
+                    self.image = ButtonImage::Failed;
+                    self.clickable = false;
+                    self.active = false;
+                }
+                self.changed = true;
+                None
+            }
+        }
+    }
+
+    // images that look the same until the button is marked changed
+    fn cacheable(&self) -> bool {
+        self.sparkline.is_none()
+            && matches!(
+                self.image,
+                ButtonImage::Text(_)
+                    | ButtonImage::Svg(_)
+                    | ButtonImage::Bitmap(_)
+                    | ButtonImage::IconText { .. }
+                    | ButtonImage::NiriWorkspace { .. }
+                    | ButtonImage::WorkspaceOverflow { .. }
+                    | ButtonImage::NiriWindowTitle(..)
+                    | ButtonImage::NiriWindow { .. }
+            )
+    }
+
+    // Renders the content once into a surface of its own and paints that
+    // until the button changes, so a layer drawn again as a whole does not
+    // lay out and rasterize text and icons that did not change.
+    fn render_cached(&mut self, c: &Context, height: i32, left_edge: f64, width: u64, cfg: &Config) -> error::Result<()> {
+        if !self.cacheable() {
+            self.render_cache = None;
+            return self.render(c, height, left_edge, width, 0.0, cfg);
+        }
+        let stale = match self.render_cache {
+            Some((_, w)) => self.changed || w != width,
+            None => true,
+        };
+        if stale {
+            let surface = ImageSurface::create(Format::ARgb32, width as i32, height)?;
+            let cc = Context::new(&surface)?;
+            cc.set_font_face(&cfg.font_face);
+            cc.set_font_size(cfg.font_size);
+            let (r, g, b) = cfg.theme.foreground;
+            cc.set_source_rgb(r, g, b);
+            self.render(&cc, height, 0.0, width, 0.0, cfg)?;
+            drop(cc);
+            self.render_cache = Some((surface, width));
+        }
+        let Some((ref surface, _)) = self.render_cache else {
+            unreachable!()
+        };
+        c.set_source_surface(surface, left_edge, 0.0)?;
+        c.rectangle(left_edge, 0.0, width as f64, height as f64);
+        c.fill()?;
+        Ok(())
     }
 
     fn set_active<F>(&mut self, uinput: &mut UInputHandle<F>, active: bool)
@@ -662,21 +1744,72 @@ impl Button {
         if self.active != active {
             self.active = active;
             self.changed = true;
-            toggle_keys(uinput, &self.action, active as i32);
+            if self.has_long_press() {
+                // keys are sent on release or once held long enough, not on touch down
+                self.press_started = active.then(std::time::Instant::now);
+            } else if !self.chord_modifier.is_empty() {
+                if active {
+                    self.chorded = false;
+                }
+            } else {
+                toggle_keys(uinput, &self.action, active as i32);
+            }
+        }
+    }
+
+    // drawn with a background even when outlines are off
+    fn alert(&self) -> bool {
+        match self.image {
+            ButtonImage::Temperature { warning, celsius: Some(t), .. } => t >= warning,
+            ButtonImage::Custom { output: Some(ref o), .. } => o.highlight().is_some(),
+            ButtonImage::Failed => true,
+            ButtonImage::NiriWorkspace { urgent, focused, .. } => urgent && !focused,
+            _ => false,
         }
     }
 
-    fn set_background_color(&self, c: &Context, active: bool, theme: &crate::config::Theme) {
-        let (r, g, b) = if active { theme.button_active } else { theme.button_inactive };
+    // level is how far a press has faded in, at 0 outlined buttons use
+    // button_inactive and the rest the bar background
+    fn set_background_color(&self, c: &Context, level: f64, outlined: bool, theme: &crate::config::Theme) {
+        let rest = if outlined { theme.button_inactive } else { theme.background };
+        let (r, g, b) = animation::mix(rest, theme.button_active, level);
+        let active = level >= 0.5;
         match &self.image {
-            ButtonImage::Battery(battery, _, _) => {
-                let (_, state) = get_battery_state(battery);
+            ButtonImage::Battery(_, _, _, (_, state)) => {
                 match state {
-                    BatteryState::NotCharging => c.set_source_rgb(r, g, b),
+                    BatteryState::NotCharging | BatteryState::Absent => c.set_source_rgb(r, g, b),
                     BatteryState::Charging    => { let (r,g,b) = theme.success; c.set_source_rgb(r, g, b); }
                     BatteryState::Low         => { let (r,g,b) = theme.warning; c.set_source_rgb(r, g, b); }
                 }
             }
+            ButtonImage::Temperature { .. } | ButtonImage::Failed if self.alert() => {
+                let (r, g, b) = theme.warning;
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::Custom { output: Some(o), .. } if o.highlight().is_some() => {
+                let (r, g, b) = match o.highlight() {
+                    Some(custom::Highlight::Warning) => theme.warning,
+                    Some(custom::Highlight::Success) => theme.success,
+                    _ => theme.accent,
+                };
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::Bluetooth { state, .. } if state.connected && !active => {
+                let (r, g, b) = theme.accent;
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::DoNotDisturb(Some(s)) if s.dnd && !active => {
+                let (r, g, b) = theme.accent;
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::SinkToggle { on: Some(true), .. } if !active => {
+                let (r, g, b) = theme.accent;
+                c.set_source_rgb(r, g, b);
+            }
+            ButtonImage::NiriWorkspace { urgent: true, focused: false, .. } if !active => {
+                let (r, g, b) = theme.warning;
+                c.set_source_rgb(r, g, b);
+            }
             ButtonImage::NiriWorkspace { focused, .. } => {
                 if *focused {
                     let (r,g,b) = theme.accent;
@@ -690,20 +1823,268 @@ impl Button {
     }
 }
 
+// Moves the already drawn layer by the pixel shift instead of drawing every
+// button again. The shift is in bar coordinates, the surfaces are rotated
+// like in FunctionLayer::draw.
+fn compose_shifted(
+    target: &ImageSurface,
+    content: &ImageSurface,
+    shift: (f64, f64),
+    cfg: &Config,
+) -> error::Result<()> {
+    let (x, y) = shift;
+    let c = Context::new(target)?;
+    let (r, g, b) = cfg.theme.background;
+    c.set_source_rgb(r, g, b);
+    c.paint()?;
+    c.set_source_surface(content, -y, x)?;
+    c.paint()?;
+    Ok(())
+}
+
+// A button that fails to draw is turned into a spacer by FunctionLayer::draw,
+// the layer is then drawn again from scratch without it.
+fn draw_layer(
+    layer: &mut FunctionLayer,
+    cfg: &Config,
+    width: i32,
+    height: i32,
+    surface: &Surface,
+    complete_redraw: bool,
+) -> Vec<ClipRect> {
+    let mut complete_redraw = complete_redraw;
+    // every button may fail once, or panic until it is replaced
+    for _ in 0..=layer.buttons.len() * MAX_WIDGET_PANICS as usize {
+        match layer.draw(cfg, width, height, surface, complete_redraw) {
+            Ok(clips) => return clips,
+            Err(e) => {
+                eprintln!("[render] layer failed to draw: {}", e);
+                complete_redraw = true;
+            }
+        }
+    }
+    Vec::new()
+}
+
+// copies what changed in a finished frame to the panel
+fn present(
+    drm: &mut DrmBackend,
+    frame: &mut ImageSurface,
+    clips: &[ClipRect],
+    cfg: &Config,
+) -> error::Result<()> {
+    let stride = frame.stride() as usize;
+    let data = frame.data()?;
+    let damage = drm.back_damage(clips);
+    let mut map = drm.map_back()?;
+    let fb = map.as_mut();
+    for r in &damage {
+        for y in r.y1() as usize..r.y2() as usize {
+            let row = y * stride + r.x1() as usize * 4..y * stride + r.x2() as usize * 4;
+            fb[row.clone()].copy_from_slice(&data[row.clone()]);
+            if let Some(ref lut) = cfg.color_lut {
+                lut.apply(&mut fb[row]);
+            }
+        }
+    }
+    drop(map);
+    drm.flip(clips)
+}
+
+// a damaged region of the content, where it ended up after compose_shifted
+fn shift_clip(clip: &ClipRect, shift: (f64, f64), height: u16, width: u16) -> ClipRect {
+    let (x, y) = shift;
+    // one more pixel on each side for the subpixel part of the shift
+    let dx = -y.floor() as i32;
+    let dy = x.floor() as i32;
+    let clamp = |v: i32, max: u16| v.clamp(0, max as i32) as u16;
+    ClipRect::new(
+        clamp(clip.x1() as i32 + dx - 1, height),
+        clamp(clip.y1() as i32 + dy - 1, width),
+        clamp(clip.x2() as i32 + dx + 2, height),
+        clamp(clip.y2() as i32 + dy + 2, width),
+    )
+}
+
+// Every animated widget picks its frame from here, so ReduceMotion
+// freezes all of them on their first frame.
+fn animation_frame(cfg: &Config) -> i64 {
+    if cfg.reduce_motion {
+        return 0;
+    }
+    Local::now().timestamp_millis() / ANIMATION_FRAME_MS as i64
+}
+
+// Icons standing in for the Nerd Font glyphs of the volume, brightness and
+// wifi widgets, for fonts that have none.
+pub struct FallbackIcons {
+    icons: HashMap<&'static str, ButtonImage>,
+}
+
+impl FallbackIcons {
+    const NAMES: [&'static str; 7] = [
+        "volume_off",
+        "volume_down",
+        "volume_up",
+        "brightness_low",
+        "brightness_high",
+        "wifi",
+        "wifi_off",
+    ];
+
+    fn load() -> FallbackIcons {
+        let mut icons = HashMap::new();
+        for name in FallbackIcons::NAMES {
+            match try_load_image(name, None::<&str>) {
+                Ok(image) => {
+                    icons.insert(name, image);
+                }
+                Err(e) => eprintln!("[config] fallback icon {} missing: {}", name, e),
+            }
+        }
+        FallbackIcons { icons }
+    }
+}
+
+// a widget value after its fallback icon, centered on the button
+fn render_fallback(
+    c: &Context,
+    font: &FontDescription,
+    icons: &FallbackIcons,
+    (name, text): (&str, &str),
+    height: i32,
+    (left, width): (f64, u64),
+    y_shift: f64,
+) -> error::Result<()> {
+    let Some(icon) = icons.icons.get(name) else {
+        return render_centered_text(c, font, height, left, width, y_shift, text);
+    };
+    let spacing = if text.is_empty() { 0.0 } else { 8.0 };
+    let line = text::Line::new(c, font, text);
+    let x = left + (width as f64 / 2.0 - (ICON_SIZE as f64 + spacing + line.width()) / 2.0).round();
+    let y = y_shift + ((height as f64 - ICON_SIZE as f64) / 2.0).round();
+    render_icon(c, icon, x, y)?;
+    c.move_to(
+        (x + ICON_SIZE as f64 + spacing).round(),
+        y_shift + (height as f64 / 2.0 + line.height() / 2.0).round(),
+    );
+    line.show(c)?;
+    Ok(())
+}
+
+// an Svg or Bitmap image with its top left corner at x, y
+fn render_icon(c: &Context, image: &ButtonImage, x: f64, y: f64) -> error::Result<()> {
+    match image {
+        ButtonImage::Svg(svg) => {
+            svg.render_document(c, &Rectangle::new(x, y, ICON_SIZE as f64, ICON_SIZE as f64))
+                .map_err(|e| error::Error::Svg(e.to_string()))?;
+        }
+        ButtonImage::Bitmap(surf) => {
+            c.set_source_surface(surf, x, y)?;
+            c.rectangle(x, y, ICON_SIZE as f64, ICON_SIZE as f64);
+            c.fill()?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+// The month as a single row of days, weekday initials above the dates and
+// today highlighted, there is no room for a grid of weeks on the bar.
+fn render_calendar(
+    c: &Context,
+    height: i32,
+    button_left_edge: f64,
+    button_width: u64,
+    y_shift: f64,
+    today: &DateTime<Local>,
+    locale: Locale,
+    cfg: &Config,
+) -> error::Result<()> {
+    let first = *today - Days::new(today.day0() as u64);
+    let days = (first + Months::new(1)).signed_duration_since(first).num_days() as u32;
+    // the month name takes the room of three days
+    let cell = button_width as f64 / (days + 3) as f64;
+    let (r, g, b) = cfg.theme.foreground;
+
+    let month = text::Line::new(c, &cfg.font, &first.format_localized("%b %Y", locale).to_string());
+    c.move_to(
+        button_left_edge + (cell * 1.5 - month.width() / 2.0).round(),
+        y_shift + (height as f64 / 2.0 + month.height() / 2.0).round(),
+    );
+    month.show(c)?;
+
+    for day in 0..days {
+        let date = first + Days::new(day as u64);
+        let x = button_left_edge + cell * (day + 3) as f64;
+        if date.day() == today.day() {
+            let (ar, ag, ab) = cfg.theme.accent;
+            c.set_source_rgb(ar, ag, ab);
+            c.rectangle(x + 2.0, y_shift + height as f64 * 0.1, cell - 4.0, height as f64 * 0.8);
+            c.fill()?;
+        }
+        // weekends a little dimmer, like most calendars
+        let alpha = if matches!(date.weekday(), Weekday::Sat | Weekday::Sun) { 0.6 } else { 1.0 };
+        c.set_source_rgba(r, g, b, alpha);
+
+        let weekday = date.format_localized("%a", locale).to_string();
+        let initial: String = weekday.chars().take(1).collect();
+        c.set_font_size(cfg.font_size * 0.45);
+        let initial = text::Line::new(c, &cfg.font, &initial);
+        c.move_to(
+            x + (cell / 2.0 - initial.width() / 2.0).round(),
+            y_shift + (height as f64 * 0.4).round(),
+        );
+        initial.show(c)?;
+
+        c.set_font_size(cfg.font_size * 0.7);
+        let number = text::Line::new(c, &cfg.font, &date.day().to_string());
+        c.move_to(
+            x + (cell / 2.0 - number.width() / 2.0).round(),
+            y_shift + (height as f64 * 0.8).round(),
+        );
+        number.show(c)?;
+    }
+    c.set_font_size(cfg.font_size);
+    c.set_source_rgb(r, g, b);
+    Ok(())
+}
+
+// like render_centered_text, but cuts the text with an ellipsis to fit the button
+fn render_ellipsized_text(
+    c: &Context,
+    font: &FontDescription,
+    height: i32,
+    button_left_edge: f64,
+    button_width: u64,
+    y_shift: f64,
+    title: &str,
+) -> error::Result<()> {
+    let line = text::Line::new(c, font, title).ellipsized(button_width as f64 - 16.0);
+    c.move_to(
+        button_left_edge + (button_width as f64 / 2.0 - line.width() / 2.0).round(),
+        y_shift + (height as f64 / 2.0 + line.height() / 2.0).round(),
+    );
+    line.show(c)?;
+    Ok(())
+}
+
 fn render_centered_text(
     c: &Context,
+    font: &FontDescription,
     height: i32,
     left: f64,
     width: u64,
     y_shift: f64,
     text: &str,
-) {
-    let extents = c.text_extents(text).unwrap();
+) -> error::Result<()> {
+    let line = text::Line::new(c, font, text);
     c.move_to(
-        left + (width as f64 / 2.0 - extents.width() / 2.0).round(),
-        y_shift + (height as f64 / 2.0 + extents.height() / 2.0).round(),
+        left + (width as f64 / 2.0 - line.width() / 2.0).round(),
+        y_shift + (height as f64 / 2.0 + line.height() / 2.0).round(),
     );
-    c.show_text(text).unwrap();
+    line.show(c)?;
+    Ok(())
 }
 
 // Nerd Font wifi icons by signal strength: 󰤯 󰤟 󰤢 󰤥 󰤨
@@ -729,6 +2110,20 @@ fn truncate_ssid(ssid: &str, max_chars: usize) -> String {
 
 #[derive(Default)]
 pub struct FunctionLayer {
+    pub name: String,
+    // diagnostics, grid lines behind the buttons
+    draw_grid: bool,
+    // shown while Fn is held, the last layer if none is marked
+    pub fn_hold: bool,
+    // app_ids this layer is switched to for while focused
+    pub apps: Vec<String>,
+    // the OffLayer, drawn as background only
+    pub off: Option<OffLayer>,
+    // reserved at the left and right edge, the buttons share the rest
+    left_px: i32,
+    right_px: i32,
+    // DoNotCover, left blank with the buttons moved past them
+    covered: Vec<(i32, i32)>,
     displays_time: bool,
     displays_battery: bool,
     displays_live: bool,
@@ -736,7 +2131,16 @@ pub struct FunctionLayer {
     pub virtual_button_count: usize,
     faster_refresh: bool,
     pub niri_workspace_ids: Vec<(usize, u8)>,
+    // first workspace shown while MaxWorkspaces caps the strip, and the
+    // focused one it was placed for
+    pub workspace_first: usize,
+    workspace_focus: Option<u8>,
     pub source_config: Vec<ButtonConfig>,
+    // VisibleButtons, the layer scrolls once it has more than fit
+    pub visible_units: Option<usize>,
+    // TypingBlockMs of this layer, over the global one
+    pub typing_block_ms: Option<u32>,
+    scroll: animation::Scroll,
 }
 
 impl FunctionLayer {
@@ -749,13 +2153,22 @@ impl FunctionLayer {
         let displays_time = cfg.iter().any(|cfg| cfg.time.is_some());
         let displays_battery = cfg.iter().any(|cfg| cfg.battery.is_some());
         let displays_live = cfg.iter().any(|cfg| {
-            cfg.volume == Some(true) || cfg.brightness == Some(true) || cfg.wifi == Some(true)
+            cfg.brightness == Some(true)
+                || cfg.slider.is_some()
+                || cfg.privacy == Some(true)
+                || cfg.cpu.is_some()
+                || cfg.memory.is_some()
+                || cfg.load_avg == Some(true)
+                || cfg.temperature.is_some()
+                || cfg.sparkline.is_some()
+                || cfg.displays == Some(true)
+                || cfg.do_not_disturb == Some(true)
         });
         let buttons = cfg
             .into_iter()
             .scan(&mut virtual_button_count, |state, cfg| {
                 let i = **state;
-                let mut stretch = cfg.stretch.unwrap_or(1);
+                let mut stretch = cfg.units();
                 if stretch < 1 {
                     println!("Stretch value must be at least 1, setting to 1.");
                     stretch = 1;
@@ -766,6 +2179,14 @@ impl FunctionLayer {
             .collect::<Vec<_>>();
         let faster_refresh = buttons.iter().any(|(_, b)| b.needs_faster_refresh());
         FunctionLayer {
+            name: String::new(),
+            draw_grid: false,
+            fn_hold: false,
+            apps: vec![],
+            off: None,
+            left_px: 0,
+            right_px: 0,
+            covered: vec![],
             displays_time,
             displays_battery,
             displays_live,
@@ -773,20 +2194,62 @@ impl FunctionLayer {
             virtual_button_count,
             faster_refresh,
             niri_workspace_ids: vec![],
+            workspace_first: 0,
+            workspace_focus: None,
             source_config: vec![],
+            visible_units: None,
+            typing_block_ms: None,
+            scroll: Default::default(),
         }
     }
 
+    pub fn off(mode: OffLayer) -> FunctionLayer {
+        FunctionLayer {
+            name: "off".into(),
+            draw_grid: false,
+            fn_hold: false,
+            apps: vec![],
+            off: Some(mode),
+            left_px: 0,
+            right_px: 0,
+            covered: vec![],
+            displays_time: false,
+            displays_battery: false,
+            displays_live: false,
+            buttons: vec![],
+            // keeps the width math of draw away from a division by zero
+            virtual_button_count: 1,
+            faster_refresh: false,
+            niri_workspace_ids: vec![],
+            workspace_first: 0,
+            workspace_focus: None,
+            source_config: vec![],
+            visible_units: None,
+            typing_block_ms: None,
+            scroll: Default::default(),
+        }
+    }
+
+    // workspace or window title buttons, rebuilt on compositor events
+    fn uses_compositor(&self) -> bool {
+        self.source_config
+            .iter()
+            .any(|c| {
+                c.niri_workspaces == Some(true)
+                    || c.niri_window_title == Some(true)
+                    || c.niri_windows == Some(true)
+            })
+    }
+
     fn draw(
         &mut self,
         config: &Config,
         width: i32,
         height: i32,
         surface: &Surface,
-        pixel_shift: (f64, f64),
         complete_redraw: bool,
-    ) -> Vec<ClipRect> {
-        let c = Context::new(surface).unwrap();
+    ) -> error::Result<Vec<ClipRect>> {
+        let c = Context::new(surface)?;
         let mut modified_regions = if complete_redraw {
             vec![ClipRect::new(0, 0, height as u16, width as u16)]
         } else {
@@ -799,104 +2262,349 @@ impl FunctionLayer {
         } else {
             0
         };
-        let virtual_button_width = ((width - pixel_shift_width as i32)
-            - (BUTTON_SPACING_PX * (self.virtual_button_count - 1) as i32))
-            as f64
-            / self.virtual_button_count as f64;
+        let spans = self.spans(width - pixel_shift_width as i32);
         let radius = 8.0f64;
         let bot = (height as f64) * 0.15;
         let top = (height as f64) * 0.85;
-        let (pixel_shift_x, pixel_shift_y) = pixel_shift;
 
         if complete_redraw {
             let (r,g,b) = config.theme.background;
             c.set_source_rgb(r, g, b);
-            c.paint().unwrap();
+            // from our left edge on, the layers sharing the surface are
+            // drawn left to right
+            c.rectangle(self.left_px as f64, 0.0, (width - self.left_px) as f64, height as f64);
+            c.fill()?;
+            if self.draw_grid {
+                let (r, g, b) = config.theme.accent;
+                c.set_source_rgb(r, g, b);
+                c.set_line_width(1.0);
+                for x in (0..width).step_by(GRID_SPACING_PX) {
+                    c.move_to(x as f64 + 0.5, 0.0);
+                    c.line_to(x as f64 + 0.5, height as f64);
+                }
+                for y in (0..height).step_by(GRID_SPACING_PX / 2) {
+                    c.move_to(0.0, y as f64 + 0.5);
+                    c.line_to(width as f64, y as f64 + 0.5);
+                }
+                c.stroke()?;
+            }
         }
+        // buttons scrolled halfway out stay off the docks next to the layer
+        c.rectangle(
+            self.left_px as f64,
+            0.0,
+            (width - self.right_px - self.left_px) as f64,
+            height as f64,
+        );
+        c.clip();
         c.set_font_face(&config.font_face);
         c.set_font_size(config.font_size);
 
-        for i in 0..self.buttons.len() {
-            let end = if i + 1 < self.buttons.len() {
-                self.buttons[i + 1].0
-            } else {
-                self.virtual_button_count
+        // reach into the spacing between buttons of one Segment, up to a thin divider
+        let reach = BUTTON_SPACING_PX as f64 / 2.0 - SEGMENT_DIVIDER_PX / 2.0;
+        for (i, &(left, button_width)) in spans.iter().enumerate() {
+            let joined = |a: usize, b: usize| {
+                let (a, b) = (&self.buttons[a].1, &self.buttons[b].1);
+                a.segment.is_some() && a.segment == b.segment && a.align == b.align
             };
-            let (start, button) = &mut self.buttons[i];
-            let start = *start;
+            let join_left = i > 0 && joined(i - 1, i);
+            let join_right = i + 1 < spans.len() && joined(i, i + 1);
+            let button = &mut self.buttons[i].1;
 
             if !button.changed && !complete_redraw {
                 continue;
             };
+            // scrolled out of view
+            if left + button_width < self.left_px as f64 || left > (width - self.right_px) as f64 {
+                continue;
+            }
 
-            let left_edge = (start as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64))
-                .floor()
-                + pixel_shift_x
-                + (pixel_shift_width / 2) as f64;
-
-            let button_width = virtual_button_width
-                + ((end - start - 1) as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64))
-                    .floor();
+            let left_edge = left + (pixel_shift_width / 2) as f64;
+            let outer_left = left_edge - if join_left { reach } else { 0.0 };
+            let outer_right = left_edge + button_width.ceil() + if join_right { reach } else { 0.0 };
 
             if !complete_redraw {
                 let (r,g,b) = config.theme.background;
                 c.set_source_rgb(r, g, b);
                 c.rectangle(
-                    left_edge,
+                    outer_left,
                     bot - radius,
-                    button_width,
+                    outer_right - outer_left,
                     top - bot + radius * 2.0,
                 );
-                c.fill().unwrap();
+                c.fill()?;
             }
 
-            let draw_active = button.active;
-            let draw_outline = config.show_button_outlines || button.active;
-            if !matches!(button.image, ButtonImage::Spacer) && button.clickable && draw_outline {
-                button.set_background_color(&c, draw_active, &config.theme);
+            let level = button.press_level(config);
+            let in_segment = join_left || join_right;
+            let outlined = config.show_button_outlines || in_segment;
+            let draw_outline = outlined || level > 0.0;
+            // lit up by a keyboard key, widgets that can not be pressed too
+            if !matches!(button.image, ButtonImage::Spacer)
+                && (((button.clickable || in_segment) && draw_outline)
+                    || button.alert()
+                    || level > 0.0)
+            {
+                button.set_background_color(&c, level, outlined, &config.theme);
                 c.new_sub_path();
                 let left = left_edge + radius;
                 let right = (left_edge + button_width.ceil()) - radius;
-                c.arc(right, bot, radius, (-90.0f64).to_radians(), (0.0f64).to_radians());
-                c.arc(right, top, radius, (0.0f64).to_radians(), (90.0f64).to_radians());
-                c.arc(left, top, radius, (90.0f64).to_radians(), (180.0f64).to_radians());
-                c.arc(left, bot, radius, (180.0f64).to_radians(), (270.0f64).to_radians());
+                // sides joined to the rest of the segment are square
+                if join_right {
+                    c.line_to(outer_right, bot - radius);
+                    c.line_to(outer_right, top + radius);
+                } else {
+                    c.arc(right, bot, radius, (-90.0f64).to_radians(), (0.0f64).to_radians());
+                    c.arc(right, top, radius, (0.0f64).to_radians(), (90.0f64).to_radians());
+                }
+                if join_left {
+                    c.line_to(outer_left, top + radius);
+                    c.line_to(outer_left, bot - radius);
+                } else {
+                    c.arc(left, top, radius, (90.0f64).to_radians(), (180.0f64).to_radians());
+                    c.arc(left, bot, radius, (180.0f64).to_radians(), (270.0f64).to_radians());
+                }
                 c.close_path();
-                c.fill().unwrap();
+                c.fill_preserve()?;
+                if let Some(progress) = button.long_press_progress(config) {
+                    let (r, g, b) = config.theme.accent;
+                    c.set_source_rgb(r, g, b);
+                    c.save()?;
+                    c.clip();
+                    c.rectangle(
+                        left_edge,
+                        bot - radius,
+                        button_width * progress,
+                        top - bot + radius * 2.0,
+                    );
+                    c.fill()?;
+                    c.restore()?;
+                } else {
+                    c.new_path();
+                }
             }
 
             let (r,g,b) = config.theme.foreground;
             c.set_source_rgb(r, g, b);
-            button.render(&c, height, left_edge, button_width.ceil() as u64, pixel_shift_y, config);
+            let rendered = button.sandboxed(|b| {
+                b.render_cached(&c, height, left_edge, button_width.ceil() as u64, config)
+            });
+            let Some(rendered) = rendered else {
+                // the context may be left in any state, start over on a new one
+                return Err(error::Error::Panic);
+            };
+            if let Err(e) = rendered {
+                eprintln!("[render] disabling a button that failed to draw: {}", e);
+                button.image = ButtonImage::Spacer;
+                button.clickable = false;
+                // cairo errors stick to the context, draw_layer starts over on a new one
+                return Err(e);
+            }
 
             button.changed = false;
 
             if !complete_redraw {
                 modified_regions.push(ClipRect::new(
                     height as u16 - top as u16 - radius as u16,
-                    left_edge as u16,
+                    outer_left as u16,
                     height as u16 - bot as u16 + radius as u16,
-                    left_edge as u16 + button_width as u16,
+                    outer_right as u16,
                 ));
             }
         }
 
-        modified_regions
+        // nothing shows under whatever covers the bar there
+        let (r, g, b) = config.theme.background;
+        c.set_source_rgb(r, g, b);
+        for (start, end) in self.covered_in(width - self.right_px) {
+            c.rectangle(start, 0.0, end - start, height as f64);
+        }
+        c.fill()?;
+
+        Ok(modified_regions)
+    }
+
+    // Left edge and width of every button across `width` pixels, the parts
+    // left of left_px and in right_px excluded. Buttons held to their
+    // MinWidth/MaxWidth get exactly that, the others share what is left by
+    // their stretch.
+    fn spans(&self, width: i32) -> Vec<(f64, f64)> {
+        let content = self.content_width(width);
+        // a scrolling layer moves under the covered ranges, they only hide it
+        let covered = if content > width - self.right_px {
+            vec![]
+        } else {
+            self.covered_in(content)
+        };
+        let room = content - covered.iter().map(|(a, b)| b - a).sum::<f64>() as i32;
+        let mut spans = if self.buttons.iter().any(|(_, b)| b.align.is_some()) {
+            self.aligned_spans(room)
+        } else {
+            self.flex_spans(room)
+        };
+        uncover(&mut spans, &covered);
+        let offset = self.scroll.offset.min(self.max_scroll(width)).round();
+        for span in &mut spans {
+            span.0 -= offset;
+        }
+        spans
+    }
+
+    // the covered ranges between left_px and end
+    fn covered_in(&self, end: i32) -> Vec<(f64, f64)> {
+        self.covered
+            .iter()
+            .map(|&(a, b)| (a.max(self.left_px), b.min(end)))
+            .filter(|(a, b)| a < b)
+            .map(|(a, b)| (a as f64, b as f64))
+            .collect()
+    }
+
+    // the whole layer laid out as if the bar was this wide
+    fn content_width(&self, width: i32) -> i32 {
+        let width = width - self.right_px;
+        match self.visible_units {
+            Some(v) if self.virtual_button_count > v && v > 0 => {
+                let per_unit = (width - self.left_px) as f64 / v as f64;
+                self.left_px + (per_unit * self.virtual_button_count as f64).round() as i32
+            }
+            _ => width,
+        }
+    }
+
+    fn max_scroll(&self, width: i32) -> f64 {
+        (self.content_width(width) - (width - self.right_px)) as f64
+    }
+
+    fn scrolls(&self, width: u16) -> bool {
+        self.max_scroll(width as i32) > 0.0
+    }
+
+    fn flex_spans(&self, width: i32) -> Vec<(f64, f64)> {
+        let n = self.buttons.len();
+        let spacing = BUTTON_SPACING_PX as f64;
+        let stretch = self.stretches();
+        let mut held: Vec<Option<f64>> = vec![None; n];
+        let mut unit = 0.0;
+        // every round holds at least one more button or ends
+        for _ in 0..=n {
+            let free_stretch: f64 = (0..n).filter(|&i| held[i].is_none()).map(|i| stretch[i]).sum();
+            let free_count = held.iter().filter(|w| w.is_none()).count() as f64;
+            let free = (width - self.left_px) as f64
+                - held.iter().flatten().sum::<f64>()
+                - spacing * n.saturating_sub(1) as f64;
+            // a button of stretch s covers the s - 1 gaps between its units as well
+            unit = ((free - (free_stretch - free_count) * spacing) / free_stretch.max(1.0)).max(0.0);
+            let mut changed = false;
+            for i in (0..n).filter(|&i| held[i].is_none()) {
+                let w = stretch[i] * unit + (stretch[i] - 1.0) * spacing;
+                let bound = match self.buttons[i].1.width_range {
+                    (_, Some(max)) if w > max => max,
+                    (Some(min), _) if w < min => min,
+                    _ => continue,
+                };
+                held[i] = Some(bound);
+                changed = true;
+            }
+            if !changed {
+                break;
+            }
+        }
+        let mut x = self.left_px as f64;
+        (0..n)
+            .map(|i| {
+                let w = held[i]
+                    .unwrap_or_else(|| unit + ((stretch[i] - 1.0) * (unit + spacing)).floor());
+                let span = (x.floor(), w);
+                x += w + spacing;
+                span
+            })
+            .collect()
+    }
+
+    fn stretches(&self) -> Vec<f64> {
+        (0..self.buttons.len())
+            .map(|i| {
+                let end = self.buttons.get(i + 1).map_or(self.virtual_button_count, |b| b.0);
+                (end - self.buttons[i].0) as f64
+            })
+            .collect()
+    }
+
+    // Status bar style layout for layers using Align. Buttons keep the width
+    // stretch gives them on the plain layout, held to MinWidth/MaxWidth, and
+    // whatever that leaves goes between the left, center and right groups.
+    // Buttons without Align are in the left group.
+    fn aligned_spans(&self, width: i32) -> Vec<(f64, f64)> {
+        let spacing = BUTTON_SPACING_PX as f64;
+        let avail = (width - self.left_px) as f64;
+        let count = self.virtual_button_count.max(1) as f64;
+        let unit = ((avail - spacing * (count - 1.0)) / count).max(0.0);
+        let widths: Vec<f64> = self
+            .stretches()
+            .iter()
+            .zip(&self.buttons)
+            .map(|(s, (_, b))| {
+                let w = s * unit + (s - 1.0) * spacing;
+                let (min, max) = b.width_range;
+                w.min(max.unwrap_or(w)).max(min.unwrap_or(0.0))
+            })
+            .collect();
+        let group = |align: ContentAlign| -> Vec<usize> {
+            (0..self.buttons.len())
+                .filter(|&i| self.buttons[i].1.align.unwrap_or(ContentAlign::Left) == align)
+                .collect()
+        };
+        let extent = |members: &[usize]| -> f64 {
+            members.iter().map(|&i| widths[i] + spacing).sum::<f64>() - spacing
+        };
+        let (left, center, right) =
+            (group(ContentAlign::Left), group(ContentAlign::Center), group(ContentAlign::Right));
+        let left_end = self.left_px as f64 + extent(&left).max(0.0);
+        let right_start = self.left_px as f64 + avail - extent(&right).max(0.0);
+        // centered on the bar, pushed aside by the other groups if they are in the way
+        let center_start = (self.left_px as f64 + (avail - extent(&center)) / 2.0)
+            .min(right_start - spacing - extent(&center))
+            .max(left_end + spacing);
+        let mut spans = vec![(0.0, 0.0); self.buttons.len()];
+        for (members, start) in [
+            (left, self.left_px as f64),
+            (center, center_start),
+            (right, right_start),
+        ] {
+            let mut x = start;
+            for i in members {
+                spans[i] = (x.floor(), widths[i]);
+                x += widths[i] + spacing;
+            }
+        }
+        spans
+    }
+
+    // left edge and width of button i in touch coordinates
+    fn button_span(&self, width: u16, i: usize) -> (f64, f64) {
+        self.spans(width as i32)[i]
     }
 
     fn hit(&self, width: u16, height: u16, x: f64, y: f64, i: Option<usize>) -> Option<usize> {
-        let virtual_button_width =
-            (width as i32 - (BUTTON_SPACING_PX * (self.virtual_button_count - 1) as i32)) as f64
-                / self.virtual_button_count as f64;
-
-        let i = i.unwrap_or_else(|| {
-            let virtual_i = (x / (width as f64 / self.virtual_button_count as f64)) as usize;
-            self.buttons
-                .iter()
-                .position(|(start, _)| *start > virtual_i)
-                .unwrap_or(self.buttons.len())
-                - 1
-        });
+        if x < self.left_px as f64
+            || x > (width as i32 - self.right_px) as f64
+            || self.buttons.is_empty()
+        {
+            return None;
+        }
+        if self
+            .covered_in(width as i32 - self.right_px)
+            .iter()
+            .any(|&(a, b)| x >= a && x < b)
+        {
+            return None;
+        }
+        let spans = self.spans(width as i32);
+        let i = match i {
+            Some(i) => i,
+            None => spans.iter().position(|&(left, w)| x >= left && x <= left + w)?,
+        };
         if i >= self.buttons.len() {
             return None;
         }
@@ -905,18 +2613,7 @@ impl FunctionLayer {
             return None;
         }
 
-        let start = self.buttons[i].0;
-        let end = if i + 1 < self.buttons.len() {
-            self.buttons[i + 1].0
-        } else {
-            self.virtual_button_count
-        };
-
-        let left_edge = (start as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64)).floor();
-        let button_width = virtual_button_width
-            + ((end - start - 1) as f64 * (virtual_button_width + BUTTON_SPACING_PX as f64))
-                .floor();
-
+        let (left_edge, button_width) = spans[i];
         if x < left_edge
             || x > (left_edge + button_width)
             || y < 0.1 * height as f64
@@ -929,12 +2626,172 @@ impl FunctionLayer {
     }
 }
 
-fn rebuild_info_layer(layers: &mut Vec<FunctionLayer>, niri_state: &niri::NiriState) {
-    let Some(info_cfg) = layers.get(1).map(|l| l.source_config.clone()) else {
-        return;
-    };
-    let Some(layer) = layers.get_mut(1) else { return };
+// Moves spans laid out in the room the covered ranges leave out from under
+// them, in order. A button a range would split goes past it whole.
+fn uncover(spans: &mut [(f64, f64)], covered: &[(f64, f64)]) {
+    let mut order: Vec<usize> = (0..spans.len()).collect();
+    order.sort_by(|&a, &b| spans[a].0.total_cmp(&spans[b].0));
+    let mut shift = 0.0;
+    let mut next = 0;
+    for i in order {
+        let (mut left, w) = spans[i];
+        left += shift;
+        while let Some(&(start, end)) = covered.get(next) {
+            if left + w <= start {
+                break;
+            }
+            let moved = (left + end - start).max(end);
+            shift += moved - left;
+            left = moved;
+            next += 1;
+        }
+        spans[i].0 = left;
+    }
+}
+
+// the first layer listing the app in Apps, a trailing * matches any app_id
+// starting with the rest, case is ignored
+// the Esc key of PersistentEscape, drawn and hit on its own over the left
+// edge of whatever layer is shown
+fn escape_layer(cfg: &Config) -> Option<FunctionLayer> {
+    if !cfg.persistent_escape {
+        return None;
+    }
+    Some(FunctionLayer::with_config(vec![ButtonConfig {
+        text: Some("esc".into()),
+        action: vec![Key::Esc],
+        ..Default::default()
+    }]))
+}
+
+// the font or theme changed, what was rendered with the old ones goes
+fn drop_render_caches(layers: &mut [FunctionLayer], escape: &mut Option<FunctionLayer>) {
+    for layer in layers.iter_mut().chain(escape.iter_mut()) {
+        for (_, button) in &mut layer.buttons {
+            button.render_cache = None;
+        }
+    }
+}
+
+// the docks drawn next to the active layer, none next to the OffLayer or
+// while presentation mode or diagnostics have the whole bar
+fn shown_docks(cfg: &Config, layers: &[FunctionLayer], active_layer: usize, hidden: bool) -> Vec<usize> {
+    if hidden || layers[active_layer].off.is_some() {
+        return vec![];
+    }
+    [cfg.left_dock, cfg.right_dock]
+        .into_iter()
+        .flatten()
+        .map(|(idx, _)| idx)
+        .filter(|&idx| idx != active_layer)
+        .collect()
+}
+
+// Moves the shown docks to their edge of the bar, right of the Esc key of
+// PersistentEscape. Returns what they take from the left and right edge of
+// the layer between them.
+fn place_docks(cfg: &Config, layers: &mut [FunctionLayer], docks: &[usize], escape: bool, width: i32) -> (i32, i32) {
+    let mut left = if escape { ESCAPE_WIDTH_PX } else { 0 };
+    let mut right = 0;
+    if let Some((idx, w)) = cfg.left_dock.filter(|(idx, _)| docks.contains(idx)) {
+        layers[idx].left_px = left;
+        layers[idx].right_px = width - left - w;
+        left += w + BUTTON_SPACING_PX;
+    }
+    if let Some((idx, w)) = cfg.right_dock.filter(|(idx, _)| docks.contains(idx)) {
+        layers[idx].left_px = width - w;
+        layers[idx].right_px = 0;
+        right = w + BUTTON_SPACING_PX;
+    }
+    (left, right)
+}
+
+// the active layer and the docks next to it
+fn on_bar<'a>(
+    layers: &'a [FunctionLayer],
+    active_layer: usize,
+    docks: &'a [usize],
+) -> impl Iterator<Item = &'a FunctionLayer> {
+    std::iter::once(active_layer).chain(docks.iter().copied()).map(move |i| &layers[i])
+}
+
+// what FKeyLockHotkey locks the bar to, plain keys that can not switch
+// layers or run anything
+fn fkey_layer(cfg: &Config, width: u16) -> FunctionLayer {
+    let mut keys = FKEYS
+        .iter()
+        .enumerate()
+        .map(|(i, key)| ButtonConfig {
+            text: Some(format!("F{}", i + 1)),
+            action: vec![*key],
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    if width >= 2170 && !cfg.persistent_escape {
+        keys.insert(
+            0,
+            ButtonConfig {
+                text: Some("esc".into()),
+                action: vec![Key::Esc],
+                ..Default::default()
+            },
+        );
+    }
+    let mut layer = FunctionLayer::with_config(keys.clone());
+    layer.name = "fkeys".into();
+    layer.source_config = keys;
+    layer
+}
+
+fn release_escape<F: AsRawFd>(
+    escape: &mut Option<FunctionLayer>,
+    touches: &mut HashSet<i32>,
+    uinput: &mut UInputHandle<F>,
+) {
+    if let Some(esc) = escape {
+        for _ in touches.drain() {
+            esc.buttons[0].1.set_active(uinput, false);
+        }
+    }
+}
+
+fn app_layer(layers: &[FunctionLayer], app: Option<&str>) -> Option<usize> {
+    let app = app?.to_lowercase();
+    layers.iter().position(|l| {
+        l.apps.iter().any(|pattern| {
+            let pattern = pattern.to_lowercase();
+            match pattern.strip_suffix('*') {
+                Some(prefix) => app.starts_with(prefix),
+                None => app == pattern,
+            }
+        })
+    })
+}
+
+fn rebuild_compositor_layers(layers: &mut [FunctionLayer], compositor: &dyn CompositorBackend) {
+    for layer in layers.iter_mut().filter(|l| l.uses_compositor()) {
+        rebuild_compositor_layer(layer, compositor);
+    }
+}
+
+fn rebuild_compositor_layer(layer: &mut FunctionLayer, compositor: &dyn CompositorBackend) {
+    let info_cfg = layer.source_config.clone();
 
+    // icons of named workspaces and apps are loaded once, not again on every event
+    let mut workspace_icons: HashMap<String, Box<ButtonImage>> = HashMap::new();
+    let mut window_icons: HashMap<String, Vec<Option<Box<ButtonImage>>>> = HashMap::new();
+    for (_, button) in std::mem::take(&mut layer.buttons) {
+        match button.image {
+            ButtonImage::NiriWorkspace { name: Some(name), icon: Some(icon), .. } => {
+                workspace_icons.insert(name, icon);
+            }
+            // app icons too, None when the theme has none for the app
+            ButtonImage::NiriWindow { app_id: Some(app_id), icon, .. } => {
+                window_icons.entry(app_id).or_default().push(icon);
+            }
+            _ => {}
+        }
+    }
     let mut buttons: Vec<(usize, Button)> = Vec::new();
     let mut niri_workspace_ids: Vec<(usize, u8)> = Vec::new();
     let mut virt = 0usize;
@@ -944,22 +2801,95 @@ fn rebuild_info_layer(layers: &mut Vec<FunctionLayer>, niri_state: &niri::NiriSt
     let mut displays_live = false;
 
     for cfg in &info_cfg {
-        let stretch = cfg.stretch.unwrap_or(1);
+        let stretch = cfg.units().max(1);
 
         if cfg.niri_workspaces == Some(true) {
-            for ws in &niri_state.workspaces {
+            let workspaces = compositor.workspaces();
+            let cap = cfg.max_workspaces.unwrap_or(DEFAULT_MAX_WORKSPACES).max(1);
+            let last_first = workspaces.len().saturating_sub(cap);
+            let focus = workspaces.iter().find(|w| w.is_focused).map(|w| w.idx);
+            let pos = workspaces.iter().position(|w| w.is_focused).unwrap_or(0);
+            // stays where taps on the "…" put it until the focus moves out of it
+            let mut first = layer.workspace_first.min(last_first);
+            if focus != layer.workspace_focus && !(first..first + cap).contains(&pos) {
+                first = pos.saturating_sub(cap / 2).min(last_first);
+            }
+            layer.workspace_first = first;
+            layer.workspace_focus = focus;
+            let shown = &workspaces[first..workspaces.len().min(first + cap)];
+            let hidden_after = workspaces.len() - first - shown.len();
+            if first > 0 {
+                let mut button = Button::new_workspace_overflow(false, first);
+                button.width_range = width_range(cfg);
+                button.align = button_align(cfg);
+                button.segment = cfg.segment.clone();
+                buttons.push((virt, button));
+                virt += 1;
+                total += 1;
+            }
+            for ws in shown {
                 let btn_index = buttons.len();
                 niri_workspace_ids.push((btn_index, ws.idx));
-                buttons.push((virt, Button::new_niri_workspace(ws.idx, ws.is_focused, ws.id)));
+                let icon = ws.name.as_ref().and_then(|name| {
+                    workspace_icons.remove(name).or_else(|| {
+                        let icon = cfg.workspace_icons.as_ref()?.get(name)?;
+                        try_load_image(icon, cfg.theme.as_deref()).ok().map(Box::new)
+                    })
+                });
+                let mut button = Button::new_niri_workspace(ws, icon);
+                button.width_range = width_range(cfg);
+                button.align = button_align(cfg);
+                button.segment = cfg.segment.clone();
+                buttons.push((virt, button));
+                virt += 1;
+                total += 1;
+            }
+            if hidden_after > 0 {
+                let mut button = Button::new_workspace_overflow(true, hidden_after);
+                button.width_range = width_range(cfg);
+                button.align = button_align(cfg);
+                button.segment = cfg.segment.clone();
+                buttons.push((virt, button));
                 virt += 1;
                 total += 1;
             }
             continue;
         }
 
+        if cfg.niri_windows == Some(true) {
+            let windows = compositor.windows();
+            if windows.is_empty() {
+                buttons.push((virt, Button::new_text("No windows".to_string(), vec![])));
+                virt += stretch;
+                total += stretch;
+            }
+            for window in windows {
+                let icon = window.app_id.as_ref().and_then(|app_id| {
+                    match window_icons.get_mut(app_id).and_then(|icons| icons.pop()) {
+                        Some(icon) => icon,
+                        None => try_load_image(app_id, cfg.theme.as_deref()).ok().map(Box::new),
+                    }
+                });
+                let mut button = Button::new_niri_window(&window, icon);
+                button.width_range = width_range(cfg);
+                button.align = button_align(cfg);
+                button.segment = cfg.segment.clone();
+                buttons.push((virt, button));
+                virt += stretch;
+                total += stretch;
+            }
+            continue;
+        }
+
         if cfg.niri_window_title == Some(true) {
-            let title = niri_state.focused_window_title.clone().unwrap_or_default();
-            buttons.push((virt, Button::new_niri_window_title(title)));
+            let title = compositor.focused_window_title().unwrap_or_default().to_string();
+            let layout = compositor.focused_window_layout();
+            let mut button = Button::new_niri_window_title(title, layout);
+            button.id = cfg.id.clone();
+            button.width_range = width_range(cfg);
+            button.align = button_align(cfg);
+            button.segment = cfg.segment.clone();
+            buttons.push((virt, button));
             virt += stretch;
             total += stretch;
             continue;
@@ -970,7 +2900,16 @@ fn rebuild_info_layer(layers: &mut Vec<FunctionLayer>, niri_state: &niri::NiriSt
             displays_time = true;
             faster_refresh = btn.needs_faster_refresh();
         }
-        if matches!(btn.image, ButtonImage::Volume | ButtonImage::Brightness | ButtonImage::Wifi) {
+        if matches!(
+            btn.image,
+            ButtonImage::Brightness
+                | ButtonImage::Privacy { .. }
+                | ButtonImage::Cpu { .. }
+                | ButtonImage::Memory { .. }
+                | ButtonImage::LoadAvg(_)
+                | ButtonImage::Temperature { .. }
+        ) || btn.sparkline.is_some()
+        {
             displays_live = true;
         }
         buttons.push((virt, btn));
@@ -986,6 +2925,425 @@ fn rebuild_info_layer(layers: &mut Vec<FunctionLayer>, niri_state: &niri::NiriSt
     layer.displays_live = displays_live;
 }
 
+// re-reads every battery button's device, true if any of them changed
+// `read` gives the state of a power_supply by name, None leaves its buttons alone
+fn update_battery(
+    layers: &mut [FunctionLayer],
+    read: impl Fn(&str) -> Option<(u32, BatteryState)>,
+) -> bool {
+    let mut changed = false;
+    for layer in layers.iter_mut().filter(|l| l.displays_battery) {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Battery(ref battery, _, _, ref mut shown) = button.image {
+                let Some(current) = read(battery) else { continue };
+                if current != *shown {
+                    *shown = current;
+                    button.changed = true;
+                    changed = true;
+                }
+            }
+        }
+    }
+    changed
+}
+
+// the workspace after or before the focused one, without wrapping around
+fn step_workspace(compositor: &mut dyn CompositorBackend, forward: bool) {
+    let workspaces = compositor.workspaces();
+    let Some(pos) = workspaces.iter().position(|w| w.is_focused) else { return };
+    let target = if forward {
+        workspaces.get(pos + 1)
+    } else {
+        pos.checked_sub(1).and_then(|p| workspaces.get(p))
+    };
+    if let Some(idx) = target.map(|w| w.idx) {
+        compositor.focus_workspace(idx);
+    }
+}
+
+fn notification_popup(
+    note: &notifications::Notification,
+    timeout_ms: u64,
+) -> (FunctionLayer, std::time::Instant) {
+    let layer = FunctionLayer::with_config(note.to_buttons());
+    (layer, std::time::Instant::now() + Duration::from_millis(timeout_ms))
+}
+
+fn calendar_popup(locale: Locale) -> (FunctionLayer, std::time::Instant) {
+    let mut layer = FunctionLayer::with_config(vec![ButtonConfig::default()]);
+    layer.buttons[0].1 = Button::new_calendar(locale);
+    (layer, std::time::Instant::now() + Duration::from_millis(POPUP_TIMEOUT_MS as u64))
+}
+
+fn apply_slider(
+    kind: SliderKind,
+    percent: u32,
+    backlight: &mut BacklightManager,
+    server: &mut Option<ipc::IpcServer>,
+    app: Option<&str>,
+) {
+    match kind {
+        SliderKind::Brightness => backlight.set_display_brightness(percent),
+        // applied by session-helper.sh, the daemon cannot reach the audio server
+        SliderKind::Volume => broadcast(server, ipc::Event::Volume(percent)),
+        SliderKind::AppVolume => {
+            if let Some(app) = app {
+                let app_id = app.to_string();
+                broadcast(server, ipc::Event::AppVolume { app_id, percent });
+            }
+        }
+    }
+}
+
+fn update_displays(layers: &mut [FunctionLayer], current: Option<DisplayMode>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Displays(ref mut mode) = button.image {
+                if *mode != current {
+                    *mode = current;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+// None while no session-helper.sh is around to tell or switch the default sink
+fn update_sink_toggles(layers: &mut [FunctionLayer], default_sink: Option<&str>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::SinkToggle { ref sink, ref mut on, .. } = button.image {
+                let is_default = default_sink.map(|d| sink == d);
+                if *on != is_default {
+                    *on = is_default;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+// the default output's volume on Volume buttons and volume sliders
+fn update_volume(layers: &mut [FunctionLayer], level: Option<(u32, bool)>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            match button.image {
+                ButtonImage::Volume(ref mut shown) if *shown != level => *shown = level,
+                ButtonImage::Slider { kind: SliderKind::Volume, ref mut value }
+                    if *value != level.map(|(v, _)| v) =>
+                {
+                    *value = level.map(|(v, _)| v)
+                }
+                _ => continue,
+            }
+            button.changed = true;
+        }
+    }
+}
+
+// the focused app's volume on app volume sliders, None until it is known
+fn update_app_volume(layers: &mut [FunctionLayer], percent: Option<u32>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Slider { kind: SliderKind::AppVolume, ref mut value } = button.image {
+                if *value != percent {
+                    *value = percent;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn update_screen_recording(layers: &mut [FunctionLayer], active: bool) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::ScreenRecording(ref mut shown) = button.image {
+                if *shown != active {
+                    *shown = active;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn update_privacy(layers: &mut [FunctionLayer], camera_on: bool, mic_on: bool) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Privacy { ref mut camera, ref mut mic } = button.image {
+                if *camera != camera_on || *mic != mic_on {
+                    *camera = camera_on;
+                    *mic = mic_on;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn update_bluetooth(layers: &mut [FunctionLayer], bt: &bluetooth::BluetoothManager) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Bluetooth { ref mac, ref mut state } = button.image {
+                if let Some(dev) = bt.device(mac) {
+                    if state != dev {
+                        *state = dev.clone();
+                        button.changed = true;
+                    }
+                }
+            }
+            if let ButtonImage::BluetoothBattery { ref device, ref mut shown } = button.image {
+                let current = bt.battery_device(device).and_then(|d| {
+                    let name = d.alias.clone().unwrap_or_else(|| device.clone());
+                    d.battery.map(|p| (name, p))
+                });
+                if *shown != current {
+                    *shown = current;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn update_media(layers: &mut [FunctionLayer], state: Option<&mpris::MediaState>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Media(ref mut shown) = button.image {
+                if shown.as_ref() != state {
+                    *shown = state.cloned();
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn update_dnd(layers: &mut [FunctionLayer], state: Option<dnd::DndState>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::DoNotDisturb(ref mut shown) = button.image {
+                if *shown != state {
+                    *shown = state;
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn update_wifi(layers: &mut [FunctionLayer], info: Option<&network::WifiInfo>) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Wifi(ref mut shown) = button.image {
+                if shown.as_ref() != info {
+                    *shown = info.cloned();
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+fn custom_keys(layers: &[FunctionLayer]) -> Vec<custom::CustomKey> {
+    let mut keys = Vec::new();
+    for (_, button) in layers.iter().flat_map(|l| &l.buttons) {
+        if let ButtonImage::Custom { ref key, .. } = button.image {
+            if !keys.contains(key) {
+                keys.push(key.clone());
+            }
+        }
+    }
+    keys
+}
+
+// Script widgets by the Id of their button, their state is kept under it
+fn script_widgets(layers: &[FunctionLayer]) -> Vec<(&str, &script::ScriptWidget)> {
+    let mut widgets = Vec::new();
+    for (_, button) in layers.iter().flat_map(|l| &l.buttons) {
+        if let (ButtonImage::Script(widget), Some(id)) = (&button.image, &button.id) {
+            widgets.push((id.as_str(), &**widget));
+        }
+    }
+    widgets
+}
+
+fn update_custom(layers: &mut [FunctionLayer], runner: &custom::CustomRunner) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if let ButtonImage::Custom { ref key, ref mut output } = button.image {
+                let latest = runner.output(key);
+                if output.as_ref() != latest {
+                    *output = latest.cloned();
+                    button.changed = true;
+                }
+            }
+        }
+    }
+}
+
+// Modifier keys of the chord modifiers other fingers are holding, which are
+// then used up and no longer send their own keys or long press.
+fn chord_modifiers(layers: &mut [FunctionLayer], touches: &HashMap<i32, (usize, usize)>, slot: i32) -> Vec<Key> {
+    let mut mods = Vec::new();
+    for (&s, &(layer, btn)) in touches {
+        let button = &mut layers[layer].buttons[btn].1;
+        if s == slot || !button.active || button.chord_modifier.is_empty() {
+            continue;
+        }
+        button.chorded = true;
+        button.press_started = None;
+        mods.extend(button.chord_modifier.iter().copied());
+    }
+    mods
+}
+
+// Layer switches wait until no button is held, a finger never ends up over
+// another layer than the one it pressed. True if the switch happened now.
+fn switch_layer(active_layer: &mut usize, pending_layer: &mut Option<usize>, touching: bool, idx: usize) -> bool {
+    if touching {
+        *pending_layer = (idx != *active_layer).then_some(idx);
+        return false;
+    }
+    *pending_layer = None;
+    let changed = *active_layer != idx;
+    *active_layer = idx;
+    changed
+}
+
+// the hot corner action for a touch at x, if it landed in one
+fn hot_corner(cfg: &Config, width: u16, x: f64) -> Option<SwipeAction> {
+    let zone = width as f64 * cfg.hot_corner_percent as f64 / 100.0;
+    if x < zone {
+        cfg.hot_corner_left_action.clone()
+    } else if x >= width as f64 - zone {
+        cfg.hot_corner_right_action.clone()
+    } else {
+        None
+    }
+}
+
+// the layer after (or before) `from` in LayerCycle, from a layer left out
+// of it the cycle starts over at its first
+fn next_layer(cycle: &[usize], from: usize, forward: bool) -> usize {
+    let Some(pos) = cycle.iter().position(|&l| l == from) else {
+        return cycle.first().copied().unwrap_or(from);
+    };
+    let n = cycle.len();
+    cycle[if forward { (pos + 1) % n } else { (pos + n - 1) % n }]
+}
+
+// the WindowSwitcher layer, or the layer it was opened from while it is shown
+fn window_switcher_target(cfg: &Config, active_layer: usize, opened_from: usize) -> Option<usize> {
+    let switcher = cfg.window_switcher?;
+    Some(if active_layer == switcher { opened_from } else { switcher })
+}
+
+// Runs a swipe or two finger tap action, returns the layer to switch to, if any.
+fn run_gesture<F: AsRawFd>(
+    action: &SwipeAction,
+    cfg: &Config,
+    (active_layer, switcher_from): (usize, usize),
+    compositor: &mut Option<Box<dyn CompositorBackend>>,
+    media: &Option<mpris::MprisMonitor>,
+    uinput: &mut UInputHandle<F>,
+) -> Option<usize> {
+    match action {
+        SwipeAction::NextLayer | SwipeAction::PreviousLayer => {
+            return Some(next_layer(&cfg.layer_cycle, active_layer, *action == SwipeAction::NextLayer));
+        }
+        SwipeAction::WindowSwitcher => {
+            return window_switcher_target(cfg, active_layer, switcher_from);
+        }
+        SwipeAction::NextWorkspace | SwipeAction::PreviousWorkspace => {
+            if let Some(ref mut c) = compositor {
+                step_workspace(c.as_mut(), *action == SwipeAction::NextWorkspace);
+            }
+        }
+        SwipeAction::SeekForward | SwipeAction::SeekBackward => {
+            if let Some(ref m) = media {
+                m.seek(if *action == SwipeAction::SeekForward {
+                    SWIPE_SEEK_US
+                } else {
+                    -SWIPE_SEEK_US
+                });
+            }
+        }
+        SwipeAction::Keys(ref keys) => {
+            toggle_keys(uinput, keys, 1);
+            toggle_keys(uinput, keys, 0);
+        }
+        SwipeAction::ToggleOverview => {
+            if let Some(ref mut c) = compositor {
+                c.toggle_overview();
+            }
+        }
+        SwipeAction::Spawn(ref command) => match compositor {
+            // still connected to the session of a user switched away from,
+            // whose account the command would run in
+            Some(ref c) if cfg.session_uid.is_some_and(|uid| compositor::owner(c.as_ref()) != Some(uid)) => {
+                eprintln!("[gesture] the compositor is not the active session's, not spawning {:?}", command);
+            }
+            Some(ref mut c) if !command.is_empty() => c.spawn(command),
+            _ => eprintln!("[gesture] no compositor to spawn {:?} with", command),
+        },
+    }
+    None
+}
+
+// themed icons in the variant the theme calls for, buttons rebuilt for the
+// compositor start out full color again
+fn resolve_icons(layers: &mut [FunctionLayer], symbolic: bool) {
+    for layer in layers {
+        for (_, button) in &mut layer.buttons {
+            if button.symbolic == symbolic {
+                continue;
+            }
+            button.symbolic = symbolic;
+            let Some((ref name, ref theme)) = button.icon_source else { continue };
+            if matches!(button.image, ButtonImage::Failed) {
+                continue;
+            }
+            let Ok(image) = try_load_variant(name, Some(theme), symbolic) else { continue };
+            match button.image {
+                ButtonImage::IconText { ref mut icon, .. } => **icon = image,
+                ref mut icon => *icon = image,
+            }
+            button.changed = true;
+        }
+    }
+}
+
+fn broadcast(server: &mut Option<ipc::IpcServer>, event: ipc::Event) {
+    if let Some(server) = server.as_mut().filter(|s| s.has_subscribers()) {
+        server.broadcast(&event);
+    }
+}
+
+fn announce(server: &mut Option<ipc::IpcServer>, cfg: &Config, text: String) {
+    if cfg.announcements {
+        broadcast(server, ipc::Event::Announce(text));
+    }
+}
+
+fn haptic_click(haptics: &mut Option<haptics::Haptics>, cfg: &Config, press: bool) {
+    if let (true, Some(h)) = (cfg.haptics, haptics) {
+        h.click(press, cfg.haptics_strength);
+    }
+}
+
+// id is a freedesktop sound naming spec event
+fn play_sound(server: &mut Option<ipc::IpcServer>, cfg: &Config, button: &Button, id: &str) {
+    if cfg.press_sounds && !button.muted {
+        let event = ipc::Event::Sound {
+            id: id.to_string(),
+            theme: cfg.sound_theme.clone(),
+        };
+        broadcast(server, event);
+    }
+}
+
 struct Interface;
 
 impl LibinputInterface for Interface {
@@ -1004,21 +3362,26 @@ impl LibinputInterface for Interface {
     }
 }
 
-fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32)
+fn emit<F>(uinput: &mut UInputHandle<F>, ty: EventKind, code: u16, value: i32) -> error::Result<()>
 where
     F: AsRawFd,
 {
-    uinput
-        .write(&[input_event {
-            value,
-            type_: ty as u16,
-            code,
-            time: timeval {
-                tv_sec: 0,
-                tv_usec: 0,
-            },
-        }])
-        .unwrap();
+    uinput.write(&[input_event {
+        value,
+        type_: ty as u16,
+        code,
+        time: timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+    }])?;
+    Ok(())
+}
+
+// a key was typed too recently for the buttons of `layer` to be pressed
+fn typing_blocks(cfg: &Config, layer: &FunctionLayer, last_keystroke: Option<std::time::Instant>) -> bool {
+    let ms = layer.typing_block_ms.unwrap_or(cfg.typing_block_ms);
+    last_keystroke.is_some_and(|t| t.elapsed().as_millis() < ms as u128)
 }
 
 fn toggle_keys<F>(uinput: &mut UInputHandle<F>, codes: &Vec<Key>, value: i32)
@@ -1028,21 +3391,21 @@ where
     if codes.is_empty() {
         return;
     }
-    for kc in codes {
-        emit(uinput, EventKind::Key, *kc as u16, value);
+    // a lost key event is not worth taking the bar down for
+    let sent = codes
+        .iter()
+        .try_for_each(|kc| emit(uinput, EventKind::Key, *kc as u16, value))
+        .and_then(|_| emit(uinput, EventKind::Synchronize, SynchronizeKind::Report as u16, 0));
+    if let Err(e) = sent {
+        eprintln!("[uinput] failed to send {:?}: {}", codes, e);
     }
-    emit(
-        uinput,
-        EventKind::Synchronize,
-        SynchronizeKind::Report as u16,
-        0,
-    );
 }
 
 fn main() {
-    let mut drm = DrmBackend::open_card().unwrap();
+    let mut cards = DrmBackend::open_cards(&config::load_drm_selection()).unwrap();
+    let mut drm = cards.remove(0);
     let (height, width) = drm.mode().size();
-    let _ = panic::catch_unwind(AssertUnwindSafe(|| real_main(&mut drm)));
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| real_main(&mut drm, cards)));
     let crash_bitmap = include_bytes!("crash_bitmap.raw");
     let mut map = drm.map().unwrap();
     let data = map.as_mut();
@@ -1065,8 +3428,41 @@ fn main() {
     sigset.wait().unwrap();
 }
 
-fn real_main(drm: &mut DrmBackend) {
+// picks the touch bar display up again after it was unplugged, as long as it
+// comes back at the size everything was laid out for
+fn reopen_drm(drm: &mut DrmBackend, height: u16, width: u16) -> bool {
+    match DrmBackend::open_card(&config::load_drm_selection()) {
+        Ok(card) if card.mode().size() == (height, width) => {
+            eprintln!("[drm] reopened {}", card.path().display());
+            *drm = card;
+            true
+        }
+        Ok(card) => {
+            let (h, w) = card.mode().size();
+            eprintln!("[drm] {} came back at {}x{}, restart to use it", card.path().display(), h, w);
+            false
+        }
+        Err(e) => {
+            eprintln!("[drm] failed to reopen: {}", e);
+            false
+        }
+    }
+}
+
+// Touch bar displays past the first show the same frames and their
+// digitizers work the same layers, as long as they are the same size.
+fn real_main(drm: &mut DrmBackend, mirrors: Vec<DrmBackend>) {
     let (height, width) = drm.mode().size();
+    let mut mirrors: Vec<DrmBackend> = mirrors
+        .into_iter()
+        .filter(|m| {
+            let same = m.mode().size() == (height, width);
+            if !same {
+                eprintln!("[drm] {} is not the size of {}, left dark", m.path().display(), drm.path().display());
+            }
+            same
+        })
+        .collect();
     let (db_width, db_height) = drm.fb_info().unwrap().size();
     let mut uinput = UInputHandle::new(OpenOptions::new().write(true).open("/dev/uinput").unwrap());
     let mut backlight = BacklightManager::new();
@@ -1074,10 +3470,131 @@ fn real_main(drm: &mut DrmBackend) {
     let (mut cfg, mut layers) = cfg_mgr.load_config(width);
     let mut pixel_shift = PixelShiftManager::new();
 
-    let mut niri: Option<niri::NiriState> = niri::NiriState::connect();
-    if let Some(ref n) = niri {
-        rebuild_info_layer(&mut layers, n);
+    let mut compositor = compositor::connect(cfg.compositor.as_deref());
+    if let Some(ref mut c) = compositor {
+        if cfg.release_output {
+            c.release_output(drm.connector());
+        }
+        rebuild_compositor_layers(&mut layers, c.as_ref());
     }
+    // at boot the compositor is usually not up yet, it is connected to once
+    // it is, and again after it restarted
+    let mut compositor_waiter = compositor::Waiter::spawn(cfg.compositor.as_deref(), compositor.is_some());
+
+    let mut tz_watcher = if cfg.auto_timezone {
+        timezone::TimezoneWatcher::connect()
+    } else {
+        None
+    };
+
+    let mut sleep_monitor = sleep::SleepMonitor::connect();
+    let mut seat_idle = if cfg.follow_seat_idle {
+        idle::SeatIdleMonitor::connect()
+    } else {
+        None
+    };
+
+    let wants_screencast = layers.iter().any(|l| {
+        l.buttons
+            .iter()
+            .any(|(_, b)| matches!(b.image, ButtonImage::ScreenRecording(_)))
+    });
+    let mut screencast = if wants_screencast {
+        screencast::ScreenCastMonitor::connect()
+    } else {
+        None
+    };
+
+    let wants_privacy = layers.iter().any(|l| {
+        l.buttons
+            .iter()
+            .any(|(_, b)| matches!(b.image, ButtonImage::Privacy { .. }))
+    });
+    let mut privacy = if wants_privacy {
+        Some(privacy::PrivacyMonitor::new())
+    } else {
+        None
+    };
+
+    let bt_macs: Vec<String> = layers
+        .iter()
+        .flat_map(|l| l.buttons.iter())
+        .filter_map(|(_, b)| match b.image {
+            ButtonImage::Bluetooth { ref mac, .. } => Some(mac.clone()),
+            ButtonImage::BluetoothBattery { ref device, .. } if device != bluetooth::FIRST_AUDIO => {
+                Some(device.clone())
+            }
+            _ => None,
+        })
+        .collect();
+    let wants_bt_battery = layers.iter().any(|l| {
+        l.buttons
+            .iter()
+            .any(|(_, b)| matches!(b.image, ButtonImage::BluetoothBattery { .. }))
+    });
+    let mut bluetooth = if bt_macs.is_empty() && !wants_bt_battery {
+        None
+    } else {
+        bluetooth::BluetoothManager::connect(bt_macs)
+    };
+
+    let wants_wifi = layers.iter().any(|l| {
+        l.buttons
+            .iter()
+            .any(|(_, b)| matches!(b.image, ButtonImage::Wifi(_)))
+    });
+    let mut network = if wants_wifi {
+        network::NetworkMonitor::connect()
+    } else {
+        None
+    };
+
+    let wants_media = layers.iter().any(|l| {
+        l.buttons
+            .iter()
+            .any(|(_, b)| matches!(b.image, ButtonImage::Media(_)))
+    }) || [
+        &cfg.swipe_left_action,
+        &cfg.swipe_right_action,
+        &cfg.two_finger_tap_action,
+        &cfg.hot_corner_left_action,
+        &cfg.hot_corner_right_action,
+    ]
+    .iter()
+    .any(|a| matches!(a, Some(SwipeAction::SeekForward | SwipeAction::SeekBackward)));
+    let mut media = if wants_media {
+        mpris::MprisMonitor::connect()
+    } else {
+        None
+    };
+
+    let wants_dnd = layers.iter().any(|l| {
+        l.buttons
+            .iter()
+            .any(|(_, b)| matches!(b.image, ButtonImage::DoNotDisturb(_)))
+    });
+    let mut dnd = if wants_dnd {
+        dnd::DndMonitor::connect()
+    } else {
+        None
+    };
+
+    let mut ipc_server = ipc::IpcServer::bind();
+    let control_service = service::ControlService::connect();
+    let mut notification_monitor = if cfg.notification_popups {
+        notifications::NotificationMonitor::connect()
+    } else {
+        None
+    };
+    // the user whose session bus the monitors above are on, if any
+    let bus_uid = cfg.session_uid;
+    // opened even when off, Haptics can be turned on by a reload
+    let mut haptics = haptics::Haptics::open(cfg.haptics_device.as_deref());
+    let mut widget_store = persist::WidgetStore::open();
+    // LongPressExec and Custom commands, without the input and video groups
+    let launcher = launcher::Launcher::spawn();
+    // the keyboards can not be opened any more without root
+    let mut remapper = remap::KeyRemapper::open(&cfg.remap_keys);
 
     let groups = ["input", "video"];
     PrivDrop::default()
@@ -1086,11 +3603,27 @@ fn real_main(drm: &mut DrmBackend) {
         .apply()
         .unwrap_or_else(|e| panic!("Failed to drop privileges: {}", e));
 
+    // commands of Custom buttons must never run as root
+    let mut custom_runner = launcher.clone().and_then(custom::CustomRunner::new);
+    if let Some(ref mut r) = custom_runner {
+        r.sync(custom_keys(&layers));
+    }
+
     let mut surface =
         ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
+    // the layer as drawn, before the pixel shift moves it around on `surface`
+    let mut content =
+        ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
+    let mut shift_changed = false;
     let mut active_layer = 0usize;
+    // where switch_layer goes once the last held button is let go
+    let mut pending_layer: Option<usize> = None;
     let mut fn_tap_layer = 0usize;
+    // where the WindowSwitcher goes back to
+    let mut switcher_from = 0usize;
     let mut fn_press_time: Option<std::time::Instant> = None;
+    // another key or the bar was used while Fn was down, its release is no tap then
+    let mut fn_used = false;
     let mut needs_complete_redraw = true;
 
     let mut input_tb = Libinput::new_with_udev(Interface);
@@ -1102,6 +3635,8 @@ fn real_main(drm: &mut DrmBackend) {
         .unwrap()
         .match_subsystem("power_supply")
         .unwrap()
+        .match_subsystem("drm")
+        .unwrap()
         .listen()
         .unwrap();
 
@@ -1118,21 +3653,113 @@ fn real_main(drm: &mut DrmBackend) {
     epoll
         .add(&udev_monitor, EpollEvent::new(EpollFlags::EPOLLIN, 3))
         .unwrap();
-    if let Some(ref n) = niri {
-        epoll.add(n, EpollEvent::new(EpollFlags::EPOLLIN, 4)).unwrap();
+    // names of everything registered below, for the diagnostics layer
+    let mut epoll_sources = EPOLL_SOURCES[..4].to_vec();
+    if let Some(fd) = compositor.as_ref().and_then(|c| c.event_fd()) {
+        epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, 4)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[4]);
+    }
+    if let Some(ref tz) = tz_watcher {
+        epoll.add(tz, EpollEvent::new(EpollFlags::EPOLLIN, 5)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[5]);
+    }
+    if let Some(ref sc) = screencast {
+        epoll.add(sc, EpollEvent::new(EpollFlags::EPOLLIN, 6)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[6]);
+    }
+    if let Some(ref p) = privacy {
+        if p.watches_camera() {
+            epoll.add(p, EpollEvent::new(EpollFlags::EPOLLIN, 7)).unwrap();
+            epoll_sources.push(EPOLL_SOURCES[7]);
+        }
+    }
+    if let Some(ref bt) = bluetooth {
+        epoll.add(bt, EpollEvent::new(EpollFlags::EPOLLIN, 8)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[8]);
+    }
+    if let Some(ref net) = network {
+        epoll.add(net, EpollEvent::new(EpollFlags::EPOLLIN, 9)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[9]);
+    }
+    if let Some(ref server) = ipc_server {
+        epoll.add(server, EpollEvent::new(EpollFlags::EPOLLIN, 10)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[10]);
+    }
+    if let Some(ref m) = media {
+        epoll.add(m, EpollEvent::new(EpollFlags::EPOLLIN, 11)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[11]);
+    }
+    if let Some(ref svc) = control_service {
+        epoll.add(svc, EpollEvent::new(EpollFlags::EPOLLIN, 12)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[12]);
+    }
+    if let Some(ref n) = notification_monitor {
+        epoll.add(n, EpollEvent::new(EpollFlags::EPOLLIN, 13)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[13]);
+    }
+    if let Some(ref r) = custom_runner {
+        epoll.add(r, EpollEvent::new(EpollFlags::EPOLLIN, 14)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[14]);
+    }
+    if let Some(ref m) = sleep_monitor {
+        epoll.add(m, EpollEvent::new(EpollFlags::EPOLLIN, 15)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[15]);
+    }
+    if let Some(ref m) = seat_idle {
+        epoll.add(m, EpollEvent::new(EpollFlags::EPOLLIN, 16)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[16]);
+    }
+    if let Some(ref d) = dnd {
+        epoll.add(d, EpollEvent::new(EpollFlags::EPOLLIN, 17)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[17]);
+    }
+    if let Some(ref w) = compositor_waiter {
+        epoll.add(w, EpollEvent::new(EpollFlags::EPOLLIN, 18)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[18]);
+    }
+    if let Some(fd) = cfg_mgr.session_fd() {
+        epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, 19)).unwrap();
+        epoll_sources.push(EPOLL_SOURCES[19]);
+    }
+    if let Some(ref r) = remapper {
+        for fd in r.fds() {
+            epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, 20)).unwrap();
+        }
+        epoll_sources.push(EPOLL_SOURCES[20]);
     }
 
     uinput.set_evbit(EventKind::Key).unwrap();
     for layer in &layers {
         for button in &layer.buttons {
-            for k in &button.1.action {
+            for k in button.1.action.iter().chain(&button.1.long_press_action) {
+                uinput.set_keybit(*k).unwrap();
+            }
+        }
+    }
+    // the F-key lock can be turned on whatever the layers have
+    for k in FKEYS.iter().chain(&[Key::Esc]) {
+        uinput.set_keybit(*k).unwrap();
+    }
+    if let Some(ref r) = remapper {
+        for k in r.keys() {
+            uinput.set_keybit(k).unwrap();
+        }
+    }
+    for action in [
+        &cfg.swipe_left_action,
+        &cfg.swipe_right_action,
+        &cfg.hot_corner_left_action,
+        &cfg.hot_corner_right_action,
+    ] {
+        if let Some(SwipeAction::Keys(keys)) = action {
+            for k in keys {
                 uinput.set_keybit(*k).unwrap();
             }
         }
     }
 
     let mut dev_name_c = [0 as c_char; 80];
-    let dev_name = "Dynamic Function Row Virtual Input Device".as_bytes();
+    let dev_name = VIRTUAL_DEVICE_NAME.as_bytes();
     for i in 0..dev_name.len() {
         dev_name_c[i] = dev_name[i] as c_char;
     }
@@ -1150,129 +3777,1038 @@ fn real_main(drm: &mut DrmBackend) {
         .unwrap();
     uinput.dev_create().unwrap();
 
-    let mut digitizer: Option<InputDevice> = None;
+    let mut digitizers: Vec<InputDevice> = Vec::new();
+    // the touch bar card was unplugged, nothing is drawn until it is back
+    let mut drm_lost = false;
     let mut touches: HashMap<i32, (usize, usize)> = HashMap::new();
+    // where and when each finger landed, for swipe detection
+    let mut swipes: HashMap<i32, (f64, std::time::Instant)> = HashMap::new();
+    // fingers on a scrolling layer, not yet known to be a tap or a drag
+    let mut drags: HashMap<i32, Drag> = HashMap::new();
+    // modifier keys held for the chord a finger is tapping, by slot
+    let mut chords: HashMap<i32, Vec<Key>> = HashMap::new();
+    // fingers of a two finger tap in progress and when the second landed
+    let mut two_finger_tap: Option<(HashSet<i32>, std::time::Instant)> = None;
+    // every finger on the digitizer, not just the ones that landed on a button
+    let mut fingers_down = 0usize;
+    // the layer ReturnToDefaultAfterMs is timing and the last time it was touched
+    let mut returning: (usize, std::time::Instant) = (active_layer, std::time::Instant::now());
+    // keys currently held on any keyboard, for ResetHotkey
+    let mut held_keys: HashSet<u32> = HashSet::new();
+    let mut last_three_finger_tap: Option<std::time::Instant> = None;
+    // last key typed on a keyboard, buttons are not pressed for a while after it
+    let mut last_keystroke: Option<std::time::Instant> = None;
     let mut last_redraw_ts = if layers[active_layer].faster_refresh {
         Local::now().second()
     } else {
         Local::now().minute()
     };
 
-    // Poll live modules (vol/brt/wifi) every N seconds
-    const LIVE_POLL_MS: u64 = 3000;
+    // transient layer shown over the active one until tapped or the deadline
+    let mut popup: Option<(FunctionLayer, std::time::Instant)> = None;
+
+    // commands from the control socket and from Command buttons, replied to if they came over IPC
+    let mut requests: Vec<(ipc::Request, Origin)> = Vec::new();
+    // the layer presentation mode or the F-key lock has locked the bar to
+    let mut presentation: Option<usize> = None;
+    // locked to the fkey_layer pushed after the configured ones
+    let mut fkey_lock = false;
+    // when FKeyLockHotkey was completed, cleared once it fired or a key is let go
+    let mut fkey_combo_since: Option<std::time::Instant> = None;
+    let mut announced_layer = active_layer;
+    // diagnostics layer, shown over everything else until turned off over IPC
+    let mut diagnostics: Option<(diagnostics::Diagnostics, FunctionLayer)> = None;
+    let mut diag_touch: Option<(f64, f64)> = None;
+
+    // Poll live modules (vol/brt/mic) every N seconds
     let mut last_live_poll = std::time::Instant::now();
+    // re-read battery state on power_supply uevents and every minute
+    const BATTERY_POLL_MS: u64 = 60 * 1000;
+    let mut last_battery_poll = std::time::Instant::now();
+    let mut battery_event = false;
+    let mut was_off = false;
+    let mut frame_stats = FrameStats::default();
+    let mut wakeup_audit: Option<WakeupAudit> = None;
+    // what ended the last epoll wait and when, for the audit
+    let mut woke: (Vec<&'static str>, std::time::Instant) = (vec![], std::time::Instant::now());
 
-    loop {
-        if cfg_mgr.update_config(&mut cfg, &mut layers, width) {
-            active_layer = 0;
-            fn_tap_layer = 0;
-            needs_complete_redraw = true;
-            if let Some(ref n) = niri {
-                rebuild_info_layer(&mut layers, n);
-            }
-        }
+    // the app the layer was last picked for and the layer to go back to
+    // once an app without one is focused
+    let mut focused_app: Option<String> = None;
+    let mut app_return: Option<usize> = None;
+    // focused app_id for app volume sliders, followed even in presentation mode
+    let mut volume_app: Option<String> = None;
+    // whether session-helper.sh is subscribed, audio widgets are inert without it
+    let mut audio_helper = false;
+    let mut escape = escape_layer(&cfg);
+    // slots of fingers on the Esc key, kept apart from `touches`
+    let mut escape_touches: HashSet<i32> = HashSet::new();
+    // layers of LeftDock and RightDock on the bar right now
+    let mut docks: Vec<usize> = Vec::new();
 
-        if let Some(ref mut n) = niri {
-            if n.process_events() {
-                rebuild_info_layer(&mut layers, n);
-                if active_layer == 1 {
-                    needs_complete_redraw = true;
+    loop {
+        let (reload, mut old_layers) = cfg_mgr.update_config(&mut cfg, &mut layers, width, fkey_lock as usize);
+        // the F-key lock layer has an Esc key only without PersistentEscape
+        if let (true, Reload::Options) = (fkey_lock, reload) {
+            let fkeys = fkey_layer(&cfg, width);
+            if layers.last().is_some_and(|l| l.source_config != fkeys.source_config) {
+                for (_, (layer, btn)) in touches.drain() {
+                    layers[layer].buttons[btn].1.set_active(&mut uinput, false);
                 }
-            }
-        }
-
-        if layers[active_layer].displays_live
-            && last_live_poll.elapsed().as_millis() as u64 >= LIVE_POLL_MS
-        {
-            last_live_poll = std::time::Instant::now();
-            for button in &mut layers[active_layer].buttons {
-                if matches!(
-                    button.1.image,
-                    ButtonImage::Volume | ButtonImage::Brightness | ButtonImage::Wifi
-                ) {
-                    button.1.changed = true;
+                for (_, mods) in chords.drain() {
+                    toggle_keys(&mut uinput, &mods, 0);
                 }
+                drags.clear();
+                *layers.last_mut().unwrap() = fkeys;
             }
         }
-
-        let now = Local::now();
-        let ms_left = ((60 - now.second()) * 1000) as i32;
-        let mut next_timeout_ms = min(ms_left, TIMEOUT_MS);
-
-        if cfg.enable_pixel_shift {
-            let (pixel_shift_needs_redraw, pixel_shift_next_timeout_ms) = pixel_shift.update();
-            if pixel_shift_needs_redraw {
-                needs_complete_redraw = true;
+        if reload == Reload::Layers {
+            // fingers still down point into the old layers
+            for (_, (layer, btn)) in touches.drain() {
+                old_layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+            }
+            for (_, mods) in chords.drain() {
+                toggle_keys(&mut uinput, &mods, 0);
+            }
+            swipes.clear();
+            if fkey_lock {
+                layers.push(fkey_layer(&cfg, width));
+                presentation = Some(layers.len() - 1);
+            }
+            active_layer = presentation.unwrap_or(0);
+            pending_layer = None;
+            fn_tap_layer = active_layer;
+            drags.clear();
+            (focused_app, app_return, volume_app) = (None, None, None);
+            if let Some(ref c) = compositor {
+                rebuild_compositor_layers(&mut layers, c.as_ref());
+            }
+            if let Some(ref mut r) = custom_runner {
+                r.sync(custom_keys(&layers));
             }
-            next_timeout_ms = min(next_timeout_ms, pixel_shift_next_timeout_ms);
         }
-
-        let current_ts = if layers[active_layer].faster_refresh {
-            Local::now().second()
+        if reload == Reload::Layers || escape.is_some() != cfg.persistent_escape {
+            release_escape(&mut escape, &mut escape_touches, &mut uinput);
+            escape = escape_layer(&cfg);
+        }
+        if reload != Reload::Nothing {
+            drop_render_caches(&mut layers, &mut escape);
+            needs_complete_redraw = true;
+            frame_stats.cause(RedrawCause::Config);
+        }
+        docks = shown_docks(&cfg, &layers, active_layer, presentation.is_some() || diagnostics.is_some());
+
+        // nobody can not connect to another user's bus, systemd starts the
+        // daemon again as root and it connects to the new session's
+        if cfg.session_uid.is_some() && cfg.session_uid != bus_uid {
+            eprintln!("[bus] the active session changed, restarting to follow its bus");
+            std::process::exit(0);
+        }
+        if let Some(ref mut server) = ipc_server {
+            server.set_session(cfg.session_uid);
+            requests.extend(server.accept().into_iter().map(|(r, s)| (r, Origin::Socket(s))));
+        }
+        if let Some(ref svc) = control_service {
+            requests.extend(svc.process_events().into_iter().map(|(r, m)| (r, Origin::Bus(m))));
+        }
+        // Commands of buttons tapped over IPC, run on the next round
+        let mut queued = Vec::new();
+        for (req, client) in requests.drain(..) {
+            frame_stats.cause(RedrawCause::Ipc);
+            let res: ipc::Response = match req {
+                ipc::Request::DisplayOff => {
+                    backlight.blank();
+                    Ok(json!(null))
+                }
+                ipc::Request::TogglePresentation | ipc::Request::SetPresentation(_)
+                    if fkey_lock =>
+                {
+                    Err("the F-key lock is on".to_string())
+                }
+                ipc::Request::TogglePresentation | ipc::Request::SetPresentation(_) => {
+                    let on = match req {
+                        ipc::Request::SetPresentation(on) => on,
+                        _ => presentation.is_none(),
+                    };
+                    if on != presentation.is_some() {
+                        presentation = if on {
+                            let locked = cfg
+                                .presentation_layer
+                                .as_deref()
+                                .and_then(|name| layers.iter().position(|l| l.name == name))
+                                .unwrap_or(active_layer);
+                            active_layer = locked;
+                            pending_layer = None;
+                            fn_tap_layer = locked;
+                            fn_press_time = None;
+                            Some(locked)
+                        } else {
+                            None
+                        };
+                        popup = None;
+                        backlight.set_boost(on);
+                        needs_complete_redraw = true;
+                    }
+                    Ok(json!({ "Presentation": on }))
+                }
+                ipc::Request::Reset => {
+                    for (_, (layer, btn)) in touches.drain() {
+                        layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                    }
+                    for (_, mods) in chords.drain() {
+                        toggle_keys(&mut uinput, &mods, 0);
+                    }
+                    (cfg, layers) = cfg_mgr.load_config(width);
+                    if let Some(ref c) = compositor {
+                        rebuild_compositor_layers(&mut layers, c.as_ref());
+                    }
+                    if let Some(ref mut r) = custom_runner {
+                        r.sync(custom_keys(&layers));
+                    }
+                    if fkey_lock {
+                        layers.push(fkey_layer(&cfg, width));
+                        presentation = Some(layers.len() - 1);
+                    }
+                    active_layer = presentation.unwrap_or(0);
+                    pending_layer = None;
+                    fn_tap_layer = active_layer;
+                    drags.clear();
+                    (focused_app, app_return, volume_app) = (None, None, None);
+                    release_escape(&mut escape, &mut escape_touches, &mut uinput);
+                    escape = escape_layer(&cfg);
+                    popup = None;
+                    needs_complete_redraw = true;
+                    Ok(json!(null))
+                }
+                ipc::Request::ToggleFKeyLock => {
+                    // held buttons may be in the layer that is about to go
+                    for (_, (layer, btn)) in touches.drain() {
+                        layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                    }
+                    for (_, mods) in chords.drain() {
+                        toggle_keys(&mut uinput, &mods, 0);
+                    }
+                    fkey_lock = !fkey_lock;
+                    if fkey_lock {
+                        layers.push(fkey_layer(&cfg, width));
+                        presentation = Some(layers.len() - 1);
+                        // presentation mode gives way, the boost with it
+                        backlight.set_boost(false);
+                        diagnostics = None;
+                        fn_press_time = None;
+                    } else {
+                        layers.pop();
+                        presentation = None;
+                    }
+                    eprintln!("[fkeys] lock {}", if fkey_lock { "on" } else { "off" });
+                    active_layer = presentation.unwrap_or(0);
+                    pending_layer = None;
+                    fn_tap_layer = active_layer;
+                    drags.clear();
+                    popup = None;
+                    needs_complete_redraw = true;
+                    Ok(json!({ "FKeyLock": fkey_lock }))
+                }
+                ipc::Request::ToggleDiagnostics => {
+                    if diagnostics.take().is_none() {
+                        // the layer is built from the first snapshot below, before drawing
+                        diagnostics = Some(Default::default());
+                    }
+                    diag_touch = None;
+                    needs_complete_redraw = true;
+                    Ok(json!({ "Diagnostics": diagnostics.is_some() }))
+                }
+                ipc::Request::SetLayer(ref name) => {
+                    match layers.iter().position(|l| l.name == *name) {
+                        _ if presentation.is_some() => Err("presentation mode is on".to_string()),
+                        Some(idx) => {
+                            switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
+                            fn_tap_layer = idx;
+                            popup = None;
+                            needs_complete_redraw = true;
+                            Ok(json!(null))
+                        }
+                        None => Err(format!("no layer named {}", name)),
+                    }
+                }
+                ipc::Request::Buttons => Ok(json!(layers
+                    .iter()
+                    .flat_map(|l| l.buttons.iter().map(move |(_, b)| (l, b)))
+                    .filter_map(|(l, b)| {
+                        Some(json!({ "Id": b.id.as_ref()?, "Layer": l.name, "Label": b.spoken_label() }))
+                    })
+                    .collect::<Vec<_>>())),
+                ipc::Request::TapButton(ref id) => {
+                    match layers.iter().flat_map(|l| &l.buttons).find(|(_, b)| b.id.as_ref() == Some(id)) {
+                        Some((_, b)) => {
+                            match b.command {
+                                Some(ref cmd) => queued.push((cmd.clone(), Origin::Local)),
+                                None => {
+                                    toggle_keys(&mut uinput, &b.action, 1);
+                                    toggle_keys(&mut uinput, &b.action, 0);
+                                }
+                            }
+                            Ok(json!(null))
+                        }
+                        None => Err(format!("no button with Id {}", id)),
+                    }
+                }
+                ipc::Request::ToggleWindowSwitcher => {
+                    match window_switcher_target(&cfg, active_layer, switcher_from) {
+                        _ if presentation.is_some() => Err("presentation mode is on".to_string()),
+                        Some(idx) => {
+                            switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
+                            fn_tap_layer = idx;
+                            popup = None;
+                            needs_complete_redraw = true;
+                            Ok(json!(null))
+                        }
+                        None => Err("WindowSwitcher is off".to_string()),
+                    }
+                }
+                ipc::Request::SetBrightness(brightness) => {
+                    cfg.active_brightness = brightness.min(255);
+                    Ok(json!(null))
+                }
+                ipc::Request::DefaultSink(ref name) => {
+                    update_sink_toggles(&mut layers, Some(name.as_str()));
+                    Ok(json!(null))
+                }
+                ipc::Request::Volume(percent, muted) => {
+                    update_volume(&mut layers, Some((percent.min(100), muted)));
+                    Ok(json!(null))
+                }
+                ipc::Request::AppVolume(ref app, percent) => {
+                    if volume_app.as_deref() == Some(app.as_str()) {
+                        update_app_volume(&mut layers, Some(percent.min(100)));
+                    }
+                    Ok(json!(null))
+                }
+                ipc::Request::Notify(ref text, timeout_ms) => {
+                    let timeout_ms = if timeout_ms == 0 {
+                        POPUP_TIMEOUT_MS as u64
+                    } else {
+                        timeout_ms as u64
+                    };
+                    let note = notifications::Notification {
+                        summary: text.clone(),
+                        body: String::new(),
+                    };
+                    popup = Some(notification_popup(&note, timeout_ms));
+                    needs_complete_redraw = true;
+                    Ok(json!(null))
+                }
+                ipc::Request::FrameStats => Ok(frame_stats.to_json()),
+                ipc::Request::WakeupAudit(minutes) => {
+                    if let Some(audit) = wakeup_audit.take() {
+                        audit.print_summary();
+                    }
+                    if minutes > 0 {
+                        wakeup_audit = Some(WakeupAudit::new(minutes));
+                    }
+                    Ok(json!({ "WakeupAudit": minutes }))
+                }
+                ipc::Request::EventStream => {
+                    if let (Origin::Socket(stream), Some(server)) = (client, ipc_server.as_mut()) {
+                        server.subscribe(stream);
+                    }
+                    continue;
+                }
+            };
+            match client {
+                Origin::Socket(stream) => ipc::reply(stream, res),
+                Origin::Bus(call) => {
+                    if let Some(ref svc) = control_service {
+                        svc.reply(&call, res);
+                    }
+                }
+                Origin::Local => {}
+            }
+        }
+        requests.extend(queued);
+
+        let helper = ipc_server.as_ref().is_some_and(|s| s.has_subscribers());
+        if helper != audio_helper {
+            audio_helper = helper;
+            if helper {
+                // it only hears about focus changes from here on
+                if let Some(ref a) = volume_app {
+                    broadcast(&mut ipc_server, ipc::Event::FocusedApp(a.clone()));
+                }
+            } else {
+                update_volume(&mut layers, None);
+                update_app_volume(&mut layers, None);
+                update_sink_toggles(&mut layers, None);
+            }
+        }
+
+        if let Some(mut c) = compositor_waiter.as_mut().and_then(|w| w.receive()) {
+            if cfg.release_output {
+                c.release_output(drm.connector());
+            }
+            rebuild_compositor_layers(&mut layers, c.as_ref());
+            if let Some(fd) = c.event_fd() {
+                epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, 4)).unwrap();
+                epoll_sources.push(EPOLL_SOURCES[4]);
+            }
+            compositor = Some(c);
+            needs_complete_redraw = true;
+            frame_stats.cause(RedrawCause::Compositor);
+        }
+        if compositor_waiter.as_ref().is_some_and(|w| w.done()) {
+            compositor_waiter = None;
+            epoll_sources.retain(|s| *s != EPOLL_SOURCES[18]);
+        }
+        if let Some(ref mut c) = compositor {
+            if c.process_events() {
+                // a layer under a finger keeps its buttons until the next event,
+                // windows coming and going would move them away from it
+                let held: Vec<usize> = touches.values().map(|&(l, _)| l).collect();
+                for (i, layer) in layers.iter_mut().enumerate() {
+                    if layer.uses_compositor() && !held.contains(&i) {
+                        rebuild_compositor_layer(layer, c.as_ref());
+                    }
+                }
+                if on_bar(&layers, active_layer, &docks).any(|l| l.uses_compositor()) {
+                    needs_complete_redraw = true;
+                    frame_stats.cause(RedrawCause::Compositor);
+                }
+            }
+            let app = c.focused_app_id().map(|a| a.to_string());
+            if app != volume_app {
+                volume_app = app.clone();
+                update_app_volume(&mut layers, None);
+                if let Some(ref a) = volume_app {
+                    broadcast(&mut ipc_server, ipc::Event::FocusedApp(a.clone()));
+                }
+            }
+            if app != focused_app && presentation.is_none() && fn_press_time.is_none() {
+                focused_app = app;
+                let target = match app_layer(&layers, focused_app.as_deref()) {
+                    Some(idx) => {
+                        app_return.get_or_insert(fn_tap_layer);
+                        Some(idx)
+                    }
+                    None => app_return.take(),
+                };
+                if let Some(idx) = target.filter(|&idx| idx != active_layer) {
+                    switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
+                    fn_tap_layer = idx;
+                    needs_complete_redraw = true;
+                    frame_stats.cause(RedrawCause::Compositor);
+                }
+            }
+        }
+        // the compositor restarted or crashed, the waiter hands over the next
+        // one and the layers are rebuilt from it like at startup
+        if compositor.as_ref().is_some_and(|c| c.disconnected()) {
+            let c = compositor.take().unwrap();
+            if let Some(fd) = c.event_fd() {
+                let _ = epoll.delete(fd);
+            }
+            epoll_sources.retain(|s| *s != EPOLL_SOURCES[4]);
+            if let Some(ref mut w) = compositor_waiter {
+                w.reconnect();
+            }
+        }
+
+        if let Some(ref mut m) = sleep_monitor {
+            match m.process_events() {
+                Some(true) => {
+                    // dark before the delay lock goes, polling stops with the backlight
+                    backlight.set_suspended(true);
+                    backlight.update_backlight(&cfg);
+                    m.ready();
+                }
+                Some(false) => {
+                    // the panel may come back with old contents and any brightness
+                    backlight.set_suspended(false);
+                    backlight.update_backlight(&cfg);
+                    m.resumed();
+                    needs_complete_redraw = true;
+                    frame_stats.cause(RedrawCause::Config);
+                }
+                None => {}
+            }
+        }
+
+        if let Some(ref mut m) = seat_idle {
+            m.process_events();
+        }
+
+        if let Some(ref mut tz) = tz_watcher {
+            let changed = tz.process_events();
+            // every round, a reloaded Config starts without it
+            cfg.time_zone = tz.zone();
+            if changed && on_bar(&layers, active_layer, &docks).any(|l| l.displays_time) {
+                needs_complete_redraw = true;
+                frame_stats.cause(RedrawCause::Clock);
+            }
+        }
+
+        if let Some(ref mut sc) = screencast {
+            sc.process_events();
+            // applied every iteration so rebuilt layers pick the state up too
+            update_screen_recording(&mut layers, sc.is_active());
+        }
+
+        // nothing is visible while the backlight is off, catch up once it is back on
+        let bar_off = backlight.current_bl() == 0;
+        if was_off && !bar_off {
+            needs_complete_redraw = true;
+            battery_event = true;
+            frame_stats.cause(RedrawCause::Input);
+        }
+        was_off = bar_off;
+
+        if !cfg.dim_theme.is_empty() {
+            let theme = cfg.full_theme.with_contrast(backlight.theme_contrast(&cfg.dim_theme));
+            if theme != cfg.theme {
+                cfg.theme = theme;
+                drop_render_caches(&mut layers, &mut escape);
+                needs_complete_redraw = true;
+                frame_stats.cause(RedrawCause::Config);
+            }
+        }
+
+        if !bar_off
+            && on_bar(&layers, active_layer, &docks).any(|l| l.displays_live)
+            && last_live_poll.elapsed().as_millis() as u64 >= cfg.live_poll_ms as u64
+        {
+            last_live_poll = std::time::Instant::now();
+            frame_stats.cause(RedrawCause::LivePoll);
+            if let Some(ref mut p) = privacy {
+                p.poll_mic();
+            }
+            // not every daemon says when a notification arrives
+            if let Some(ref mut d) = dnd {
+                d.poll();
+            }
+            // outputs come and go without an event from every compositor
+            let shows_displays = on_bar(&layers, active_layer, &docks)
+                .flat_map(|l| &l.buttons)
+                .any(|(_, b)| matches!(b.image, ButtonImage::Displays(_)));
+            if let (true, Some(c)) = (shows_displays, compositor.as_mut()) {
+                let mode = compositor::display_mode(c.as_mut(), drm.connector());
+                update_displays(&mut layers, mode);
+            }
+            let polled = layers
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| *i == active_layer || docks.contains(i))
+                .flat_map(|(_, l)| &mut l.buttons);
+            for button in polled {
+                if matches!(button.1.image, ButtonImage::Brightness) {
+                    button.1.changed = true;
+                }
+                button.1.sandboxed(|b| b.poll_monitor());
+                if let ButtonImage::Slider { kind: SliderKind::Brightness, ref mut value } =
+                    button.1.image
+                {
+                    let current = Some(backlight.display_brightness_percent());
+                    if *value != current {
+                        *value = current;
+                        button.1.changed = true;
+                    }
+                }
+            }
+        }
+
+        if let Some(ref mut p) = privacy {
+            p.process_events();
+            update_privacy(&mut layers, p.camera_active(), p.mic_active());
+        }
+
+        if let Some(ref mut bt) = bluetooth {
+            bt.process_events();
+            update_bluetooth(&mut layers, bt);
+        }
+
+        if let Some(ref mut net) = network {
+            net.process_events();
+            update_wifi(&mut layers, net.info());
+        }
+
+        if let Some(ref mut m) = media {
+            m.process_events();
+            update_media(&mut layers, m.state());
+        }
+
+        if let Some(ref mut d) = dnd {
+            d.process_events();
+            update_dnd(&mut layers, d.state());
+        }
+
+        if let Some(ref mut r) = remapper {
+            for (key, value) in r.process_events() {
+                // they come back through the virtual device, which is not typing
+                if value == 1 && !MODIFIER_KEYS.contains(&key) {
+                    last_keystroke = Some(std::time::Instant::now());
+                }
+                toggle_keys(&mut uinput, &vec![key], value);
+            }
+        }
+
+        if let Some(ref mut r) = custom_runner {
+            r.process_events();
+            // also fills in buttons rebuilt for the compositor since the last result
+            update_custom(&mut layers, r);
+        }
+        resolve_icons(&mut layers, cfg.symbolic_icons);
+
+        let now = Local::now();
+        let ms_left = ((60 - now.second()) * 1000) as i32;
+        let mut next_timeout_ms = min(ms_left, TIMEOUT_MS);
+        if let Some(ms) = backlight.next_change_ms(&cfg) {
+            next_timeout_ms = min(next_timeout_ms, ms.min(TIMEOUT_MS as u64) as i32);
+        }
+
+        if let Some(since) = fkey_combo_since {
+            let held = since.elapsed().as_millis() as u64;
+            if held >= FKEY_LOCK_HOLD_MS {
+                fkey_combo_since = None;
+                requests.push((ipc::Request::ToggleFKeyLock, Origin::Local));
+                next_timeout_ms = 0;
+            } else {
+                next_timeout_ms = min(next_timeout_ms, (FKEY_LOCK_HOLD_MS - held) as i32);
+            }
+        }
+
+        if cfg.enable_pixel_shift && !bar_off {
+            let (pixel_shift_needs_redraw, pixel_shift_next_timeout_ms) = pixel_shift.update();
+            if pixel_shift_needs_redraw {
+                shift_changed = true;
+                frame_stats.cause(RedrawCause::PixelShift);
+            }
+            next_timeout_ms = min(next_timeout_ms, pixel_shift_next_timeout_ms);
+        }
+
+        if !bar_off && on_bar(&layers, active_layer, &docks).any(|l| l.displays_live) {
+            let left = (cfg.live_poll_ms as u128).saturating_sub(last_live_poll.elapsed().as_millis());
+            next_timeout_ms = min(next_timeout_ms, left as i32);
+        }
+
+        let seconds = on_bar(&layers, active_layer, &docks).any(|l| l.faster_refresh)
+            && !(cfg.power_aware_refresh && backlight.dimmed());
+        let current_ts = if seconds {
+            Local::now().second()
         } else {
             Local::now().minute()
         };
-        if layers[active_layer].displays_time && (current_ts != last_redraw_ts) {
+        let displays_time = on_bar(&layers, active_layer, &docks).any(|l| l.displays_time);
+        if seconds && displays_time {
+            next_timeout_ms = min(next_timeout_ms, (1000 - now.timestamp_subsec_millis().min(999)) as i32);
+        }
+        if !bar_off && displays_time && (current_ts != last_redraw_ts) {
             needs_complete_redraw = true;
             last_redraw_ts = current_ts;
+            frame_stats.cause(RedrawCause::Clock);
         }
 
-        if layers[active_layer].displays_battery {
-            for button in &mut layers[active_layer].buttons {
-                if let ButtonImage::Battery(_, _, _) = button.1.image {
-                    button.1.changed = true;
+        // uevents cover plugging in and out, capacity is not always reported that way
+        if !bar_off
+            && (battery_event || last_battery_poll.elapsed().as_millis() as u64 >= BATTERY_POLL_MS)
+        {
+            battery_event = false;
+            last_battery_poll = std::time::Instant::now();
+            if update_battery(&mut layers, |b| Some(get_battery_state(b))) {
+                frame_stats.cause(RedrawCause::Battery);
+            }
+        }
+
+        if let Some(ref mut n) = notification_monitor {
+            // a burst only shows the newest one
+            if let Some(note) = n.process_events().pop() {
+                if presentation.is_none() {
+                    popup = Some(notification_popup(&note, cfg.notification_timeout_ms as u64));
+                    needs_complete_redraw = true;
+                    frame_stats.cause(RedrawCause::Popup);
+                }
+            }
+        }
+
+        if let Some((_, until)) = popup {
+            let left = until.saturating_duration_since(std::time::Instant::now());
+            if left.is_zero() {
+                popup = None;
+                needs_complete_redraw = true;
+                frame_stats.cause(RedrawCause::Popup);
+            } else {
+                next_timeout_ms = min(next_timeout_ms, left.as_millis().min(i32::MAX as u128) as i32);
+            }
+        }
+
+        if returning.0 != active_layer {
+            returning = (active_layer, std::time::Instant::now());
+        }
+        if let (Some(ms), true) = (cfg.return_to_default_after_ms, active_layer != 0) {
+            // a layer picked for a focused app or dark on purpose is left alone
+            let idle = fingers_down == 0
+                && presentation.is_none()
+                && diagnostics.is_none()
+                && fn_press_time.is_none()
+                && app_return.is_none()
+                && layers[active_layer].off.is_none();
+            let left = (ms as u128).saturating_sub(returning.1.elapsed().as_millis());
+            if idle && left == 0 {
+                switch_layer(&mut active_layer, &mut pending_layer, false, 0);
+                fn_tap_layer = 0;
+                popup = None;
+                needs_complete_redraw = true;
+            } else if idle {
+                next_timeout_ms = min(next_timeout_ms, left as i32);
+            }
+        }
+
+        for &(layer, btn) in touches.values() {
+            let button = &mut layers[layer].buttons[btn].1;
+            let Some(started) = button.press_started else { continue };
+            let held = started.elapsed().as_millis();
+            if held >= cfg.long_press_ms as u128 {
+                button.fire_long_press(&mut uinput, &launcher);
+                if let (ButtonImage::NiriWorkspace { idx, .. }, Some(c)) = (&button.image, compositor.as_mut()) {
+                    c.move_window_to_workspace(*idx);
                 }
+                frame_stats.cause(RedrawCause::Input);
+            } else if cfg.reduce_motion {
+                next_timeout_ms = min(next_timeout_ms, (cfg.long_press_ms as u128 - held) as i32);
+            } else {
+                button.changed = true;
+                frame_stats.cause(RedrawCause::Animation);
+                next_timeout_ms = min(next_timeout_ms, LONG_PRESS_FRAME_MS);
+            }
+        }
+
+        if active_layer != announced_layer {
+            announced_layer = active_layer;
+            let name = format!("{} layer", layers[active_layer].name);
+            announce(&mut ipc_server, &cfg, name);
+        }
+
+        if let Some((ref mut shown, ref mut layer)) = diagnostics {
+            let mode = drm.mode();
+            let snapshot = diagnostics::Diagnostics {
+                touch: diag_touch,
+                fingers: fingers_down,
+                digitizer: digitizers.first().map(|d| d.name().to_string()),
+                mode: (mode.size().0, mode.size().1, mode.vrefresh()),
+                backlight: (backlight.current_bl(), backlight.max_bl()),
+                sources: epoll_sources.clone(),
+            };
+            if snapshot != *shown {
+                *layer = FunctionLayer::with_config(snapshot.to_buttons());
+                layer.draw_grid = true;
+                *shown = snapshot;
+                needs_complete_redraw = true;
             }
         }
 
-        if needs_complete_redraw || layers[active_layer].buttons.iter().any(|b| b.1.changed) {
-            let shift = if cfg.enable_pixel_shift {
-                pixel_shift.get()
+        // freshly loaded widgets pick up where the last ones left off
+        for (key, widget) in script_widgets(&layers) {
+            widget.restore(widget_store.get(key));
+        }
+
+        docks = shown_docks(&cfg, &layers, active_layer, presentation.is_some() || diagnostics.is_some());
+        let edges = place_docks(&cfg, &mut layers, &docks, escape.is_some(), width as i32);
+        (layers[active_layer].left_px, layers[active_layer].right_px) = edges;
+        for &i in docks.iter().chain([active_layer].iter()) {
+            layers[i].covered.clone_from(&cfg.do_not_cover);
+        }
+        if let Some(ref mut esc) = escape {
+            esc.covered.clone_from(&cfg.do_not_cover);
+        }
+
+        let max_scroll = layers[active_layer].max_scroll(width as i32);
+        if layers[active_layer].scroll.step(max_scroll) {
+            needs_complete_redraw = true;
+            frame_stats.cause(RedrawCause::Animation);
+            next_timeout_ms = min(next_timeout_ms, PRESS_FADE_FRAME_MS);
+        }
+
+        // the docks are never the active layer, so all of them can be held at once
+        let mut docked = Vec::new();
+        let mut active = None;
+        for (i, layer) in layers.iter_mut().enumerate() {
+            if i == active_layer {
+                active = Some(layer);
+            } else if docks.contains(&i) {
+                docked.push((i, layer));
+            }
+        }
+        let shown_layer = match (&mut diagnostics, &mut popup) {
+            (Some((_, l)), _) => l,
+            (None, Some((l, _))) => l,
+            (None, None) => active.unwrap(),
+        };
+        (shown_layer.left_px, shown_layer.right_px) = edges;
+        shown_layer.covered.clone_from(&cfg.do_not_cover);
+        if !cfg.reduce_motion {
+            let mut animating = false;
+            let dock_buttons = docked.iter_mut().flat_map(|(_, l)| l.buttons.iter_mut());
+            for (_, button) in shown_layer.buttons.iter_mut().chain(dock_buttons) {
+                if button.is_animated() {
+                    button.changed = true;
+                    animating = true;
+                }
+            }
+            if animating {
+                frame_stats.cause(RedrawCause::Animation);
+                next_timeout_ms = min(next_timeout_ms, ANIMATION_FRAME_MS);
+            }
+        }
+        let mut fading = false;
+        let escape_buttons = escape.iter_mut().flat_map(|e| e.buttons.iter_mut());
+        let dock_buttons = docked.iter_mut().flat_map(|(_, l)| l.buttons.iter_mut());
+        for (_, button) in shown_layer.buttons.iter_mut().chain(escape_buttons).chain(dock_buttons) {
+            if button.fading() {
+                button.changed = true;
+                fading = true;
+            }
+            if let ButtonImage::Script(ref widget) = button.image {
+                let left = widget.redraw_in_ms();
+                if left == 0 {
+                    button.changed = true;
+                    frame_stats.cause(RedrawCause::Widget);
+                }
+                let wait = if left == 0 { widget.interval_ms as u128 } else { left };
+                next_timeout_ms = min(next_timeout_ms, wait.min(i32::MAX as u128) as i32);
+            }
+        }
+        if fading {
+            frame_stats.cause(RedrawCause::Animation);
+            next_timeout_ms = min(next_timeout_ms, PRESS_FADE_FRAME_MS);
+        }
+        let content_changed = needs_complete_redraw
+            || shown_layer.buttons.iter().any(|b| b.1.changed)
+            || docked.iter().any(|(_, l)| l.buttons.iter().any(|b| b.1.changed))
+            || escape.iter().any(|e| e.buttons.iter().any(|b| b.1.changed));
+        if !bar_off && !drm_lost && (content_changed || shift_changed) {
+            let render_start = std::time::Instant::now();
+            let mut clips = if content_changed {
+                // each layer clears the bar from its left edge on, drawn left
+                // to right every one keeps its own part
+                let (left, right): (Vec<_>, Vec<_>) =
+                    docked.iter_mut().partition(|(i, _)| cfg.left_dock.is_some_and(|(d, _)| d == *i));
+                let mut clips = Vec::new();
+                for (_, dock) in left {
+                    clips.extend(draw_layer(dock, &cfg, width as i32, height as i32, &content, needs_complete_redraw));
+                }
+                clips.extend(draw_layer(shown_layer, &cfg, width as i32, height as i32, &content, needs_complete_redraw));
+                for (_, dock) in right {
+                    clips.extend(draw_layer(dock, &cfg, width as i32, height as i32, &content, needs_complete_redraw));
+                }
+                if let Some(ref mut esc) = escape {
+                    clips.extend(draw_layer(esc, &cfg, ESCAPE_WIDTH_PX, height as i32, &content, needs_complete_redraw));
+                }
+                clips
             } else {
-                (0.0, 0.0)
+                Vec::new()
             };
-            let clips = layers[active_layer].draw(
-                &cfg,
-                width as i32,
-                height as i32,
-                &surface,
-                shift,
-                needs_complete_redraw,
-            );
-            let data = surface.data().unwrap();
-            drm.map().unwrap().as_mut()[..data.len()].copy_from_slice(&data);
-            drm.dirty(&clips).unwrap();
+            let out = if cfg.enable_pixel_shift {
+                let shift = pixel_shift.get();
+                if let Err(e) = compose_shifted(&surface, &content, shift, &cfg) {
+                    eprintln!("[render] pixel shift failed: {}", e);
+                }
+                clips = if shift_changed || needs_complete_redraw {
+                    vec![ClipRect::new(0, 0, height, width)]
+                } else {
+                    clips.iter().map(|r| shift_clip(r, shift, height, width)).collect()
+                };
+                &mut surface
+            } else {
+                &mut content
+            };
+            if let Err(e) = present(&mut drm, out, &clips, &cfg) {
+                eprintln!("[render] failed to present a frame: {}", e);
+            }
+            for m in &mut mirrors {
+                if let Err(e) = present(m, out, &clips, &cfg) {
+                    eprintln!("[render] failed to present a frame on {}: {}", m.path().display(), e);
+                }
+            }
+            frame_stats.frame(render_start.elapsed(), &clips, needs_complete_redraw);
             needs_complete_redraw = false;
+            shift_changed = false;
+        } else {
+            frame_stats.discard();
+        }
+        for (key, widget) in script_widgets(&layers) {
+            if let Some(memory) = widget.take_memory() {
+                widget_store.set(key.to_string(), memory);
+            }
         }
+        widget_store.save_if_due();
 
-        match epoll.wait(
-            &mut [EpollEvent::new(EpollFlags::EPOLLIN, 0)],
-            next_timeout_ms as u16,
-        ) {
-            Err(Errno::EINTR) | Ok(_) => 0,
+        if let Some(ref mut audit) = wakeup_audit {
+            audit.record(&woke.0, woke.1.elapsed());
+            if audit.expired() {
+                audit.print_summary();
+                wakeup_audit = None;
+            }
+        }
+
+        let mut events = [EpollEvent::empty(); EPOLL_SOURCES.len()];
+        // only input can turn the bar back on, no timer needs to run until then
+        // unless FKeyLockHotkey is being held
+        let timeout = if bar_off && fkey_combo_since.is_none() {
+            EpollTimeout::NONE
+        } else {
+            EpollTimeout::from(next_timeout_ms as u16)
+        };
+        let ready = match epoll.wait(&mut events, timeout) {
+            Ok(n) => n,
+            Err(Errno::EINTR) => 0,
             e => e.unwrap(),
         };
+        frame_stats.wakeup();
+        woke.0 = events[..ready]
+            .iter()
+            .map(|e| EPOLL_SOURCES[e.data() as usize])
+            .collect();
+        if woke.0.is_empty() {
+            woke.0.push("timeout");
+        }
+        woke.1 = std::time::Instant::now();
 
-        _ = udev_monitor.iter().last();
+        // the digitizer needs no udev handling here, libinput sees input
+        // devices come and go on its own and reports them as device events
+        for event in udev_monitor.iter() {
+            match event.subsystem().and_then(|s| s.to_str()) {
+                Some("power_supply") => {
+                    let name = event.sysname().to_string_lossy().into_owned();
+                    let state = if event.event_type() == EventType::Remove {
+                        (0, BatteryState::Absent)
+                    } else {
+                        battery_state_from(|attr| {
+                            let key = format!("POWER_SUPPLY_{}", attr.to_uppercase());
+                            event.property_value(key).map(|v| v.to_string_lossy().into_owned())
+                        })
+                    };
+                    let watched = layers.iter().flat_map(|l| &l.buttons).any(|(_, b)| {
+                        matches!(b.image, ButtonImage::Battery(ref battery, ..) if *battery == name)
+                    });
+                    if !watched {
+                        // a charger, the batteries may follow without a uevent of their own
+                        battery_event = true;
+                    } else if update_battery(&mut layers, |b| (b == name).then_some(state)) {
+                        frame_stats.cause(RedrawCause::Battery);
+                    }
+                }
+                Some("drm") if event.devnode().is_some() => {
+                    // mirrors are not picked up again, a restart does that
+                    if event.event_type() == EventType::Remove {
+                        mirrors.retain(|m| {
+                            let gone = event.devnode() == Some(m.path());
+                            if gone {
+                                eprintln!("[drm] {} went away", m.path().display());
+                            }
+                            !gone
+                        });
+                    }
+                    if event.event_type() == EventType::Remove && event.devnode() == Some(drm.path()) {
+                        eprintln!("[drm] {} went away, waiting for it to come back", drm.path().display());
+                        drm_lost = true;
+                    } else if drm_lost && event.event_type() != EventType::Remove {
+                        drm_lost = !reopen_drm(drm, height, width);
+                        needs_complete_redraw = true;
+                    }
+                }
+                _ => {}
+            }
+        }
 
-        input_tb.dispatch().unwrap();
-        input_main.dispatch().unwrap();
+        for input in [&mut input_tb, &mut input_main] {
+            if let Err(e) = input.dispatch() {
+                eprintln!("[input] dispatch failed: {}", e);
+            }
+        }
         for event in &mut input_tb.clone().chain(input_main.clone()) {
             backlight.process_event(&event);
             match event {
                 Event::Device(DeviceEvent::Added(evt)) => {
                     let dev = evt.device();
                     if dev.name().contains(" Touch Bar") {
-                        digitizer = Some(dev);
+                        digitizers.push(dev);
                     }
                 }
+                Event::Device(DeviceEvent::Removed(evt)) => {
+                    let dev = evt.device();
+                    if !digitizers.contains(&dev) {
+                        continue;
+                    }
+                    eprintln!("[input] touch bar digitizer went away");
+                    // the fingers on it will never lift, let go of what they hold,
+                    // which ones were on it is not known
+                    for (_, (layer, btn)) in touches.drain() {
+                        layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                    }
+                    for (_, mods) in chords.drain() {
+                        toggle_keys(&mut uinput, &mods, 0);
+                    }
+                    release_escape(&mut escape, &mut escape_touches, &mut uinput);
+                    two_finger_tap = None;
+                    drags.clear();
+                    fingers_down = 0;
+                    digitizers.retain(|d| *d != dev);
+                }
                 Event::Keyboard(KeyboardEvent::Key(key)) => {
                     if key.key() == Key::Fn as u32 {
+                        frame_stats.cause(RedrawCause::Input);
+                    }
+                    match key.key_state() {
+                        KeyState::Pressed => {
+                            held_keys.insert(key.key());
+                            let typed = !MODIFIER_KEYS.iter().any(|k| *k as u32 == key.key());
+                            if typed && key.device().name() != VIRTUAL_DEVICE_NAME {
+                                last_keystroke = Some(std::time::Instant::now());
+                            }
+                            let flashed = FLASH_KEYS.iter().find(|k| **k as u32 == key.key());
+                            if let (true, Some(&k)) = (cfg.flash_on_keypress, flashed) {
+                                let now = std::time::Instant::now();
+                                for &i in docks.iter().chain([active_layer].iter()) {
+                                    for (_, button) in &mut layers[i].buttons {
+                                        if button.shows_key(k) {
+                                            button.flash = Some(now);
+                                            button.changed = true;
+                                        }
+                                    }
+                                }
+                            }
+                            if key.key() != Key::Fn as u32 && fn_press_time.is_some() {
+                                fn_used = true;
+                            }
+                            let combo = &cfg.reset_hotkey;
+                            if combo.iter().any(|k| *k as u32 == key.key())
+                                && combo.iter().all(|k| held_keys.contains(&(*k as u32)))
+                            {
+                                requests.push((ipc::Request::Reset, Origin::Local));
+                            }
+                            let combo = &cfg.fkey_lock_hotkey;
+                            if combo.iter().any(|k| *k as u32 == key.key())
+                                && combo.iter().all(|k| held_keys.contains(&(*k as u32)))
+                            {
+                                fkey_combo_since = Some(std::time::Instant::now());
+                            }
+                        }
+                        KeyState::Released => {
+                            held_keys.remove(&key.key());
+                            if cfg.fkey_lock_hotkey.iter().any(|k| *k as u32 == key.key()) {
+                                fkey_combo_since = None;
+                            }
+                        }
+                    }
+                    if key.key() == Key::Fn as u32 && presentation.is_none() {
                         match key.key_state() {
                             KeyState::Pressed => {
                                 fn_press_time = Some(std::time::Instant::now());
+                                fn_used = false;
+                                popup = None;
                                 if layers.len() > 1 {
-                                    active_layer = layers.len() - 1;
+                                    let idx = layers
+                                        .iter()
+                                        .position(|l| l.fn_hold)
+                                        .unwrap_or(layers.len() - 1);
+                                    switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
                                     needs_complete_redraw = true;
                                 }
                             }
@@ -1280,77 +4816,502 @@ fn real_main(drm: &mut DrmBackend) {
                                 let was_tap = fn_press_time
                                     .take()
                                     .map(|t| t.elapsed().as_millis() < FN_TAP_THRESHOLD_MS)
-                                    .unwrap_or(false);
+                                    .unwrap_or(false)
+                                    && !fn_used;
                                 if was_tap {
-                                    fn_tap_layer = (fn_tap_layer + 1) % layers.len();
-                                    active_layer = fn_tap_layer;
-                                } else {
-                                    active_layer = fn_tap_layer;
+                                    fn_tap_layer = next_layer(&cfg.layer_cycle, fn_tap_layer, true);
                                 }
+                                switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), fn_tap_layer);
                                 needs_complete_redraw = true;
                             }
                         }
                     }
                 }
                 Event::Touch(te) => {
-                    if Some(te.device()) != digitizer || backlight.current_bl() == 0 {
+                    if !digitizers.contains(&te.device()) {
+                        continue;
+                    }
+                    // the end of a batch of slot events, each was handled as it came
+                    if matches!(te, TouchEvent::Frame(_)) {
                         continue;
                     }
+                    frame_stats.cause(RedrawCause::Input);
+                    returning.1 = std::time::Instant::now();
+                    if fn_press_time.is_some() && matches!(te, TouchEvent::Down(_)) {
+                        fn_used = true;
+                    }
                     match te {
-                        TouchEvent::Down(dn) => {
-                            let x = dn.x_transformed(width as u32);
-                            let y = dn.y_transformed(height as u32);
-                            if let Some(btn) =
-                                layers[active_layer].hit(width, height, x, y, None)
-                            {
-                                touches.insert(dn.seat_slot() as i32, (active_layer, btn));
-                                let is_niri_ws = matches!(
-                                    layers[active_layer].buttons[btn].1.image,
-                                    ButtonImage::NiriWorkspace { .. }
-                                );
-                                if is_niri_ws {
-                                    if let Some(ref mut n) = niri {
-                                        if let Some(&(_, ws_idx)) = layers[active_layer]
-                                            .niri_workspace_ids
-                                            .iter()
-                                            .find(|&&(bi, _)| bi == btn)
+                        TouchEvent::Down(_) => fingers_down += 1,
+                        TouchEvent::Up(_) | TouchEvent::Cancel(_) => {
+                            fingers_down = fingers_down.saturating_sub(1)
+                        }
+                        _ => {}
+                    }
+                    // libinput gave up on the sequence (palm detection, the
+                    // device going away), let go of whatever the finger held
+                    // but do not count it as a tap, even with the bar off
+                    if let TouchEvent::Cancel(ref cancel) = te {
+                        let slot = cancel.seat_slot() as i32;
+                        if let (true, Some(esc)) = (escape_touches.remove(&slot), &mut escape) {
+                            esc.buttons[0].1.set_active(&mut uinput, false);
+                        }
+                        if let Some((ref mut slots, _)) = two_finger_tap {
+                            if slots.remove(&slot) && slots.is_empty() {
+                                two_finger_tap = None;
+                            }
+                        }
+                        swipes.remove(&slot);
+                        drags.remove(&slot);
+                        // unlike Up no finish_tap, long presses and lone chord
+                        // modifiers send nothing
+                        if let Some((layer, btn)) = touches.remove(&slot) {
+                            layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                        }
+                        if let Some(mods) = chords.remove(&slot) {
+                            toggle_keys(&mut uinput, &mods, 0);
+                        }
+                        continue;
+                    }
+                    if backlight.current_bl() == 0 {
+                        continue;
+                    }
+                    if diagnostics.is_some() {
+                        match te {
+                            TouchEvent::Down(ref dn) => {
+                                diag_touch = Some((dn.x_transformed(width as u32), dn.y_transformed(height as u32)));
+                            }
+                            TouchEvent::Motion(ref mtn) => {
+                                diag_touch = Some((mtn.x_transformed(width as u32), mtn.y_transformed(height as u32)));
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    if cfg.display_off_gesture
+                        && presentation.is_none()
+                        && fingers_down == 3
+                        && matches!(te, TouchEvent::Down(_))
+                    {
+                        let is_double = last_three_finger_tap
+                            .is_some_and(|t| t.elapsed().as_millis() < DOUBLE_TAP_MS);
+                        if is_double {
+                            last_three_finger_tap = None;
+                            for (_, (layer, btn)) in touches.drain() {
+                                layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                            }
+                            for (_, mods) in chords.drain() {
+                                toggle_keys(&mut uinput, &mods, 0);
+                            }
+                            release_escape(&mut escape, &mut escape_touches, &mut uinput);
+                            backlight.blank();
+                            continue;
+                        }
+                        last_three_finger_tap = Some(std::time::Instant::now());
+                    }
+                    let (slot, x, y) = match te {
+                        TouchEvent::Down(ref dn) => (
+                            dn.seat_slot() as i32,
+                            dn.x_transformed(width as u32),
+                            dn.y_transformed(height as u32),
+                        ),
+                        TouchEvent::Motion(ref mtn) => (
+                            mtn.seat_slot() as i32,
+                            mtn.x_transformed(width as u32),
+                            mtn.y_transformed(height as u32),
+                        ),
+                        TouchEvent::Up(ref up) => (up.seat_slot() as i32, 0.0, 0.0),
+                        _ => continue,
+                    };
+                    let phase = match te {
+                        TouchEvent::Down(_) => TouchPhase::Down,
+                        TouchEvent::Motion(_) => TouchPhase::Motion,
+                        _ => TouchPhase::Up,
+                    };
+                    // a tap held back on a scrolling layer is played again
+                    // as a Down and Up where the finger landed
+                    let mut phases = vec![(phase, x, y)];
+                    let mut replay = false;
+                    let mut next = 0;
+                    while next < phases.len() {
+                        let (phase, x, y) = phases[next];
+                        next += 1;
+                        match phase {
+                            TouchPhase::Down => {
+                                if let Some(ref mut esc) = escape {
+                                    if esc.hit(ESCAPE_WIDTH_PX as u16, height, x, y, None).is_some() {
+                                        if typing_blocks(&cfg, &layers[active_layer], last_keystroke) {
+                                            continue;
+                                        }
+                                        escape_touches.insert(slot);
+                                        let button = &mut esc.buttons[0].1;
+                                        button.set_active(&mut uinput, true);
+                                        play_sound(&mut ipc_server, &cfg, button, "button-pressed");
+                                        haptic_click(&mut haptics, &cfg, true);
+                                        continue;
+                                    }
+                                }
+                                if popup.take().is_some() {
+                                    needs_complete_redraw = true;
+                                    continue;
+                                }
+                                // over whatever layer is up, the rest of the touch is ignored
+                                if let (None, Some(action)) =
+                                    (presentation, hot_corner(&cfg, width, x))
+                                {
+                                    haptic_click(&mut haptics, &cfg, true);
+                                    if let Some(idx) = run_gesture(&action, &cfg, (active_layer, switcher_from), &mut compositor, &media, &mut uinput) {
+                                        switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
+                                        fn_tap_layer = idx;
+                                        needs_complete_redraw = true;
+                                    }
+                                    continue;
+                                }
+                                // no buttons and no gestures, only Fn gets away from it
+                                if layers[active_layer].off.is_some() {
+                                    continue;
+                                }
+                                // pressed on a dock the rest is the same, only in its layer
+                                let target = docks
+                                    .iter()
+                                    .copied()
+                                    .find(|&i| layers[i].hit(width, height, x, y, None).is_some())
+                                    .unwrap_or(active_layer);
+                                // most likely a palm, the rest of the touch is ignored
+                                if typing_blocks(&cfg, &layers[target], last_keystroke) {
+                                    continue;
+                                }
+                                // a drag or a tap, which one is known once it moves or lifts
+                                if !replay && target == active_layer && layers[active_layer].scrolls(width) {
+                                    layers[active_layer].scroll.grab();
+                                    drags.insert(slot, Drag { start: (x, y), last_x: x, scrolling: false, layer: active_layer });
+                                    continue;
+                                }
+                                if presentation.is_none() {
+                                    swipes.insert(slot, (x, std::time::Instant::now()));
+                                }
+                                let other = swipes
+                                    .iter()
+                                    .find(|&(&s, &(_, t))| s != slot && t.elapsed().as_millis() < TWO_FINGER_WINDOW_MS)
+                                    .map(|(&s, _)| s);
+                                if let (true, 2, Some(other)) =
+                                    (cfg.two_finger_tap_action.is_some(), fingers_down, other)
+                                {
+                                    // the first finger's button is let go, the second never pressed
+                                    if let Some((layer, btn)) = touches.remove(&other) {
+                                        layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                                    }
+                                    if let Some(mods) = chords.remove(&other) {
+                                        toggle_keys(&mut uinput, &mods, 0);
+                                    }
+                                    swipes.remove(&other);
+                                    swipes.remove(&slot);
+                                    two_finger_tap = Some(([other, slot].into(), std::time::Instant::now()));
+                                    continue;
+                                }
+                                if let Some(btn) =
+                                    layers[target].hit(width, height, x, y, None)
+                                {
+                                    let button = &layers[target].buttons[btn].1;
+                                    if let Some(label) = button.spoken_label() {
+                                        announce(&mut ipc_server, &cfg, label);
+                                    }
+                                    play_sound(&mut ipc_server, &cfg, button, "button-pressed");
+                                    haptic_click(&mut haptics, &cfg, true);
+                                    if let Some(ref cmd) = button.command {
+                                        // leaving presentation mode is the one thing still allowed
+                                        if presentation.is_none()
+                                            || *cmd == ipc::Request::TogglePresentation
                                         {
-                                            n.focus_workspace(ws_idx);
+                                            requests.push((cmd.clone(), Origin::Local));
                                         }
+                                        continue;
+                                    }
+                                    if presentation.is_some() && button.has_tap_action() {
+                                        continue;
+                                    }
+                                    if let ButtonImage::Battery(ref battery, _, _, _) =
+                                        layers[target].buttons[btn].1.image
+                                    {
+                                        let stats = power::PowerStats::read(battery);
+                                        popup = Some((
+                                            FunctionLayer::with_config(stats.to_buttons()),
+                                            std::time::Instant::now()
+                                                + Duration::from_millis(POPUP_TIMEOUT_MS as u64),
+                                        ));
+                                        needs_complete_redraw = true;
+                                        continue;
+                                    }
+                                    if let ButtonImage::Time(_, locale) =
+                                        layers[target].buttons[btn].1.image
+                                    {
+                                        popup = Some(calendar_popup(locale));
+                                        needs_complete_redraw = true;
+                                        frame_stats.cause(RedrawCause::Popup);
+                                        continue;
+                                    }
+                                    // pages the strip, nothing is held as the buttons are rebuilt
+                                    if let ButtonImage::WorkspaceOverflow { forward, .. } =
+                                        layers[target].buttons[btn].1.image
+                                    {
+                                        let layer = &mut layers[target];
+                                        let page = layer.niri_workspace_ids.len().max(1);
+                                        layer.workspace_first = if forward {
+                                            layer.workspace_first + page
+                                        } else {
+                                            layer.workspace_first.saturating_sub(page)
+                                        };
+                                        if let Some(ref c) = compositor {
+                                            rebuild_compositor_layer(layer, c.as_ref());
+                                        }
+                                        needs_complete_redraw = true;
+                                        continue;
+                                    }
+                                    touches.insert(slot, (target, btn));
+                                    let is_niri_ws = matches!(
+                                        layers[target].buttons[btn].1.image,
+                                        ButtonImage::NiriWorkspace { .. }
+                                    );
+                                    let bt_mac = match layers[target].buttons[btn].1.image {
+                                        ButtonImage::Bluetooth { ref mac, .. } => Some(mac.clone()),
+                                        _ => None,
+                                    };
+                                    let is_media = matches!(
+                                        layers[target].buttons[btn].1.image,
+                                        ButtonImage::Media(_)
+                                    );
+                                    let is_displays = matches!(
+                                        layers[target].buttons[btn].1.image,
+                                        ButtonImage::Displays(_)
+                                    );
+                                    let is_dnd = matches!(
+                                        layers[target].buttons[btn].1.image,
+                                        ButtonImage::DoNotDisturb(_)
+                                    );
+                                    let sink = match layers[target].buttons[btn].1.image {
+                                        ButtonImage::SinkToggle { ref sink, on: Some(_), .. } => Some(sink.clone()),
+                                        _ => None,
+                                    };
+                                    let is_slider = matches!(
+                                        layers[target].buttons[btn].1.image,
+                                        ButtonImage::Slider { .. }
+                                    );
+                                    if is_slider {
+                                        let (left, w) = layers[target].button_span(width, btn);
+                                        let button = &mut layers[target].buttons[btn].1;
+                                        if let Some((kind, percent)) = button.set_slider((x - left) / w) {
+                                            let app = volume_app.as_deref();
+                                            apply_slider(kind, percent, &mut backlight, &mut ipc_server, app);
+                                        }
+                                        button.set_active(&mut uinput, true);
+                                    } else if is_media {
+                                        if let Some(ref m) = media {
+                                            m.play_pause();
+                                        }
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
+                                    } else if is_dnd {
+                                        if let Some(ref mut d) = dnd {
+                                            d.toggle();
+                                            update_dnd(&mut layers, d.state());
+                                        }
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
+                                    } else if is_displays {
+                                        if let Some(ref mut c) = compositor {
+                                            let mode = compositor::cycle_displays(c.as_mut(), drm.connector());
+                                            update_displays(&mut layers, mode);
+                                        }
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
+                                    } else if let Some(sink) = sink {
+                                        // switched by session-helper.sh, which reports the new default back
+                                        broadcast(&mut ipc_server, ipc::Event::ToggleSink(sink));
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
+                                    } else if let Some(mac) = bt_mac {
+                                        if let Some(ref mut bt) = bluetooth {
+                                            bt.toggle(&mac);
+                                        }
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
+                                    } else if is_niri_ws {
+                                        // focused on release, unless held long enough to move the window
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
+                                    } else {
+                                        let mods = chord_modifiers(&mut layers, &touches, slot);
+                                        if !mods.is_empty() {
+                                            toggle_keys(&mut uinput, &mods, 1);
+                                            chords.insert(slot, mods);
+                                        }
+                                        layers[target].buttons[btn]
+                                            .1
+                                            .set_active(&mut uinput, true);
                                     }
-                                } else {
-                                    layers[active_layer].buttons[btn]
-                                        .1
-                                        .set_active(&mut uinput, true);
                                 }
                             }
-                        }
-                        TouchEvent::Motion(mtn) => {
-                            if !touches.contains_key(&(mtn.seat_slot() as i32)) {
-                                continue;
+                            TouchPhase::Motion => {
+                                if let (true, Some(esc)) = (escape_touches.contains(&slot), &mut escape) {
+                                    let hit = esc.hit(ESCAPE_WIDTH_PX as u16, height, x, y, Some(0)).is_some();
+                                    esc.buttons[0].1.set_active(&mut uinput, hit);
+                                    continue;
+                                }
+                                if let Some(drag) = drags.get_mut(&slot) {
+                                    let layer = &mut layers[drag.layer];
+                                    if !drag.scrolling && (x - drag.start.0).abs() >= SCROLL_SLOP_PX {
+                                        drag.scrolling = true;
+                                    }
+                                    if drag.scrolling {
+                                        let max = layer.max_scroll(width as i32);
+                                        layer.scroll.drag(x - drag.last_x, max);
+                                        needs_complete_redraw = true;
+                                    }
+                                    drag.last_x = x;
+                                    continue;
+                                }
+                                if two_finger_tap.as_ref().is_some_and(|(slots, _)| slots.contains(&slot)) {
+                                    continue;
+                                }
+                                if let Some(&(start_x, started)) = swipes.get(&slot) {
+                                    let dx = x - start_x;
+                                    let ms = started.elapsed().as_millis().max(1) as f64;
+                                    let action = if dx < 0.0 {
+                                        cfg.swipe_left_action.clone()
+                                    } else {
+                                        cfg.swipe_right_action.clone()
+                                    };
+                                    if dx.abs() >= SWIPE_MIN_DISTANCE_PX && dx.abs() / ms >= SWIPE_MIN_SPEED {
+                                        swipes.remove(&slot);
+                                        if let Some(action) = action {
+                                            if let Some((layer, btn)) = touches.remove(&slot) {
+                                                layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                                            }
+                                            if let Some(mods) = chords.remove(&slot) {
+                                                toggle_keys(&mut uinput, &mods, 0);
+                                            }
+                                            if let Some(idx) = run_gesture(&action, &cfg, (active_layer, switcher_from), &mut compositor, &media, &mut uinput) {
+                                                switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
+                                                fn_tap_layer = idx;
+                                                needs_complete_redraw = true;
+                                            }
+                                            continue;
+                                        }
+                                    }
+                                }
+                                if !touches.contains_key(&slot) {
+                                    continue;
+                                }
+                                let (layer, btn) = *touches.get(&slot).unwrap();
+                                // sliders keep following the finger, past their ends too
+                                if matches!(layers[layer].buttons[btn].1.image, ButtonImage::Slider { .. }) {
+                                    let (left, w) = layers[layer].button_span(width, btn);
+                                    if let Some((kind, percent)) =
+                                        layers[layer].buttons[btn].1.set_slider((x - left) / w)
+                                    {
+                                        let app = volume_app.as_deref();
+                                        apply_slider(kind, percent, &mut backlight, &mut ipc_server, app);
+                                    }
+                                    continue;
+                                }
+                                let hit = layers[layer]
+                                    .hit(width, height, x, y, Some(btn))
+                                    .is_some();
+                                layers[layer].buttons[btn].1.set_active(&mut uinput, hit);
                             }
-                            let x = mtn.x_transformed(width as u32);
-                            let y = mtn.y_transformed(height as u32);
-                            let (layer, btn) = *touches.get(&(mtn.seat_slot() as i32)).unwrap();
-                            let hit = layers[active_layer]
-                                .hit(width, height, x, y, Some(btn))
-                                .is_some();
-                            layers[layer].buttons[btn].1.set_active(&mut uinput, hit);
-                        }
-                        TouchEvent::Up(up) => {
-                            if !touches.contains_key(&(up.seat_slot() as i32)) {
-                                continue;
+                            TouchPhase::Up => {
+                                if let (true, Some(esc)) = (escape_touches.remove(&slot), &mut escape) {
+                                    let button = &mut esc.buttons[0].1;
+                                    button.set_active(&mut uinput, false);
+                                    play_sound(&mut ipc_server, &cfg, button, "button-released");
+                                    haptic_click(&mut haptics, &cfg, false);
+                                    continue;
+                                }
+                                if let Some(drag) = drags.remove(&slot) {
+                                    if drag.scrolling {
+                                        layers[drag.layer].scroll.release(!cfg.reduce_motion);
+                                    } else if drag.layer == active_layer {
+                                        let (x, y) = drag.start;
+                                        phases.push((TouchPhase::Down, x, y));
+                                        phases.push((TouchPhase::Up, x, y));
+                                        replay = true;
+                                    }
+                                    continue;
+                                }
+                                if let Some((ref mut slots, started)) = two_finger_tap {
+                                    if slots.remove(&slot) {
+                                        if slots.is_empty() {
+                                            two_finger_tap = None;
+                                            let action = cfg.two_finger_tap_action.clone();
+                                            if let (true, Some(action)) =
+                                                (started.elapsed().as_millis() < TWO_FINGER_TAP_MS, action)
+                                            {
+                                                if let Some(idx) = run_gesture(&action, &cfg, (active_layer, switcher_from), &mut compositor, &media, &mut uinput) {
+                                                    switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), idx);
+                                                    fn_tap_layer = idx;
+                                                    needs_complete_redraw = true;
+                                                }
+                                            }
+                                        }
+                                        continue;
+                                    }
+                                }
+                                swipes.remove(&slot);
+                                if !touches.contains_key(&slot) {
+                                    continue;
+                                }
+                                let (layer, btn) = *touches.get(&slot).unwrap();
+                                let button = &layers[layer].buttons[btn].1;
+                                if let (ButtonImage::NiriWorkspace { idx, .. }, Some(_), Some(c)) =
+                                    (&button.image, button.press_started, compositor.as_mut())
+                                {
+                                    c.focus_workspace(*idx);
+                                }
+                                // a picked window closes the switcher
+                                let mut picked = false;
+                                if let (ButtonImage::NiriWindow { id, .. }, true, Some(c)) =
+                                    (&button.image, button.active, compositor.as_mut())
+                                {
+                                    c.focus_window(*id);
+                                    picked = cfg.window_switcher == Some(layer);
+                                }
+                                layers[layer].buttons[btn].1.finish_tap(&mut uinput);
+                                layers[layer].buttons[btn].1.set_active(&mut uinput, false);
+                                if let Some(mods) = chords.remove(&slot) {
+                                    toggle_keys(&mut uinput, &mods, 0);
+                                }
+                                play_sound(&mut ipc_server, &cfg, &layers[layer].buttons[btn].1, "button-released");
+                                haptic_click(&mut haptics, &cfg, false);
+                                touches.remove(&slot);
+                                if picked {
+                                    switch_layer(&mut active_layer, &mut pending_layer, !touches.is_empty(), switcher_from);
+                                    fn_tap_layer = switcher_from;
+                                    needs_complete_redraw = true;
+                                }
                             }
-                            let (layer, btn) = *touches.get(&(up.seat_slot() as i32)).unwrap();
-                            layers[layer].buttons[btn].1.set_active(&mut uinput, false);
-                            touches.remove(&(up.seat_slot() as i32));
                         }
-                        _ => {}
                     }
                 }
                 _ => {}
             }
         }
+        if let (true, Some(idx)) = (touches.is_empty(), pending_layer.take()) {
+            active_layer = idx;
+            needs_complete_redraw = true;
+        }
+        if cfg.window_switcher != Some(active_layer) {
+            switcher_from = active_layer;
+        }
+        backlight.set_layer_off(layers[active_layer].off == Some(OffLayer::Dark));
+        backlight.set_seat_idle(match seat_idle {
+            Some(ref m) if cfg.follow_seat_idle => Some(m.idle_since()),
+            _ => None,
+        });
         backlight.update_backlight(&cfg);
     }
 }
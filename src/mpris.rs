@@ -0,0 +1,159 @@
+use crate::bus;
+use dbus::{
+    arg::{PropMap, RefArg},
+    blocking::{stdintf::org_freedesktop_dbus::Properties, Connection},
+    Message,
+};
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+const MPRIS_PATH: &str = "/org/mpris/MediaPlayer2";
+const PLAYER_IFACE: &str = "org.mpris.MediaPlayer2.Player";
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct MediaState {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub playing: bool,
+}
+
+pub struct MprisMonitor {
+    conn: Connection,
+    // well-known name of the player shown on the bar
+    player: Option<String>,
+    state: Option<MediaState>,
+}
+
+impl MprisMonitor {
+    // must be called before privilege drop
+    pub fn connect() -> Option<MprisMonitor> {
+        let conn = match bus::open_session() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[mpris] session bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let rules = [
+            format!(
+                "type='signal',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged',path='{}',arg0='{}'",
+                MPRIS_PATH, PLAYER_IFACE
+            ),
+            "type='signal',sender='org.freedesktop.DBus',member='NameOwnerChanged',arg0namespace='org.mpris.MediaPlayer2'".to_string(),
+        ];
+        for rule in &rules {
+            if let Err(e) = conn.add_match_no_cb(rule) {
+                eprintln!("[mpris] failed to subscribe: {}", e);
+                return None;
+            }
+        }
+        let mut monitor = MprisMonitor {
+            conn,
+            player: None,
+            state: None,
+        };
+        monitor.refresh();
+        Some(monitor)
+    }
+
+    pub fn state(&self) -> Option<&MediaState> {
+        self.state.as_ref()
+    }
+
+    fn players(&self) -> Vec<String> {
+        let proxy = self.conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", bus::CALL_TIMEOUT);
+        proxy
+            .method_call("org.freedesktop.DBus", "ListNames", ())
+            .map(|(names,): (Vec<String>,)| names)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|n| n.starts_with(MPRIS_PREFIX))
+            .collect()
+    }
+
+    fn query(&self, player: &str) -> MediaState {
+        let proxy = self.conn.with_proxy(player, MPRIS_PATH, bus::CALL_TIMEOUT);
+        let status: String = proxy.get(PLAYER_IFACE, "PlaybackStatus").unwrap_or_default();
+        let metadata: PropMap = proxy.get(PLAYER_IFACE, "Metadata").unwrap_or_default();
+        let title = metadata
+            .get("xesam:title")
+            .and_then(|v| v.0.as_str())
+            .filter(|t| !t.is_empty())
+            .map(|t| t.to_string());
+        let artist = metadata
+            .get("xesam:artist")
+            .and_then(|v| v.0.as_iter())
+            .and_then(|mut artists| artists.next().and_then(|a| a.as_str().map(|a| a.to_string())))
+            .filter(|a| !a.is_empty());
+        MediaState {
+            title,
+            artist,
+            playing: status == "Playing",
+        }
+    }
+
+    // a playing player wins, otherwise stick to the last one shown
+    fn refresh(&mut self) -> bool {
+        let players = self.players();
+        let states: Vec<(String, MediaState)> = players
+            .into_iter()
+            .map(|p| {
+                let state = self.query(&p);
+                (p, state)
+            })
+            .collect();
+        let chosen = states
+            .iter()
+            .find(|(_, s)| s.playing)
+            .or_else(|| states.iter().find(|(p, _)| Some(p) == self.player.as_ref()))
+            .or_else(|| states.first())
+            .cloned();
+        let (player, state) = match chosen {
+            Some((p, s)) => (Some(p), Some(s)),
+            None => (None, None),
+        };
+        self.player = player;
+        if state != self.state {
+            self.state = state;
+            return true;
+        }
+        false
+    }
+
+    fn player_call(&self, method: &str) -> Option<Message> {
+        let player = self.player.as_ref()?;
+        match Message::new_method_call(player.as_str(), MPRIS_PATH, PLAYER_IFACE, method) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                eprintln!("[mpris] bad player name {}: {}", player, e);
+                None
+            }
+        }
+    }
+
+    pub fn play_pause(&self) {
+        let Some(msg) = self.player_call("PlayPause") else { return };
+        // the new status comes back as a PropertiesChanged
+        let _ = self.conn.channel().send(msg);
+    }
+
+    // relative to the current position, negative seeks backwards
+    pub fn seek(&self, offset_us: i64) {
+        let Some(msg) = self.player_call("Seek") else { return };
+        let _ = self.conn.channel().send(msg.append1(offset_us));
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        // signals come from unique names, cheaper to requery than to map them back
+        let dirty = bus::drain(&self.conn)
+            .iter()
+            .any(|m| m.msg_type() == dbus::message::MessageType::Signal);
+        dirty && self.refresh()
+    }
+}
+
+impl AsFd for MprisMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
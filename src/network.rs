@@ -0,0 +1,192 @@
+use crate::bus;
+use dbus::{
+    arg::{prop_cast, RefArg},
+    blocking::{
+        stdintf::org_freedesktop_dbus::{ObjectManager, Properties},
+        Connection,
+    },
+    channel::BusType,
+    Path,
+};
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+const NM_DEST: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_DEVICE_TYPE_WIFI: u32 = 2;
+const IWD_DEST: &str = "net.connman.iwd";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WifiInfo {
+    pub ssid: String,
+    pub signal: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    NetworkManager,
+    Iwd,
+}
+
+pub struct NetworkMonitor {
+    conn: Connection,
+    backend: Backend,
+    info: Option<WifiInfo>,
+    // object paths whose property changes can affect `info`
+    watched: Vec<String>,
+}
+
+fn name_has_owner(conn: &Connection, name: &str) -> bool {
+    let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", bus::CALL_TIMEOUT);
+    proxy
+        .method_call("org.freedesktop.DBus", "NameHasOwner", (name,))
+        .map(|(owned,): (bool,)| owned)
+        .unwrap_or(false)
+}
+
+// iwd reports signal strength in 100 * dBm
+fn dbm_to_percent(dbm: i32) -> i32 {
+    (2 * (dbm + 100)).clamp(0, 100)
+}
+
+impl NetworkMonitor {
+    pub fn connect() -> Option<NetworkMonitor> {
+        let conn = match bus::open(BusType::System) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[network] system bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let backend = if name_has_owner(&conn, NM_DEST) {
+            Backend::NetworkManager
+        } else if name_has_owner(&conn, IWD_DEST) {
+            Backend::Iwd
+        } else {
+            eprintln!("[network] neither NetworkManager nor iwd is running");
+            return None;
+        };
+        let rule = match backend {
+            Backend::NetworkManager => format!(
+                "type='signal',sender='{}',interface='org.freedesktop.DBus.Properties',member='PropertiesChanged'",
+                NM_DEST
+            ),
+            Backend::Iwd => format!("type='signal',sender='{}'", IWD_DEST),
+        };
+        if let Err(e) = conn.add_match_no_cb(&rule) {
+            eprintln!("[network] failed to subscribe: {}", e);
+            return None;
+        }
+        let mut monitor = NetworkMonitor {
+            conn,
+            backend,
+            info: None,
+            watched: vec![],
+        };
+        monitor.refresh();
+        Some(monitor)
+    }
+
+    pub fn info(&self) -> Option<&WifiInfo> {
+        self.info.as_ref()
+    }
+
+    fn refresh(&mut self) -> bool {
+        let info = match self.backend {
+            Backend::NetworkManager => self.query_nm(),
+            Backend::Iwd => self.query_iwd(),
+        };
+        if info != self.info {
+            self.info = info;
+            return true;
+        }
+        false
+    }
+
+    fn query_nm(&mut self) -> Option<WifiInfo> {
+        self.watched = vec![NM_PATH.to_string()];
+        let nm = self.conn.with_proxy(NM_DEST, NM_PATH, bus::CALL_TIMEOUT);
+        let (devices,): (Vec<Path<'static>>,) = nm.method_call(NM_DEST, "GetDevices", ()).ok()?;
+        let mut found = None;
+        for dev in devices {
+            let proxy = self.conn.with_proxy(NM_DEST, dev.clone(), bus::CALL_TIMEOUT);
+            let dev_type: u32 = proxy.get("org.freedesktop.NetworkManager.Device", "DeviceType").unwrap_or(0);
+            if dev_type != NM_DEVICE_TYPE_WIFI {
+                continue;
+            }
+            self.watched.push(dev.to_string());
+            let Ok(ap) = proxy.get::<Path<'static>>(
+                "org.freedesktop.NetworkManager.Device.Wireless",
+                "ActiveAccessPoint",
+            ) else {
+                continue;
+            };
+            if &*ap == "/" {
+                continue;
+            }
+            self.watched.push(ap.to_string());
+            let ap_proxy = self.conn.with_proxy(NM_DEST, ap, bus::CALL_TIMEOUT);
+            let iface = "org.freedesktop.NetworkManager.AccessPoint";
+            let ssid: Vec<u8> = ap_proxy.get(iface, "Ssid").unwrap_or_default();
+            let strength: u8 = ap_proxy.get(iface, "Strength").unwrap_or(0);
+            found = Some(WifiInfo {
+                ssid: String::from_utf8_lossy(&ssid).into_owned(),
+                signal: strength as i32,
+            });
+        }
+        found
+    }
+
+    fn query_iwd(&mut self) -> Option<WifiInfo> {
+        let proxy = self.conn.with_proxy(IWD_DEST, "/", bus::CALL_TIMEOUT);
+        let objects = proxy.get_managed_objects().ok()?;
+        for (path, ifaces) in &objects {
+            let Some(station) = ifaces.get("net.connman.iwd.Station") else { continue };
+            if prop_cast::<String>(station, "State").map(|s| s.as_str()) != Some("connected") {
+                continue;
+            }
+            let Some(network) = station.get("ConnectedNetwork").and_then(|v| v.0.as_str()) else {
+                continue;
+            };
+            let ssid = objects
+                .iter()
+                .find(|(p, _)| &***p == network)
+                .and_then(|(_, i)| i.get("net.connman.iwd.Network"))
+                .and_then(|n| prop_cast::<String>(n, "Name"))
+                .cloned()
+                .unwrap_or_default();
+            let st = self.conn.with_proxy(IWD_DEST, path.clone(), bus::CALL_TIMEOUT);
+            let signal = st
+                .method_call("net.connman.iwd.Station", "GetOrderedNetworks", ())
+                .ok()
+                .and_then(|(nets,): (Vec<(Path<'static>, i16)>,)| {
+                    nets.into_iter().find(|(p, _)| &**p == network).map(|(_, s)| s)
+                })
+                .map(|s| dbm_to_percent(s as i32 / 100))
+                .unwrap_or(0);
+            return Some(WifiInfo { ssid, signal });
+        }
+        None
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        let mut dirty = false;
+        for msg in bus::drain(&self.conn) {
+            match self.backend {
+                // NM broadcasts strength updates for every scanned AP, ignore unrelated ones
+                Backend::NetworkManager => {
+                    if let Some(path) = msg.path() {
+                        dirty |= self.watched.iter().any(|w| **w == *path);
+                    }
+                }
+                Backend::Iwd => dirty = true,
+            }
+        }
+        dirty && self.refresh()
+    }
+}
+
+impl AsFd for NetworkMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
@@ -1,29 +1,162 @@
+use crate::compositor::{CompositorBackend, Handoff, Output, Window, WindowLayout, Workspace};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::{
     collections::HashMap,
-    io::{BufRead, BufReader, Write},
-    os::unix::{io::{AsFd, BorrowedFd}, net::UnixStream},
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    os::unix::{
+        io::{AsFd, BorrowedFd, OwnedFd},
+        net::UnixStream,
+    },
     path::PathBuf,
 };
 
-#[derive(Debug, Clone)]
-pub struct Workspace {
-    pub id: u64,
-    pub idx: u8,
-    pub is_focused: bool,
+// The parts of niri's IPC the bar uses, fields it does not need are left out
+// and ignored when deserializing.
+
+#[derive(Serialize, Debug)]
+enum Request {
+    EventStream,
+    Outputs,
+    Action(Action),
+    Output { output: String, action: OutputAction },
+}
+
+#[derive(Serialize, Debug)]
+enum Action {
+    FocusWorkspace { reference: WorkspaceReference },
+    MoveWindowToWorkspace { window_id: Option<u64>, reference: WorkspaceReference, focus: bool },
+    FocusWindow { id: u64 },
+    ToggleOverview {},
+    Spawn { command: Vec<String> },
+}
+
+#[derive(Serialize, Debug)]
+enum WorkspaceReference {
+    Index(u8),
+}
+
+#[derive(Serialize, Debug)]
+enum OutputAction {
+    On,
+    Off,
+}
+
+#[derive(Deserialize, Debug)]
+struct WorkspaceInfo {
+    id: u64,
+    idx: u8,
+    #[serde(default)]
+    is_focused: bool,
+    // None on an empty workspace
+    active_window_id: Option<u64>,
+    name: Option<String>,
+    // missing before niri 25.02
+    #[serde(default)]
+    is_urgent: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct WindowInfo {
+    id: u64,
+    title: Option<String>,
+    app_id: Option<String>,
+    workspace_id: Option<u64>,
+    #[serde(default)]
+    is_focused: bool,
+    #[serde(default)]
+    is_floating: bool,
+    // missing before niri 25.05
+    layout: Option<WindowLayoutInfo>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WindowLayoutInfo {
+    // None for floating windows
+    pos_in_scrolling_layout: Option<(usize, usize)>,
+    // None when the tile is not on screen
+    tile_pos_in_workspace_view: Option<(f64, f64)>,
+    #[serde(default)]
+    window_offset_in_tile: (f64, f64),
+}
+
+#[derive(Deserialize, Debug)]
+struct OutputInfo {
+    name: String,
+    // None while the output is off
+    logical: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+enum Response {
+    Outputs(HashMap<String, OutputInfo>),
+}
+
+// Every event niri sends, one per line, externally tagged. Ones from a newer
+// niri than this list knows about become Unknown instead of parse errors.
+#[derive(Deserialize, Debug)]
+enum Event {
+    WorkspacesChanged { workspaces: Vec<WorkspaceInfo> },
+    WorkspaceUrgencyChanged { id: u64, urgent: bool },
+    WorkspaceActivated { id: u64, focused: bool },
+    WorkspaceActiveWindowChanged { workspace_id: u64, active_window_id: Option<u64> },
+    WindowsChanged { windows: Vec<WindowInfo> },
+    WindowOpenedOrChanged { window: WindowInfo },
+    WindowClosed { id: u64 },
+    WindowFocusChanged { id: Option<u64> },
+    WindowFocusTimestampChanged {},
+    WindowUrgencyChanged {},
+    WindowLayoutsChanged { changes: Vec<(u64, WindowLayoutInfo)> },
+    KeyboardLayoutsChanged {},
+    KeyboardLayoutSwitched {},
+    OverviewOpenedOrClosed {},
+    ConfigLoaded {},
+    ScreenshotCaptured {},
+    #[serde(skip)]
+    Unknown(String),
+}
+
+fn parse_event(line: &str) -> Option<Event> {
+    let err = match serde_json::from_str::<Event>(line) {
+        Ok(event) => return Some(event),
+        Err(e) => e,
+    };
+    // only an unknown tag is fine, a known event in an unexpected shape is not
+    match serde_json::from_str::<HashMap<String, Value>>(line) {
+        Ok(map) if map.len() == 1 && err.to_string().starts_with("unknown variant") => {
+            map.into_keys().next().map(Event::Unknown)
+        }
+        _ => {
+            eprintln!("[niri] parse error: {}: {}", err, line);
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NiriWindow {
+    title: String,
+    app_id: Option<String>,
+    workspace_id: Option<u64>,
+    is_floating: bool,
+    layout: Option<WindowLayout>,
 }
 
 #[derive(Debug, Default)]
 pub struct NiriState {
-    pub workspaces: Vec<Workspace>,
-    pub focused_window_title: Option<String>,
+    workspaces: Vec<Workspace>,
+    focused_window_title: Option<String>,
+    focused_app_id: Option<String>,
+    focused_layout: Option<WindowLayout>,
     // title lookup for WindowFocusChanged which only carries an id
-    windows: HashMap<u64, String>,
+    windows: HashMap<u64, NiriWindow>,
     focused_window_id: Option<u64>,
     socket_path: Option<PathBuf>,
     event_stream: Option<BufReader<UnixStream>>,
     // opened before privilege drop so actions still work as nobody
     action_stream: Option<UnixStream>,
+    // niri went away, the event stream only reports EOF from now on
+    lost: bool,
 }
 
 fn find_socket() -> Option<PathBuf> {
@@ -38,7 +171,11 @@ fn find_socket() -> Option<PathBuf> {
             for entry in entries.flatten() {
                 let name = entry.file_name();
                 let name = name.to_string_lossy();
-                if name.starts_with("niri.wayland-") && name.ends_with(".sock") {
+                // a niri that crashed leaves its socket behind
+                if name.starts_with("niri.wayland-")
+                    && name.ends_with(".sock")
+                    && UnixStream::connect(entry.path()).is_ok()
+                {
                     return Some(entry.path());
                 }
             }
@@ -47,34 +184,45 @@ fn find_socket() -> Option<PathBuf> {
     None
 }
 
-fn drain_lines(reader: &mut BufReader<UnixStream>) -> Vec<String> {
+// the lines that came in, and false once niri closed the stream or it broke
+fn drain_lines(reader: &mut BufReader<UnixStream>) -> (Vec<String>, bool) {
     let mut lines = Vec::new();
     loop {
         let mut line = String::new();
         match reader.read_line(&mut line) {
-            Ok(0) => break,
+            Ok(0) => return (lines, false),
             Ok(_) => lines.push(line),
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-            Err(_) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return (lines, true),
+            Err(e) => {
+                eprintln!("[niri] event stream read failed: {}", e);
+                return (lines, false);
+            }
         }
     }
-    lines
+}
+
+// The event stream, already asked for events, and the action stream. The
+// reply to EventStream is left unread, whoever reads it owns the buffer.
+// Must be called before privilege drop or by the session user.
+pub fn handoff() -> Option<Handoff> {
+    let socket = find_socket()?;
+    let mut stream = UnixStream::connect(&socket).ok()?;
+    stream.write_all(format!("{}\n", encode(&Request::EventStream)).as_bytes()).ok()?;
+    let mut fds = vec![OwnedFd::from(stream)];
+    fds.extend(UnixStream::connect(&socket).ok().map(OwnedFd::from));
+    Some(Handoff { socket, extra: Value::Null, fds })
 }
 
 impl NiriState {
-    // must be called before privilege drop
-    pub fn connect() -> Option<NiriState> {
-        let socket_path = find_socket()?;
+    pub fn from_handoff(handoff: Handoff) -> Option<NiriState> {
+        let socket_path = handoff.socket;
+        let mut fds = handoff.fds.into_iter().map(UnixStream::from);
+        let (stream, action_stream) = (fds.next()?, fds.next());
         eprintln!("[niri] socket: {}", socket_path.display());
-
-        let mut stream = UnixStream::connect(&socket_path).ok()?;
-        stream.write_all(b"\"EventStream\"\n").ok()?;
         let mut reader = BufReader::new(stream);
         let mut ack = String::new();
         reader.read_line(&mut ack).ok()?;
 
-        let action_stream = UnixStream::connect(&socket_path).ok();
-
         let mut state = NiriState {
             socket_path: Some(socket_path),
             event_stream: Some(reader),
@@ -94,6 +242,50 @@ impl NiriState {
         Some(state)
     }
 
+    // one request on the action socket, replies are not read
+    fn send(&mut self, req: &Request) {
+        if let Some(ref mut sock) = self.action_stream {
+            if sock.write_all(format!("{}\n", encode(req)).as_bytes()).is_err() {
+                eprintln!("[niri] action socket write failed");
+                self.action_stream = None;
+            }
+        }
+    }
+
+    // a request whose reply is needed, the replies to earlier actions still
+    // waiting on the socket are thrown away first
+    fn query(&mut self, req: &Request) -> Option<Response> {
+        let sock = self.action_stream.as_mut()?;
+        let _ = sock.set_nonblocking(true);
+        let mut scratch = [0u8; 4096];
+        while matches!(sock.read(&mut scratch), Ok(n) if n > 0) {}
+        let _ = sock.set_nonblocking(false);
+        let _ = sock.set_read_timeout(Some(std::time::Duration::from_secs(1)));
+        let mut line = String::new();
+        let res = sock
+            .write_all(format!("{}\n", encode(req)).as_bytes())
+            .and_then(|_| BufReader::new(&*sock).read_line(&mut line));
+        match res {
+            Ok(n) if n > 0 => match serde_json::from_str::<Result<Response, String>>(&line) {
+                Ok(Ok(response)) => Some(response),
+                Ok(Err(e)) => {
+                    eprintln!("[niri] {:?} failed: {}", req, e);
+                    None
+                }
+                Err(e) => {
+                    eprintln!("[niri] unexpected reply to {:?}: {}", req, e);
+                    None
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => None,
+            _ => {
+                eprintln!("[niri] action socket read failed");
+                self.action_stream = None;
+                None
+            }
+        }
+    }
+
     fn read_initial_state(&mut self) {
         let reader = match self.event_stream.as_mut() {
             Some(r) => r,
@@ -121,157 +313,284 @@ impl NiriState {
         }
     }
 
-    pub fn process_events(&mut self) -> bool {
-        let lines = match self.event_stream.as_mut() {
-            Some(r) => drain_lines(r),
-            None => return false,
-        };
-        let mut changed = false;
-        for line in &lines {
-            if self.apply_event_line(line.trim()) { changed = true; }
-        }
+    // title, app and layout of the focused window from the window list, true if any changed
+    fn refresh_focused(&mut self) -> bool {
+        let window = self.focused_window_id.and_then(|id| self.windows.get(&id));
+        let title = window.map(|w| w.title.clone());
+        let app_id = window.and_then(|w| w.app_id.clone());
+        let layout = window.and_then(|w| w.layout);
+        let changed = title != self.focused_window_title
+            || app_id != self.focused_app_id
+            || layout != self.focused_layout;
+        self.focused_window_title = title;
+        self.focused_app_id = app_id;
+        self.focused_layout = layout;
         changed
     }
 
     fn apply_event_line(&mut self, line: &str) -> bool {
         if line.is_empty() { return false; }
-        let Ok(event) = serde_json::from_str::<Value>(line) else {
-            eprintln!("[niri] parse error: {}", line);
-            return false;
-        };
-
-        // workspace focus changed
-        if let Some(inner) = event.get("WorkspaceActivated") {
-            if let (Some(id), Some(focused)) = (inner["id"].as_u64(), inner["focused"].as_bool()) {
+        let Some(event) = parse_event(line) else { return false };
+        match event {
+            // workspace focus changed
+            Event::WorkspaceActivated { id, focused } => {
                 let mut changed = false;
                 for ws in &mut self.workspaces {
                     let was = ws.is_focused;
                     ws.is_focused = focused && ws.id == id;
                     if ws.is_focused != was { changed = true; }
                 }
-                return changed;
+                changed
             }
-            return false;
-        }
-
-        // workspace added or removed
-        if let Some(inner) = event.get("WorkspacesChanged") {
-            if let Some(arr) = inner["workspaces"].as_array() {
-                let mut new_ws: Vec<Workspace> = arr.iter().filter_map(parse_workspace).collect();
+            // workspace added or removed
+            Event::WorkspacesChanged { workspaces } => {
+                let mut new_ws: Vec<Workspace> = workspaces
+                    .into_iter()
+                    .map(|w| Workspace {
+                        id: w.id,
+                        idx: w.idx,
+                        is_focused: w.is_focused,
+                        occupied: w.active_window_id.is_some(),
+                        urgent: w.is_urgent,
+                        name: w.name,
+                    })
+                    .collect();
                 new_ws.sort_by_key(|w| w.idx);
-                if !workspaces_eq(&self.workspaces, &new_ws) {
-                    self.workspaces = new_ws;
-                    return true;
-                }
+                if workspaces_eq(&self.workspaces, &new_ws) { return false; }
+                self.workspaces = new_ws;
+                true
             }
-            return false;
-        }
-
-        // full window list on initial connect
-        if let Some(inner) = event.get("WindowsChanged") {
-            if let Some(arr) = inner["windows"].as_array() {
+            // only whether it became empty or stopped being so is shown
+            Event::WorkspaceActiveWindowChanged { workspace_id, active_window_id } => {
+                let Some(ws) = self.workspaces.iter_mut().find(|w| w.id == workspace_id) else {
+                    return false;
+                };
+                let was = ws.occupied;
+                ws.occupied = active_window_id.is_some();
+                ws.occupied != was
+            }
+            Event::WorkspaceUrgencyChanged { id, urgent } => {
+                let Some(ws) = self.workspaces.iter_mut().find(|w| w.id == id) else {
+                    return false;
+                };
+                let was = ws.urgent;
+                ws.urgent = urgent;
+                ws.urgent != was
+            }
+            // full window list on initial connect
+            Event::WindowsChanged { windows } => {
                 self.windows.clear();
                 self.focused_window_id = None;
-                let mut new_title = None;
-                for w in arr {
-                    if let (Some(id), Some(title)) = (w["id"].as_u64(), w["title"].as_str()) {
-                        self.windows.insert(id, title.to_string());
-                        if w["is_focused"].as_bool().unwrap_or(false) {
-                            self.focused_window_id = Some(id);
-                            new_title = Some(title.to_string());
-                        }
+                for w in windows {
+                    if w.is_focused {
+                        self.focused_window_id = Some(w.id);
                     }
+                    self.windows.insert(w.id, NiriWindow::from(w));
                 }
-                if new_title != self.focused_window_title {
-                    self.focused_window_title = new_title;
-                    return true;
-                }
+                self.refresh_focused();
+                true
             }
-            return false;
-        }
-
-        // focused window id changed (event carries id only not the full window)
-        if let Some(inner) = event.get("WindowFocusChanged") {
-            let new_id = inner["id"].as_u64();
-            if new_id == self.focused_window_id { return false; }
-            self.focused_window_id = new_id;
-            let new_title = new_id.and_then(|id| self.windows.get(&id)).cloned();
-            if new_title != self.focused_window_title {
-                self.focused_window_title = new_title;
-                return true;
+            // focused window id changed (event carries id only not the full window)
+            Event::WindowFocusChanged { id } => {
+                if id == self.focused_window_id { return false; }
+                self.focused_window_id = id;
+                // the switcher marks the focused window even if the title stays
+                self.refresh_focused();
+                true
             }
-            return false;
-        }
-
-        // single window opened or title changed
-        if let Some(inner) = event.get("WindowOpenedOrChanged") {
-            if let Some(w) = inner.get("window") {
-                if let (Some(id), Some(title)) = (w["id"].as_u64(), w["title"].as_str()) {
-                    self.windows.insert(id, title.to_string());
-                    if self.focused_window_id == Some(id) {
-                        let new_title = Some(title.to_string());
-                        if new_title != self.focused_window_title {
-                            self.focused_window_title = new_title;
-                            return true;
-                        }
-                    }
-                }
+            // single window opened or title changed, the window switcher
+            // lists every window so not only the focused one counts
+            Event::WindowOpenedOrChanged { window } => {
+                let id = window.id;
+                let window = NiriWindow::from(window);
+                let listed = self.windows.get(&id).map_or(true, |w| {
+                    w.title != window.title
+                        || w.app_id != window.app_id
+                        || w.workspace_id != window.workspace_id
+                });
+                self.windows.insert(id, window);
+                let focused = self.focused_window_id == Some(id) && self.refresh_focused();
+                listed || focused
             }
-            return false;
-        }
-
-        // window closed
-        if let Some(inner) = event.get("WindowClosed") {
-            if let Some(id) = inner["id"].as_u64() {
+            Event::WindowClosed { id } => {
                 self.windows.remove(&id);
                 if self.focused_window_id == Some(id) {
                     self.focused_window_id = None;
-                    if self.focused_window_title.is_some() {
-                        self.focused_window_title = None;
-                        return true;
+                    self.refresh_focused();
+                }
+                true
+            }
+            // windows moved or resized, only the focused one is shown
+            Event::WindowLayoutsChanged { changes } => {
+                for (id, info) in changes {
+                    if let Some(w) = self.windows.get_mut(&id) {
+                        w.layout = window_layout(w.is_floating, &info);
                     }
                 }
+                self.refresh_focused()
             }
-            return false;
+            Event::WindowFocusTimestampChanged {}
+            | Event::WindowUrgencyChanged {}
+            | Event::KeyboardLayoutsChanged {}
+            | Event::KeyboardLayoutSwitched {}
+            | Event::OverviewOpenedOrClosed {}
+            | Event::ConfigLoaded {}
+            | Event::ScreenshotCaptured {}
+            | Event::Unknown(_) => false,
         }
+    }
+}
 
-        false
+impl CompositorBackend for NiriState {
+    fn event_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.event_stream.as_ref().map(|r| r.get_ref().as_fd())
     }
 
-    pub fn focus_workspace(&mut self, idx: u8) {
-        let req = format!(
-            "{{\"Action\":{{\"FocusWorkspace\":{{\"reference\":{{\"Index\":{}}}}}}}}}\n",
-            idx
-        );
-        if let Some(ref mut sock) = self.action_stream {
-            if sock.write_all(req.as_bytes()).is_err() {
-                eprintln!("[niri] action socket write failed");
-                self.action_stream = None;
-            }
+    fn workspaces(&self) -> &[Workspace] {
+        &self.workspaces
+    }
+
+    fn focused_window_title(&self) -> Option<&str> {
+        self.focused_window_title.as_deref()
+    }
+
+    fn focused_app_id(&self) -> Option<&str> {
+        self.focused_app_id.as_deref()
+    }
+
+    fn focused_window_layout(&self) -> Option<WindowLayout> {
+        self.focused_layout
+    }
+
+    fn process_events(&mut self) -> bool {
+        let (lines, open) = match self.event_stream.as_mut() {
+            Some(r) => drain_lines(r),
+            None => return false,
+        };
+        if !open && !self.lost {
+            eprintln!("[niri] connection lost");
+            self.lost = true;
+            self.action_stream = None;
         }
+        let mut changed = false;
+        for line in &lines {
+            if self.apply_event_line(line.trim()) { changed = true; }
+        }
+        changed
+    }
+
+    fn focus_workspace(&mut self, idx: u8) {
+        self.send(&Request::Action(Action::FocusWorkspace {
+            reference: WorkspaceReference::Index(idx),
+        }));
+    }
+
+    fn move_window_to_workspace(&mut self, idx: u8) {
+        self.send(&Request::Action(Action::MoveWindowToWorkspace {
+            window_id: None,
+            reference: WorkspaceReference::Index(idx),
+            focus: false,
+        }));
+    }
+
+    fn windows(&self) -> Vec<Window> {
+        let ws_pos = |id: Option<u64>| {
+            self.workspaces.iter().position(|ws| Some(ws.id) == id).unwrap_or(usize::MAX)
+        };
+        let mut windows: Vec<(usize, Window)> = self
+            .windows
+            .iter()
+            .map(|(&id, w)| {
+                let window = Window {
+                    id,
+                    title: w.title.clone(),
+                    app_id: w.app_id.clone(),
+                    is_focused: self.focused_window_id == Some(id),
+                };
+                (ws_pos(w.workspace_id), window)
+            })
+            .collect();
+        // by workspace, then in the order they were opened
+        windows.sort_by_key(|(pos, w)| (*pos, w.id));
+        windows.into_iter().map(|(_, w)| w).collect()
+    }
+
+    fn focus_window(&mut self, id: u64) {
+        self.send(&Request::Action(Action::FocusWindow { id }));
+    }
+
+    fn release_output(&mut self, name: &str) {
+        self.set_output_enabled(name, false);
+    }
+
+    fn outputs(&mut self) -> Vec<Output> {
+        let Some(Response::Outputs(outputs)) = self.query(&Request::Outputs) else {
+            return Vec::new();
+        };
+        outputs
+            .into_values()
+            .map(|o| Output {
+                name: o.name,
+                // off outputs have no place in the layout
+                enabled: o.logical.is_some(),
+            })
+            .collect()
+    }
+
+    fn set_output_enabled(&mut self, name: &str, enabled: bool) {
+        let action = if enabled { OutputAction::On } else { OutputAction::Off };
+        self.send(&Request::Output { output: name.to_string(), action });
+    }
+
+    fn spawn(&mut self, command: &[String]) {
+        self.send(&Request::Action(Action::Spawn { command: command.to_vec() }));
+    }
+
+    fn toggle_overview(&mut self) {
+        self.send(&Request::Action(Action::ToggleOverview {}));
     }
+
+    fn disconnected(&self) -> bool {
+        self.lost
+    }
+}
+
+fn encode(req: &Request) -> String {
+    serde_json::to_string(req).expect("niri requests always serialize")
 }
 
-impl AsFd for NiriState {
-    fn as_fd(&self) -> BorrowedFd<'_> {
-        self.event_stream
-            .as_ref()
-            .expect("NiriState has no event stream")
-            .get_ref()
-            .as_fd()
+impl From<WindowInfo> for NiriWindow {
+    fn from(w: WindowInfo) -> NiriWindow {
+        NiriWindow {
+            layout: w.layout.as_ref().and_then(|l| window_layout(w.is_floating, l)),
+            title: w.title.unwrap_or_default(),
+            app_id: w.app_id,
+            workspace_id: w.workspace_id,
+            is_floating: w.is_floating,
+        }
     }
 }
 
-fn parse_workspace(w: &Value) -> Option<Workspace> {
-    Some(Workspace {
-        id: w["id"].as_u64()?,
-        idx: w["idx"].as_u64()? as u8,
-        is_focused: w["is_focused"].as_bool().unwrap_or(false),
-    })
+// niri does not say whether a window is fullscreen, a borderless tile in the
+// top left corner of the view is taken as one
+fn window_layout(is_floating: bool, info: &WindowLayoutInfo) -> Option<WindowLayout> {
+    if info.tile_pos_in_workspace_view == Some((0.0, 0.0)) && info.window_offset_in_tile == (0.0, 0.0) {
+        return Some(WindowLayout::Fullscreen);
+    }
+    if is_floating {
+        return Some(WindowLayout::Floating);
+    }
+    info.pos_in_scrolling_layout
+        .map(|(column, tile)| WindowLayout::Tiled { column, tile })
 }
 
 fn workspaces_eq(a: &[Workspace], b: &[Workspace]) -> bool {
     a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| {
-        x.id == y.id && x.idx == y.idx && x.is_focused == y.is_focused
+        x.id == y.id
+            && x.idx == y.idx
+            && x.is_focused == y.is_focused
+            && x.occupied == y.occupied
+            && x.urgent == y.urgent
+            && x.name == y.name
     })
 }
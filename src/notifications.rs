@@ -0,0 +1,112 @@
+use crate::{bus, config::ButtonConfig};
+use dbus::{blocking::Connection, message::MessageType};
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+// Like the screencast monitor we only watch the calls going to whichever
+// notification daemon the session runs, we never become one ourselves.
+const MONITOR_RULES: [&str; 1] =
+    ["type='method_call',interface='org.freedesktop.Notifications',member='Notify'"];
+
+pub struct Notification {
+    pub summary: String,
+    pub body: String,
+}
+
+// the body may carry the small markup subset of the spec, the bar only shows plain text
+fn strip_markup(text: &str) -> String {
+    let mut out = String::new();
+    let mut in_tag = false;
+    for ch in text.chars() {
+        match ch {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            '\n' if !in_tag => out.push(' '),
+            _ if !in_tag => out.push(ch),
+            _ => {}
+        }
+    }
+    out.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+impl Notification {
+    pub fn to_buttons(&self) -> Vec<ButtonConfig> {
+        let mut buttons = vec![ButtonConfig {
+            text: Some(self.summary.clone()),
+            stretch: Some(if self.body.is_empty() { 8 } else { 3 }),
+            ..Default::default()
+        }];
+        if !self.body.is_empty() {
+            buttons.push(ButtonConfig {
+                text: Some(self.body.clone()),
+                stretch: Some(5),
+                ..Default::default()
+            });
+        }
+        buttons
+    }
+}
+
+pub struct NotificationMonitor {
+    conn: Connection,
+}
+
+impl NotificationMonitor {
+    // must be called before privilege drop
+    pub fn connect() -> Option<NotificationMonitor> {
+        let conn = match bus::open_session() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[notifications] session bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", bus::CALL_TIMEOUT);
+        let res: Result<(), _> = proxy.method_call(
+            "org.freedesktop.DBus.Monitoring",
+            "BecomeMonitor",
+            (MONITOR_RULES.to_vec(), 0u32),
+        );
+        if let Err(e) = res {
+            eprintln!("[notifications] failed to become monitor: {}", e);
+            return None;
+        }
+        Some(NotificationMonitor { conn })
+    }
+
+    // notifications sent since the last call, oldest first
+    pub fn process_events(&mut self) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        for msg in bus::drain(&self.conn) {
+            if msg.msg_type() != MessageType::MethodCall {
+                continue;
+            }
+            // app_name, replaces_id, app_icon, summary, body, actions, hints, expire_timeout
+            let mut args = msg.iter_init();
+            let parsed = (|| {
+                let _app: String = args.read().ok()?;
+                let _replaces: u32 = args.read().ok()?;
+                let _icon: String = args.read().ok()?;
+                let summary: String = args.read().ok()?;
+                let body: String = args.read().ok()?;
+                Some((summary, body))
+            })();
+            if let Some((summary, body)) = parsed {
+                notifications.push(Notification {
+                    summary: strip_markup(&summary),
+                    body: strip_markup(&body),
+                });
+            }
+        }
+        notifications
+    }
+}
+
+impl AsFd for NotificationMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
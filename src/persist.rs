@@ -0,0 +1,86 @@
+use serde_json::{Map, Value};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    time::{Duration, Instant},
+};
+
+const STATE_DIR: &str = "/var/lib/tiny-dfr";
+const STATE_PATH: &str = "/var/lib/tiny-dfr/widgets.json";
+// scripts may change their state on every draw, the disk does not need all of it
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// State of widgets by a key that survives reloads, kept in one JSON object
+// on disk so it also survives a restart of the daemon.
+pub struct WidgetStore {
+    file: Option<File>,
+    values: Map<String, Value>,
+    dirty: bool,
+    saved: Instant,
+}
+
+impl WidgetStore {
+    // must be called before privilege drop, /var/lib is not writable for nobody
+    // so the file is kept open
+    pub fn open() -> WidgetStore {
+        let _ = fs::create_dir_all(STATE_DIR);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(STATE_PATH);
+        let mut file = match file {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("[persist] cannot open {}, widget state will not be kept: {}", STATE_PATH, e);
+                None
+            }
+        };
+        let mut text = String::new();
+        if let Some(ref mut f) = file {
+            let _ = f.read_to_string(&mut text);
+        }
+        let values = match serde_json::from_str(&text) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        WidgetStore {
+            file,
+            values,
+            dirty: false,
+            saved: Instant::now(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.values.get(key)
+    }
+
+    pub fn set(&mut self, key: String, value: Value) {
+        if self.values.get(&key) != Some(&value) {
+            self.values.insert(key, value);
+            self.dirty = true;
+        }
+    }
+
+    // writes the state if it changed and the last write is long enough ago
+    pub fn save_if_due(&mut self) {
+        if !self.dirty || self.saved.elapsed() < SAVE_INTERVAL {
+            return;
+        }
+        self.dirty = false;
+        self.saved = Instant::now();
+        let Some(ref mut f) = self.file else {
+            return;
+        };
+        let text = serde_json::to_string(&self.values).unwrap();
+        let res = f
+            .set_len(0)
+            .and_then(|_| f.seek(SeekFrom::Start(0)))
+            .and_then(|_| f.write_all(text.as_bytes()));
+        if let Err(e) = res {
+            eprintln!("[persist] failed to write {}: {}", STATE_PATH, e);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::config::ButtonConfig;
+use std::{fs, path::Path};
+
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+fn read_num(dir: &Path, attr: &str) -> Option<f64> {
+    fs::read_to_string(dir.join(attr))
+        .ok()
+        .and_then(|s| s.trim().parse::<f64>().ok())
+}
+
+// first online external supply (AC adapter or USB-C PD)
+fn find_charger() -> Option<String> {
+    for entry in fs::read_dir(POWER_SUPPLY_PATH).ok()?.flatten() {
+        let dir = entry.path();
+        let typ = fs::read_to_string(dir.join("type")).unwrap_or_default();
+        if !matches!(typ.trim(), "Mains" | "USB") {
+            continue;
+        }
+        if read_num(&dir, "online") == Some(1.0) {
+            return entry.file_name().to_str().map(|s| s.to_string());
+        }
+    }
+    None
+}
+
+// sysfs reports micro units
+fn watts(dir: &Path) -> Option<f64> {
+    if let Some(p) = read_num(dir, "power_now") {
+        return Some(p / 1_000_000.0);
+    }
+    let v = read_num(dir, "voltage_now")?;
+    let a = read_num(dir, "current_now")?;
+    Some(v * a / 1_000_000_000_000.0)
+}
+
+pub struct PowerStats {
+    pub charger_watts: Option<f64>,
+    pub cycle_count: Option<u32>,
+    pub health_percent: Option<f64>,
+    pub temperature_c: Option<f64>,
+    pub draw_watts: Option<f64>,
+}
+
+impl PowerStats {
+    pub fn read(battery: &str) -> PowerStats {
+        let dir = Path::new(POWER_SUPPLY_PATH).join(battery);
+        let full = read_num(&dir, "charge_full").or_else(|| read_num(&dir, "energy_full"));
+        let design = read_num(&dir, "charge_full_design")
+            .or_else(|| read_num(&dir, "energy_full_design"));
+        let health_percent = match (full, design) {
+            (Some(f), Some(d)) if d > 0.0 => Some(f / d * 100.0),
+            _ => None,
+        };
+        let charger_watts = find_charger().and_then(|c| {
+            let cdir = Path::new(POWER_SUPPLY_PATH).join(c);
+            watts(&cdir).or_else(|| {
+                // PD adapters usually only advertise what they can deliver
+                let v = read_num(&cdir, "voltage_max")?;
+                let a = read_num(&cdir, "current_max")?;
+                Some(v * a / 1_000_000_000_000.0)
+            })
+        });
+        PowerStats {
+            charger_watts,
+            cycle_count: read_num(&dir, "cycle_count").map(|c| c as u32),
+            health_percent,
+            // tenths of a degree
+            temperature_c: read_num(&dir, "temp").map(|t| t / 10.0),
+            draw_watts: watts(&dir).map(f64::abs),
+        }
+    }
+
+    pub fn to_buttons(&self) -> Vec<ButtonConfig> {
+        fn or_na(v: Option<String>) -> String {
+            v.unwrap_or_else(|| "N/A".to_string())
+        }
+        [
+            format!("Charger {}", or_na(self.charger_watts.map(|w| format!("{:.0}W", w)))),
+            format!("Draw {}", or_na(self.draw_watts.map(|w| format!("{:.1}W", w)))),
+            format!("Health {}", or_na(self.health_percent.map(|h| format!("{:.0}%", h)))),
+            format!("Cycles {}", or_na(self.cycle_count.map(|c| c.to_string()))),
+            format!("Temp {}", or_na(self.temperature_c.map(|t| format!("{:.1}°C", t)))),
+        ]
+        .into_iter()
+        .map(|text| ButtonConfig {
+            text: Some(text),
+            stretch: Some(2),
+            ..Default::default()
+        })
+        .collect()
+    }
+}
@@ -0,0 +1,137 @@
+use nix::{
+    errno::Errno,
+    sys::fanotify::{EventFFlags, Fanotify, InitFlags, MarkFlags, MaskFlags},
+};
+use std::{
+    fs,
+    os::unix::io::{AsFd, BorrowedFd},
+};
+
+pub struct PrivacyMonitor {
+    fanotify: Option<Fanotify>,
+    camera_opens: u32,
+    mic_active: bool,
+}
+
+// a capture substream is only RUNNING while something records from it,
+// pipewire suspends idle sources so this also works behind it
+fn any_capture_running() -> bool {
+    let Ok(cards) = fs::read_dir("/proc/asound") else {
+        return false;
+    };
+    for card in cards.flatten() {
+        if !card.file_name().to_string_lossy().starts_with("card") {
+            continue;
+        }
+        let Ok(pcms) = fs::read_dir(card.path()) else { continue };
+        for pcm in pcms.flatten() {
+            let name = pcm.file_name();
+            let name = name.to_string_lossy();
+            if !name.starts_with("pcm") || !name.ends_with('c') {
+                continue;
+            }
+            let Ok(subs) = fs::read_dir(pcm.path()) else { continue };
+            for sub in subs.flatten() {
+                if let Ok(status) = fs::read_to_string(sub.path().join("status")) {
+                    if status.lines().any(|l| l.trim() == "state: RUNNING") {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+    false
+}
+
+impl PrivacyMonitor {
+    // fanotify_init needs CAP_SYS_ADMIN, must be called before privilege drop
+    pub fn new() -> PrivacyMonitor {
+        let fanotify = match Fanotify::init(
+            InitFlags::FAN_CLASS_NOTIF | InitFlags::FAN_NONBLOCK | InitFlags::FAN_CLOEXEC,
+            EventFFlags::O_RDONLY,
+        ) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("[privacy] fanotify unavailable: {}", e);
+                None
+            }
+        };
+        if let Some(ref f) = fanotify {
+            for entry in fs::read_dir("/dev").into_iter().flatten().flatten() {
+                if !entry.file_name().to_string_lossy().starts_with("video") {
+                    continue;
+                }
+                let path = entry.path();
+                if let Err(e) = f.mark(
+                    MarkFlags::FAN_MARK_ADD,
+                    MaskFlags::FAN_OPEN | MaskFlags::FAN_CLOSE,
+                    None,
+                    Some(&path),
+                ) {
+                    eprintln!("[privacy] failed to watch {}: {}", path.display(), e);
+                }
+            }
+        }
+        PrivacyMonitor {
+            fanotify,
+            camera_opens: 0,
+            mic_active: any_capture_running(),
+        }
+    }
+
+    pub fn watches_camera(&self) -> bool {
+        self.fanotify.is_some()
+    }
+
+    pub fn camera_active(&self) -> bool {
+        self.camera_opens > 0
+    }
+
+    pub fn mic_active(&self) -> bool {
+        self.mic_active
+    }
+
+    pub fn poll_mic(&mut self) -> bool {
+        let active = any_capture_running();
+        let changed = active != self.mic_active;
+        self.mic_active = active;
+        changed
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        let Some(ref f) = self.fanotify else {
+            return false;
+        };
+        let was_active = self.camera_active();
+        loop {
+            let events = match f.read_events() {
+                Ok(evts) => evts,
+                Err(Errno::EAGAIN) => break,
+                Err(e) => {
+                    eprintln!("[privacy] fanotify read failed: {}", e);
+                    break;
+                }
+            };
+            for evt in events {
+                let mask = evt.mask();
+                // open and close of the same fd can be merged into one event
+                if mask.contains(MaskFlags::FAN_OPEN) {
+                    self.camera_opens += 1;
+                }
+                if mask.intersects(MaskFlags::FAN_CLOSE) {
+                    self.camera_opens = self.camera_opens.saturating_sub(1);
+                }
+            }
+        }
+        was_active != self.camera_active()
+    }
+}
+
+impl AsFd for PrivacyMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fanotify
+            .as_ref()
+            .expect("PrivacyMonitor has no fanotify group")
+            .as_fd()
+    }
+}
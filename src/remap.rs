@@ -0,0 +1,181 @@
+use input_linux::Key;
+use input_linux_sys::input_event;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Read,
+    mem,
+    os::{
+        fd::{AsFd, AsRawFd, BorrowedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::{Path, PathBuf},
+    ptr,
+};
+
+const EV_SYN: u16 = 0x00;
+const EV_KEY: u16 = 0x01;
+const EV_MSC: u16 = 0x04;
+const EV_LED: u16 = 0x11;
+const EV_REP: u16 = 0x14;
+// only keys are passed on, the grab would swallow pointer motion and the like
+const KEYBOARD_EVENTS: [u16; 5] = [EV_SYN, EV_KEY, EV_MSC, EV_LED, EV_REP];
+// KEY_MAX / 8 + 1
+const KEY_BYTES: usize = 96;
+// our own uinput device, sending its keys back to it would loop
+const OWN_DEVICE: &str = "Dynamic Function Row Virtual Input Device";
+
+nix::ioctl_read_buf!(eviocgbit_ev, b'E', 0x20, u8);
+nix::ioctl_read_buf!(eviocgbit_key, b'E', 0x20 + EV_KEY, u8);
+nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+
+fn key_bits(file: &File) -> Option<[u8; KEY_BYTES]> {
+    let mut bits = [0u8; KEY_BYTES];
+    unsafe { eviocgbit_key(file.as_raw_fd(), &mut bits) }.ok()?;
+    Some(bits)
+}
+
+// a plain keyboard, nothing but keys and their LEDs, scan codes and repeat
+fn only_keys(file: &File) -> bool {
+    // EV_MAX / 8 + 1
+    let mut bits = [0u8; 4];
+    if unsafe { eviocgbit_ev(file.as_raw_fd(), &mut bits) }.is_err() {
+        return false;
+    }
+    (0..32u16)
+        .filter(|&ty| bits[ty as usize / 8] & (1 << (ty % 8)) != 0)
+        .all(|ty| KEYBOARD_EVENTS.contains(&ty))
+}
+
+fn has_key(bits: &[u8; KEY_BYTES], code: u16) -> bool {
+    bits.get(code as usize / 8).is_some_and(|b| b & (1 << (code % 8)) != 0)
+}
+
+fn event_nodes() -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir("/sys/class/input") else { return Vec::new() };
+    let mut found = entries
+        .flatten()
+        .filter(|e| e.file_name().to_string_lossy().starts_with("event"))
+        .filter(|e| {
+            let name = fs::read_to_string(e.path().join("device/name")).unwrap_or_default();
+            name.trim() != OWN_DEVICE
+        })
+        .map(|e| Path::new("/dev/input").join(e.file_name()))
+        .collect::<Vec<_>>();
+    found.sort();
+    found
+}
+
+// A grabbed keyboard, whatever it sends goes out through the uinput device
+// with the remapped keys changed on the way.
+struct Grabbed {
+    file: File,
+    path: PathBuf,
+    keys: Vec<Key>,
+}
+
+// RemapKeys, taken over from every device that has one of the keys. Must be
+// opened before privilege drop, the devices are not found again later.
+pub struct KeyRemapper {
+    devices: Vec<Grabbed>,
+    map: Vec<(Key, Key)>,
+}
+
+impl KeyRemapper {
+    pub fn open(map: &[(Key, Key)]) -> Option<KeyRemapper> {
+        if map.is_empty() {
+            return None;
+        }
+        let mut devices = Vec::new();
+        for path in event_nodes() {
+            let file = match OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NONBLOCK)
+                .open(&path)
+            {
+                Ok(f) => f,
+                Err(e) => {
+                    eprintln!("[remap] failed to open {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let Some(bits) = key_bits(&file) else { continue };
+            if !map.iter().any(|(from, _)| has_key(&bits, *from as u16)) {
+                continue;
+            }
+            if !only_keys(&file) {
+                eprintln!("[remap] {} is more than a keyboard, leaving it alone", path.display());
+                continue;
+            }
+            if let Err(e) = unsafe { eviocgrab(file.as_raw_fd(), 1) } {
+                eprintln!("[remap] failed to grab {}: {}", path.display(), e);
+                continue;
+            }
+            let keys = (0..KEY_BYTES as u16 * 8)
+                .filter(|&code| has_key(&bits, code))
+                .filter_map(|code| Key::from_code(code).ok())
+                .collect();
+            eprintln!("[remap] took over {}", path.display());
+            devices.push(Grabbed { file, path, keys });
+        }
+        if devices.is_empty() {
+            eprintln!("[remap] no device has any of the keys in RemapKeys");
+            return None;
+        }
+        Some(KeyRemapper { devices, map: map.to_vec() })
+    }
+
+    pub fn fds(&self) -> Vec<BorrowedFd<'_>> {
+        self.devices.iter().map(|d| d.file.as_fd()).collect()
+    }
+
+    fn remapped(&self, key: Key) -> Key {
+        self.map.iter().find(|(from, _)| *from == key).map_or(key, |&(_, to)| to)
+    }
+
+    // every key the uinput device has to be able to send for the grabbed ones
+    pub fn keys(&self) -> Vec<Key> {
+        self.devices
+            .iter()
+            .flat_map(|d| &d.keys)
+            .map(|&k| self.remapped(k))
+            .collect()
+    }
+
+    // key presses, releases and repeats read since the last call, remapped
+    pub fn process_events(&mut self) -> Vec<(Key, i32)> {
+        let mut out = Vec::new();
+        let size = mem::size_of::<input_event>();
+        let mut gone = Vec::new();
+        for (i, dev) in self.devices.iter_mut().enumerate() {
+            let mut buf = [0u8; 64 * mem::size_of::<input_event>()];
+            loop {
+                let n = match dev.file.read(&mut buf) {
+                    Ok(0) => {
+                        gone.push(i);
+                        break;
+                    }
+                    Ok(n) => n,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        eprintln!("[remap] {} went away: {}", dev.path.display(), e);
+                        gone.push(i);
+                        break;
+                    }
+                };
+                for chunk in buf[..n].chunks_exact(size) {
+                    let ev: input_event = unsafe { ptr::read_unaligned(chunk.as_ptr() as *const _) };
+                    if ev.type_ != EV_KEY {
+                        continue;
+                    }
+                    if let Ok(key) = Key::from_code(ev.code) {
+                        out.push((key, ev.value));
+                    }
+                }
+            }
+        }
+        for i in gone.into_iter().rev() {
+            self.devices.remove(i);
+        }
+        out.into_iter().map(|(k, v)| (self.remapped(k), v)).collect()
+    }
+}
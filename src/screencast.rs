@@ -0,0 +1,133 @@
+use crate::bus;
+use dbus::{blocking::Connection, message::MessageType, Message, Path};
+use std::{
+    collections::HashMap,
+    os::unix::io::{AsFd, BorrowedFd},
+};
+
+// The portal does not publish a list of running casts, so we become a monitor on the
+// session bus and follow session handles from ScreenCast.Start until they get closed.
+// A session only counts once the Response to Start says it went ahead, and it is
+// dropped when the client that started it leaves the bus without closing it.
+const MONITOR_RULES: [&str; 8] = [
+    "type='method_call',interface='org.freedesktop.portal.ScreenCast',member='Start'",
+    // the Request object Start answers with, and the error if it failed
+    "type='method_return',sender='org.freedesktop.portal.Desktop'",
+    "type='error',sender='org.freedesktop.portal.Desktop'",
+    "type='signal',interface='org.freedesktop.portal.Request',member='Response'",
+    "type='method_call',interface='org.freedesktop.portal.Session',member='Close'",
+    "type='method_call',interface='org.freedesktop.impl.portal.Session',member='Close'",
+    "type='signal',interface='org.freedesktop.portal.Session',member='Closed'",
+    "type='signal',sender='org.freedesktop.DBus',member='NameOwnerChanged',arg2=''",
+];
+
+pub struct ScreenCastMonitor {
+    conn: Connection,
+    // Start calls waiting for their reply, by client and serial, to the session
+    starts: HashMap<(String, u32), String>,
+    // Requests waiting for their Response, to the session and its client
+    requests: HashMap<String, (String, String)>,
+    // running casts, to the client that started them
+    sessions: HashMap<String, String>,
+}
+
+impl ScreenCastMonitor {
+    // must be called before privilege drop
+    pub fn connect() -> Option<ScreenCastMonitor> {
+        let conn = match bus::open_session() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[screencast] session bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let proxy = conn.with_proxy("org.freedesktop.DBus", "/org/freedesktop/DBus", bus::CALL_TIMEOUT);
+        let res: Result<(), _> = proxy.method_call(
+            "org.freedesktop.DBus.Monitoring",
+            "BecomeMonitor",
+            (MONITOR_RULES.to_vec(), 0u32),
+        );
+        if let Err(e) = res {
+            eprintln!("[screencast] failed to become monitor: {}", e);
+            return None;
+        }
+        Some(ScreenCastMonitor {
+            conn,
+            starts: HashMap::new(),
+            requests: HashMap::new(),
+            sessions: HashMap::new(),
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.sessions.is_empty()
+    }
+
+    // the reply to a Start call, the Request its Response comes from
+    fn start_replied(&mut self, msg: &Message) {
+        let (Some(client), Some(serial)) = (msg.destination(), msg.get_reply_serial()) else { return };
+        let Some(session) = self.starts.remove(&(client.to_string(), serial)) else { return };
+        if let Ok(request) = msg.read1::<Path>() {
+            self.requests.insert(request.to_string(), (session, client.to_string()));
+        }
+    }
+
+    fn closed(&mut self, session: &str) {
+        self.sessions.remove(session);
+        self.requests.retain(|_, (s, _)| s != session);
+    }
+
+    // a client left the bus, its casts end with it
+    fn client_gone(&mut self, client: &str) {
+        self.starts.retain(|(c, _), _| c != client);
+        self.requests.retain(|_, (_, c)| c != client);
+        self.sessions.retain(|_, c| c != client);
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        let was_active = self.is_active();
+        for msg in bus::drain(&self.conn) {
+            let member = msg.member();
+            match (msg.msg_type(), member.as_deref()) {
+                (MessageType::MethodCall, Some("Start")) => {
+                    let (Some(client), Some(serial)) = (msg.sender(), msg.get_serial()) else { continue };
+                    if let Ok(handle) = msg.read1::<Path>() {
+                        self.starts.insert((client.to_string(), serial), handle.to_string());
+                    }
+                }
+                (MessageType::MethodReturn, _) => self.start_replied(&msg),
+                (MessageType::Error, _) => {
+                    if let (Some(client), Some(serial)) = (msg.destination(), msg.get_reply_serial()) {
+                        self.starts.remove(&(client.to_string(), serial));
+                    }
+                }
+                // 0 is success, anything else means the user cancelled or it failed
+                (MessageType::Signal, Some("Response")) => {
+                    let Some(path) = msg.path() else { continue };
+                    let Some((session, client)) = self.requests.remove(&*path) else { continue };
+                    if matches!(msg.read1::<u32>(), Ok(0)) {
+                        self.sessions.insert(session, client);
+                    }
+                }
+                (MessageType::Signal, Some("NameOwnerChanged")) => {
+                    if let Ok(name) = msg.read1::<&str>() {
+                        self.client_gone(name);
+                    }
+                }
+                (MessageType::MethodCall, Some("Close")) | (MessageType::Signal, Some("Closed")) => {
+                    if let Some(path) = msg.path() {
+                        self.closed(&path);
+                    }
+                }
+                _ => {}
+            }
+        }
+        was_active != self.is_active()
+    }
+}
+
+impl AsFd for ScreenCastMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
@@ -0,0 +1,289 @@
+use crate::{config::Theme, error, history};
+use anyhow::{anyhow, Result};
+use cairo::Context;
+use chrono::{Local, Timelike};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST};
+use serde_json::Value;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::Instant,
+};
+
+// keeps a runaway loop in a script from freezing the bar
+const MAX_OPERATIONS: u64 = 200_000;
+
+// what a script asked to draw, replayed on the real context once it returns
+#[derive(Clone)]
+enum Op {
+    Color(f64, f64, f64),
+    LineWidth(f64),
+    FontSize(f64),
+    MoveTo(f64, f64),
+    LineTo(f64, f64),
+    Rect(f64, f64, f64, f64),
+    Arc(f64, f64, f64, f64, f64),
+    Fill,
+    Stroke,
+    Text(f64, f64, String),
+    CenteredText(String),
+    Sparkline(Vec<f64>, Option<f64>),
+}
+
+// the ctx argument of draw(), scripts never get hold of cairo itself
+#[derive(Clone, Default)]
+struct Canvas(Rc<RefCell<Vec<Op>>>);
+
+impl Canvas {
+    fn push(&mut self, op: Op) {
+        self.0.borrow_mut().push(op);
+    }
+}
+
+// scripts may pass integers where a float is expected
+fn num(d: Dynamic) -> f64 {
+    d.as_float().or_else(|_| d.as_int().map(|i| i as f64)).unwrap_or(0.0)
+}
+
+fn register_canvas(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Canvas>("Canvas")
+        .register_fn("color", |c: &mut Canvas, rgb: Array| {
+            let v = |i: usize| rgb.get(i).cloned().map(num).unwrap_or(0.0);
+            c.push(Op::Color(v(0), v(1), v(2)));
+        })
+        .register_fn("line_width", |c: &mut Canvas, w: Dynamic| c.push(Op::LineWidth(num(w))))
+        .register_fn("font_size", |c: &mut Canvas, s: Dynamic| c.push(Op::FontSize(num(s))))
+        .register_fn("move_to", |c: &mut Canvas, x: Dynamic, y: Dynamic| {
+            c.push(Op::MoveTo(num(x), num(y)))
+        })
+        .register_fn("line_to", |c: &mut Canvas, x: Dynamic, y: Dynamic| {
+            c.push(Op::LineTo(num(x), num(y)))
+        })
+        .register_fn("rect", |c: &mut Canvas, x: Dynamic, y: Dynamic, w: Dynamic, h: Dynamic| {
+            c.push(Op::Rect(num(x), num(y), num(w), num(h)))
+        })
+        .register_fn(
+            "arc",
+            |c: &mut Canvas, x: Dynamic, y: Dynamic, r: Dynamic, from: Dynamic, to: Dynamic| {
+                c.push(Op::Arc(num(x), num(y), num(r), num(from), num(to)))
+            },
+        )
+        .register_fn("fill", |c: &mut Canvas| c.push(Op::Fill))
+        .register_fn("stroke", |c: &mut Canvas| c.push(Op::Stroke))
+        .register_fn("text", |c: &mut Canvas, x: Dynamic, y: Dynamic, s: &str| {
+            c.push(Op::Text(num(x), num(y), s.to_string()))
+        })
+        .register_fn("centered_text", |c: &mut Canvas, s: &str| {
+            c.push(Op::CenteredText(s.to_string()))
+        })
+        .register_fn("sparkline", |c: &mut Canvas, values: Array| {
+            c.push(Op::Sparkline(values.into_iter().map(num).collect(), None))
+        })
+        .register_fn("sparkline", |c: &mut Canvas, values: Array, max: Dynamic| {
+            c.push(Op::Sparkline(values.into_iter().map(num).collect(), Some(num(max))))
+        });
+}
+
+fn rgb((r, g, b): (f64, f64, f64)) -> Dynamic {
+    Dynamic::from_array(vec![r.into(), g.into(), b.into()])
+}
+
+fn theme_map(theme: &Theme) -> Map {
+    let mut map = Map::new();
+    map.insert("background".into(), rgb(theme.background));
+    map.insert("foreground".into(), rgb(theme.foreground));
+    map.insert("button_inactive".into(), rgb(theme.button_inactive));
+    map.insert("button_active".into(), rgb(theme.button_active));
+    map.insert("accent".into(), rgb(theme.accent));
+    map.insert("success".into(), rgb(theme.success));
+    map.insert("warning".into(), rgb(theme.warning));
+    map
+}
+
+// A button drawn by a Rhai script. The script's draw(ctx, state) function is
+// called every interval with the size of the button, the time and the theme,
+// and may keep values across calls in `this`.
+pub struct ScriptWidget {
+    engine: Engine,
+    ast: AST,
+    scope: RefCell<Scope<'static>>,
+    memory: RefCell<Dynamic>,
+    pub interval_ms: u32,
+    last_draw: Cell<Option<Instant>>,
+    // `this` was handed back from an earlier run or there was none
+    restored: Cell<bool>,
+    // `this` may have changed since it was last taken
+    drawn: Cell<bool>,
+}
+
+impl ScriptWidget {
+    pub fn load(path: &str, interval_ms: u32) -> Result<ScriptWidget> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        register_canvas(&mut engine);
+        let ast = engine
+            .compile_file(path.into())
+            .map_err(|e| anyhow!("{}: {}", path, e))?;
+        if !ast.iter_functions().any(|f| f.name == "draw" && f.params.len() == 2) {
+            return Err(anyhow!("{}: no draw(ctx, state) function", path));
+        }
+        Ok(ScriptWidget {
+            engine,
+            ast,
+            scope: RefCell::new(Scope::new()),
+            memory: RefCell::new(Dynamic::from_map(Map::new())),
+            interval_ms,
+            last_draw: Cell::new(None),
+            restored: Cell::new(false),
+            drawn: Cell::new(false),
+        })
+    }
+
+    // `this` as saved by an earlier widget of the same key, only before the
+    // first draw so a reload does not wind a running widget back
+    pub fn restore(&self, saved: Option<&Value>) {
+        if self.restored.replace(true) {
+            return;
+        }
+        if let Some(v) = saved {
+            *self.memory.borrow_mut() = from_json(v);
+        }
+    }
+
+    // `this` for the widget store, once after every draw
+    pub fn take_memory(&self) -> Option<Value> {
+        if !self.drawn.replace(false) {
+            return None;
+        }
+        Some(to_json(&self.memory.borrow()))
+    }
+
+    // time until the script wants to draw again, 0 when it is due
+    pub fn redraw_in_ms(&self) -> u128 {
+        match self.last_draw.get() {
+            Some(t) => (self.interval_ms as u128).saturating_sub(t.elapsed().as_millis()),
+            None => 0,
+        }
+    }
+
+    pub fn draw(
+        &self,
+        c: &Context,
+        // left edge of the button and y shift
+        (left, y_shift): (f64, f64),
+        width: f64,
+        height: f64,
+        active: bool,
+        theme: &Theme,
+    ) -> error::Result<()> {
+        self.last_draw.set(Some(Instant::now()));
+        self.drawn.set(true);
+        let now = Local::now();
+        let mut state = Map::new();
+        state.insert("width".into(), width.into());
+        state.insert("height".into(), height.into());
+        state.insert("active".into(), active.into());
+        state.insert("time_ms".into(), Dynamic::from_int(now.timestamp_millis()));
+        state.insert("hour".into(), Dynamic::from_int(now.hour() as i64));
+        state.insert("minute".into(), Dynamic::from_int(now.minute() as i64));
+        state.insert("second".into(), Dynamic::from_int(now.second() as i64));
+        state.insert("theme".into(), Dynamic::from_map(theme_map(theme)));
+
+        let canvas = Canvas::default();
+        let mut memory = self.memory.borrow_mut();
+        let options = CallFnOptions::new().bind_this_ptr(&mut *memory);
+        self.engine
+            .call_fn_with_options::<Dynamic>(
+                options,
+                &mut self.scope.borrow_mut(),
+                &self.ast,
+                "draw",
+                (canvas.clone(), state),
+            )
+            .map_err(|e| error::Error::Script(e.to_string()))?;
+
+        c.save()?;
+        c.rectangle(left, 0.0, width, height);
+        c.clip();
+        c.translate(left, y_shift);
+        c.new_path();
+        // sparklines take the last color set
+        let mut color = theme.accent;
+        for op in canvas.0.borrow().iter() {
+            match op {
+                Op::Color(r, g, b) => {
+                    color = (*r, *g, *b);
+                    c.set_source_rgb(*r, *g, *b);
+                }
+                Op::LineWidth(w) => c.set_line_width(*w),
+                Op::FontSize(s) => c.set_font_size(*s),
+                Op::MoveTo(x, y) => c.move_to(*x, *y),
+                Op::LineTo(x, y) => c.line_to(*x, *y),
+                Op::Rect(x, y, w, h) => c.rectangle(*x, *y, *w, *h),
+                Op::Arc(x, y, r, from, to) => {
+                    c.new_sub_path();
+                    c.arc(*x, *y, *r, *from, *to);
+                }
+                Op::Fill => c.fill()?,
+                Op::Stroke => c.stroke()?,
+                Op::Text(x, y, s) => {
+                    c.move_to(*x, *y);
+                    c.show_text(s)?;
+                }
+                Op::CenteredText(s) => {
+                    let extents = c.text_extents(s)?;
+                    c.move_to(
+                        (width / 2.0 - extents.width() / 2.0).round(),
+                        (height / 2.0 + extents.height() / 2.0).round(),
+                    );
+                    c.show_text(s)?;
+                }
+                Op::Sparkline(values, max) => {
+                    let area = (0.0, height * 0.2, width, height * 0.6);
+                    history::render_sparkline(c, values, *max, area, color)?;
+                    let (r, g, b) = color;
+                    c.set_source_rgb(r, g, b);
+                }
+            }
+        }
+        c.restore()?;
+        Ok(())
+    }
+}
+
+// rhai is built without serde, only the plain types make it to disk
+fn to_json(d: &Dynamic) -> Value {
+    if let Ok(b) = d.as_bool() {
+        Value::from(b)
+    } else if let Ok(i) = d.as_int() {
+        Value::from(i)
+    } else if let Ok(f) = d.as_float() {
+        Value::from(f)
+    } else if d.is_string() {
+        Value::from(d.clone().into_string().unwrap())
+    } else if d.is_array() {
+        Value::Array(d.clone().into_array().unwrap().iter().map(to_json).collect())
+    } else if d.is_map() {
+        let map = d.read_lock::<Map>().unwrap();
+        Value::Object(map.iter().map(|(k, v)| (k.to_string(), to_json(v))).collect())
+    } else {
+        Value::Null
+    }
+}
+
+fn from_json(v: &Value) -> Dynamic {
+    match v {
+        Value::Null => Dynamic::UNIT,
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Dynamic::from_int(i),
+            None => n.as_f64().unwrap_or(0.0).into(),
+        },
+        Value::String(s) => s.clone().into(),
+        Value::Array(a) => Dynamic::from_array(a.iter().map(from_json).collect()),
+        Value::Object(o) => {
+            Dynamic::from_map(o.iter().map(|(k, v)| (k.as_str().into(), from_json(v))).collect())
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{bus, ipc::Request};
+use dbus::{blocking::Connection, message::MessageType, strings::ErrorName, Message};
+use std::{
+    ffi::CString,
+    os::unix::io::{AsFd, BorrowedFd},
+};
+
+const NAME: &str = "org.gmt.Dfr";
+const PATH: &str = "/org/gmt/Dfr";
+const INTROSPECT: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.gmt.Dfr">
+    <method name="SetLayer"><arg name="name" type="s" direction="in"/></method>
+    <method name="SetBrightness"><arg name="brightness" type="u" direction="in"/></method>
+    <method name="Notify">
+      <arg name="text" type="s" direction="in"/>
+      <arg name="timeout_ms" type="u" direction="in"/>
+    </method>
+    <method name="Reload"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect"><arg name="xml" type="s" direction="out"/></method>
+  </interface>
+</node>
+"#;
+
+// org.gmt.Dfr on the session bus, the same requests as the control socket
+// for tools that would rather speak D-Bus. Calls are answered once the main
+// loop has handled them, through `reply`.
+pub struct ControlService {
+    conn: Connection,
+}
+
+impl ControlService {
+    // must be called before privilege drop
+    pub fn connect() -> Option<ControlService> {
+        let conn = match bus::open_session() {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[service] session bus unavailable: {}", e);
+                return None;
+            }
+        };
+        if let Err(e) = conn.request_name(NAME, false, true, true) {
+            eprintln!("[service] failed to own {}: {}", NAME, e);
+            return None;
+        }
+        Some(ControlService { conn })
+    }
+
+    fn parse(msg: &Message) -> Result<Request, String> {
+        let member = msg.member().map(|m| m.to_string()).unwrap_or_default();
+        let bad_args = |e: dbus::arg::TypeMismatchError| format!("invalid arguments: {}", e);
+        match member.as_str() {
+            "SetLayer" => msg.read1::<String>().map(Request::SetLayer).map_err(bad_args),
+            "SetBrightness" => msg.read1::<u32>().map(Request::SetBrightness).map_err(bad_args),
+            "Notify" => msg
+                .read2::<String, u32>()
+                .map(|(text, timeout)| Request::Notify(text, timeout))
+                .map_err(bad_args),
+            "Reload" => Ok(Request::Reset),
+            _ => Err(format!("unknown method {}", member)),
+        }
+    }
+
+    pub fn process_events(&self) -> Vec<(Request, Message)> {
+        let mut requests = Vec::new();
+        for msg in bus::drain(&self.conn) {
+            if msg.msg_type() != MessageType::MethodCall
+                || msg.path().as_deref() != Some(PATH)
+            {
+                continue;
+            }
+            let interface = msg.interface().map(|i| i.to_string());
+            match interface.as_deref() {
+                Some("org.freedesktop.DBus.Introspectable") => {
+                    self.send(msg.method_return().append1(INTROSPECT));
+                }
+                Some(NAME) | None => match Self::parse(&msg) {
+                    Ok(req) => requests.push((req, msg)),
+                    Err(e) => self.reply(&msg, Err(e)),
+                },
+                _ => self.reply(&msg, Err("unknown interface".to_string())),
+            }
+        }
+        requests
+    }
+
+    pub fn reply(&self, call: &Message, response: Result<serde_json::Value, String>) {
+        let msg = match response {
+            Ok(_) => call.method_return(),
+            Err(e) => {
+                let text = CString::new(e).unwrap_or_default();
+                call.error(&ErrorName::from("org.gmt.Dfr.Error.Failed"), &text)
+            }
+        };
+        self.send(msg);
+    }
+
+    fn send(&self, msg: Message) {
+        let channel = self.conn.channel();
+        let _ = channel.send(msg);
+        channel.flush();
+    }
+}
+
+impl AsFd for ControlService {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
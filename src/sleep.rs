@@ -0,0 +1,85 @@
+use crate::bus;
+use dbus::{arg::OwnedFd, blocking::Connection, channel::BusType};
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER: &str = "org.freedesktop.login1.Manager";
+
+// Follows logind's PrepareForSleep. A delay inhibitor is held while awake so
+// suspend waits until the bar is dark, and is taken again after resume.
+pub struct SleepMonitor {
+    conn: Connection,
+    inhibitor: Option<OwnedFd>,
+}
+
+impl SleepMonitor {
+    // connected before privilege drop, the first lock is taken as root
+    pub fn connect() -> Option<SleepMonitor> {
+        let conn = match bus::open(BusType::System) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[sleep] system bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let rule = format!(
+            "type='signal',interface='{}',member='PrepareForSleep',path='{}'",
+            LOGIND_MANAGER, LOGIND_PATH
+        );
+        if let Err(e) = conn.add_match_no_cb(&rule) {
+            eprintln!("[sleep] failed to subscribe: {}", e);
+            return None;
+        }
+        let mut monitor = SleepMonitor { conn, inhibitor: None };
+        monitor.inhibit();
+        Some(monitor)
+    }
+
+    fn inhibit(&mut self) {
+        let proxy = self.conn.with_proxy(LOGIND_DEST, LOGIND_PATH, bus::CALL_TIMEOUT);
+        let lock: Result<(OwnedFd,), _> = proxy.method_call(
+            LOGIND_MANAGER,
+            "Inhibit",
+            ("sleep", "tiny-dfr", "Turn the touch bar off", "delay"),
+        );
+        match lock {
+            Ok((fd,)) => self.inhibitor = Some(fd),
+            Err(e) => eprintln!("[sleep] failed to take a delay lock: {}", e),
+        }
+    }
+
+    // Some(true) when the system is about to sleep, Some(false) once it woke
+    // up, the last of the queued signals wins
+    pub fn process_events(&mut self) -> Option<bool> {
+        let mut sleeping = None;
+        for msg in bus::drain(&self.conn) {
+            if msg.interface().as_deref() != Some(LOGIND_MANAGER)
+                || msg.member().as_deref() != Some("PrepareForSleep")
+            {
+                continue;
+            }
+            if let Ok(start) = msg.read1::<bool>() {
+                sleeping = Some(start);
+            }
+        }
+        sleeping
+    }
+
+    // lets the suspend go ahead, the bar is dark by now
+    pub fn ready(&mut self) {
+        self.inhibitor = None;
+    }
+
+    pub fn resumed(&mut self) {
+        if self.inhibitor.is_none() {
+            self.inhibit();
+        }
+    }
+}
+
+impl AsFd for SleepMonitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}
@@ -0,0 +1,146 @@
+use drm::control::ClipRect;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+const RECENT_FRAMES: usize = 64;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RedrawCause {
+    Config,
+    Clock,
+    Battery,
+    Compositor,
+    PixelShift,
+    LivePoll,
+    Animation,
+    Popup,
+    Input,
+    Ipc,
+    // a monitor (network, bluetooth, ...) changed a single button
+    Widget,
+}
+
+#[derive(Serialize, Clone)]
+struct Frame {
+    render_us: u64,
+    damage_px: u64,
+    complete: bool,
+    causes: Vec<RedrawCause>,
+}
+
+#[derive(Default)]
+pub struct FrameStats {
+    recent: VecDeque<Frame>,
+    frames: u64,
+    wakeups: u64,
+    render_us_total: u64,
+    render_us_max: u64,
+    by_cause: BTreeMap<RedrawCause, u64>,
+    // collected since the last drawn frame
+    pending: Vec<RedrawCause>,
+}
+
+impl FrameStats {
+    pub fn cause(&mut self, cause: RedrawCause) {
+        if !self.pending.contains(&cause) {
+            self.pending.push(cause);
+        }
+    }
+
+    // nothing was drawn, the causes collected so far did not change anything
+    pub fn discard(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn wakeup(&mut self) {
+        self.wakeups += 1;
+    }
+
+    pub fn frame(&mut self, render_time: Duration, clips: &[ClipRect], complete: bool) {
+        if self.pending.is_empty() {
+            self.pending.push(RedrawCause::Widget);
+        }
+        let render_us = render_time.as_micros() as u64;
+        let damage_px = clips
+            .iter()
+            .map(|c| (c.x2().saturating_sub(c.x1()) as u64) * (c.y2().saturating_sub(c.y1()) as u64))
+            .sum();
+        for cause in &self.pending {
+            *self.by_cause.entry(*cause).or_default() += 1;
+        }
+        self.frames += 1;
+        self.render_us_total += render_us;
+        self.render_us_max = self.render_us_max.max(render_us);
+        if self.recent.len() == RECENT_FRAMES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Frame {
+            render_us,
+            damage_px,
+            complete,
+            causes: std::mem::take(&mut self.pending),
+        });
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "frames": self.frames,
+            "wakeups": self.wakeups,
+            "render_us_avg": self.render_us_total.checked_div(self.frames).unwrap_or(0),
+            "render_us_max": self.render_us_max,
+            "by_cause": self.by_cause.iter().map(|(c, n)| (format!("{:?}", c), *n)).collect::<BTreeMap<_, _>>(),
+            "recent": self.recent,
+        })
+    }
+}
+
+// Opt-in log of every main loop wakeup, started over the control socket.
+// Each wakeup is charged to every epoll source that was ready, or to the timeout.
+pub struct WakeupAudit {
+    started: Instant,
+    duration: Duration,
+    // wakeups and time spent handling them
+    by_source: BTreeMap<&'static str, (u64, Duration)>,
+}
+
+impl WakeupAudit {
+    pub fn new(minutes: u32) -> WakeupAudit {
+        eprintln!("[audit] logging wakeups for {} minutes", minutes);
+        WakeupAudit {
+            started: Instant::now(),
+            duration: Duration::from_secs(minutes as u64 * 60),
+            by_source: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, sources: &[&'static str], busy: Duration) {
+        eprintln!("[audit] wakeup {} handled in {}us", sources.join(","), busy.as_micros());
+        for source in sources {
+            let entry = self.by_source.entry(source).or_default();
+            entry.0 += 1;
+            entry.1 += busy;
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.started.elapsed() >= self.duration
+    }
+
+    pub fn print_summary(&self) {
+        let minutes = self.started.elapsed().as_secs_f64() / 60.0;
+        eprintln!("[audit] summary over {:.1} minutes:", minutes);
+        for (source, (count, busy)) in &self.by_source {
+            eprintln!(
+                "[audit]   {:<12} {:>6} wakeups {:>8.1}/min {:>8}us avg",
+                source,
+                count,
+                *count as f64 / minutes.max(1.0 / 60.0),
+                busy.as_micros() as u64 / count
+            );
+        }
+    }
+}
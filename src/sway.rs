@@ -0,0 +1,320 @@
+use crate::compositor::{self, CompositorBackend, Handoff, Output, Workspace};
+use serde_json::Value;
+use std::{
+    io::{ErrorKind, Read, Write},
+    os::unix::{
+        io::{AsFd, BorrowedFd, OwnedFd},
+        net::UnixStream,
+    },
+    path::PathBuf,
+};
+
+// i3 IPC, spoken by both sway and i3
+const MAGIC: &[u8] = b"i3-ipc";
+const HEADER_LEN: usize = 14;
+const RUN_COMMAND: u32 = 0;
+const GET_WORKSPACES: u32 = 1;
+const SUBSCRIBE: u32 = 2;
+const GET_OUTPUTS: u32 = 3;
+const GET_TREE: u32 = 4;
+const EVENT_WORKSPACE: u32 = 0x8000_0000;
+const EVENT_WINDOW: u32 = 0x8000_0003;
+
+#[derive(Default)]
+pub struct SwayState {
+    workspaces: Vec<Workspace>,
+    focused_window_title: Option<String>,
+    focused_app_id: Option<String>,
+    // opened before privilege drop, i3 IPC connections stay usable for any number of requests
+    command_stream: Option<UnixStream>,
+    event_stream: Option<UnixStream>,
+    // partial frames read from the non-blocking event stream
+    event_buf: Vec<u8>,
+    // sway closed the event stream, it exited or restarted
+    lost: bool,
+}
+
+fn find_socket() -> Option<PathBuf> {
+    for var in ["SWAYSOCK", "I3SOCK"] {
+        if let Ok(p) = std::env::var(var) {
+            let path = PathBuf::from(p);
+            if path.exists() {
+                return Some(path);
+            }
+        }
+    }
+    // glob all uid dirs because we may be running as root
+    let uid_dirs = std::fs::read_dir("/run/user").ok()?;
+    for uid_dir in uid_dirs.flatten() {
+        let i3_dir = uid_dir.path().join("i3");
+        let entries = std::fs::read_dir(uid_dir.path())
+            .into_iter()
+            .chain(std::fs::read_dir(i3_dir))
+            .flatten()
+            .flatten();
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if (name.starts_with("sway-ipc.") && name.ends_with(".sock"))
+                || name.starts_with("ipc-socket.")
+            {
+                return Some(entry.path());
+            }
+        }
+    }
+    None
+}
+
+fn encode(msg_type: u32, payload: &str) -> Vec<u8> {
+    let mut msg = MAGIC.to_vec();
+    msg.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    msg.extend_from_slice(&msg_type.to_ne_bytes());
+    msg.extend_from_slice(payload.as_bytes());
+    msg
+}
+
+// (type, payload) of the first complete frame in buf, and how many bytes it took
+fn decode(buf: &[u8]) -> Option<(u32, &[u8], usize)> {
+    if buf.len() < HEADER_LEN || &buf[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    let len = u32::from_ne_bytes(buf[6..10].try_into().ok()?) as usize;
+    let msg_type = u32::from_ne_bytes(buf[10..14].try_into().ok()?);
+    let end = HEADER_LEN + len;
+    if buf.len() < end {
+        return None;
+    }
+    Some((msg_type, &buf[HEADER_LEN..end], end))
+}
+
+fn request(stream: &mut UnixStream, msg_type: u32, payload: &str) -> Option<Value> {
+    stream.write_all(&encode(msg_type, payload)).ok()?;
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).ok()?;
+    let len = u32::from_ne_bytes(header[6..10].try_into().ok()?) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+fn find_focused(node: &Value) -> Option<&Value> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+    ["nodes", "floating_nodes"]
+        .iter()
+        .filter_map(|k| node[*k].as_array())
+        .flatten()
+        .find_map(find_focused)
+}
+
+// workspaces and outputs can be focused too, only windows have a title worth showing
+fn window_title(node: &Value) -> Option<String> {
+    if !matches!(node["type"].as_str(), Some("con") | Some("floating_con")) {
+        return None;
+    }
+    node["name"].as_str().map(|t| t.to_string())
+}
+
+// sway only sets app_id for wayland clients, X11 ones (and everything on i3) have a class
+fn window_app_id(node: &Value) -> Option<String> {
+    window_title(node)?;
+    node["app_id"]
+        .as_str()
+        .or_else(|| node["window_properties"]["class"].as_str())
+        .map(|a| a.to_string())
+}
+
+// The event stream, already subscribed, and the command stream. Must be
+// called before privilege drop or by the session user.
+pub fn handoff() -> Option<Handoff> {
+    let socket = find_socket()?;
+    let mut events = UnixStream::connect(&socket).ok()?;
+    let ack = request(&mut events, SUBSCRIBE, r#"["workspace","window"]"#)?;
+    if ack["success"].as_bool() != Some(true) {
+        eprintln!("[sway] subscribe failed: {}", ack);
+        return None;
+    }
+    let mut fds = vec![OwnedFd::from(events)];
+    fds.extend(UnixStream::connect(&socket).ok().map(OwnedFd::from));
+    Some(Handoff { socket, extra: Value::Null, fds })
+}
+
+impl SwayState {
+    pub fn from_handoff(handoff: Handoff) -> Option<SwayState> {
+        eprintln!("[sway] socket: {}", handoff.socket.display());
+        let mut fds = handoff.fds.into_iter().map(UnixStream::from);
+        let events = fds.next()?;
+        let _ = events.set_nonblocking(true);
+
+        let mut state = SwayState {
+            command_stream: fds.next(),
+            event_stream: Some(events),
+            ..Default::default()
+        };
+        state.refresh_workspaces();
+        if let Some(tree) = state.command(GET_TREE, "") {
+            let focused = find_focused(&tree);
+            state.focused_window_title = focused.and_then(window_title);
+            state.focused_app_id = focused.and_then(window_app_id);
+        }
+
+        eprintln!("[sway] ready: {} workspaces window: {:?}",
+            state.workspaces.len(), state.focused_window_title);
+        Some(state)
+    }
+
+    fn command(&mut self, msg_type: u32, payload: &str) -> Option<Value> {
+        let stream = self.command_stream.as_mut()?;
+        let reply = request(stream, msg_type, payload);
+        if reply.is_none() {
+            eprintln!("[sway] command socket failed");
+            self.command_stream = None;
+        }
+        reply
+    }
+
+    fn refresh_workspaces(&mut self) -> bool {
+        let Some(Value::Array(arr)) = self.command(GET_WORKSPACES, "") else {
+            return false;
+        };
+        let mut new_ws: Vec<Workspace> = arr
+            .iter()
+            .filter_map(|w| {
+                // named workspaces without a number have num -1
+                let num = w["num"].as_i64().filter(|n| *n > 0)?;
+                Some(Workspace {
+                    id: w["id"].as_u64().unwrap_or(num as u64),
+                    idx: num.min(u8::MAX as i64) as u8,
+                    is_focused: w["focused"].as_bool().unwrap_or(false),
+                    occupied: true,
+                    urgent: false,
+                    name: None,
+                })
+            })
+            .collect();
+        new_ws.sort_by_key(|w| w.idx);
+        let same = new_ws.len() == self.workspaces.len()
+            && new_ws.iter().zip(&self.workspaces).all(|(a, b)| {
+                a.id == b.id && a.idx == b.idx && a.is_focused == b.is_focused
+            });
+        if !same {
+            self.workspaces = new_ws;
+        }
+        !same
+    }
+
+    fn apply_window_event(&mut self, event: &Value) -> bool {
+        let container = &event["container"];
+        let focused = container["focused"].as_bool() == Some(true);
+        let (title, app_id) = match event["change"].as_str() {
+            Some("focus") | Some("title") if focused => {
+                (window_title(container), window_app_id(container))
+            }
+            Some("close") if focused => (None, None),
+            _ => return false,
+        };
+        let changed = title != self.focused_window_title || app_id != self.focused_app_id;
+        self.focused_window_title = title;
+        self.focused_app_id = app_id;
+        changed
+    }
+}
+
+impl CompositorBackend for SwayState {
+    fn event_fd(&self) -> Option<BorrowedFd<'_>> {
+        self.event_stream.as_ref().map(|s| s.as_fd())
+    }
+
+    fn workspaces(&self) -> &[Workspace] {
+        &self.workspaces
+    }
+
+    fn focused_window_title(&self) -> Option<&str> {
+        self.focused_window_title.as_deref()
+    }
+
+    fn focused_app_id(&self) -> Option<&str> {
+        self.focused_app_id.as_deref()
+    }
+
+    fn process_events(&mut self) -> bool {
+        let Some(ref mut stream) = self.event_stream else { return false };
+        if self.lost {
+            return false;
+        }
+        let mut chunk = [0u8; 4096];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    eprintln!("[sway] event stream closed");
+                    self.lost = true;
+                    break;
+                }
+                Ok(n) => self.event_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("[sway] event stream broke: {}", e);
+                    self.lost = true;
+                    break;
+                }
+            }
+        }
+        let mut events = Vec::new();
+        while let Some((msg_type, payload, used)) = decode(&self.event_buf) {
+            events.push((msg_type, serde_json::from_slice::<Value>(payload).ok()));
+            self.event_buf.drain(..used);
+        }
+        let mut changed = false;
+        let mut workspaces_dirty = false;
+        for (msg_type, event) in events {
+            match (msg_type, event) {
+                // the event only describes one workspace, asking for all of them is simpler
+                (EVENT_WORKSPACE, _) => workspaces_dirty = true,
+                (EVENT_WINDOW, Some(event)) => changed |= self.apply_window_event(&event),
+                _ => {}
+            }
+        }
+        if workspaces_dirty {
+            changed |= self.refresh_workspaces();
+        }
+        changed
+    }
+
+    fn focus_workspace(&mut self, idx: u8) {
+        self.command(RUN_COMMAND, &format!("workspace number {}", idx));
+    }
+
+    fn move_window_to_workspace(&mut self, idx: u8) {
+        self.command(RUN_COMMAND, &format!("move container to workspace number {}", idx));
+    }
+
+    fn release_output(&mut self, name: &str) {
+        self.set_output_enabled(name, false);
+    }
+
+    fn outputs(&mut self) -> Vec<Output> {
+        let Some(Value::Array(arr)) = self.command(GET_OUTPUTS, "") else { return Vec::new() };
+        arr.iter()
+            .filter_map(|o| {
+                Some(Output {
+                    name: o["name"].as_str()?.to_string(),
+                    enabled: o["active"].as_bool().unwrap_or(false),
+                })
+            })
+            .collect()
+    }
+
+    fn set_output_enabled(&mut self, name: &str, enabled: bool) {
+        let state = if enabled { "enable" } else { "disable" };
+        self.command(RUN_COMMAND, &format!("output {} {}", name, state));
+    }
+
+    fn spawn(&mut self, command: &[String]) {
+        self.command(RUN_COMMAND, &format!("exec {}", compositor::shell_join(command)));
+    }
+
+    fn disconnected(&self) -> bool {
+        self.lost
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const HWMON_PATH: &str = "/sys/class/hwmon";
+
+// jiffies spent busy and in total since boot, from the first line of /proc/stat
+fn cpu_times() -> Option<(u64, u64)> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let fields = stat
+        .lines()
+        .next()?
+        .split_whitespace()
+        .skip(1)
+        .map(|f| f.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    // user nice system idle iowait irq softirq steal, guest time is counted in user already
+    let total: u64 = fields.iter().take(8).sum();
+    let idle = fields.get(3)? + fields.get(4).copied().unwrap_or(0);
+    Some((total.saturating_sub(idle), total))
+}
+
+// usage is a rate, every sample is measured against the previous one
+pub struct CpuSampler {
+    last: Option<(u64, u64)>,
+}
+
+impl CpuSampler {
+    pub fn new() -> CpuSampler {
+        CpuSampler { last: cpu_times() }
+    }
+
+    // percent busy since the last call
+    pub fn sample(&mut self) -> Option<u32> {
+        let now = cpu_times()?;
+        let (busy, total) = self.last.replace(now)?;
+        let elapsed = now.1.checked_sub(total).filter(|&t| t > 0)?;
+        Some((now.0.saturating_sub(busy) * 100 / elapsed).min(100) as u32)
+    }
+}
+
+// memory in use, page cache that can be dropped does not count
+pub fn memory_percent() -> Option<u32> {
+    let info = fs::read_to_string("/proc/meminfo").ok()?;
+    let field = |name: &str| {
+        info.lines()
+            .find(|l| l.starts_with(name))?
+            .split_whitespace()
+            .nth(1)?
+            .parse::<u64>()
+            .ok()
+    };
+    let total = field("MemTotal:").filter(|&t| t > 0)?;
+    let available = field("MemAvailable:")?.min(total);
+    Some(((total - available) * 100 / total) as u32)
+}
+
+// the one minute load average
+pub fn load_avg() -> Option<f64> {
+    fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+// A temp*_input file given directly, or the first sensor whose label (or
+// hwmon chip name) matches, like "Tdie", "coretemp" or "macsmc_hwmon".
+pub fn find_temperature_sensor(sensor: &str) -> Option<PathBuf> {
+    if sensor.starts_with('/') {
+        return Some(PathBuf::from(sensor));
+    }
+    let read = |p: &Path| fs::read_to_string(p).ok().map(|s| s.trim().to_string());
+    let mut chips = fs::read_dir(HWMON_PATH).ok()?.flatten().map(|e| e.path()).collect::<Vec<_>>();
+    chips.sort();
+    for chip in chips {
+        let Ok(entries) = fs::read_dir(&chip) else { continue };
+        let mut inputs = entries
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|n| n.starts_with("temp") && n.ends_with("_input"))
+            .collect::<Vec<_>>();
+        inputs.sort();
+        let chip_matches = read(&chip.join("name")).as_deref() == Some(sensor);
+        for input in inputs {
+            let label = chip.join(input.replace("_input", "_label"));
+            if chip_matches || read(&label).as_deref() == Some(sensor) {
+                return Some(chip.join(input));
+            }
+        }
+    }
+    None
+}
+
+// degrees Celsius, hwmon reports millidegrees
+pub fn read_temperature(path: &Path) -> Option<f64> {
+    let milli: f64 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(milli / 1000.0)
+}
@@ -0,0 +1,52 @@
+use cairo::Context;
+use pango::{EllipsizeMode, FontDescription, Layout, SCALE};
+
+// A line of text laid out by pango. Unlike cairo's toy text API it shapes
+// complex scripts, orders right to left runs and takes glyphs the
+// FontTemplate lacks, emoji and Nerd Font icons too, from other fonts.
+pub struct Line {
+    layout: Layout,
+}
+
+impl Line {
+    // in `font` at the size last given to set_font_size on `c`
+    pub fn new(c: &Context, font: &FontDescription, text: &str) -> Line {
+        let layout = pangocairo::functions::create_layout(c);
+        let mut font = font.clone();
+        font.set_absolute_size(c.font_matrix().yy() * SCALE as f64);
+        layout.set_font_description(Some(&font));
+        layout.set_text(text);
+        Line { layout }
+    }
+
+    // cut with an ellipsis at the end when wider than `width`
+    pub fn ellipsized(self, width: f64) -> Line {
+        self.layout.set_width((width * SCALE as f64) as i32);
+        self.layout.set_ellipsize(EllipsizeMode::End);
+        self
+    }
+
+    fn ink(&self) -> pango::Rectangle {
+        self.layout.extents().0
+    }
+
+    // of the ink, like the extents of show_text
+    pub fn width(&self) -> f64 {
+        self.ink().width() as f64 / SCALE as f64
+    }
+
+    pub fn height(&self) -> f64 {
+        self.ink().height() as f64 / SCALE as f64
+    }
+
+    // with the ink starting at the current point on the baseline, like show_text
+    pub fn show(&self, c: &Context) -> Result<(), cairo::Error> {
+        let (x, y) = c.current_point()?;
+        c.move_to(
+            x - self.ink().x() as f64 / SCALE as f64,
+            y - self.layout.baseline() as f64 / SCALE as f64,
+        );
+        pangocairo::functions::show_layout(c, &self.layout);
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use crate::bus;
+use dbus::{
+    blocking::{
+        stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged},
+        Connection,
+    },
+    channel::BusType,
+    message::SignalArgs,
+};
+use chrono_tz::Tz;
+use std::os::unix::io::{AsFd, BorrowedFd};
+
+const TIMEDATE_DEST: &str = "org.freedesktop.timedate1";
+const TIMEDATE_PATH: &str = "/org/freedesktop/timedate1";
+
+pub struct TimezoneWatcher {
+    conn: Connection,
+    current: Option<Tz>,
+}
+
+impl TimezoneWatcher {
+    // timedated is on the system bus so this works as nobody too,
+    // but it is connected alongside niri before privilege drop anyway
+    pub fn connect() -> Option<TimezoneWatcher> {
+        let conn = match bus::open(BusType::System) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("[timezone] system bus unavailable: {}", e);
+                return None;
+            }
+        };
+        let rule = format!(
+            "type='signal',interface='org.freedesktop.DBus.Properties',\
+             member='PropertiesChanged',path='{}',arg0='{}'",
+            TIMEDATE_PATH, TIMEDATE_DEST
+        );
+        if let Err(e) = conn.add_match_no_cb(&rule) {
+            eprintln!("[timezone] failed to subscribe: {}", e);
+            return None;
+        }
+        let mut watcher = TimezoneWatcher { conn, current: None };
+        watcher.refresh();
+        eprintln!("[timezone] following timedated: {:?}", watcher.current);
+        Some(watcher)
+    }
+
+    // the zone timedated reports, None until it reported a known one
+    pub fn zone(&self) -> Option<Tz> {
+        self.current
+    }
+
+    fn refresh(&mut self) -> bool {
+        let proxy = self.conn.with_proxy(TIMEDATE_DEST, TIMEDATE_PATH, bus::CALL_TIMEOUT);
+        match proxy.get::<String>(TIMEDATE_DEST, "Timezone") {
+            Ok(tz) => self.apply(tz),
+            Err(e) => {
+                eprintln!("[timezone] failed to read Timezone: {}", e);
+                false
+            }
+        }
+    }
+
+    // The process environment is left alone, other threads read TZ through
+    // libc, the Time widget is handed the zone instead.
+    fn apply(&mut self, tz: String) -> bool {
+        let zone = match tz.parse::<Tz>() {
+            Ok(zone) => zone,
+            Err(e) => {
+                eprintln!("[timezone] unknown timezone {:?}: {}", tz, e);
+                return false;
+            }
+        };
+        if self.current == Some(zone) {
+            return false;
+        }
+        self.current = Some(zone);
+        true
+    }
+
+    pub fn process_events(&mut self) -> bool {
+        let mut changed = false;
+        for msg in bus::drain(&self.conn) {
+            let Some(sig) = PropertiesPropertiesChanged::from_message(&msg) else {
+                continue;
+            };
+            if sig.interface_name != TIMEDATE_DEST {
+                continue;
+            }
+            if let Some(tz) = dbus::arg::prop_cast::<String>(&sig.changed_properties, "Timezone") {
+                changed |= self.apply(tz.clone());
+            } else if sig.invalidated_properties.iter().any(|p| p == "Timezone") {
+                changed |= self.refresh();
+            }
+        }
+        changed
+    }
+}
+
+impl AsFd for TimezoneWatcher {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        bus::fd(&self.conn)
+    }
+}